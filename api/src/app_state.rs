@@ -1,6 +1,7 @@
-use crate::services::stripe::StripeService;
+use crate::services::{signing_keys::SigningKeyService, stripe::StripeService};
 use crate::{config::Config, services::resend::ResendService};
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
 
 #[derive(Clone)]
 pub struct AppState {
@@ -9,4 +10,7 @@ pub struct AppState {
     pub config: Config,
     pub stripe: Arc<StripeService>,
     pub resend: Arc<ResendService>,
+    pub signing_keys: Arc<SigningKeyService>,
+    /// Set once shutdown starts so readiness reports false
+    pub draining: Arc<AtomicBool>,
 }
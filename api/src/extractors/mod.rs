@@ -3,4 +3,5 @@ pub mod database_connection;
 pub mod redis_connection;
 pub mod resend;
 pub mod session;
+pub mod signing_keys;
 pub mod stripe;
@@ -0,0 +1,23 @@
+use axum::{
+    extract::{FromRef, FromRequestParts},
+    http::request::Parts,
+    response::Response,
+};
+use std::sync::Arc;
+
+use crate::{app_state::AppState, services::signing_keys::SigningKeyService};
+
+pub struct SigningKeys(pub Arc<SigningKeyService>);
+
+impl<S> FromRequestParts<S> for SigningKeys
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(_parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let state = AppState::from_ref(state);
+        Ok(Self(state.signing_keys.clone()))
+    }
+}
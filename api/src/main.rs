@@ -1,12 +1,13 @@
 use axum::{
-    Router,
+    Router, middleware,
     routing::{delete, get, post, put},
 };
 use config::Config;
 use dotenvy::dotenv;
 use sqlx::postgres::PgPoolOptions;
 use std::sync::Arc;
-use tokio::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::{io, signal, time::sleep};
 use tower_http::cors::CorsLayer;
 use tracing::{error, info};
 use tracing_subscriber::{EnvFilter, fmt, prelude::*, registry};
@@ -15,27 +16,38 @@ mod app_state;
 mod config;
 mod error;
 mod extractors;
+mod refresh_token;
 mod routes;
 mod services;
 mod session;
+mod telemetry;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
 
+    let config = Config::from_env().expect("Failed to load configuration");
+
     let filter_layer = EnvFilter::from_default_env();
     let fmt_layer = fmt::layer().with_target(false).with_line_number(true);
 
-    registry().with(filter_layer).with(fmt_layer).init();
+    let tracer_provider = telemetry::setup_tracer_provider(&config)?;
+    let otel_layer = tracer_provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(telemetry::tracer(provider)));
 
-    let config = Config::from_env().expect("Failed to load configuration");
+    registry()
+        .with(filter_layer)
+        .with(fmt_layer)
+        .with(otel_layer)
+        .init();
 
     let db = PgPoolOptions::new()
         .connect(&config.database_url)
         .await
         .map_err(|e| {
             error!("Failed to connect to database: {e:?}");
-            io::Error::new(io::ErrorKind::Other, "Failed to connect to database")
+            io::Error::other("Failed to connect to database")
         })?;
 
     let redis_client = redis::Client::open(config.redis_url.clone())?;
@@ -46,9 +58,19 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let stripe = Arc::new(services::stripe::StripeService::new(&config)?);
     let resend = Arc::new(services::resend::ResendService::new(&config)?);
 
+    // Make sure a key can sign before serving, then keep rotating in the background
+    let signing_keys = Arc::new(services::signing_keys::SigningKeyService::new(
+        db.clone(),
+        &config,
+    ));
+    signing_keys.rotate().await?;
+    signing_keys.clone().start_rotation();
+    refresh_token::start_pruning(db.clone());
+
     let jwt_router = Router::new()
         .route("/issue", post(routes::jwt::issue))
-        .route("/refresh", post(routes::jwt::refresh));
+        .route("/refresh", post(routes::jwt::refresh))
+        .route("/revoke", post(routes::jwt::revoke));
 
     let auth_router = Router::new()
         .route("/login", post(routes::auth::login))
@@ -62,7 +84,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .route("/", post(routes::apps::create))
         .route("/", get(routes::apps::list))
         .route("/{app_id}", post(routes::apps::edit))
-        .route("/{app_id}", delete(routes::apps::delete));
+        .route("/{app_id}", delete(routes::apps::delete))
+        .route("/{app_id}/keys", get(routes::apps::list_keys))
+        .route("/{app_id}/keys", post(routes::apps::add_key))
+        .route("/{app_id}/keys/{kid}", delete(routes::apps::delete_key))
+        .route("/{app_id}/rate-limits", get(routes::apps::get_rate_limits))
+        .route("/{app_id}/rate-limits", post(routes::apps::set_rate_limits));
 
     let invites_router = Router::new()
         .route("/", get(routes::invites::list_invites))
@@ -116,6 +143,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .route(
             "/organizations/{organization_id}/summary",
             get(routes::metrics::get_organization_metrics),
+        )
+        .route(
+            "/organizations/{organization_id}/limits",
+            get(routes::metrics::get_usage_limits),
         );
 
     let api_v0 = Router::new()
@@ -127,8 +158,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .nest("/metrics", metrics_router)
         .route("/invites/accept", post(routes::invites::accept_invite));
 
+    let draining = Arc::new(AtomicBool::new(false));
+
     let app = Router::new()
+        .route("/health/live", get(routes::health::live))
+        .route("/health/ready", get(routes::health::ready))
+        .route("/.well-known/jwks.json", get(routes::jwt::jwks))
         .nest("/api/v0", api_v0)
+        .layer(middleware::from_fn(telemetry::trace_request))
         .layer(CorsLayer::permissive())
         .with_state(app_state::AppState {
             db,
@@ -136,12 +173,53 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             config: config.clone(),
             stripe,
             resend,
+            signing_keys,
+            draining: draining.clone(),
         });
 
     let listener = tokio::net::TcpListener::bind(&config.listen_addr).await?;
     info!("Successfully bound to {}", config.listen_addr);
 
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(drain(draining, config.drain_timeout_seconds))
+        .await?;
+
+    if let Some(tracer_provider) = tracer_provider
+        && let Err(e) = tracer_provider.shutdown()
+    {
+        error!("Failed to flush traces: {e}");
+    }
 
     Ok(())
 }
+
+/// Wait for Ctrl+C or SIGTERM, then report not ready for `drain_timeout_seconds`
+/// so the orchestrator stops routing traffic before the server shuts down
+async fn drain(draining: Arc<AtomicBool>, drain_timeout_seconds: u64) {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    draining.store(true, Ordering::SeqCst);
+    info!("Draining for {drain_timeout_seconds}s before shutdown");
+
+    sleep(std::time::Duration::from_secs(drain_timeout_seconds)).await;
+}
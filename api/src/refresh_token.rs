@@ -0,0 +1,272 @@
+use sqlx::{PgConnection, PgPool};
+use time::OffsetDateTime;
+use tracing::{error, info};
+use uuid::Uuid;
+
+const PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// Outcome of presenting a refresh token
+pub enum Redemption {
+    /// The token was unused and is now spent, its successor joins `family_id`
+    Rotated { family_id: Uuid },
+    /// The token was already spent, whoever holds the family may have stolen it
+    Reused { family_id: Uuid },
+    /// Unknown, expired or revoked
+    Invalid,
+}
+
+pub async fn store(
+    conn: &mut PgConnection,
+    id: Uuid,
+    family_id: Uuid,
+    app_id: &str,
+    user_id: &str,
+    expires_at: OffsetDateTime,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO refresh_tokens (id, family_id, app_id, user_id, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+        id,
+        family_id,
+        app_id,
+        user_id,
+        expires_at,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Spend the refresh token `id`. Each token can be spent once, so two
+/// concurrent refreshes with the same token see it reused.
+pub async fn redeem(conn: &mut PgConnection, id: Uuid, app_id: &str) -> sqlx::Result<Redemption> {
+    let spent = sqlx::query!(
+        r#"
+        UPDATE refresh_tokens
+        SET used_at = NOW()
+        WHERE id = $1
+        AND app_id = $2
+        AND used_at IS NULL
+        AND revoked_at IS NULL
+        AND expires_at > NOW()
+        RETURNING family_id
+        "#,
+        id,
+        app_id,
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    if let Some(spent) = spent {
+        return Ok(Redemption::Rotated {
+            family_id: spent.family_id,
+        });
+    }
+
+    let token = sqlx::query!(
+        r#"
+        SELECT family_id, used_at
+        FROM refresh_tokens
+        WHERE id = $1
+        AND app_id = $2
+        "#,
+        id,
+        app_id,
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    Ok(match token {
+        Some(token) if token.used_at.is_some() => Redemption::Reused {
+            family_id: token.family_id,
+        },
+        _ => Redemption::Invalid,
+    })
+}
+
+pub async fn revoke_family(conn: &mut PgConnection, family_id: Uuid) -> sqlx::Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE refresh_tokens
+        SET revoked_at = NOW()
+        WHERE family_id = $1
+        AND revoked_at IS NULL
+        "#,
+        family_id,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Revoke the refresh tokens of one user of the app, or of every user when
+/// `user_id` is `None`
+pub async fn revoke(
+    conn: &mut PgConnection,
+    app_id: &str,
+    user_id: Option<&str>,
+) -> sqlx::Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE refresh_tokens
+        SET revoked_at = NOW()
+        WHERE app_id = $1
+        AND ($2::TEXT IS NULL OR user_id = $2)
+        AND revoked_at IS NULL
+        AND expires_at > NOW()
+        "#,
+        app_id,
+        user_id,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Delete expired refresh tokens every hour. Until then spent tokens are kept
+/// around to catch reuse.
+pub fn start_pruning(db: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let result = sqlx::query!(
+                r#"
+                DELETE FROM refresh_tokens WHERE expires_at <= NOW()
+                "#
+            )
+            .execute(&db)
+            .await;
+
+            match result {
+                Ok(result) if result.rows_affected() > 0 => {
+                    info!("Pruned {} expired refresh tokens", result.rows_affected());
+                }
+                Ok(_) => {}
+                Err(e) => error!("Failed to prune refresh tokens: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Duration;
+
+    use super::*;
+
+    const APP_ID: &str = "test_app";
+
+    async fn create_app(conn: &mut PgConnection) {
+        sqlx::query(
+            r#"
+            WITH organization AS (
+                INSERT INTO organizations (name) VALUES ('Test') RETURNING id
+            )
+            INSERT INTO apps (app_id, app_secret_hash, name, organization_id)
+            SELECT $1, 'hash', 'Test', id FROM organization
+            "#,
+        )
+        .bind(APP_ID)
+        .execute(conn)
+        .await
+        .unwrap();
+    }
+
+    async fn issue(conn: &mut PgConnection, family_id: Uuid, user_id: &str) -> Uuid {
+        let id = Uuid::new_v4();
+        let expires_at = OffsetDateTime::now_utc() + Duration::days(30);
+
+        store(conn, id, family_id, APP_ID, user_id, expires_at)
+            .await
+            .unwrap();
+
+        id
+    }
+
+    #[sqlx::test(migrator = "db::MIGRATOR")]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn rotation_spends_each_token_once(pool: PgPool) {
+        let mut conn = pool.acquire().await.unwrap();
+        create_app(&mut conn).await;
+
+        let family_id = Uuid::new_v4();
+        let first = issue(&mut conn, family_id, "user").await;
+
+        assert!(matches!(
+            redeem(&mut conn, first, APP_ID).await.unwrap(),
+            Redemption::Rotated { family_id: id } if id == family_id
+        ));
+
+        let second = issue(&mut conn, family_id, "user").await;
+
+        assert!(matches!(
+            redeem(&mut conn, second, APP_ID).await.unwrap(),
+            Redemption::Rotated { family_id: id } if id == family_id
+        ));
+        assert!(matches!(
+            redeem(&mut conn, Uuid::new_v4(), APP_ID).await.unwrap(),
+            Redemption::Invalid
+        ));
+    }
+
+    #[sqlx::test(migrator = "db::MIGRATOR")]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn reuse_revokes_the_family(pool: PgPool) {
+        let mut conn = pool.acquire().await.unwrap();
+        create_app(&mut conn).await;
+
+        let family_id = Uuid::new_v4();
+        let first = issue(&mut conn, family_id, "user").await;
+
+        redeem(&mut conn, first, APP_ID).await.unwrap();
+        let second = issue(&mut conn, family_id, "user").await;
+
+        let Redemption::Reused { family_id: reused } =
+            redeem(&mut conn, first, APP_ID).await.unwrap()
+        else {
+            panic!("Expected the spent token to be reported as reused");
+        };
+
+        assert_eq!(reused, family_id);
+        assert_eq!(revoke_family(&mut conn, reused).await.unwrap(), 2);
+        assert!(matches!(
+            redeem(&mut conn, second, APP_ID).await.unwrap(),
+            Redemption::Invalid
+        ));
+    }
+
+    #[sqlx::test(migrator = "db::MIGRATOR")]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn revoke_targets_one_user_or_the_whole_app(pool: PgPool) {
+        let mut conn = pool.acquire().await.unwrap();
+        create_app(&mut conn).await;
+
+        let alice = issue(&mut conn, Uuid::new_v4(), "alice").await;
+        let bob = issue(&mut conn, Uuid::new_v4(), "bob").await;
+        let carol = issue(&mut conn, Uuid::new_v4(), "carol").await;
+
+        assert_eq!(revoke(&mut conn, APP_ID, Some("alice")).await.unwrap(), 1);
+        assert!(matches!(
+            redeem(&mut conn, alice, APP_ID).await.unwrap(),
+            Redemption::Invalid
+        ));
+        assert!(matches!(
+            redeem(&mut conn, bob, APP_ID).await.unwrap(),
+            Redemption::Rotated { .. }
+        ));
+
+        assert_eq!(revoke(&mut conn, APP_ID, None).await.unwrap(), 2);
+        assert!(matches!(
+            redeem(&mut conn, carol, APP_ID).await.unwrap(),
+            Redemption::Invalid
+        ));
+    }
+}
@@ -5,15 +5,24 @@ use argon2::{
 use axum::{
     Json,
     extract::{Path, Query},
+    http::{StatusCode, Uri},
 };
 use base64::{Engine as _, engine::general_purpose::URL_SAFE};
+use jsonwebtoken::jwk::Jwk;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::PgConnection;
 use time::OffsetDateTime;
 use uuid::Uuid;
 use validator::Validate;
 
-use crate::extractors::{database_connection::DatabaseConnection, session::Session};
+use crate::{
+    extractors::{database_connection::DatabaseConnection, session::Session},
+    services::signing_keys::SigningAlgorithm,
+};
+
+const MAX_KEY_ID_LENGTH: usize = 128;
 
 #[derive(Debug, Serialize, Deserialize, Validate)]
 #[serde(rename_all = "camelCase")]
@@ -23,6 +32,12 @@ pub struct CreateAppRequest {
     pub name: String,
     #[validate(length(max = 200))]
     pub description: Option<String>,
+    pub allow_query_token: Option<bool>,
+    pub allowed_origins: Option<Vec<String>>,
+    pub members_in_document: Option<bool>,
+    pub relay_channel_prefixes: Option<Vec<String>>,
+    pub relay_keep_last: Option<bool>,
+    pub allow_client_relay: Option<bool>,
 }
 
 #[derive(Debug, Serialize)]
@@ -67,8 +82,41 @@ impl AppCredentials {
     }
 }
 
+/// Check that every entry is a bare http(s) origin, optionally with a `*.`
+/// subdomain wildcard, and normalize it the way the platform compares origins
+fn normalize_origins(origins: Vec<String>) -> Result<Vec<String>, (StatusCode, String)> {
+    origins
+        .into_iter()
+        .map(|origin| {
+            let origin = origin.trim().trim_end_matches('/').to_lowercase();
+            let uri = origin.replacen("://*.", "://wildcard.", 1).parse::<Uri>();
+
+            let is_origin = uri.is_ok_and(|uri| {
+                matches!(uri.scheme_str(), Some("http" | "https"))
+                    && uri.authority().is_some()
+                    && uri.path_and_query().is_none_or(|pq| pq.as_str() == "/")
+            });
+
+            if is_origin {
+                Ok(origin)
+            } else {
+                Err((StatusCode::BAD_REQUEST, format!("Invalid origin: {origin}")))
+            }
+        })
+        .collect()
+}
+
+/// Drop blank relay channel prefixes, which would turn every channel into a
+/// relay
+fn normalize_relay_prefixes(prefixes: Vec<String>) -> Vec<String> {
+    prefixes
+        .into_iter()
+        .filter(|prefix| !prefix.is_empty())
+        .collect()
+}
+
 pub async fn create(
-    Session(_session): Session,
+    Session(session): Session,
     DatabaseConnection(mut conn): DatabaseConnection,
     Json(payload): Json<CreateAppRequest>,
 ) -> Result<Json<CreateAppResponse>, (axum::http::StatusCode, String)> {
@@ -76,6 +124,24 @@ pub async fn create(
     //     .validate()
     //     .map_err(|e| (axum::http::StatusCode::BAD_REQUEST, e.to_string()))?;
 
+    let has_access = sqlx::query!(
+        "SELECT 1 as exists FROM user_organizations WHERE user_id = $1 AND organization_id = $2",
+        session.user_id,
+        payload.organization_id
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .is_some();
+
+    if !has_access {
+        return Err((StatusCode::FORBIDDEN, "Access denied".to_string()));
+    }
+
+    let allowed_origins = normalize_origins(payload.allowed_origins.unwrap_or_default())?;
+    let relay_channel_prefixes =
+        normalize_relay_prefixes(payload.relay_channel_prefixes.unwrap_or_default());
+
     let credentials = AppCredentials::generate().map_err(|e| {
         (
             axum::http::StatusCode::INTERNAL_SERVER_ERROR,
@@ -85,14 +151,24 @@ pub async fn create(
 
     sqlx::query!(
         r#"
-        INSERT INTO apps (app_id, app_secret_hash, name, description, organization_id)
-        VALUES ($1, $2, $3, $4, $5)
+        INSERT INTO apps (
+            app_id, app_secret_hash, name, description, organization_id,
+            allow_query_token, allowed_origins, members_in_document,
+            relay_channel_prefixes, relay_keep_last, allow_client_relay
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
         "#,
         credentials.app_id,
         credentials.secret_hash,
         payload.name,
         payload.description,
         payload.organization_id,
+        payload.allow_query_token.unwrap_or(true),
+        &allowed_origins,
+        payload.members_in_document.unwrap_or(false),
+        &relay_channel_prefixes,
+        payload.relay_keep_last.unwrap_or(false),
+        payload.allow_client_relay.unwrap_or(false),
     )
     .execute(&mut *conn)
     .await
@@ -124,6 +200,12 @@ pub struct AppInfo {
     pub app_id: String,
     pub name: String,
     pub description: Option<String>,
+    pub allow_query_token: bool,
+    pub allowed_origins: Vec<String>,
+    pub members_in_document: bool,
+    pub relay_channel_prefixes: Vec<String>,
+    pub relay_keep_last: bool,
+    pub allow_client_relay: bool,
     #[serde(with = "time::serde::rfc3339")]
     pub created_at: OffsetDateTime,
 }
@@ -151,7 +233,7 @@ pub async fn list(
                 FROM user_organizations
                 WHERE user_id = $1
             )
-            SELECT a.id, a.app_id, a.name, a.description, a.created_at
+            SELECT a.id, a.app_id, a.name, a.description, a.allow_query_token, a.allowed_origins, a.members_in_document, a.relay_channel_prefixes, a.relay_keep_last, a.allow_client_relay, a.created_at
             FROM apps a
             INNER JOIN user_orgs uo ON uo.organization_id = a.organization_id
             WHERE a.created_at < (
@@ -177,7 +259,7 @@ pub async fn list(
                 FROM user_organizations
                 WHERE user_id = $1
             )
-            SELECT a.id, a.app_id, a.name, a.description, a.created_at
+            SELECT a.id, a.app_id, a.name, a.description, a.allow_query_token, a.allowed_origins, a.members_in_document, a.relay_channel_prefixes, a.relay_keep_last, a.allow_client_relay, a.created_at
             FROM apps a
             INNER JOIN user_orgs uo ON uo.organization_id = a.organization_id
             WHERE a.organization_id = $3::uuid
@@ -215,6 +297,12 @@ pub struct EditAppRequest {
     pub name: Option<String>,
     #[validate(length(max = 200))]
     pub description: Option<String>,
+    pub allow_query_token: Option<bool>,
+    pub allowed_origins: Option<Vec<String>>,
+    pub members_in_document: Option<bool>,
+    pub relay_channel_prefixes: Option<Vec<String>>,
+    pub relay_keep_last: Option<bool>,
+    pub allow_client_relay: Option<bool>,
 }
 
 #[derive(Debug, Serialize)]
@@ -223,10 +311,16 @@ pub struct EditAppResponse {
     pub id: Uuid,
     pub name: String,
     pub description: Option<String>,
+    pub allow_query_token: bool,
+    pub allowed_origins: Vec<String>,
+    pub members_in_document: bool,
+    pub relay_channel_prefixes: Vec<String>,
+    pub relay_keep_last: bool,
+    pub allow_client_relay: bool,
 }
 
 pub async fn edit(
-    Session(_session): Session,
+    Session(session): Session,
     DatabaseConnection(mut conn): DatabaseConnection,
     Path(app_id): Path<Uuid>,
     Json(payload): Json<EditAppRequest>,
@@ -235,14 +329,35 @@ pub async fn edit(
         .validate()
         .map_err(|e| (axum::http::StatusCode::BAD_REQUEST, e.to_string()))?;
 
+    member_app_id(&mut conn, session.user_id, app_id).await?;
+
+    let allowed_origins = payload.allowed_origins.map(normalize_origins).transpose()?;
+    let relay_channel_prefixes = payload.relay_channel_prefixes.map(normalize_relay_prefixes);
+
     let result = sqlx::query!(
         r#"
-        UPDATE apps SET name = $1, description = $2 WHERE id = $3
-        RETURNING name, description
+        UPDATE apps
+        SET name = COALESCE($1, name),
+            description = COALESCE($2, description),
+            allow_query_token = COALESCE($4, allow_query_token),
+            allowed_origins = COALESCE($5, allowed_origins),
+            members_in_document = COALESCE($6, members_in_document),
+            relay_channel_prefixes = COALESCE($7, relay_channel_prefixes),
+            relay_keep_last = COALESCE($8, relay_keep_last),
+            allow_client_relay = COALESCE($9, allow_client_relay)
+        WHERE id = $3
+        RETURNING name, description, allow_query_token, allowed_origins, members_in_document,
+            relay_channel_prefixes, relay_keep_last, allow_client_relay
         "#,
         payload.name,
         payload.description,
         app_id,
+        payload.allow_query_token,
+        allowed_origins.as_deref(),
+        payload.members_in_document,
+        relay_channel_prefixes.as_deref(),
+        payload.relay_keep_last,
+        payload.allow_client_relay,
     )
     .fetch_one(&mut *conn)
     .await
@@ -257,14 +372,22 @@ pub async fn edit(
         id: app_id,
         name: result.name,
         description: result.description,
+        allow_query_token: result.allow_query_token,
+        allowed_origins: result.allowed_origins,
+        members_in_document: result.members_in_document,
+        relay_channel_prefixes: result.relay_channel_prefixes,
+        relay_keep_last: result.relay_keep_last,
+        allow_client_relay: result.allow_client_relay,
     }))
 }
 
 pub async fn delete(
-    Session(_session): Session,
+    Session(session): Session,
     DatabaseConnection(mut conn): DatabaseConnection,
     Path(app_id): Path<Uuid>,
 ) -> Result<axum::http::StatusCode, (axum::http::StatusCode, String)> {
+    member_app_id(&mut conn, session.user_id, app_id).await?;
+
     let result = sqlx::query!(
         r#"
         DELETE FROM apps WHERE id = $1
@@ -286,3 +409,517 @@ pub async fn delete(
         )),
     }
 }
+
+/// The public id of an app in one of the user's organizations
+async fn member_app_id(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    app_id: Uuid,
+) -> Result<String, (StatusCode, String)> {
+    sqlx::query!(
+        r#"
+        SELECT a.app_id
+        FROM apps a
+        INNER JOIN user_organizations uo ON uo.organization_id = a.organization_id
+        WHERE a.id = $1
+        AND uo.user_id = $2
+        "#,
+        app_id,
+        user_id,
+    )
+    .fetch_optional(conn)
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to fetch app: {e}"),
+        )
+    })?
+    .map(|row| row.app_id)
+    .ok_or((StatusCode::NOT_FOUND, "App not found".to_string()))
+}
+
+/// Per-app overrides of the WebSocket rate limits. Missing values use the
+/// platform defaults and zero disables a limit.
+#[derive(Debug, Default, Serialize, Deserialize, Validate)]
+#[serde(rename_all = "camelCase")]
+pub struct AppRateLimits {
+    #[validate(range(min = 0))]
+    pub connection_messages_per_second: Option<i32>,
+    #[validate(range(min = 0))]
+    pub connection_bytes_per_second: Option<i32>,
+    #[validate(range(min = 0))]
+    pub user_messages_per_second: Option<i32>,
+    #[validate(range(min = 0))]
+    pub user_bytes_per_second: Option<i32>,
+    #[validate(range(min = 0))]
+    pub app_messages_per_second: Option<i32>,
+    #[validate(range(min = 0))]
+    pub app_bytes_per_second: Option<i32>,
+    #[validate(range(min = 0))]
+    pub max_violations: Option<i32>,
+}
+
+pub async fn get_rate_limits(
+    Session(session): Session,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Path(app_id): Path<Uuid>,
+) -> Result<Json<AppRateLimits>, (StatusCode, String)> {
+    let app_id = member_app_id(&mut conn, session.user_id, app_id).await?;
+
+    let limits = sqlx::query_as!(
+        AppRateLimits,
+        r#"
+        SELECT connection_messages_per_second, connection_bytes_per_second,
+            user_messages_per_second, user_bytes_per_second,
+            app_messages_per_second, app_bytes_per_second, max_violations
+        FROM app_rate_limits
+        WHERE app_id = $1
+        "#,
+        app_id,
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to fetch rate limits: {e}"),
+        )
+    })?;
+
+    Ok(Json(limits.unwrap_or_default()))
+}
+
+/// Replace the app's rate limits. Nodes pick them up within their limits cache TTL.
+pub async fn set_rate_limits(
+    Session(session): Session,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Path(app_id): Path<Uuid>,
+    Json(payload): Json<AppRateLimits>,
+) -> Result<Json<AppRateLimits>, (StatusCode, String)> {
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let app_id = member_app_id(&mut conn, session.user_id, app_id).await?;
+
+    let limits = sqlx::query_as!(
+        AppRateLimits,
+        r#"
+        INSERT INTO app_rate_limits (
+            app_id, connection_messages_per_second, connection_bytes_per_second,
+            user_messages_per_second, user_bytes_per_second,
+            app_messages_per_second, app_bytes_per_second, max_violations
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (app_id) DO UPDATE
+        SET connection_messages_per_second = EXCLUDED.connection_messages_per_second,
+            connection_bytes_per_second = EXCLUDED.connection_bytes_per_second,
+            user_messages_per_second = EXCLUDED.user_messages_per_second,
+            user_bytes_per_second = EXCLUDED.user_bytes_per_second,
+            app_messages_per_second = EXCLUDED.app_messages_per_second,
+            app_bytes_per_second = EXCLUDED.app_bytes_per_second,
+            max_violations = EXCLUDED.max_violations
+        RETURNING connection_messages_per_second, connection_bytes_per_second,
+            user_messages_per_second, user_bytes_per_second,
+            app_messages_per_second, app_bytes_per_second, max_violations
+        "#,
+        app_id,
+        payload.connection_messages_per_second,
+        payload.connection_bytes_per_second,
+        payload.user_messages_per_second,
+        payload.user_bytes_per_second,
+        payload.app_messages_per_second,
+        payload.app_bytes_per_second,
+        payload.max_violations,
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to store rate limits: {e}"),
+        )
+    })?;
+
+    Ok(Json(limits))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AddAppKeyRequest {
+    /// Public key the app signs its own tokens with, RSA or Ed25519
+    pub jwk: Jwk,
+    /// Stop accepting tokens signed with this key, for rotating it out
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppKeyInfo {
+    pub kid: String,
+    pub algorithm: String,
+    pub jwk: Value,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListAppKeysResponse {
+    pub keys: Vec<AppKeyInfo>,
+}
+
+pub async fn list_keys(
+    Session(session): Session,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Path(app_id): Path<Uuid>,
+) -> Result<Json<ListAppKeysResponse>, (StatusCode, String)> {
+    let app_id = member_app_id(&mut conn, session.user_id, app_id).await?;
+
+    let keys = sqlx::query_as!(
+        AppKeyInfo,
+        r#"
+        SELECT kid, algorithm, public_jwk AS jwk, expires_at, created_at
+        FROM signing_keys
+        WHERE app_id = $1
+        ORDER BY created_at DESC
+        "#,
+        app_id,
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to fetch keys: {e}"),
+        )
+    })?;
+
+    Ok(Json(ListAppKeysResponse { keys }))
+}
+
+/// Register a public key the app signs its own tokens with. Tokens must carry
+/// its `kid` and can only be issued for this app.
+pub async fn add_key(
+    Session(session): Session,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Path(app_id): Path<Uuid>,
+    Json(payload): Json<AddAppKeyRequest>,
+) -> Result<Json<AppKeyInfo>, (StatusCode, String)> {
+    let app_id = member_app_id(&mut conn, session.user_id, app_id).await?;
+
+    let algorithm = SigningAlgorithm::for_jwk(&payload.jwk).ok_or((
+        StatusCode::BAD_REQUEST,
+        "Key must be an RSA key of at least 2048 bits or an Ed25519 key".to_string(),
+    ))?;
+
+    let mut jwk = payload.jwk;
+    let kid = jwk
+        .common
+        .key_id
+        .get_or_insert_with(|| Uuid::new_v4().to_string())
+        .clone();
+
+    if kid.len() > MAX_KEY_ID_LENGTH {
+        return Err((StatusCode::BAD_REQUEST, "Key id is too long".to_string()));
+    }
+
+    // Only public parameters are deserialized, a private part sent by mistake is dropped
+    let public_jwk = serde_json::to_value(&jwk).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to encode key: {e}"),
+        )
+    })?;
+
+    let key = sqlx::query!(
+        r#"
+        INSERT INTO signing_keys (kid, app_id, algorithm, public_jwk, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (kid) DO NOTHING
+        RETURNING created_at
+        "#,
+        kid,
+        app_id,
+        algorithm.as_str(),
+        public_jwk,
+        payload.expires_at,
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to store key: {e}"),
+        )
+    })?
+    .ok_or((
+        StatusCode::CONFLICT,
+        format!("Key id {kid} is already registered"),
+    ))?;
+
+    Ok(Json(AppKeyInfo {
+        kid,
+        algorithm: algorithm.as_str().to_string(),
+        jwk: public_jwk,
+        expires_at: payload.expires_at,
+        created_at: key.created_at,
+    }))
+}
+
+pub async fn delete_key(
+    Session(session): Session,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    Path((app_id, kid)): Path<(Uuid, String)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let app_id = member_app_id(&mut conn, session.user_id, app_id).await?;
+
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM signing_keys WHERE app_id = $1 AND kid = $2
+        "#,
+        app_id,
+        kid,
+    )
+    .execute(&mut *conn)
+    .await;
+
+    match result {
+        Ok(res) if res.rows_affected() > 0 => Ok(StatusCode::NO_CONTENT),
+        Ok(_) => Err((StatusCode::NOT_FOUND, "Key not found".to_string())),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to delete key: {e}"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Session as SessionValue;
+    use sqlx::PgPool;
+
+    // An app in a new organization with one member, returning the member and
+    // the app's id
+    async fn create_app(conn: &mut PgConnection) -> (Uuid, Uuid) {
+        let user_id = create_user(conn, "member@example.com").await;
+
+        let app_id = sqlx::query_scalar::<_, Uuid>(
+            r#"
+            WITH organization AS (
+                INSERT INTO organizations (name) VALUES ('Test') RETURNING id
+            ), membership AS (
+                INSERT INTO user_organizations (user_id, organization_id)
+                SELECT $1, id FROM organization
+            )
+            INSERT INTO apps (app_id, app_secret_hash, name, description, organization_id)
+            SELECT 'test_app', 'hash', 'Test', 'A test app', id FROM organization
+            RETURNING id
+            "#,
+        )
+        .bind(user_id)
+        .fetch_one(conn)
+        .await
+        .unwrap();
+
+        (user_id, app_id)
+    }
+
+    async fn create_user(conn: &mut PgConnection, email: &str) -> Uuid {
+        sqlx::query_scalar("INSERT INTO users (name, email) VALUES ('Test', $1) RETURNING id")
+            .bind(email)
+            .fetch_one(conn)
+            .await
+            .unwrap()
+    }
+
+    fn session(user_id: Uuid) -> Session {
+        Session(SessionValue {
+            id: "session".to_string(),
+            user_id,
+            username: "Test".to_string(),
+            expires_at: OffsetDateTime::now_utc() + time::Duration::hours(1),
+        })
+    }
+
+    fn no_change() -> EditAppRequest {
+        EditAppRequest {
+            name: None,
+            description: None,
+            allow_query_token: None,
+            allowed_origins: None,
+            members_in_document: None,
+            relay_channel_prefixes: None,
+            relay_keep_last: None,
+            allow_client_relay: None,
+        }
+    }
+
+    fn settings_change() -> EditAppRequest {
+        EditAppRequest {
+            name: Some("Renamed".to_string()),
+            allow_query_token: Some(false),
+            allowed_origins: Some(vec!["https://evil.example.com".to_string()]),
+            allow_client_relay: Some(true),
+            ..no_change()
+        }
+    }
+
+    async fn app_name(pool: &PgPool, app_id: Uuid) -> Option<String> {
+        sqlx::query_scalar("SELECT name FROM apps WHERE id = $1")
+            .bind(app_id)
+            .fetch_optional(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrator = "db::MIGRATOR")]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn only_members_edit_an_app(pool: PgPool) {
+        let mut conn = pool.acquire().await.unwrap();
+        let (member, app_id) = create_app(&mut conn).await;
+        let outsider = create_user(&mut conn, "outsider@example.com").await;
+
+        let refused = edit(
+            session(outsider),
+            DatabaseConnection(pool.acquire().await.unwrap()),
+            Path(app_id),
+            Json(settings_change()),
+        )
+        .await;
+
+        assert_eq!(
+            refused.err().map(|(status, _)| status),
+            Some(StatusCode::NOT_FOUND)
+        );
+        assert_eq!(app_name(&pool, app_id).await.as_deref(), Some("Test"));
+
+        let Json(edited) = edit(
+            session(member),
+            DatabaseConnection(pool.acquire().await.unwrap()),
+            Path(app_id),
+            Json(settings_change()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(edited.name, "Renamed");
+        assert!(!edited.allow_query_token);
+    }
+
+    #[sqlx::test(migrator = "db::MIGRATOR")]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn only_members_delete_an_app(pool: PgPool) {
+        let mut conn = pool.acquire().await.unwrap();
+        let (member, app_id) = create_app(&mut conn).await;
+        let outsider = create_user(&mut conn, "outsider@example.com").await;
+
+        let refused = delete(
+            session(outsider),
+            DatabaseConnection(pool.acquire().await.unwrap()),
+            Path(app_id),
+        )
+        .await;
+
+        assert_eq!(
+            refused.err().map(|(status, _)| status),
+            Some(StatusCode::NOT_FOUND)
+        );
+        assert!(app_name(&pool, app_id).await.is_some());
+
+        let deleted = delete(
+            session(member),
+            DatabaseConnection(pool.acquire().await.unwrap()),
+            Path(app_id),
+        )
+        .await;
+
+        assert_eq!(deleted, Ok(StatusCode::NO_CONTENT));
+        assert!(app_name(&pool, app_id).await.is_none());
+    }
+
+    #[sqlx::test(migrator = "db::MIGRATOR")]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn editing_one_setting_keeps_the_name_and_description(pool: PgPool) {
+        let mut conn = pool.acquire().await.unwrap();
+        let (member, app_id) = create_app(&mut conn).await;
+
+        let Json(edited) = edit(
+            session(member),
+            DatabaseConnection(pool.acquire().await.unwrap()),
+            Path(app_id),
+            Json(EditAppRequest {
+                allow_query_token: Some(false),
+                ..no_change()
+            }),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(edited.name, "Test");
+        assert_eq!(edited.description.as_deref(), Some("A test app"));
+        assert!(!edited.allow_query_token);
+    }
+
+    #[sqlx::test(migrator = "db::MIGRATOR")]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn only_members_create_apps_in_an_organization(pool: PgPool) {
+        let mut conn = pool.acquire().await.unwrap();
+        let (member, app_id) = create_app(&mut conn).await;
+        let outsider = create_user(&mut conn, "outsider@example.com").await;
+        let organization_id: Uuid =
+            sqlx::query_scalar("SELECT organization_id FROM apps WHERE id = $1")
+                .bind(app_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+
+        let new_app = || CreateAppRequest {
+            organization_id,
+            name: "New".to_string(),
+            description: None,
+            allow_query_token: None,
+            allowed_origins: Some(vec!["https://evil.example.com".to_string()]),
+            members_in_document: None,
+            relay_channel_prefixes: None,
+            relay_keep_last: None,
+            allow_client_relay: None,
+        };
+        let app_count = || async {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM apps WHERE organization_id = $1")
+                .bind(organization_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        };
+
+        let refused = create(
+            session(outsider),
+            DatabaseConnection(pool.acquire().await.unwrap()),
+            Json(new_app()),
+        )
+        .await;
+
+        assert_eq!(
+            refused.err().map(|(status, _)| status),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(app_count().await, 1);
+
+        let Json(created) = create(
+            session(member),
+            DatabaseConnection(pool.acquire().await.unwrap()),
+            Json(new_app()),
+        )
+        .await
+        .unwrap();
+
+        assert!(!created.app_secret.is_empty());
+        assert_eq!(app_count().await, 2);
+    }
+}
@@ -8,7 +8,6 @@ use argon2::{
 };
 use axum::Json;
 use db::dto::key_provider_type::KeyProviderType;
-use jsonwebtoken::{DecodingKey, Validation, decode};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use sqlx::Acquire;
@@ -17,7 +16,7 @@ use tracing::error;
 
 use crate::{
     error::AuthError,
-    extractors::{config::ConfigExtractor, session::Session},
+    extractors::{config::ConfigExtractor, session::Session, signing_keys::SigningKeys},
     session::{
         Session as SessionValue, create_session, generate_session_token,
         invalidate_all_user_sessions, invalidate_session, validate_session_token,
@@ -467,20 +466,19 @@ pub async fn reset_password(
     DatabaseConnection(mut conn): DatabaseConnection,
     RedisConnection(redis): RedisConnection,
     ConfigExtractor(config): ConfigExtractor,
+    SigningKeys(signing_keys): SigningKeys,
     Json(ResetPasswordRequest {
         token,
         new_password,
     }): Json<ResetPasswordRequest>,
 ) -> Result<Json<LoginResponse>, AuthError> {
     // Validate token and extract user ID
-    let token_data = decode::<ResetPasswordJwtClaims>(
-        &token,
-        &DecodingKey::from_secret(config.jwt_secret.as_bytes()),
-        &Validation::default(),
-    )
-    .map_err(|e| AuthError::Internal(e.to_string()))?;
+    let claims = signing_keys
+        .verify::<ResetPasswordJwtClaims>(&token)
+        .await
+        .map_err(|e| AuthError::Internal(e.to_string()))?;
 
-    let user_id = token_data.claims.payload.user_id;
+    let user_id = claims.payload.user_id;
 
     // Check that this is an existing user
     let user = sqlx::query!(
@@ -0,0 +1,71 @@
+use axum::{Json, extract::State, http::StatusCode};
+use health::{DependencyCheck, HealthReport};
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+
+use crate::app_state::AppState;
+
+pub async fn live() -> StatusCode {
+    StatusCode::OK
+}
+
+fn respond(report: HealthReport) -> (StatusCode, Json<HealthReport>) {
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(report))
+}
+
+pub async fn ready(State(state): State<AppState>) -> (StatusCode, Json<HealthReport>) {
+    let mut checks = BTreeMap::new();
+
+    checks.insert("postgres", state.db.is_up().await);
+    checks.insert("redis", state.redis.is_up().await);
+
+    respond(HealthReport::new(
+        checks,
+        state.draining.load(Ordering::SeqCst),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(postgres: bool, redis: bool, draining: bool) -> HealthReport {
+        HealthReport::new(
+            BTreeMap::from([("postgres", postgres), ("redis", redis)]),
+            draining,
+        )
+    }
+
+    #[test]
+    fn ready_when_every_dependency_is_up() {
+        let (status, Json(report)) = respond(report(true, true, false));
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(report.ready);
+    }
+
+    #[test]
+    fn degraded_when_redis_is_unreachable() {
+        let (status, Json(report)) = respond(report(true, false, false));
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!report.ready);
+        assert!(!report.checks["redis"]);
+        assert!(report.checks["postgres"]);
+    }
+
+    #[test]
+    fn not_ready_while_draining() {
+        let (status, Json(report)) = respond(report(true, true, true));
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!report.ready);
+        assert!(report.draining);
+    }
+}
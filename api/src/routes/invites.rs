@@ -5,7 +5,6 @@ use argon2::{
 use axum::http::StatusCode;
 use axum::{Json, extract::Path};
 use db::dto::key_provider_type::KeyProviderType;
-use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use sqlx::Acquire;
@@ -15,8 +14,9 @@ use validator::{Validate, ValidateEmail, ValidationError};
 
 use crate::extractors::{
     config::ConfigExtractor, database_connection::DatabaseConnection, resend::Resend,
-    session::Session,
+    session::Session, signing_keys::SigningKeys,
 };
+use crate::services::signing_keys::SigningKeyService;
 
 fn validate_emails(emails: &[String]) -> Result<(), ValidationError> {
     for email in emails {
@@ -55,9 +55,9 @@ struct InviteJwtClaims {
     pub payload: InviteJwtPayload,
 }
 
-fn generate_invite_jwt_token(
+async fn generate_invite_jwt_token(
     invite_id: Uuid,
-    jwt_secret: &str,
+    signing_keys: &SigningKeyService,
 ) -> Result<String, (StatusCode, String)> {
     let payload = InviteJwtPayload { invite_id };
     let now = OffsetDateTime::now_utc();
@@ -70,12 +70,10 @@ fn generate_invite_jwt_token(
         payload,
     };
 
-    let jwt_token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(jwt_secret.as_bytes()),
-    )
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let jwt_token = signing_keys
+        .sign(&claims)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
     Ok(jwt_token)
 }
@@ -93,9 +91,9 @@ pub struct ResetPasswordJwtClaims {
     pub payload: ResetPasswordJwtPayload,
 }
 
-fn generate_reset_password_jwt_token(
+async fn generate_reset_password_jwt_token(
     user_id: Uuid,
-    jwt_secret: &str,
+    signing_keys: &SigningKeyService,
 ) -> Result<String, (StatusCode, String)> {
     let payload = ResetPasswordJwtPayload { user_id };
     let now = OffsetDateTime::now_utc();
@@ -108,12 +106,10 @@ fn generate_reset_password_jwt_token(
         payload,
     };
 
-    let jwt_token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(jwt_secret.as_bytes()),
-    )
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let jwt_token = signing_keys
+        .sign(&claims)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
     Ok(jwt_token)
 }
@@ -152,6 +148,7 @@ pub async fn invite_members(
     Session(session): Session,
     DatabaseConnection(mut conn): DatabaseConnection,
     ConfigExtractor(config): ConfigExtractor,
+    SigningKeys(signing_keys): SigningKeys,
     Resend(resend): Resend,
     Json(payload): Json<InviteRequest>,
 ) -> Result<Json<Vec<Invite>>, (StatusCode, String)> {
@@ -234,7 +231,7 @@ pub async fn invite_members(
     // Generate JWT tokens and magic links for each invite
     let mut magic_links = Vec::new();
     for invite in &invites {
-        let jwt_token = generate_invite_jwt_token(invite.id, &config.jwt_secret)?;
+        let jwt_token = generate_invite_jwt_token(invite.id, &signing_keys).await?;
         let magic_link = format!("{}/accept-invite?token={}", config.frontend_url, jwt_token);
         magic_links.push(magic_link);
     }
@@ -265,15 +262,13 @@ pub struct AcceptInviteResponse {
 
 pub async fn accept_invite(
     DatabaseConnection(mut conn): DatabaseConnection,
-    ConfigExtractor(config): ConfigExtractor,
+    SigningKeys(signing_keys): SigningKeys,
     Json(payload): Json<AcceptInviteRequest>,
 ) -> Result<Json<AcceptInviteResponse>, (StatusCode, String)> {
-    let token_data = decode::<InviteJwtClaims>(
-        &payload.token,
-        &DecodingKey::from_secret(config.jwt_secret.as_bytes()),
-        &Validation::default(),
-    )
-    .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let claims = signing_keys
+        .verify::<InviteJwtClaims>(&payload.token)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
 
     // Get the invite and check if it's valid
     let invite = sqlx::query!(
@@ -288,7 +283,7 @@ pub async fn accept_invite(
         FROM invites
         WHERE id = $1 AND status = 'pending'
         "#,
-        token_data.claims.payload.invite_id
+        claims.payload.invite_id
     )
     .fetch_optional(&mut *conn)
     .await
@@ -303,7 +298,7 @@ pub async fn accept_invite(
         // Mark as expired
         sqlx::query!(
             "UPDATE invites SET status = 'expired' WHERE id = $1",
-            token_data.claims.payload.invite_id
+            claims.payload.invite_id
         )
         .execute(&mut *conn)
         .await
@@ -400,7 +395,7 @@ pub async fn accept_invite(
     // Mark invite as accepted
     sqlx::query!(
         "UPDATE invites SET status = 'accepted' WHERE id = $1",
-        token_data.claims.payload.invite_id
+        claims.payload.invite_id
     )
     .execute(&mut *transaction)
     .await
@@ -412,7 +407,8 @@ pub async fn accept_invite(
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
 
     if password_reset_required {
-        let reset_password_token = generate_reset_password_jwt_token(user_id, &config.jwt_secret)?;
+        let reset_password_token =
+            generate_reset_password_jwt_token(user_id, &signing_keys).await?;
 
         return Ok(Json(AcceptInviteResponse {
             reset_password_token: Some(reset_password_token),
@@ -2,19 +2,34 @@ use argon2::{
     Argon2,
     password_hash::{PasswordHash, PasswordVerifier},
 };
-use axum::{Json, http::StatusCode};
+use axum::{
+    Json,
+    http::{StatusCode, header},
+    response::IntoResponse,
+};
 use axum_extra::{
     TypedHeader,
     headers::{Authorization, authorization::Bearer},
 };
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::{Acquire, PgConnection};
 use time::{Duration, OffsetDateTime};
-use tracing::info;
+use tracing::{info, warn};
 use uuid::Uuid;
 
-use crate::extractors::{config::ConfigExtractor, database_connection::DatabaseConnection};
+use crate::{
+    extractors::{database_connection::DatabaseConnection, signing_keys::SigningKeys},
+    refresh_token::{self, Redemption},
+    services::signing_keys::SigningKeyService,
+};
+
+const JWKS_CACHE_CONTROL: &str = "public, max-age=300";
+const ACCESS_TOKEN_LIFETIME: Duration = Duration::hours(1);
+const REFRESH_TOKEN_LIFETIME: Duration = Duration::days(30);
+// `typ` claim of refresh tokens, which the platform refuses as access tokens
+const REFRESH_TOKEN_TYPE: &str = "refresh";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -23,16 +38,31 @@ pub struct JwtPayload {
     pub custom: Value,
 }
 
-fn generate_token_pair(
+/// Sign an access token and a refresh token in `family_id`, recording the
+/// refresh token so it can only be spent once
+async fn generate_token_pair(
+    conn: &mut PgConnection,
+    signing_keys: &SigningKeyService,
+    family_id: Uuid,
     app_id: String,
     user_id: Option<String>,
     payload: Value,
-    secret: &str,
 ) -> Result<(String, String), (StatusCode, String)> {
     let user_id = user_id.unwrap_or(Uuid::new_v4().to_string());
     let now = OffsetDateTime::now_utc();
-    let access_exp = now + Duration::hours(1);
-    let refresh_exp = now + Duration::days(30);
+    let access_exp = now + ACCESS_TOKEN_LIFETIME;
+    let refresh_exp = now + REFRESH_TOKEN_LIFETIME;
+    let refresh_id = Uuid::new_v4();
+
+    refresh_token::store(conn, refresh_id, family_id, &app_id, &user_id, refresh_exp)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to store refresh token: {e}"),
+            )
+        })?;
+
     let payload = JwtPayload {
         app_id,
         custom: payload,
@@ -45,31 +75,23 @@ fn generate_token_pair(
         payload: payload.clone(),
     };
 
-    let refresh_claims = Claims {
+    let refresh_claims = RefreshClaims {
         sub: user_id,
         exp: refresh_exp.unix_timestamp(),
         iat: now.unix_timestamp(),
+        jti: refresh_id,
+        typ: REFRESH_TOKEN_TYPE.to_string(),
         payload,
     };
 
-    let access_token = encode(
-        &Header::default(),
-        &access_claims,
-        &EncodingKey::from_secret(secret.as_bytes()),
-    )
-    .map_err(|e| {
+    let access_token = signing_keys.sign(&access_claims).await.map_err(|e| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Failed to create access token: {e}"),
         )
     })?;
 
-    let refresh_token = encode(
-        &Header::default(),
-        &refresh_claims,
-        &EncodingKey::from_secret(secret.as_bytes()),
-    )
-    .map_err(|e| {
+    let refresh_token = signing_keys.sign(&refresh_claims).await.map_err(|e| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Failed to create refresh token: {e}"),
@@ -79,36 +101,12 @@ fn generate_token_pair(
     Ok((access_token, refresh_token))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct CreateJwtRequest {
-    pub app_id: String,
-    pub user_id: Option<String>,
-    #[serde(default)]
-    pub payload: Value,
-}
-
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct CreateJwtResponse {
-    pub access_token: String,
-    pub refresh_token: String,
-    pub expires_in: i64,
-    pub token_type: String,
-}
-
-pub async fn issue(
-    DatabaseConnection(mut conn): DatabaseConnection,
-    auth: TypedHeader<Authorization<Bearer>>,
-    ConfigExtractor(config): ConfigExtractor,
-    Json(CreateJwtRequest {
-        app_id,
-        user_id,
-        payload,
-    }): Json<CreateJwtRequest>,
-) -> Result<Json<CreateJwtResponse>, (StatusCode, String)> {
-    let app_secret = auth.token().to_string();
-
+/// Check the app secret sent as a bearer token against the app's hash
+async fn verify_app_credentials(
+    conn: &mut PgConnection,
+    app_id: &str,
+    app_secret: &str,
+) -> Result<(), (StatusCode, String)> {
     // Get app secret hash from database
     let app = sqlx::query!(
         r#"
@@ -118,7 +116,7 @@ pub async fn issue(
         "#,
         app_id
     )
-    .fetch_optional(&mut *conn)
+    .fetch_optional(conn)
     .await
     .map_err(|e| {
         (
@@ -146,13 +144,51 @@ pub async fn issue(
                 StatusCode::UNAUTHORIZED,
                 "Invalid app credentials".to_string(),
             )
-        })?;
+        })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateJwtRequest {
+    pub app_id: String,
+    pub user_id: Option<String>,
+    #[serde(default)]
+    pub payload: Value,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateJwtResponse {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
+    pub token_type: String,
+}
+
+pub async fn issue(
+    DatabaseConnection(mut conn): DatabaseConnection,
+    auth: TypedHeader<Authorization<Bearer>>,
+    SigningKeys(signing_keys): SigningKeys,
+    Json(CreateJwtRequest {
+        app_id,
+        user_id,
+        payload,
+    }): Json<CreateJwtRequest>,
+) -> Result<Json<CreateJwtResponse>, (StatusCode, String)> {
+    verify_app_credentials(&mut conn, &app_id, auth.token()).await?;
 
     info!("Generating with payload: {payload:?}");
 
-    // Generate tokens
-    let (access_token, refresh_token) =
-        generate_token_pair(app_id, user_id, payload, &config.jwt_secret)?;
+    // Generate tokens, each issue starts a new refresh token family
+    let (access_token, refresh_token) = generate_token_pair(
+        &mut conn,
+        &signing_keys,
+        Uuid::new_v4(),
+        app_id,
+        user_id,
+        payload,
+    )
+    .await?;
 
     Ok(Json(CreateJwtResponse {
         access_token,
@@ -170,42 +206,171 @@ struct Claims {
     payload: JwtPayload,
 }
 
+/// Refresh tokens carry a `jti` naming their `refresh_tokens` row, which also
+/// keeps access tokens from being accepted as refresh tokens. Their `typ` keeps
+/// them from being accepted as access tokens.
+#[derive(Debug, Serialize, Deserialize)]
+struct RefreshClaims {
+    sub: String,
+    exp: i64,
+    iat: i64,
+    jti: Uuid,
+    // Missing from refresh tokens issued before it was added
+    #[serde(default = "refresh_token_type")]
+    typ: String,
+    payload: JwtPayload,
+}
+
+fn refresh_token_type() -> String {
+    REFRESH_TOKEN_TYPE.to_string()
+}
+
+/// Accept a refresh token signed with the legacy secret. It has no
+/// `refresh_tokens` row, so one named after the token is recorded in a new
+/// family and spent like any other, which keeps it from being traded in twice.
+async fn adopt_legacy_refresh_token(
+    conn: &mut PgConnection,
+    signing_keys: &SigningKeyService,
+    token: &str,
+) -> Result<Option<RefreshClaims>, (StatusCode, String)> {
+    let Ok(claims) = signing_keys.verify::<Claims>(token).await else {
+        return Ok(None);
+    };
+
+    // Legacy access and refresh tokens only differ by their lifetime
+    if claims.exp - claims.iat <= ACCESS_TOKEN_LIFETIME.whole_seconds() {
+        return Ok(None);
+    }
+
+    let digest = Sha256::digest(token.as_bytes());
+    let jti = Uuid::from_slice(&digest[..16]).expect("digest is longer than a uuid");
+
+    let expires_at = OffsetDateTime::from_unix_timestamp(claims.exp).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Invalid expiration: {e}"),
+        )
+    })?;
+
+    let database_error = |e: sqlx::Error| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Database error: {e}"),
+        )
+    };
+
+    // A failed insert would abort the caller's transaction without a savepoint
+    let mut savepoint = conn.begin().await.map_err(database_error)?;
+
+    let stored = refresh_token::store(
+        &mut savepoint,
+        jti,
+        Uuid::new_v4(),
+        &claims.payload.app_id,
+        &claims.sub,
+        expires_at,
+    )
+    .await;
+
+    match stored {
+        Ok(()) => savepoint.commit().await.map_err(database_error)?,
+        // Traded in before, redeeming it again reports the reuse
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {}
+        Err(e) => {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to store refresh token: {e}"),
+            ));
+        }
+    }
+
+    Ok(Some(RefreshClaims {
+        sub: claims.sub,
+        exp: claims.exp,
+        iat: claims.iat,
+        jti,
+        typ: refresh_token_type(),
+        payload: claims.payload,
+    }))
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RefreshTokenRequest {
     pub refresh_token: String,
 }
 
+/// Trade a refresh token for a new token pair. The refresh token is spent, and
+/// presenting a spent one again revokes every token descended from the same issue.
 pub async fn refresh(
+    DatabaseConnection(mut conn): DatabaseConnection,
+    SigningKeys(signing_keys): SigningKeys,
     Json(payload): Json<RefreshTokenRequest>,
 ) -> Result<Json<CreateJwtResponse>, (StatusCode, String)> {
-    let secret = std::env::var("JWT_SECRET").map_err(|_| {
+    let invalid_token = || {
         (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "JWT_SECRET not set".to_string(),
+            StatusCode::UNAUTHORIZED,
+            "Invalid refresh token".to_string(),
         )
-    })?;
+    };
 
-    // Decode and validate refresh token
-    let token_data = decode::<Claims>(
-        &payload.refresh_token,
-        &DecodingKey::from_secret(secret.as_bytes()),
-        &Validation::new(Algorithm::HS256),
-    )
-    .map_err(|_| {
+    let database_error = |e: sqlx::Error| {
         (
-            StatusCode::UNAUTHORIZED,
-            "Invalid refresh token".to_string(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Database error: {e}"),
         )
-    })?;
+    };
+
+    let mut transaction = conn.begin().await.map_err(database_error)?;
+
+    // Decode and validate refresh token
+    let claims = if signing_keys.is_legacy(&payload.refresh_token) {
+        adopt_legacy_refresh_token(&mut transaction, &signing_keys, &payload.refresh_token)
+            .await?
+            .ok_or_else(invalid_token)?
+    } else {
+        signing_keys
+            .verify::<RefreshClaims>(&payload.refresh_token)
+            .await
+            .ok()
+            .filter(|claims| claims.typ == REFRESH_TOKEN_TYPE)
+            .ok_or_else(invalid_token)?
+    };
+
+    let redemption = refresh_token::redeem(&mut transaction, claims.jti, &claims.payload.app_id)
+        .await
+        .map_err(database_error)?;
+
+    let family_id = match redemption {
+        Redemption::Rotated { family_id } => family_id,
+        Redemption::Reused { family_id } => {
+            let revoked = refresh_token::revoke_family(&mut transaction, family_id)
+                .await
+                .map_err(database_error)?;
+            transaction.commit().await.map_err(database_error)?;
+
+            warn!(
+                "Refresh token {} of app {} reused, revoked {revoked} tokens in family {family_id}",
+                claims.jti, claims.payload.app_id
+            );
+
+            return Err(invalid_token());
+        }
+        Redemption::Invalid => return Err(invalid_token()),
+    };
 
     // Generate new token pair
     let (access_token, refresh_token) = generate_token_pair(
-        token_data.claims.payload.app_id,
-        Some(token_data.claims.sub.clone()),
-        token_data.claims.payload.custom,
-        &secret,
-    )?;
+        &mut transaction,
+        &signing_keys,
+        family_id,
+        claims.payload.app_id,
+        Some(claims.sub),
+        claims.payload.custom,
+    )
+    .await?;
+
+    transaction.commit().await.map_err(database_error)?;
 
     Ok(Json(CreateJwtResponse {
         access_token,
@@ -214,3 +379,175 @@ pub async fn refresh(
         token_type: "Bearer".to_string(),
     }))
 }
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevokeTokensRequest {
+    pub app_id: String,
+    /// Only revoke this user's tokens, all of the app's when omitted
+    pub user_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevokeTokensResponse {
+    pub revoked: u64,
+}
+
+/// Revoke refresh tokens for app backends, e.g. when a user signs out of their
+/// product. Access tokens already handed out stay valid until they expire.
+pub async fn revoke(
+    DatabaseConnection(mut conn): DatabaseConnection,
+    auth: TypedHeader<Authorization<Bearer>>,
+    Json(RevokeTokensRequest { app_id, user_id }): Json<RevokeTokensRequest>,
+) -> Result<Json<RevokeTokensResponse>, (StatusCode, String)> {
+    verify_app_credentials(&mut conn, &app_id, auth.token()).await?;
+
+    let revoked = refresh_token::revoke(&mut conn, &app_id, user_id.as_deref())
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to revoke tokens: {e}"),
+            )
+        })?;
+
+    Ok(Json(RevokeTokensResponse { revoked }))
+}
+
+/// Public keys of the platform signing keys, for verifying app tokens
+pub async fn jwks(
+    SigningKeys(signing_keys): SigningKeys,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let jwks = signing_keys.jwks().await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to load signing keys: {e}"),
+        )
+    })?;
+
+    // New keys are published an hour before they sign, well within the cache lifetime
+    Ok(([(header::CACHE_CONTROL, JWKS_CACHE_CONTROL)], Json(jwks)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+    use sqlx::PgPool;
+    use std::sync::Arc;
+
+    use crate::services::signing_keys::SigningAlgorithm;
+
+    const LEGACY_SECRET: &str = "legacy";
+
+    async fn create_app(db: &PgPool) {
+        sqlx::query(
+            r#"
+            WITH organization AS (
+                INSERT INTO organizations (name) VALUES ('Test') RETURNING id
+            )
+            INSERT INTO apps (app_id, app_secret_hash, name, organization_id)
+            SELECT 'test_app', 'hash', 'Test', id FROM organization
+            "#,
+        )
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    async fn signing_keys(db: &PgPool) -> Arc<SigningKeyService> {
+        let signing_keys = SigningKeyService::with_settings(
+            db.clone(),
+            SigningAlgorithm::EdDSA,
+            Duration::days(30),
+            Duration::days(30),
+            Some(LEGACY_SECRET.to_string()),
+        );
+        signing_keys.rotate().await.unwrap();
+
+        Arc::new(signing_keys)
+    }
+
+    // Tokens were signed like this before signing keys, access and refresh alike
+    fn legacy_token(lifetime: Duration) -> String {
+        let now = OffsetDateTime::now_utc();
+        let claims = Claims {
+            sub: "user".to_string(),
+            exp: (now + lifetime).unix_timestamp(),
+            iat: now.unix_timestamp(),
+            payload: JwtPayload {
+                app_id: "test_app".to_string(),
+                custom: Value::Null,
+            },
+        };
+
+        encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(LEGACY_SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    async fn refresh_with(
+        db: &PgPool,
+        signing_keys: &Arc<SigningKeyService>,
+        refresh_token: String,
+    ) -> Result<CreateJwtResponse, StatusCode> {
+        refresh(
+            DatabaseConnection(db.acquire().await.unwrap()),
+            SigningKeys(signing_keys.clone()),
+            Json(RefreshTokenRequest { refresh_token }),
+        )
+        .await
+        .map(|Json(response)| response)
+        .map_err(|(status, _)| status)
+    }
+
+    #[sqlx::test(migrator = "db::MIGRATOR")]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn legacy_refresh_tokens_are_traded_in_once(db: PgPool) {
+        create_app(&db).await;
+        let signing_keys = signing_keys(&db).await;
+        let legacy = legacy_token(REFRESH_TOKEN_LIFETIME);
+
+        let traded = refresh_with(&db, &signing_keys, legacy.clone())
+            .await
+            .unwrap();
+
+        // The new pair is signed with a key
+        let claims = signing_keys
+            .verify::<RefreshClaims>(&traded.refresh_token)
+            .await
+            .unwrap();
+        assert_eq!(claims.sub, "user");
+        assert_eq!(claims.typ, REFRESH_TOKEN_TYPE);
+
+        // Presenting it again revokes what it was traded for
+        assert_eq!(
+            refresh_with(&db, &signing_keys, legacy).await.err(),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            refresh_with(&db, &signing_keys, traded.refresh_token)
+                .await
+                .err(),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[sqlx::test(migrator = "db::MIGRATOR")]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn legacy_access_tokens_are_not_refresh_tokens(db: PgPool) {
+        create_app(&db).await;
+        let signing_keys = signing_keys(&db).await;
+
+        assert_eq!(
+            refresh_with(&db, &signing_keys, legacy_token(ACCESS_TOKEN_LIFETIME))
+                .await
+                .err(),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+    }
+}
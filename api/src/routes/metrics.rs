@@ -3,11 +3,17 @@ use axum::{
     extract::{Path, Query},
     http::StatusCode,
 };
+use db::quota;
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
 use uuid::Uuid;
 
-use crate::extractors::{database_connection::DatabaseConnection, session::Session};
+use crate::extractors::{
+    database_connection::DatabaseConnection, redis_connection::RedisConnection, session::Session,
+};
+
+// Share of a limit after which the dashboard shows a "near limit" warning
+const NEAR_LIMIT_RATIO: f64 = 0.8;
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -80,6 +86,38 @@ pub struct OrganizationMetrics {
     pub last_updated: OffsetDateTime,
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageLimit {
+    pub name: String,
+    pub used: i64,
+    pub limit: Option<i64>,
+    pub near_limit: bool,
+    pub exceeded: bool,
+}
+
+impl UsageLimit {
+    fn new(name: &str, used: i64, limit: Option<i64>) -> Self {
+        Self {
+            name: name.to_string(),
+            used,
+            limit,
+            near_limit: limit.is_some_and(|limit| used as f64 >= limit as f64 * NEAR_LIMIT_RATIO),
+            exceeded: limit.is_some_and(|limit| used >= limit),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrganizationLimits {
+    pub organization_id: Uuid,
+    pub plan: String,
+    pub max_document_size_bytes: Option<i64>,
+    pub limits: Vec<UsageLimit>,
+    pub near_limit: bool,
+}
+
 // Route handlers
 
 /// Get comprehensive billing summary for organization's apps
@@ -427,3 +465,100 @@ pub async fn get_organization_metrics(
         last_updated: OffsetDateTime::now_utc(),
     }))
 }
+
+/// Get plan limits and current usage, flagging limits that are nearly used up
+pub async fn get_usage_limits(
+    Session(session): Session,
+    DatabaseConnection(mut conn): DatabaseConnection,
+    RedisConnection(mut redis): RedisConnection,
+    Path(organization_id): Path<Uuid>,
+) -> Result<Json<OrganizationLimits>, (StatusCode, String)> {
+    // Check access
+    let has_access = sqlx::query!(
+        "SELECT 1 as exists FROM user_organizations WHERE user_id = $1 AND organization_id = $2",
+        session.user_id,
+        organization_id
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .is_some();
+
+    if !has_access {
+        return Err((StatusCode::FORBIDDEN, "Access denied".to_string()));
+    }
+
+    let plan = sqlx::query!(
+        r#"
+        SELECT plan, max_connections, max_channels, max_document_size_bytes, monthly_transfer_bytes
+        FROM organization_limits
+        WHERE organization_id = $1
+        "#,
+        organization_id
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    // Counters are maintained by the platform's QuotaEnforcer
+    let organization = organization_id.to_string();
+
+    let connections: i64 = redis::cmd("SCARD")
+        .arg(quota::organization_key(
+            &organization,
+            quota::CONNECTIONS_COUNTER,
+        ))
+        .query_async(&mut redis)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let channels: i64 = redis::cmd("SCARD")
+        .arg(quota::organization_key(
+            &organization,
+            quota::CHANNELS_COUNTER,
+        ))
+        .query_async(&mut redis)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let transfer: Option<i64> = redis::cmd("GET")
+        .arg(quota::organization_key(
+            &organization,
+            &quota::transfer_counter(OffsetDateTime::now_utc()),
+        ))
+        .query_async(&mut redis)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let (plan_name, max_connections, max_channels, max_document_size_bytes, monthly_transfer) =
+        match plan {
+            Some(plan) => (
+                plan.plan,
+                plan.max_connections.map(i64::from),
+                plan.max_channels.map(i64::from),
+                plan.max_document_size_bytes,
+                plan.monthly_transfer_bytes,
+            ),
+            None => ("free".to_string(), None, None, None, None),
+        };
+
+    let limits = vec![
+        UsageLimit::new("connections", connections, max_connections),
+        UsageLimit::new("channels", channels, max_channels),
+        UsageLimit::new(
+            "monthly_transfer_bytes",
+            transfer.unwrap_or(0),
+            monthly_transfer,
+        ),
+    ];
+
+    let near_limit = limits.iter().any(|limit| limit.near_limit);
+
+    Ok(Json(OrganizationLimits {
+        organization_id,
+        plan: plan_name,
+        max_document_size_bytes,
+        limits,
+        near_limit,
+    }))
+}
@@ -1,5 +1,6 @@
 pub mod apps;
 pub mod auth;
+pub mod health;
 pub mod invites;
 pub mod jwt;
 pub mod metrics;
@@ -1,2 +1,3 @@
 pub mod resend;
+pub mod signing_keys;
 pub mod stripe;
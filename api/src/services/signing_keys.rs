@@ -0,0 +1,563 @@
+use anyhow::{Context, anyhow};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, decode_header, encode,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+};
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use rsa::{RsaPrivateKey, pkcs1::EncodeRsaPrivateKey, rand_core::OsRng, traits::PublicKeyParts};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use sqlx::PgPool;
+use std::sync::Arc;
+use time::{Duration, OffsetDateTime};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::config::Config;
+
+// New keys are published this long before they start signing, so verifiers
+// that cache the JWKS pick them up first
+const PUBLISH_LEAD: Duration = Duration::hours(1);
+const ROTATION_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+const RSA_KEY_BITS: usize = 2048;
+const ED25519_PUBLIC_KEY_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum SigningAlgorithm {
+    RS256,
+    EdDSA,
+}
+
+impl SigningAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SigningAlgorithm::RS256 => "RS256",
+            SigningAlgorithm::EdDSA => "EdDSA",
+        }
+    }
+
+    pub fn parse(algorithm: &str) -> anyhow::Result<Self> {
+        match algorithm {
+            "RS256" => Ok(SigningAlgorithm::RS256),
+            "EdDSA" => Ok(SigningAlgorithm::EdDSA),
+            _ => Err(anyhow!("Unsupported signing algorithm: {algorithm}")),
+        }
+    }
+
+    /// The algorithm a registered public key signs with, `None` for key types
+    /// tokens cannot be signed with
+    pub fn for_jwk(jwk: &Jwk) -> Option<Self> {
+        let algorithm = match &jwk.algorithm {
+            AlgorithmParameters::RSA(params) => {
+                let modulus = URL_SAFE_NO_PAD.decode(&params.n).ok()?;
+                let bits = modulus.iter().skip_while(|byte| **byte == 0).count() * 8;
+                URL_SAFE_NO_PAD.decode(&params.e).ok()?;
+
+                (bits >= RSA_KEY_BITS).then_some(SigningAlgorithm::RS256)?
+            }
+            AlgorithmParameters::OctetKeyPair(params) if params.curve == EllipticCurve::Ed25519 => {
+                let public_key = URL_SAFE_NO_PAD.decode(&params.x).ok()?;
+
+                (public_key.len() == ED25519_PUBLIC_KEY_LEN).then_some(SigningAlgorithm::EdDSA)?
+            }
+            _ => return None,
+        };
+
+        let declared = jwk.common.key_algorithm.map(|alg| alg.to_string());
+
+        declared
+            .is_none_or(|declared| declared == algorithm.as_str())
+            .then_some(algorithm)
+    }
+
+    fn encoding_key(&self, private_key: &[u8]) -> EncodingKey {
+        match self {
+            SigningAlgorithm::RS256 => EncodingKey::from_rsa_der(private_key),
+            SigningAlgorithm::EdDSA => EncodingKey::from_ed_der(private_key),
+        }
+    }
+
+    /// Generate a key pair, returning the public JWK and the DER encoded
+    /// private key (PKCS#1 for RSA, PKCS#8 for Ed25519)
+    fn generate(&self, kid: &str) -> anyhow::Result<(Jwk, Vec<u8>)> {
+        let (algorithm, key_algorithm, private_key) = match self {
+            SigningAlgorithm::RS256 => {
+                let private_key = RsaPrivateKey::new(&mut OsRng, RSA_KEY_BITS)?;
+
+                let params = AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n: URL_SAFE_NO_PAD.encode(private_key.n().to_bytes_be()),
+                    e: URL_SAFE_NO_PAD.encode(private_key.e().to_bytes_be()),
+                });
+
+                let der = private_key.to_pkcs1_der()?.as_bytes().to_vec();
+
+                (params, KeyAlgorithm::RS256, der)
+            }
+            SigningAlgorithm::EdDSA => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                    .map_err(|_| anyhow!("Failed to generate Ed25519 key"))?;
+                let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+                    .map_err(|e| anyhow!("Failed to parse Ed25519 key: {e}"))?;
+
+                let params = AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref()),
+                });
+
+                (params, KeyAlgorithm::EdDSA, pkcs8.as_ref().to_vec())
+            }
+        };
+
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(key_algorithm),
+                key_id: Some(kid.to_string()),
+                ..Default::default()
+            },
+            algorithm,
+        };
+
+        Ok((jwk, private_key))
+    }
+}
+
+impl From<SigningAlgorithm> for Algorithm {
+    fn from(algorithm: SigningAlgorithm) -> Self {
+        match algorithm {
+            SigningAlgorithm::RS256 => Algorithm::RS256,
+            SigningAlgorithm::EdDSA => Algorithm::EdDSA,
+        }
+    }
+}
+
+/// Signs app tokens with rotating platform keys.
+///
+/// Each key signs for `rotation_period`. Its successor is generated
+/// `PUBLISH_LEAD` ahead and takes over when it retires, and retired keys keep
+/// verifying for `overlap` so tokens signed just before a rotation stay valid.
+pub struct SigningKeyService {
+    db: PgPool,
+    algorithm: SigningAlgorithm,
+    rotation_period: Duration,
+    overlap: Duration,
+    // HS256 secret of tokens issued before signing keys, which carry no `kid`
+    legacy_secret: Option<String>,
+}
+
+impl SigningKeyService {
+    pub fn new(db: PgPool, config: &Config) -> Self {
+        Self::with_settings(
+            db,
+            config.jwt_signing_algorithm,
+            Duration::days(config.jwt_key_rotation_days),
+            Duration::days(config.jwt_key_overlap_days),
+            config.jwt_secret.clone(),
+        )
+    }
+
+    pub fn with_settings(
+        db: PgPool,
+        algorithm: SigningAlgorithm,
+        rotation_period: Duration,
+        overlap: Duration,
+        legacy_secret: Option<String>,
+    ) -> Self {
+        Self {
+            db,
+            algorithm,
+            rotation_period,
+            overlap,
+            legacy_secret,
+        }
+    }
+
+    /// Sign `claims` with the current platform key, its id goes in the `kid` header
+    pub async fn sign<T: Serialize>(&self, claims: &T) -> anyhow::Result<String> {
+        let key = sqlx::query!(
+            r#"
+            SELECT kid, algorithm, private_key AS "private_key!"
+            FROM signing_keys
+            WHERE app_id IS NULL
+            AND not_before <= NOW()
+            AND retire_at > NOW()
+            ORDER BY not_before DESC
+            LIMIT 1
+            "#
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .context("No active signing key")?;
+
+        let algorithm = SigningAlgorithm::parse(&key.algorithm)?;
+
+        let mut header = Header::new(algorithm.into());
+        header.kid = Some(key.kid);
+
+        encode(&header, claims, &algorithm.encoding_key(&key.private_key))
+            .context("Failed to sign token")
+    }
+
+    /// Verify a token signed by a platform key that has not expired, or by the
+    /// legacy secret when it is configured
+    pub async fn verify<T: DeserializeOwned>(&self, token: &str) -> anyhow::Result<T> {
+        let Some(kid) = decode_header(token)?.kid else {
+            return self.verify_legacy(token);
+        };
+
+        let key = sqlx::query!(
+            r#"
+            SELECT algorithm, public_jwk
+            FROM signing_keys
+            WHERE kid = $1
+            AND app_id IS NULL
+            AND (expires_at IS NULL OR expires_at > NOW())
+            "#,
+            kid
+        )
+        .fetch_optional(&self.db)
+        .await?
+        .with_context(|| format!("Unknown signing key {kid}"))?;
+
+        let algorithm = SigningAlgorithm::parse(&key.algorithm)?;
+        let jwk: Jwk = serde_json::from_value(key.public_jwk)?;
+
+        let token_data = decode::<T>(
+            token,
+            &DecodingKey::from_jwk(&jwk)?,
+            &Validation::new(algorithm.into()),
+        )?;
+
+        Ok(token_data.claims)
+    }
+
+    /// Whether `token` was signed with the legacy secret, which predates key ids
+    pub fn is_legacy(&self, token: &str) -> bool {
+        self.legacy_secret.is_some()
+            && decode_header(token).is_ok_and(|header| header.kid.is_none())
+    }
+
+    fn verify_legacy<T: DeserializeOwned>(&self, token: &str) -> anyhow::Result<T> {
+        let secret = self.legacy_secret.as_ref().context("Token has no key id")?;
+
+        let token_data = decode::<T>(
+            token,
+            &DecodingKey::from_secret(secret.as_bytes()),
+            &Validation::new(Algorithm::HS256),
+        )?;
+
+        Ok(token_data.claims)
+    }
+
+    /// Public platform keys verifiers should accept: the upcoming one, the
+    /// current one and those retired within the overlap window
+    pub async fn jwks(&self) -> anyhow::Result<JwkSet> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT public_jwk
+            FROM signing_keys
+            WHERE app_id IS NULL
+            AND (expires_at IS NULL OR expires_at > NOW())
+            ORDER BY not_before DESC
+            "#
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        let keys = rows
+            .into_iter()
+            .map(|row| serde_json::from_value(row.public_jwk))
+            .collect::<Result<Vec<Jwk>, _>>()?;
+
+        Ok(JwkSet { keys })
+    }
+
+    /// Line up the next platform key once the newest one retires within
+    /// `PUBLISH_LEAD`. Also creates the first key on a fresh database.
+    pub async fn rotate(&self) -> anyhow::Result<()> {
+        let mut tx = self.db.begin().await?;
+
+        // Serialize rotations across api replicas, reads are not blocked
+        sqlx::query!("LOCK TABLE signing_keys IN SHARE ROW EXCLUSIVE MODE")
+            .execute(&mut *tx)
+            .await?;
+
+        let latest_retire_at = sqlx::query!(
+            r#"
+            SELECT MAX(retire_at) AS retire_at
+            FROM signing_keys
+            WHERE app_id IS NULL
+            "#
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .retire_at;
+
+        let now = OffsetDateTime::now_utc();
+
+        if latest_retire_at.is_some_and(|retire_at| retire_at > now + PUBLISH_LEAD) {
+            return Ok(());
+        }
+
+        // Take over exactly when the newest key retires, or right away if none signs
+        let not_before = latest_retire_at
+            .filter(|retire_at| *retire_at > now)
+            .unwrap_or(now);
+        let retire_at = not_before + self.rotation_period;
+        let expires_at = retire_at + self.overlap;
+
+        let kid = Uuid::new_v4().to_string();
+        let algorithm = self.algorithm;
+
+        // RSA key generation takes long enough to stall the runtime
+        let (jwk, private_key) = tokio::task::spawn_blocking({
+            let kid = kid.clone();
+            move || algorithm.generate(&kid)
+        })
+        .await??;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO signing_keys (
+                kid, algorithm, public_jwk, private_key, not_before, retire_at, expires_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+            kid,
+            algorithm.as_str(),
+            serde_json::to_value(&jwk)?,
+            private_key,
+            not_before,
+            retire_at,
+            expires_at,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        info!(
+            "Created {} signing key {kid}, signing from {not_before}",
+            algorithm.as_str()
+        );
+
+        Ok(())
+    }
+
+    pub fn start_rotation(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ROTATION_CHECK_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                if let Err(e) = self.rotate().await {
+                    error!("Failed to rotate signing keys: {e}");
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::jwk::{RSAKeyParameters, RSAKeyType};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestClaims {
+        sub: String,
+        exp: i64,
+    }
+
+    fn claims() -> TestClaims {
+        TestClaims {
+            sub: "user".to_string(),
+            // 2100-01-01, so every call builds the same claims
+            exp: 4_102_444_800,
+        }
+    }
+
+    fn service(
+        db: PgPool,
+        rotation_period: Duration,
+        legacy_secret: Option<&str>,
+    ) -> SigningKeyService {
+        SigningKeyService::with_settings(
+            db,
+            SigningAlgorithm::EdDSA,
+            rotation_period,
+            Duration::days(30),
+            legacy_secret.map(str::to_string),
+        )
+    }
+
+    async fn kids(db: &PgPool) -> Vec<String> {
+        sqlx::query_scalar("SELECT kid FROM signing_keys ORDER BY not_before")
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    fn rsa_jwk(modulus_bytes: usize, declared: Option<KeyAlgorithm>) -> Jwk {
+        Jwk {
+            common: CommonParameters {
+                key_algorithm: declared,
+                ..Default::default()
+            },
+            algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: URL_SAFE_NO_PAD.encode(vec![0xff; modulus_bytes]),
+                e: URL_SAFE_NO_PAD.encode([1, 0, 1]),
+            }),
+        }
+    }
+
+    #[test]
+    fn generated_keys_are_accepted_for_their_algorithm() {
+        let (jwk, _) = SigningAlgorithm::EdDSA.generate("kid").unwrap();
+
+        assert_eq!(
+            SigningAlgorithm::for_jwk(&jwk),
+            Some(SigningAlgorithm::EdDSA)
+        );
+        assert_eq!(
+            SigningAlgorithm::for_jwk(&rsa_jwk(256, Some(KeyAlgorithm::RS256))),
+            Some(SigningAlgorithm::RS256)
+        );
+    }
+
+    #[test]
+    fn weak_or_mislabelled_keys_are_refused() {
+        assert_eq!(SigningAlgorithm::for_jwk(&rsa_jwk(128, None)), None);
+        assert_eq!(
+            SigningAlgorithm::for_jwk(&rsa_jwk(256, Some(KeyAlgorithm::EdDSA))),
+            None
+        );
+
+        let short_ed25519 = Jwk {
+            common: CommonParameters::default(),
+            algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode([0; 16]),
+            }),
+        };
+
+        assert_eq!(SigningAlgorithm::for_jwk(&short_ed25519), None);
+    }
+
+    #[sqlx::test(migrator = "db::MIGRATOR")]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn rotate_creates_the_first_key_once(db: PgPool) {
+        let signing_keys = service(db.clone(), Duration::days(30), None);
+
+        signing_keys.rotate().await.unwrap();
+        signing_keys.rotate().await.unwrap();
+
+        assert_eq!(kids(&db).await.len(), 1);
+    }
+
+    #[sqlx::test(migrator = "db::MIGRATOR")]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn rotate_lines_up_a_successor_before_the_current_key_retires(db: PgPool) {
+        // Retires within the publish lead, so the next rotation adds a successor
+        let signing_keys = service(db.clone(), Duration::minutes(30), None);
+
+        signing_keys.rotate().await.unwrap();
+        signing_keys.rotate().await.unwrap();
+
+        let kids = kids(&db).await;
+        assert_eq!(kids.len(), 2);
+
+        let handover = sqlx::query_scalar::<_, bool>(
+            r#"
+            SELECT successor.not_before = current.retire_at
+            FROM signing_keys current, signing_keys successor
+            WHERE current.kid = $1 AND successor.kid = $2
+            "#,
+        )
+        .bind(&kids[0])
+        .bind(&kids[1])
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert!(handover);
+
+        // The current key keeps signing until it retires
+        let token = signing_keys.sign(&claims()).await.unwrap();
+        assert_eq!(decode_header(&token).unwrap().kid.as_ref(), Some(&kids[0]));
+
+        // Both are published
+        assert_eq!(signing_keys.jwks().await.unwrap().keys.len(), 2);
+    }
+
+    #[sqlx::test(migrator = "db::MIGRATOR")]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn signed_tokens_verify_until_their_key_expires(db: PgPool) {
+        let signing_keys = service(db.clone(), Duration::days(30), None);
+        signing_keys.rotate().await.unwrap();
+
+        let token = signing_keys.sign(&claims()).await.unwrap();
+        assert_eq!(
+            signing_keys.verify::<TestClaims>(&token).await.unwrap(),
+            claims()
+        );
+
+        sqlx::query("UPDATE signing_keys SET expires_at = NOW() - INTERVAL '1 second'")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        assert!(signing_keys.verify::<TestClaims>(&token).await.is_err());
+        assert!(signing_keys.jwks().await.unwrap().keys.is_empty());
+    }
+
+    #[sqlx::test(migrator = "db::MIGRATOR")]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn jwks_only_publishes_public_keys(db: PgPool) {
+        let signing_keys = service(db.clone(), Duration::days(30), None);
+        signing_keys.rotate().await.unwrap();
+
+        let jwks = serde_json::to_value(signing_keys.jwks().await.unwrap()).unwrap();
+        let key = &jwks["keys"][0];
+
+        assert_eq!(key["kid"], kids(&db).await[0]);
+        assert_eq!(key["alg"], "EdDSA");
+        assert!(key.get("x").is_some());
+        assert!(key.get("d").is_none());
+    }
+
+    #[sqlx::test(migrator = "db::MIGRATOR")]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn tokens_without_a_key_id_need_the_legacy_secret(db: PgPool) {
+        let token = encode(
+            &Header::new(Algorithm::HS256),
+            &claims(),
+            &EncodingKey::from_secret(b"legacy"),
+        )
+        .unwrap();
+
+        let without_secret = service(db.clone(), Duration::days(30), None);
+        assert!(!without_secret.is_legacy(&token));
+        assert!(without_secret.verify::<TestClaims>(&token).await.is_err());
+
+        let with_secret = service(db.clone(), Duration::days(30), Some("legacy"));
+        assert!(with_secret.is_legacy(&token));
+        assert_eq!(
+            with_secret.verify::<TestClaims>(&token).await.unwrap(),
+            claims()
+        );
+
+        let other_secret = service(db, Duration::days(30), Some("other"));
+        assert!(other_secret.verify::<TestClaims>(&token).await.is_err());
+    }
+}
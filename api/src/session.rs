@@ -172,12 +172,11 @@ pub async fn invalidate_all_user_sessions(
     let mut keys_to_delete = Vec::new();
 
     for (key, data) in keys.iter().zip(session_data.iter()) {
-        if let Some(session_str) = data {
-            if let Ok(session) = serde_json::from_str::<Session>(session_str) {
-                if session.user_id == user_id {
-                    keys_to_delete.push(key.clone());
-                }
-            }
+        if let Some(session_str) = data
+            && let Ok(session) = serde_json::from_str::<Session>(session_str)
+            && session.user_id == user_id
+        {
+            keys_to_delete.push(key.clone());
         }
     }
 
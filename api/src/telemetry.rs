@@ -0,0 +1,61 @@
+use axum::{extract::Request, http::HeaderMap, middleware::Next, response::Response};
+use opentelemetry::{global, propagation::Extractor, trace::TracerProvider as _};
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use tracing::{Instrument, field, info_span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::config::Config;
+
+pub use telemetry::TracingExporter;
+
+const SERVICE_NAME: &str = "api";
+
+/// Build the tracer provider selected in config, or `None` when tracing export
+/// is disabled
+pub fn setup_tracer_provider(
+    config: &Config,
+) -> Result<Option<SdkTracerProvider>, Box<dyn std::error::Error>> {
+    telemetry::setup_tracer_provider(
+        SERVICE_NAME,
+        config.tracing_exporter,
+        &config.tracing_otlp_endpoint,
+        &config.tracing_file_path,
+    )
+}
+
+pub fn tracer(provider: &SdkTracerProvider) -> SdkTracer {
+    provider.tracer(SERVICE_NAME)
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Wrap each request in a span, continuing the trace of an incoming
+/// `traceparent` header
+pub async fn trace_request(request: Request, next: Next) -> Response {
+    let span = info_span!(
+        "http.request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        status = field::Empty
+    );
+
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    span.set_parent(parent);
+
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("status", response.status().as_u16());
+
+    response
+}
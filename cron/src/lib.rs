@@ -1,9 +1,14 @@
 use aws_sdk_sqs::operation::send_message::builders::SendMessageFluentBuilder;
 use kinetics_macro::cron;
+use opentelemetry::trace::TracerProvider as _;
 use sqlx::postgres::PgPoolOptions;
 use std::collections::HashMap;
+use telemetry::TracingExporter;
 use tokio::io;
-use tracing::{debug, error};
+use tracing::{debug, error, info_span, instrument::WithSubscriber, Instrument};
+use tracing_subscriber::{fmt, prelude::*, registry, EnvFilter};
+
+const SERVICE_NAME: &str = "cron";
 
 /// A regular cron job which prints out every hour
 ///
@@ -14,6 +19,47 @@ pub async fn cron(
     secrets: &HashMap<String, String>,
     _queues: &HashMap<String, SendMessageFluentBuilder>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let exporter = match secrets.get("TRACING_EXPORTER").map(String::as_str) {
+        Some("otlp") => TracingExporter::Otlp,
+        Some("file") => TracingExporter::File,
+        _ => TracingExporter::None,
+    };
+    let otlp_endpoint = secrets
+        .get("TRACING_OTLP_ENDPOINT")
+        .map(|s| s.as_str())
+        .unwrap_or("http://localhost:4318/v1/traces");
+    let file_path = secrets
+        .get("TRACING_FILE_PATH")
+        .map(|s| s.as_str())
+        .unwrap_or("traces.jsonl");
+
+    let tracer_provider =
+        telemetry::setup_tracer_provider(SERVICE_NAME, exporter, otlp_endpoint, file_path)?;
+    let otel_layer = tracer_provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME)));
+
+    let subscriber = registry()
+        .with(EnvFilter::from_default_env())
+        .with(fmt::layer().with_target(false))
+        .with(otel_layer);
+
+    let result = run(secrets)
+        .instrument(info_span!("cron.run"))
+        .with_subscriber(subscriber)
+        .await;
+
+    // Spans are exported in batches, flushed before the invocation ends
+    if let Some(tracer_provider) = tracer_provider {
+        if let Err(e) = tracer_provider.shutdown() {
+            error!("Failed to shut down tracer provider: {e}");
+        }
+    }
+
+    result
+}
+
+async fn run(secrets: &HashMap<String, String>) -> Result<(), Box<dyn std::error::Error>> {
     let db_url = secrets
         .get("DATABASE_URL")
         .map(|s| s.as_str())
@@ -21,7 +67,7 @@ pub async fn cron(
 
     let db = PgPoolOptions::new().connect(db_url).await.map_err(|e| {
         error!("Failed to connect to database: {e:?}");
-        io::Error::new(io::ErrorKind::Other, "Failed to connect to database")
+        io::Error::other("Failed to connect to database")
     })?;
 
     let users = sqlx::query!(
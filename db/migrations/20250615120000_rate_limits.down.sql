@@ -0,0 +1,5 @@
+-- Drop triggers
+DROP TRIGGER IF EXISTS set_updated_at_app_rate_limits ON app_rate_limits;
+
+-- Drop tables
+DROP TABLE IF EXISTS app_rate_limits;
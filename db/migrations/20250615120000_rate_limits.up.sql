@@ -0,0 +1,20 @@
+-- Per-app overrides for WebSocket rate limits. NULL columns fall back to the
+-- platform defaults.
+CREATE TABLE IF NOT EXISTS app_rate_limits (
+    app_id VARCHAR(32) PRIMARY KEY REFERENCES apps(app_id) ON DELETE CASCADE,
+    connection_messages_per_second INTEGER,
+    connection_bytes_per_second INTEGER,
+    user_messages_per_second INTEGER,
+    user_bytes_per_second INTEGER,
+    app_messages_per_second INTEGER,
+    app_bytes_per_second INTEGER,
+    max_violations INTEGER,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT timezone('UTC', CURRENT_TIMESTAMP),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT timezone('UTC', CURRENT_TIMESTAMP)
+);
+
+-- Trigger for app_rate_limits updated_at
+CREATE TRIGGER set_updated_at_app_rate_limits
+BEFORE UPDATE ON app_rate_limits
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at_column();
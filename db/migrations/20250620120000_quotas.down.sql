@@ -0,0 +1,7 @@
+-- Drop triggers
+DROP TRIGGER IF EXISTS set_updated_at_app_limits ON app_limits;
+DROP TRIGGER IF EXISTS set_updated_at_organization_limits ON organization_limits;
+
+-- Drop tables
+DROP TABLE IF EXISTS app_limits;
+DROP TABLE IF EXISTS organization_limits;
@@ -0,0 +1,33 @@
+-- Plan limits per organization. NULL columns mean unlimited.
+CREATE TABLE IF NOT EXISTS organization_limits (
+    organization_id UUID PRIMARY KEY REFERENCES organizations(id) ON DELETE CASCADE,
+    plan VARCHAR(64) NOT NULL DEFAULT 'free',
+    max_connections INTEGER,
+    max_channels INTEGER,
+    max_document_size_bytes BIGINT,
+    monthly_transfer_bytes BIGINT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT timezone('UTC', CURRENT_TIMESTAMP),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT timezone('UTC', CURRENT_TIMESTAMP)
+);
+
+-- Optional tighter limits for a single app within its organization
+CREATE TABLE IF NOT EXISTS app_limits (
+    app_id VARCHAR(32) PRIMARY KEY REFERENCES apps(app_id) ON DELETE CASCADE,
+    max_connections INTEGER,
+    max_channels INTEGER,
+    max_document_size_bytes BIGINT,
+    monthly_transfer_bytes BIGINT,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT timezone('UTC', CURRENT_TIMESTAMP),
+    updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT timezone('UTC', CURRENT_TIMESTAMP)
+);
+
+-- Triggers for updated_at
+CREATE TRIGGER set_updated_at_organization_limits
+BEFORE UPDATE ON organization_limits
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at_column();
+
+CREATE TRIGGER set_updated_at_app_limits
+BEFORE UPDATE ON app_limits
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at_column();
@@ -0,0 +1,4 @@
+-- Whether clients of an app may pass their token in the connection URL query
+-- string. Apps that turn this off must use the subprotocol header or an auth
+-- message instead.
+ALTER TABLE apps ADD COLUMN IF NOT EXISTS allow_query_token BOOLEAN NOT NULL DEFAULT TRUE;
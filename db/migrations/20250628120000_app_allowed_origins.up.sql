@@ -0,0 +1,3 @@
+-- Web origins allowed to open WebSocket connections for an app. An empty list
+-- allows any origin.
+ALTER TABLE apps ADD COLUMN IF NOT EXISTS allowed_origins TEXT[] NOT NULL DEFAULT '{}';
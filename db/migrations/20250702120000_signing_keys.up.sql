@@ -0,0 +1,20 @@
+-- Keys that sign app tokens. Platform keys (app_id NULL) are generated and
+-- rotated by the api and published in its JWKS. App keys are public keys
+-- registered by customers who sign their own tokens.
+CREATE TABLE signing_keys (
+    kid TEXT PRIMARY KEY,
+    app_id VARCHAR(32) REFERENCES apps(app_id) ON DELETE CASCADE,
+    algorithm TEXT NOT NULL CHECK (algorithm IN ('RS256', 'EdDSA')),
+    public_jwk JSONB NOT NULL,
+    -- DER encoded, only platform keys have one
+    private_key BYTEA,
+    -- Platform keys sign between not_before and retire_at
+    not_before TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT timezone('UTC', CURRENT_TIMESTAMP),
+    retire_at TIMESTAMP WITH TIME ZONE,
+    -- Tokens are no longer accepted after expires_at, NULL never expires
+    expires_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT timezone('UTC', CURRENT_TIMESTAMP),
+    CHECK ((app_id IS NULL) = (private_key IS NOT NULL))
+);
+
+CREATE INDEX idx_signing_keys_app_id ON signing_keys(app_id);
@@ -0,0 +1,18 @@
+-- Refresh tokens handed out with app tokens. Each one is single use: a
+-- refresh marks it used and issues its successor in the same family. A used
+-- token coming back means it leaked, so its whole family is revoked.
+CREATE TABLE refresh_tokens (
+    -- The jti claim of the token
+    id UUID PRIMARY KEY,
+    family_id UUID NOT NULL,
+    app_id VARCHAR(32) NOT NULL REFERENCES apps(app_id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    used_at TIMESTAMP WITH TIME ZONE,
+    revoked_at TIMESTAMP WITH TIME ZONE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT timezone('UTC', CURRENT_TIMESTAMP)
+);
+
+CREATE INDEX idx_refresh_tokens_family_id ON refresh_tokens(family_id);
+CREATE INDEX idx_refresh_tokens_app_id_user_id ON refresh_tokens(app_id, user_id);
+CREATE INDEX idx_refresh_tokens_expires_at ON refresh_tokens(expires_at);
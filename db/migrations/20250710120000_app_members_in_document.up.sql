@@ -0,0 +1,7 @@
+-- Whether an app's channel members are projected into their documents under
+-- `members`. Recipients always come from the server-side membership index.
+-- Member metadata comes from the custom claims of each member's token, which
+-- every other member could read once projected, so new apps opt in. Apps that
+-- existed keep the projection they relied on.
+ALTER TABLE apps ADD COLUMN IF NOT EXISTS members_in_document BOOLEAN NOT NULL DEFAULT FALSE;
+UPDATE apps SET members_in_document = TRUE;
@@ -0,0 +1,2 @@
+ALTER TABLE apps DROP COLUMN IF EXISTS relay_keep_last;
+ALTER TABLE apps DROP COLUMN IF EXISTS relay_channel_prefixes;
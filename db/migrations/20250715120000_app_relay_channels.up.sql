@@ -0,0 +1,5 @@
+-- Channels named with one of an app's relay prefixes relay patches verbatim
+-- to their members instead of merging them into a document. Relay channels
+-- keep their last patch for joiners when `relay_keep_last` is set.
+ALTER TABLE apps ADD COLUMN IF NOT EXISTS relay_channel_prefixes TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE apps ADD COLUMN IF NOT EXISTS relay_keep_last BOOLEAN NOT NULL DEFAULT FALSE;
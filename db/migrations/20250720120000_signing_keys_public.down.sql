@@ -0,0 +1,18 @@
+REVOKE SELECT, INSERT, UPDATE ON connection_session, data_transfer_metrics FROM platform;
+REVOKE SELECT ON apps, app_rate_limits, app_limits, organization_limits FROM platform;
+REVOKE SELECT ON signing_keys_public FROM platform;
+DROP VIEW signing_keys_public;
+
+-- Leave the role alone if it predates the migration, or if another database
+-- in the cluster still grants it something
+DO $$
+BEGIN
+    IF shobj_description(
+        (SELECT oid FROM pg_roles WHERE rolname = 'platform'), 'pg_authid'
+    ) = 'created by migration 20250720120000' THEN
+        DROP ROLE platform;
+    END IF;
+EXCEPTION
+    WHEN dependent_objects_still_exist THEN NULL;
+END
+$$;
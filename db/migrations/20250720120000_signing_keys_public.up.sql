@@ -0,0 +1,22 @@
+-- The platform only verifies tokens. It reads keys through this view, which
+-- leaves out the private keys of platform keys.
+CREATE VIEW signing_keys_public AS
+SELECT kid, app_id, algorithm, public_jwk, not_before, retire_at, expires_at
+FROM signing_keys;
+
+-- Role granted to the platform's database user. The comment marks a role
+-- this migration created, so the down migration only drops that one.
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT FROM pg_roles WHERE rolname = 'platform') THEN
+        CREATE ROLE platform NOLOGIN;
+        COMMENT ON ROLE platform IS 'created by migration 20250720120000';
+    END IF;
+END
+$$;
+
+-- Only what the platform queries: keys, app settings and limits, and the
+-- connection metrics it records
+GRANT SELECT ON signing_keys_public TO platform;
+GRANT SELECT ON apps, app_rate_limits, app_limits, organization_limits TO platform;
+GRANT SELECT, INSERT, UPDATE ON connection_session, data_transfer_metrics TO platform;
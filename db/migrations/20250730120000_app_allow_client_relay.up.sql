@@ -0,0 +1,4 @@
+-- Whether clients may declare relay channels beyond the app's relay prefixes.
+-- Apps that existed keep allowing it, new apps opt in.
+ALTER TABLE apps ADD COLUMN IF NOT EXISTS allow_client_relay BOOLEAN NOT NULL DEFAULT FALSE;
+UPDATE apps SET allow_client_relay = TRUE;
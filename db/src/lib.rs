@@ -1,4 +1,5 @@
 pub mod dto;
+pub mod quota;
 pub mod seed;
 
 use sqlx::migrate::Migrator;
@@ -0,0 +1,23 @@
+//! Redis keys of the quota counters. The platform maintains them and the api
+//! reads them back for usage reports, both must build them the same way.
+
+use time::OffsetDateTime;
+
+/// Set of open connection ids
+pub const CONNECTIONS_COUNTER: &str = "connections";
+
+/// Set of channels with at least one member
+pub const CHANNELS_COUNTER: &str = "channels";
+
+pub fn app_key(app_id: &str, counter: &str) -> String {
+    format!("quota:app:{app_id}:{counter}")
+}
+
+pub fn organization_key(organization_id: &str, counter: &str) -> String {
+    format!("quota:org:{organization_id}:{counter}")
+}
+
+/// Bytes transferred during the calendar month of `at`
+pub fn transfer_counter(at: OffsetDateTime) -> String {
+    format!("transfer:{}-{:02}", at.year(), at.month() as u8)
+}
@@ -0,0 +1,121 @@
+//! Dependency checks behind the readiness endpoints of the api and the
+//! platform. Each service decides which dependencies it needs on top of this.
+
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use tokio::time::{Duration, timeout};
+use tracing::warn;
+
+// Upper bound for a single dependency check
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub ready: bool,
+    pub draining: bool,
+    pub checks: BTreeMap<&'static str, bool>,
+}
+
+impl HealthReport {
+    /// Ready only when every check passed and shutdown has not started
+    pub fn new(checks: BTreeMap<&'static str, bool>, draining: bool) -> Self {
+        Self {
+            ready: !draining && checks.values().all(|ok| *ok),
+            draining,
+            checks,
+        }
+    }
+}
+
+/// A dependency the service cannot serve without
+#[async_trait]
+pub trait DependencyCheck: Send + Sync {
+    /// Whether the dependency answered within the check timeout
+    async fn is_up(&self) -> bool;
+}
+
+async fn answers<T, E: Display>(
+    dependency: &str,
+    check: impl Future<Output = Result<T, E>>,
+) -> bool {
+    match timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            warn!("{dependency} health check failed: {e}");
+            false
+        }
+        Err(_) => {
+            warn!("{dependency} health check timed out");
+            false
+        }
+    }
+}
+
+#[async_trait]
+impl DependencyCheck for PgPool {
+    async fn is_up(&self) -> bool {
+        answers("Postgres", sqlx::query("SELECT 1").execute(self)).await
+    }
+}
+
+#[async_trait]
+impl DependencyCheck for ConnectionManager {
+    async fn is_up(&self) -> bool {
+        let mut conn = self.clone();
+        let cmd = redis::cmd("PING");
+
+        answers("Redis", cmd.query_async::<String>(&mut conn)).await
+    }
+}
+
+#[cfg(feature = "nats")]
+#[async_trait]
+impl DependencyCheck for async_nats::Client {
+    async fn is_up(&self) -> bool {
+        self.connection_state() == async_nats::connection::State::Connected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::postgres::PgPoolOptions;
+
+    fn checks(postgres: bool, redis: bool) -> BTreeMap<&'static str, bool> {
+        BTreeMap::from([("postgres", postgres), ("redis", redis)])
+    }
+
+    #[test]
+    fn ready_only_when_every_check_passed() {
+        assert!(HealthReport::new(checks(true, true), false).ready);
+        assert!(!HealthReport::new(checks(true, false), false).ready);
+    }
+
+    #[test]
+    fn not_ready_while_draining() {
+        let report = HealthReport::new(checks(true, true), true);
+
+        assert!(!report.ready);
+        assert!(report.draining);
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn postgres_check_passes_when_reachable(db: PgPool) {
+        assert!(db.is_up().await);
+    }
+
+    #[tokio::test]
+    async fn postgres_check_fails_when_unreachable() {
+        let db = PgPoolOptions::new()
+            .connect_lazy("postgres://postgres@127.0.0.1:1/postgres")
+            .unwrap();
+
+        assert!(!db.is_up().await);
+    }
+}
@@ -0,0 +1,92 @@
+// Publishing a batch as the whole document against publishing the changes it
+// makes, on a document large enough for the difference to show
+
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use platform::ws::{Crdt, crdt::CrdtDocument};
+use serde_json::json;
+use tokio::runtime::Runtime;
+
+// Entries in the document, each a few dozen bytes once encoded
+const ENTRIES: usize = 10_000;
+
+// The document as stored, and a patch adding one entry to it
+fn document_and_patch(runtime: &Runtime) -> (Vec<u8>, Vec<u8>) {
+    runtime.block_on(async {
+        let mut crdt = CrdtDocument::new().await;
+
+        for i in 0..ENTRIES {
+            crdt.insert_value(
+                &["state", &format!("entry-{i}")],
+                json!({"title": format!("Entry {i}"), "done": i % 2 == 0}),
+            )
+            .await
+            .unwrap();
+        }
+
+        let document = crdt.get_state_as_update().await;
+        let state_vector = crdt.state_vector().await;
+
+        crdt.insert_value(
+            &["state", "entry-new"],
+            json!({"title": "New", "done": false}),
+        )
+        .await
+        .unwrap();
+
+        (document, crdt.to_update(&state_vector).await)
+    })
+}
+
+fn publish_batch(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let (document, patch) = document_and_patch(&runtime);
+
+    let mut group = c.benchmark_group("publish_batch");
+
+    group.bench_function("full_state", |b| {
+        b.iter_batched(
+            || runtime.block_on(CrdtDocument::from_update(&document)),
+            |mut crdt| {
+                runtime.block_on(async {
+                    crdt.apply_delta(&patch).await.unwrap();
+                    crdt.get_state_as_update().await
+                })
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.bench_function("diff", |b| {
+        b.iter_batched(
+            || runtime.block_on(CrdtDocument::from_update(&document)),
+            |mut crdt| {
+                runtime.block_on(async {
+                    let state_vector = crdt.state_vector().await;
+                    crdt.apply_delta(&patch).await.unwrap();
+                    crdt.to_update(&state_vector).await
+                })
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.finish();
+
+    // What each recipient downloads, which the timings above do not show
+    let (full_state, diff) = runtime.block_on(async {
+        let mut crdt = CrdtDocument::from_update(&document).await;
+        let state_vector = crdt.state_vector().await;
+        crdt.apply_delta(&patch).await.unwrap();
+
+        (
+            crdt.get_state_as_update().await.len(),
+            crdt.to_update(&state_vector).await.len(),
+        )
+    });
+
+    println!("publish_batch/full_state: {full_state} bytes per recipient");
+    println!("publish_batch/diff: {diff} bytes per recipient");
+}
+
+criterion_group!(benches, publish_batch);
+criterion_main!(benches);
@@ -1,4 +1,5 @@
 pub mod messaging;
+pub mod observability;
 pub mod storage;
 pub mod utils;
 pub mod ws;
@@ -6,20 +6,28 @@ use platform::{
         transports::nats::NatsTransportBuilder,
     },
     ws::{
-        AuthMiddleware, Session,
-        metrics::{MetricsCollector, MetricsRepository},
+        AuthMiddleware, ChannelOwnership, DocumentCache, QuotaEnforcer, RedisRateLimiter,
+        ResumeStore, Session, SessionReaper,
+        apps::{AppRepository, OriginPolicy},
+        metrics::{MetricsCollector, MetricsRepository, SessionSweeper},
+        quota::{QuotaReconciler, QuotaRepository, RedisQuotaStore},
+        rate_limit::RateLimitRepository,
     },
 };
+use platform::{observability, observability::Health, utils, ws::BroadcastMiddleware};
 use platform::{
-    storage::RedisDocumentStorage,
+    storage::{
+        RedisDocumentStorage, RedisEncryptedLog, RedisMembershipIndex, RedisPatchLog,
+        RedisRelayStore,
+    },
     ws::{BusProxy, MessageHandler, connection::WsConnectionBuilder},
 };
-use platform::{utils, ws::BroadcastMiddleware};
 use sqlx::postgres::PgPoolOptions;
 use std::io;
 use std::sync::Arc;
 use tokio::sync::broadcast;
-use tracing::error;
+use tokio::time::{Duration, sleep};
+use tracing::{error, info};
 
 // Average message size (in bytes) - typical chat message with metadata
 const AVG_MESSAGE_SIZE: usize = 256;
@@ -30,24 +38,69 @@ const MAX_QUEUE_MEMORY_MB: usize = 100;
 // Calculate capacity based on memory
 const CHANNEL_CAPACITY: usize = (MAX_QUEUE_MEMORY_MB * 1024 * 1024) / AVG_MESSAGE_SIZE;
 
+// Delay before the message bus loop is restarted after it stops
+const BUS_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+// How often per-app origin allow-lists are reloaded
+const ORIGIN_POLICY_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+// How often this node reports itself alive in the session registry
+const NODE_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+// A node silent for this long is considered dead and its connections removed
+const NODE_TTL: Duration = Duration::from_secs(30);
+
+// Connection registry keys expire unless their node's heartbeat refreshes
+// them. Long enough for the reaper to find the connections of a dead node.
+const SESSION_KEY_TTL: Duration = Duration::from_secs(600);
+
+// How often nodes look for dead peers
+const NODE_REAP_INTERVAL: Duration = Duration::from_secs(15);
+
+// How long a new channel owner waits for the previous one to flush the
+// channel before merging it anyway, in case the previous one died
+const CHANNEL_HANDOFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often nodes rebuild the ring picking channel owners
+const RING_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+// How often cached documents of idle channels are dropped
+const DOCUMENT_CACHE_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+// How often open connection sessions are reconciled with the session registry
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(300);
+
+// How often quota slots of connections and channels that are gone are released
+const QUOTA_RECONCILE_INTERVAL: Duration = Duration::from_secs(60);
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
-    utils::setup_logging()?;
 
     let config = utils::Config::from_env()?;
+    let tracer_provider = utils::setup_logging(&config)?;
 
     let db = PgPoolOptions::new()
         .connect(&config.database_url)
         .await
         .map_err(|e| {
             error!("Failed to connect to database: {e:?}");
-            io::Error::new(io::ErrorKind::Other, "Failed to connect to database")
+            io::Error::other("Failed to connect to database")
         })?;
 
     // Initialize metrics system
     let metrics_repository = Arc::new(MetricsRepository::new(db.clone()));
-    let metrics_collector = Arc::new(MetricsCollector::new(metrics_repository));
+    let metrics_collector = Arc::new(MetricsCollector::new(metrics_repository.clone()));
+
+    let rate_limit_repository = Arc::new(RateLimitRepository::new(db.clone()));
+    let quota_repository = Arc::new(QuotaRepository::new(db.clone()));
+    let app_repository = Arc::new(AppRepository::new(db.clone()));
+
+    let origin_policy = Arc::new(OriginPolicy::new(app_repository.clone()));
+    origin_policy.refresh().await?;
+    origin_policy
+        .clone()
+        .start_refresh(ORIGIN_POLICY_REFRESH_INTERVAL);
 
     let nats_client = utils::setup_nats(&config.nats_url).await?;
 
@@ -56,6 +109,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .await
         .expect("Failed to create Redis connection manager");
 
+    let health = Arc::new(Health::new(db.clone(), redis.clone(), nats_client.clone()));
+
+    let metrics_port = config.metrics_port;
+    let health_clone = health.clone();
+    tokio::spawn(async move {
+        if let Err(e) = observability::serve(metrics_port, health_clone).await {
+            error!("Failed to start operations server: {e}");
+        }
+    });
+
     let tx = Arc::new(broadcast::channel(CHANNEL_CAPACITY).0);
 
     let nats_transport = NatsTransportBuilder::default()
@@ -70,37 +133,152 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .build()?;
 
     let message_bus_clone = message_bus.clone();
+    let health_clone = health.clone();
     tokio::spawn(async move {
         let message_bus = message_bus_clone;
 
-        if let Err(e) = message_bus.start().await {
-            error!("Failed to start message bus: {e}");
+        loop {
+            health_clone.set_bus_running(true);
+
+            if let Err(e) = message_bus.start().await {
+                error!("Message bus stopped: {e}");
+            }
+
+            health_clone.set_bus_running(false);
+            sleep(BUS_RESTART_DELAY).await;
         }
     });
 
     let document_storage = Arc::new(RedisDocumentStorage::new(redis.clone()));
-    let bus_proxy = BusProxy::new(Arc::new(message_bus), document_storage.clone());
+    let rate_limiter = RedisRateLimiter::new(redis.clone(), rate_limit_repository);
+    let quota_store = Arc::new(RedisQuotaStore::new(redis.clone()));
+    let quota_enforcer = Arc::new(QuotaEnforcer::with_store(
+        quota_store.clone(),
+        quota_repository,
+    ));
+    let patch_log = Arc::new(RedisPatchLog::new(redis.clone()));
+    let resume_store = Arc::new(ResumeStore::new(redis.clone()));
+    let membership = Arc::new(RedisMembershipIndex::new(redis.clone()));
+    let relay_store = Arc::new(RedisRelayStore::new(redis.clone()));
+    let encrypted_log = Arc::new(RedisEncryptedLog::new(redis.clone()));
+
+    let document_cache = Arc::new(DocumentCache::new(
+        document_storage.clone(),
+        config.document_cache_max_bytes,
+        Duration::from_secs(config.document_cache_idle_seconds),
+    ));
+    document_cache.clone().follow(&tx);
+    document_cache.clone().start(DOCUMENT_CACHE_SWEEP_INTERVAL);
+
+    let session = Arc::new(Session::new(
+        redis.clone(),
+        metrics_collector.clone(),
+        quota_enforcer.clone(),
+        membership.clone(),
+        SESSION_KEY_TTL,
+    ));
+
+    // Register before accepting connections so they are never owned by a node
+    // that looks dead
+    session.heartbeat().await?;
+    session.clone().start_heartbeat(NODE_HEARTBEAT_INTERVAL);
+
+    let ownership = Arc::new(ChannelOwnership::new(
+        session.node_id,
+        session.clone(),
+        nats_client.clone(),
+        NODE_TTL,
+        CHANNEL_HANDOFF_TIMEOUT,
+    ));
+    ownership.clone().listen().await?;
+    ownership.refresh().await?;
+
+    let bus_proxy = Arc::new(BusProxy::new(
+        Arc::new(message_bus),
+        document_storage.clone(),
+        patch_log.clone(),
+        relay_store.clone(),
+        encrypted_log.clone(),
+        document_cache.clone(),
+        metrics_collector.clone(),
+        quota_enforcer.clone(),
+        ownership,
+    ));
+    bus_proxy.clone().serve_forwarded().await?;
+    bus_proxy.clone().start_handoff(RING_REFRESH_INTERVAL);
+
+    let message_handler = Arc::new(MessageHandler::new(
+        bus_proxy.clone(),
+        bus_proxy,
+        document_storage,
+        patch_log,
+        membership.clone(),
+        relay_store,
+        encrypted_log,
+        document_cache,
+        resume_store.clone(),
+        metrics_collector.clone(),
+        quota_enforcer,
+    ));
+
+    Arc::new(SessionReaper::new(
+        session.clone(),
+        message_handler.clone(),
+        NODE_TTL,
+    ))
+    .start(NODE_REAP_INTERVAL);
+
+    Arc::new(QuotaReconciler::new(
+        quota_store,
+        session.clone(),
+        membership.clone(),
+    ))
+    .start(QUOTA_RECONCILE_INTERVAL);
+
+    Arc::new(SessionSweeper::new(
+        metrics_repository,
+        session.clone(),
+        NODE_TTL,
+        config.session_sweeper_dry_run,
+    ))
+    .start(SESSION_SWEEP_INTERVAL);
 
     let ws_connection = WsConnectionBuilder::default()
         .port(config.port)
         .enable_tls(config.is_tls())
+        .auth_timeout(Duration::from_secs(config.auth_timeout_seconds))
         .middlewares(vec![
-            Arc::new(AuthMiddleware::new(config.jwt_secret)),
+            Arc::new(AuthMiddleware::new(
+                app_repository,
+                config.jwt_secret.as_deref(),
+            )),
             Arc::new(BroadcastMiddleware::new(tx.clone())),
         ])
-        .message_handler(Arc::new(MessageHandler::new(
-            Arc::new(bus_proxy),
-            document_storage,
-            metrics_collector.clone(),
-        )))
-        .session_handler(Arc::new(Session::new(
-            redis.clone(),
-            metrics_collector.clone(),
-        )))
+        .message_handler(message_handler)
+        .session_handler(session)
+        .rate_limiter(Arc::new(rate_limiter))
+        .origin_policy(origin_policy)
+        .resume_store(resume_store)
         .build()?;
 
-    if let Err(e) = ws_connection.start().await {
-        error!("Failed to start WebSocket connection: {e}");
+    tokio::select! {
+        result = ws_connection.start() => {
+            if let Err(e) = result {
+                error!("Failed to start WebSocket connection: {e}");
+            }
+        }
+        _ = utils::shutdown_signal() => {
+            // Report not ready so the orchestrator stops routing traffic here
+            health.start_draining();
+            info!("Draining for {}s before shutdown", config.drain_timeout_seconds);
+            sleep(Duration::from_secs(config.drain_timeout_seconds)).await;
+        }
+    }
+
+    if let Some(tracer_provider) = tracer_provider
+        && let Err(e) = tracer_provider.shutdown()
+    {
+        error!("Failed to flush traces: {e}");
     }
 
     Ok(())
@@ -2,9 +2,11 @@ use async_trait::async_trait;
 use derive_builder::Builder;
 use std::sync::Arc;
 use tokio_tungstenite::tungstenite::Bytes;
+use tracing::{Instrument, error, info_span, instrument};
 
 use super::errors::MessagingResult;
 use super::events::BroadcastMessage;
+use crate::observability::telemetry::{self, TraceContext};
 
 #[derive(Builder, Clone)]
 #[builder(setter(into))]
@@ -18,12 +20,34 @@ impl MessageBus {
     pub async fn start(&self) -> MessagingResult<()> {
         let mut receiver = self.transport.subscribe().await?;
 
-        while let Ok(message) = receiver.receive().await {
-            let deserialized = self.processor.deserialize(&message).await?;
-            self.handler.handle(deserialized).await?;
+        loop {
+            let (message, headers) = receiver.receive().await?;
+
+            let span = info_span!("bus.receive", size = message.len());
+            telemetry::set_parent(&span, &headers);
+
+            self.dispatch(message).instrument(span).await;
         }
+    }
+
+    async fn dispatch(&self, message: Bytes) {
+        // A single bad message must not stop the loop
+        let mut deserialized = match self.processor.deserialize(&message).await {
+            Ok(deserialized) => deserialized,
+            Err(e) => {
+                error!("Failed to deserialize bus message: {e}");
+                return;
+            }
+        };
+
+        // Deliveries to local connections continue the trace under this span
+        let (BroadcastMessage::Patch { trace_context, .. }
+        | BroadcastMessage::KeyExchange { trace_context, .. }) = &mut deserialized;
+        *trace_context = telemetry::current_context();
 
-        Ok(())
+        if let Err(e) = self.handler.handle(deserialized).await {
+            error!("Failed to handle bus message: {e}");
+        }
     }
 }
 
@@ -34,21 +58,24 @@ pub trait MessagePublisher: Send + Sync {
 
 #[async_trait]
 impl MessagePublisher for MessageBus {
+    #[instrument(name = "bus.publish", skip_all)]
     async fn publish(&self, message: BroadcastMessage) -> MessagingResult<()> {
         let serialized = self.processor.serialize(&message).await?;
-        self.transport.publish(serialized).await
+        self.transport
+            .publish(serialized, telemetry::current_context())
+            .await
     }
 }
 
 #[async_trait]
 pub trait MessageTransport: Send + Sync {
-    async fn publish(&self, message: Bytes) -> MessagingResult<()>;
+    async fn publish(&self, message: Bytes, headers: TraceContext) -> MessagingResult<()>;
     async fn subscribe(&self) -> MessagingResult<Box<dyn MessageReceiver>>;
 }
 
 #[async_trait]
 pub trait MessageReceiver: Send + Sync {
-    async fn receive(&mut self) -> MessagingResult<Bytes>;
+    async fn receive(&mut self) -> MessagingResult<(Bytes, TraceContext)>;
 }
 
 #[async_trait]
@@ -61,3 +88,131 @@ pub trait MessageProcessor: Send + Sync {
 pub trait MessageHandler: Send + Sync {
     async fn handle(&self, message: BroadcastMessage) -> MessagingResult<()>;
 }
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::{
+        global,
+        trace::{TraceContextExt, TraceId, TracerProvider as _},
+    };
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider};
+    use tokio::sync::{Mutex, mpsc};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+    use crate::messaging::{JsonProcessor, MessagingError};
+
+    type Envelope = (Bytes, TraceContext);
+
+    /// Stands in for NATS, carrying the headers next to the payload
+    struct ChannelTransport {
+        tx: mpsc::UnboundedSender<Envelope>,
+        rx: Mutex<Option<mpsc::UnboundedReceiver<Envelope>>>,
+    }
+
+    struct ChannelReceiver(mpsc::UnboundedReceiver<Envelope>);
+
+    #[async_trait]
+    impl MessageReceiver for ChannelReceiver {
+        async fn receive(&mut self) -> MessagingResult<Envelope> {
+            self.0
+                .recv()
+                .await
+                .ok_or_else(|| MessagingError::Transport("Channel closed".to_string()))
+        }
+    }
+
+    #[async_trait]
+    impl MessageTransport for ChannelTransport {
+        async fn publish(&self, message: Bytes, headers: TraceContext) -> MessagingResult<()> {
+            self.tx
+                .send((message, headers))
+                .map_err(|e| MessagingError::Transport(e.to_string()))
+        }
+
+        async fn subscribe(&self) -> MessagingResult<Box<dyn MessageReceiver>> {
+            let rx = self
+                .rx
+                .lock()
+                .await
+                .take()
+                .ok_or_else(|| MessagingError::Transport("Already subscribed".to_string()))?;
+
+            Ok(Box::new(ChannelReceiver(rx)))
+        }
+    }
+
+    struct ForwardingHandler(mpsc::UnboundedSender<BroadcastMessage>);
+
+    #[async_trait]
+    impl MessageHandler for ForwardingHandler {
+        async fn handle(&self, message: BroadcastMessage) -> MessagingResult<()> {
+            self.0
+                .send(message)
+                .map_err(|e| MessagingError::Handler(e.to_string()))
+        }
+    }
+
+    fn trace_id(context: &TraceContext) -> TraceId {
+        global::get_text_map_propagator(|propagator| propagator.extract(context))
+            .span()
+            .span_context()
+            .trace_id()
+    }
+
+    #[tokio::test]
+    async fn trace_id_survives_the_transport_hop() {
+        let provider = SdkTracerProvider::builder().build();
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (handled_tx, mut handled_rx) = mpsc::unbounded_channel();
+
+        let transport: Arc<dyn MessageTransport> = Arc::new(ChannelTransport {
+            tx,
+            rx: Mutex::new(Some(rx)),
+        });
+        let handler: Arc<dyn MessageHandler> = Arc::new(ForwardingHandler(handled_tx));
+        let processor: Arc<dyn MessageProcessor> = Arc::new(JsonProcessor::new());
+
+        let bus = MessageBusBuilder::default()
+            .transport(transport)
+            .handler(handler)
+            .processor(processor)
+            .build()
+            .unwrap();
+
+        let receiving = bus.clone();
+        tokio::spawn(async move { receiving.start().await });
+
+        let span = info_span!("handler.key_exchange");
+        let sent = span.context().span().span_context().trace_id();
+
+        bus.publish(BroadcastMessage::KeyExchange {
+            app_id: "app".to_string(),
+            channel_id: "channel".to_string(),
+            sender: "alice".to_string(),
+            sender_connection: "connection".to_string(),
+            recipients: vec!["bob".to_string()],
+            recipient_connection: None,
+            payload: vec![1, 2, 3],
+            trace_context: TraceContext::new(),
+        })
+        .instrument(span)
+        .await
+        .unwrap();
+
+        let Some(BroadcastMessage::KeyExchange { trace_context, .. }) = handled_rx.recv().await
+        else {
+            panic!("Expected the key exchange to be handled");
+        };
+
+        assert_ne!(sent, TraceId::INVALID);
+        assert_eq!(trace_id(&trace_context), sent);
+    }
+}
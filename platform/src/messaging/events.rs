@@ -1,13 +1,50 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
+use crate::observability::telemetry::TraceContext;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum BroadcastMessage {
     Patch {
         app_id: String,
-        sender: String,
+        /// Users whose patches are merged into the payload
+        senders: Vec<String>,
         channel_id: String,
         recipients: Vec<String>,
         payload: Vec<u8>,
+        /// What a sender receives instead of the payload: the same batch
+        /// without its own patches. Senders missing here only sent patches
+        /// they already have and receive nothing.
+        #[serde(default)]
+        without_sender: HashMap<String, Vec<u8>>,
+        /// Position in the channel's patch log, set once the patch is logged
+        #[serde(default)]
+        seq: Option<u64>,
+        /// Sent on a relay or encrypted channel, the payload is opaque and
+        /// not an update
+        #[serde(default)]
+        relay: bool,
+        // Carried in transport headers between nodes, only set in-process
+        #[serde(skip)]
+        trace_context: TraceContext,
+    },
+    /// Key material a member of an encrypted channel shares with the others,
+    /// opaque to the server
+    KeyExchange {
+        app_id: String,
+        channel_id: String,
+        sender: String,
+        /// Connection the key material was sent on, never echoed back to it
+        sender_connection: String,
+        recipients: Vec<String>,
+        /// The one connection the key material is meant for, every member
+        /// connection when unset
+        #[serde(default)]
+        recipient_connection: Option<String>,
+        payload: Vec<u8>,
+        #[serde(skip)]
+        trace_context: TraceContext,
     },
 }
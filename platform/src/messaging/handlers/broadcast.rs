@@ -6,6 +6,7 @@ use tokio::sync::broadcast::Sender;
 use crate::messaging::{
     MessagingError, MessagingResult, bus::MessageHandler, events::BroadcastMessage,
 };
+use crate::observability::metrics::BROADCAST_QUEUE_DEPTH;
 
 pub struct BroadcastHandler {
     rx: Arc<Sender<BroadcastMessage>>,
@@ -20,10 +21,17 @@ impl BroadcastHandler {
 #[async_trait]
 impl MessageHandler for BroadcastHandler {
     async fn handle(&self, message: BroadcastMessage) -> MessagingResult<()> {
+        // Nothing to deliver while no connection on this node is subscribed
+        if self.rx.receiver_count() == 0 {
+            return Ok(());
+        }
+
         self.rx
             .send(message)
             .map_err(|e| MessagingError::Transport(e.to_string()))?;
 
+        BROADCAST_QUEUE_DEPTH.set(self.rx.len() as i64);
+
         Ok(())
     }
 }
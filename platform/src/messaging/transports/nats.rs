@@ -1,10 +1,10 @@
-use async_nats::Client;
-use async_nats::Subscriber;
+use async_nats::{Client, HeaderMap, Subscriber};
 use futures_util::StreamExt;
 use tokio_tungstenite::tungstenite::Bytes;
 
 use super::super::bus::{MessageReceiver, MessageTransport};
 use super::super::errors::{MessagingError, MessagingResult};
+use crate::observability::{metrics::NATS_PUBLISH_ERRORS_TOTAL, telemetry::TraceContext};
 
 #[derive(derive_builder::Builder)]
 #[builder(setter(into))]
@@ -19,24 +19,47 @@ struct NatsReceiver {
 
 #[async_trait::async_trait]
 impl MessageReceiver for NatsReceiver {
-    async fn receive(&mut self) -> MessagingResult<Bytes> {
+    async fn receive(&mut self) -> MessagingResult<(Bytes, TraceContext)> {
         let msg = self
             .sub
             .next()
             .await
             .ok_or_else(|| MessagingError::NatsSubscribe("No message received".to_string()))?;
 
-        Ok(msg.payload)
+        let headers = msg
+            .headers
+            .map(|headers| {
+                headers
+                    .iter()
+                    .filter_map(|(name, values)| {
+                        values
+                            .first()
+                            .map(|value| (name.to_string(), value.to_string()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok((msg.payload, headers))
     }
 }
 
 #[async_trait::async_trait]
 impl MessageTransport for NatsTransport {
-    async fn publish(&self, message: Bytes) -> MessagingResult<()> {
+    async fn publish(&self, message: Bytes, headers: TraceContext) -> MessagingResult<()> {
+        let mut header_map = HeaderMap::new();
+
+        for (name, value) in &headers {
+            header_map.insert(name.as_str(), value.as_str());
+        }
+
         self.client
-            .publish(self.subject.clone(), message)
+            .publish_with_headers(self.subject.clone(), header_map, message)
             .await
-            .map_err(|e| MessagingError::NatsPublish(e.to_string()))?;
+            .map_err(|e| {
+                NATS_PUBLISH_ERRORS_TOTAL.inc();
+                MessagingError::NatsPublish(e.to_string())
+            })?;
 
         Ok(())
     }
@@ -0,0 +1,59 @@
+use redis::aio::ConnectionManager;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub use ::health::{DependencyCheck, HealthReport};
+
+/// Tracks the dependencies and background loops that decide readiness
+pub struct Health {
+    postgres: Arc<dyn DependencyCheck>,
+    redis: Arc<dyn DependencyCheck>,
+    nats: Arc<dyn DependencyCheck>,
+    bus_running: AtomicBool,
+    draining: AtomicBool,
+}
+
+impl Health {
+    pub fn new(db: PgPool, redis: ConnectionManager, nats: async_nats::Client) -> Self {
+        Self::with_checks(Arc::new(db), Arc::new(redis), Arc::new(nats))
+    }
+
+    pub fn with_checks(
+        postgres: Arc<dyn DependencyCheck>,
+        redis: Arc<dyn DependencyCheck>,
+        nats: Arc<dyn DependencyCheck>,
+    ) -> Self {
+        Self {
+            postgres,
+            redis,
+            nats,
+            bus_running: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_bus_running(&self, running: bool) {
+        self.bus_running.store(running, Ordering::SeqCst);
+    }
+
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub async fn report(&self) -> HealthReport {
+        let mut checks = BTreeMap::new();
+
+        checks.insert("postgres", self.postgres.is_up().await);
+        checks.insert("redis", self.redis.is_up().await);
+        checks.insert("nats", self.nats.is_up().await);
+        checks.insert("message_bus", self.bus_running.load(Ordering::SeqCst));
+
+        HealthReport::new(checks, self.is_draining())
+    }
+}
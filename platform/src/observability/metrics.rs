@@ -0,0 +1,138 @@
+use prometheus::{
+    Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, register_histogram,
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+};
+use std::sync::LazyLock;
+
+// Operational metrics exposed on `/metrics`. These are independent of the
+// billing metrics persisted by `ws::metrics::MetricsCollector`.
+
+pub static ACTIVE_CONNECTIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "platform_active_connections",
+        "WebSocket connections currently open on this node"
+    )
+    .expect("Failed to register platform_active_connections")
+});
+
+pub static ACTIVE_CHANNELS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "platform_active_channels",
+        "Channel subscriptions held by connections on this node"
+    )
+    .expect("Failed to register platform_active_channels")
+});
+
+pub static MESSAGES_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "platform_messages_total",
+        "WebSocket messages by direction and type",
+        &["direction", "type"]
+    )
+    .expect("Failed to register platform_messages_total")
+});
+
+pub static MESSAGE_BYTES_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "platform_message_bytes_total",
+        "WebSocket message bytes by direction and type",
+        &["direction", "type"]
+    )
+    .expect("Failed to register platform_message_bytes_total")
+});
+
+pub static BUS_PROXY_BATCH_SIZE: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "platform_bus_proxy_batch_size",
+        "Number of patches merged per BusProxy flush",
+        vec![1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0]
+    )
+    .expect("Failed to register platform_bus_proxy_batch_size")
+});
+
+pub static BUS_PROXY_BATCH_SENDERS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "platform_bus_proxy_batch_senders",
+        "Number of distinct users whose patches are merged per BusProxy flush",
+        vec![1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0]
+    )
+    .expect("Failed to register platform_bus_proxy_batch_senders")
+});
+
+pub static BUS_PROXY_FLUSH_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "platform_bus_proxy_flush_seconds",
+        "Time spent merging, saving and publishing a BusProxy batch"
+    )
+    .expect("Failed to register platform_bus_proxy_flush_seconds")
+});
+
+pub static DOCUMENT_CACHE_LOOKUPS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "platform_document_cache_lookups_total",
+        "Document cache lookups by whether the document was cached",
+        &["result"]
+    )
+    .expect("Failed to register platform_document_cache_lookups_total")
+});
+
+pub static DOCUMENT_CACHE_EVICTIONS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "platform_document_cache_evictions_total",
+        "Documents dropped from the document cache by reason",
+        &["reason"]
+    )
+    .expect("Failed to register platform_document_cache_evictions_total")
+});
+
+pub static DOCUMENT_CACHE_BYTES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "platform_document_cache_bytes",
+        "Estimated encoded size of the documents cached on this node"
+    )
+    .expect("Failed to register platform_document_cache_bytes")
+});
+
+pub static BROADCAST_LAGGED_TOTAL: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "platform_broadcast_lagged_messages_total",
+        "Broadcast messages skipped by connections that fell behind"
+    )
+    .expect("Failed to register platform_broadcast_lagged_messages_total")
+});
+
+pub static BROADCAST_QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "platform_broadcast_queue_depth",
+        "Messages queued in the local broadcast channel"
+    )
+    .expect("Failed to register platform_broadcast_queue_depth")
+});
+
+pub static NATS_PUBLISH_ERRORS_TOTAL: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "platform_nats_publish_errors_total",
+        "Failed publishes to NATS"
+    )
+    .expect("Failed to register platform_nats_publish_errors_total")
+});
+
+pub static REDIS_COMMAND_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "platform_redis_command_seconds",
+        "Latency of Redis operations",
+        &["operation"],
+        vec![
+            0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0
+        ]
+    )
+    .expect("Failed to register platform_redis_command_seconds")
+});
+
+pub static METRICS_COLLECTOR_QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "platform_metrics_collector_queue_depth",
+        "Billing events waiting to be handled by the MetricsCollector worker"
+    )
+    .expect("Failed to register platform_metrics_collector_queue_depth")
+});
@@ -0,0 +1,7 @@
+pub mod health;
+pub mod metrics;
+pub mod server;
+pub mod telemetry;
+
+pub use health::Health;
+pub use server::serve;
@@ -0,0 +1,174 @@
+use axum::{
+    Json, Router,
+    extract::State,
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use prometheus::{Encoder, TextEncoder};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::info;
+
+use super::Health;
+
+async fn metrics() -> Response {
+    let encoder = TextEncoder::new();
+
+    match encoder.encode_to_string(&prometheus::gather()) {
+        Ok(body) => ([(header::CONTENT_TYPE, encoder.format_type())], body).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn live() -> StatusCode {
+    StatusCode::OK
+}
+
+async fn ready(State(health): State<Arc<Health>>) -> Response {
+    let report = health.report().await;
+
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(report)).into_response()
+}
+
+/// Serve the operational HTTP endpoints on a port separate from WebSockets
+pub async fn serve(port: u16, health: Arc<Health>) -> Result<(), Box<dyn std::error::Error>> {
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready))
+        .with_state(health);
+
+    let listener = TcpListener::bind(format!("0.0.0.0:{port}")).await?;
+    info!("Operations server listening on 0.0.0.0:{port}");
+
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observability::health::DependencyCheck;
+    use async_trait::async_trait;
+    use serde_json::{Value as JsonValue, json};
+
+    struct Dependency {
+        up: bool,
+    }
+
+    #[async_trait]
+    impl DependencyCheck for Dependency {
+        async fn is_up(&self) -> bool {
+            self.up
+        }
+    }
+
+    fn dependency(up: bool) -> Arc<dyn DependencyCheck> {
+        Arc::new(Dependency { up })
+    }
+
+    // A client that never reaches a server, as when NATS is down
+    async fn unreachable_nats() -> Arc<dyn DependencyCheck> {
+        let nats = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect("nats://127.0.0.1:1")
+            .await
+            .unwrap();
+
+        Arc::new(nats)
+    }
+
+    // A pool that never reaches a server, as when Postgres is down
+    fn unreachable_postgres() -> Arc<dyn DependencyCheck> {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://postgres@127.0.0.1:1/postgres")
+            .unwrap();
+
+        Arc::new(pool)
+    }
+
+    async fn respond(health: Health) -> (StatusCode, JsonValue) {
+        health.set_bus_running(true);
+
+        let response = ready(State(Arc::new(health))).await;
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn ready_when_every_dependency_is_up() {
+        let health = Health::with_checks(dependency(true), dependency(true), dependency(true));
+
+        let (status, report) = respond(health).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            report,
+            json!({
+                "ready": true,
+                "draining": false,
+                "checks": { "message_bus": true, "nats": true, "postgres": true, "redis": true },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn degraded_when_redis_is_unreachable() {
+        let health = Health::with_checks(dependency(true), dependency(false), dependency(true));
+
+        let (status, report) = respond(health).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report["ready"], json!(false));
+        assert_eq!(report["checks"]["redis"], json!(false));
+        assert_eq!(report["checks"]["nats"], json!(true));
+    }
+
+    #[tokio::test]
+    async fn degraded_when_nats_is_unreachable() {
+        let health =
+            Health::with_checks(dependency(true), dependency(true), unreachable_nats().await);
+
+        let (status, report) = respond(health).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report["ready"], json!(false));
+        assert_eq!(report["checks"]["nats"], json!(false));
+        assert_eq!(report["checks"]["redis"], json!(true));
+    }
+
+    #[tokio::test]
+    async fn degraded_when_postgres_is_unreachable() {
+        let health =
+            Health::with_checks(unreachable_postgres(), dependency(true), dependency(true));
+
+        let (status, report) = respond(health).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report["checks"]["postgres"], json!(false));
+    }
+
+    #[tokio::test]
+    async fn not_ready_while_draining() {
+        let health = Health::with_checks(dependency(true), dependency(true), dependency(true));
+        health.start_draining();
+
+        let (status, report) = respond(health).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(report["ready"], json!(false));
+        assert_eq!(report["draining"], json!(true));
+    }
+}
@@ -0,0 +1,57 @@
+use opentelemetry::{
+    Context, global,
+    trace::{TraceContextExt, TracerProvider as _},
+};
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use std::collections::HashMap;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+pub use telemetry::TracingExporter;
+
+const SERVICE_NAME: &str = "platform";
+
+/// W3C trace context headers carried alongside a message
+pub type TraceContext = HashMap<String, String>;
+
+/// Build the tracer provider selected by `exporter`, or `None` when tracing
+/// export is disabled
+pub fn setup_tracer_provider(
+    exporter: TracingExporter,
+    otlp_endpoint: &str,
+    file_path: &str,
+) -> Result<Option<SdkTracerProvider>, Box<dyn std::error::Error>> {
+    telemetry::setup_tracer_provider(SERVICE_NAME, exporter, otlp_endpoint, file_path)
+}
+
+pub fn tracer(provider: &SdkTracerProvider) -> SdkTracer {
+    provider.tracer(SERVICE_NAME)
+}
+
+/// Serialize the context of the current span so it can cross a process or
+/// task boundary
+pub fn current_context() -> TraceContext {
+    let mut context = TraceContext::new();
+    let cx = Span::current().context();
+
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&cx, &mut context));
+
+    context
+}
+
+fn extract(context: &TraceContext) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(context))
+}
+
+/// Make `span` a child of the span `context` was captured from
+pub fn set_parent(span: &Span, context: &TraceContext) {
+    if !context.is_empty() {
+        span.set_parent(extract(context));
+    }
+}
+
+/// Link `span` to the span `context` was captured from without parenting it
+pub fn add_link(span: &Span, context: &TraceContext) {
+    let cx = extract(context);
+    span.add_link(cx.span().span_context().clone());
+}
@@ -0,0 +1,320 @@
+use super::{DocumentStorage, EncryptedLog, LoggedPatch, PatchLog, RelayStore};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Mutex as StdMutex;
+
+// In-memory stores with the same semantics as the Redis ones, for tests
+
+// Same as the Redis patch log
+const PATCH_LOG_SIZE: usize = 128;
+
+type Key = (String, String);
+
+fn key(app_id: &str, channel_id: &str) -> Key {
+    (app_id.to_string(), channel_id.to_string())
+}
+
+#[derive(Default)]
+pub struct MemoryDocumentStorage {
+    pub documents: StdMutex<HashMap<Key, Vec<u8>>>,
+}
+
+#[async_trait]
+impl DocumentStorage for MemoryDocumentStorage {
+    async fn get_document(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        Ok(self
+            .documents
+            .lock()
+            .unwrap()
+            .get(&key(app_id, channel_id))
+            .cloned())
+    }
+
+    async fn save_document(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        update: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.documents
+            .lock()
+            .unwrap()
+            .insert(key(app_id, channel_id), update.to_vec());
+
+        Ok(())
+    }
+
+    async fn delete_document(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.documents
+            .lock()
+            .unwrap()
+            .remove(&key(app_id, channel_id));
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryPatchLog {
+    pub seqs: StdMutex<HashMap<Key, u64>>,
+    pub logs: StdMutex<HashMap<Key, Vec<(u64, LoggedPatch)>>>,
+}
+
+#[async_trait]
+impl PatchLog for MemoryPatchLog {
+    async fn append(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        count: u64,
+        payload: &[u8],
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let key = key(app_id, channel_id);
+        let mut seqs = self.seqs.lock().unwrap();
+        let last = seqs.entry(key.clone()).or_default();
+        *last += count;
+
+        let mut logs = self.logs.lock().unwrap();
+        let log = logs.entry(key).or_default();
+        log.push((
+            *last - count + 1,
+            LoggedPatch {
+                seq: *last,
+                payload: payload.to_vec(),
+            },
+        ));
+
+        if log.len() > PATCH_LOG_SIZE {
+            log.remove(0);
+        }
+
+        Ok(*last - count + 1)
+    }
+
+    async fn last_seq(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        Ok(self
+            .seqs
+            .lock()
+            .unwrap()
+            .get(&key(app_id, channel_id))
+            .copied()
+            .unwrap_or_default())
+    }
+
+    async fn since(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        seq: u64,
+    ) -> Result<Option<Vec<LoggedPatch>>, Box<dyn std::error::Error>> {
+        let last = self.last_seq(app_id, channel_id).await?;
+
+        if seq > last {
+            return Ok(None);
+        }
+
+        if seq == last {
+            return Ok(Some(vec![]));
+        }
+
+        let logs = self.logs.lock().unwrap();
+        let entries = logs
+            .get(&key(app_id, channel_id))
+            .map(|log| {
+                log.iter()
+                    .filter(|(_, patch)| patch.seq > seq)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        match entries.first() {
+            Some((first, _)) if *first <= seq + 1 => Ok(Some(
+                entries
+                    .into_iter()
+                    .map(|(_, patch)| patch.clone())
+                    .collect(),
+            )),
+            _ => Ok(None),
+        }
+    }
+
+    async fn delete(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let key = key(app_id, channel_id);
+        self.logs.lock().unwrap().remove(&key);
+        *self.seqs.lock().unwrap().entry(key).or_default() += 1;
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryRelayStore {
+    // Empty until a patch is kept, like the Redis key
+    pub channels: StdMutex<HashMap<Key, Vec<u8>>>,
+}
+
+#[async_trait]
+impl RelayStore for MemoryRelayStore {
+    async fn declare(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(key(app_id, channel_id))
+            .or_default();
+
+        Ok(())
+    }
+
+    async fn is_relay(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self
+            .channels
+            .lock()
+            .unwrap()
+            .contains_key(&key(app_id, channel_id)))
+    }
+
+    async fn save_last(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        payload: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.channels
+            .lock()
+            .unwrap()
+            .insert(key(app_id, channel_id), payload.to_vec());
+
+        Ok(())
+    }
+
+    async fn get_last(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        Ok(self
+            .channels
+            .lock()
+            .unwrap()
+            .get(&key(app_id, channel_id))
+            .filter(|payload| !payload.is_empty())
+            .cloned())
+    }
+
+    async fn delete(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.channels
+            .lock()
+            .unwrap()
+            .remove(&key(app_id, channel_id));
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryEncryptedLog {
+    pub logs: StdMutex<HashMap<Key, Vec<Vec<u8>>>>,
+}
+
+#[async_trait]
+impl EncryptedLog for MemoryEncryptedLog {
+    async fn declare(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.logs
+            .lock()
+            .unwrap()
+            .entry(key(app_id, channel_id))
+            .or_default();
+
+        Ok(())
+    }
+
+    async fn is_encrypted(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self
+            .logs
+            .lock()
+            .unwrap()
+            .contains_key(&key(app_id, channel_id)))
+    }
+
+    async fn append(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        update: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.logs
+            .lock()
+            .unwrap()
+            .entry(key(app_id, channel_id))
+            .or_default()
+            .push(update.to_vec());
+
+        Ok(())
+    }
+
+    async fn get_updates(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        start: usize,
+        count: usize,
+    ) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+        Ok(self
+            .logs
+            .lock()
+            .unwrap()
+            .get(&key(app_id, channel_id))
+            .map(|log| log.iter().skip(start).take(count).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn size(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        Ok(self
+            .logs
+            .lock()
+            .unwrap()
+            .get(&key(app_id, channel_id))
+            .map(|log| log.iter().map(Vec::len).sum())
+            .unwrap_or_default())
+    }
+}
@@ -1,4 +1,5 @@
 use async_trait::async_trait;
+use serde_json::Value as JsonValue;
 
 #[async_trait]
 pub trait DocumentStorage: Send + Sync {
@@ -22,5 +23,194 @@ pub trait DocumentStorage: Send + Sync {
     ) -> Result<(), Box<dyn std::error::Error>>;
 }
 
+/// A broadcast patch kept in a channel's recent history. A broadcast merges
+/// a batch of patches, so it covers a range of sequence numbers.
+#[derive(Debug, Clone)]
+pub struct LoggedPatch {
+    /// Sequence number of the last patch merged into the payload
+    pub seq: u64,
+    pub payload: Vec<u8>,
+}
+
+/// Short per-channel history of broadcast patches, numbered by a sequence
+/// that never goes back while the channel is in use
+#[async_trait]
+pub trait PatchLog: Send + Sync {
+    /// Append a broadcast merging `count` patches, returning the sequence
+    /// number of the first one. The others follow it without gaps.
+    async fn append(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        count: u64,
+        payload: &[u8],
+    ) -> Result<u64, Box<dyn std::error::Error>>;
+
+    /// Sequence number of the latest patch, 0 before the first one
+    async fn last_seq(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<u64, Box<dyn std::error::Error>>;
+
+    /// Every broadcast holding patches after `seq`, or `None` if some of them
+    /// are no longer kept
+    async fn since(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        seq: u64,
+    ) -> Result<Option<Vec<LoggedPatch>>, Box<dyn std::error::Error>>;
+
+    /// Drop the history of a deleted document. The sequence skips a number so
+    /// that clients of the old document can never resume into a new one.
+    async fn delete(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Members of each channel with the metadata they joined with, kept apart
+/// from the document so clients can neither read nor change them.
+///
+/// Channels in use before the index kept their members only in the document,
+/// so a channel's members are only counted once it has been seeded. Entries
+/// expire unless refreshed by the nodes with connections in the channel.
+#[async_trait]
+pub trait MembershipIndex: Send + Sync {
+    /// Add `user_id` to the channel, or replace its metadata
+    async fn add_member(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        user_id: &str,
+        metadata: &JsonValue,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Remove `user_id` from the channel, returning how many members are
+    /// left, or `None` if the channel is not seeded and the count means nothing
+    async fn remove_member(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        user_id: &str,
+    ) -> Result<Option<usize>, Box<dyn std::error::Error>>;
+
+    async fn get_member_ids(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>>;
+
+    /// Add the members the channel had before the index and mark it as
+    /// seeded. Does nothing if it already is.
+    async fn seed(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        members: &[(String, JsonValue)],
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    async fn is_seeded(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>>;
+
+    /// Keep the entries of each `(app_id, channel_id)` from expiring
+    async fn refresh(
+        &self,
+        channels: &[(String, String)],
+    ) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Channels that relay patches verbatim to their members instead of merging
+/// them into a document
+#[async_trait]
+pub trait RelayStore: Send + Sync {
+    /// Mark the channel as relaying patches
+    async fn declare(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    async fn is_relay(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>>;
+
+    /// Keep `payload` as the last patch relayed on the channel, for joiners
+    async fn save_last(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        payload: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// The last patch relayed on the channel, `None` if none was kept
+    async fn get_last(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>>;
+
+    /// Forget the channel once its last member left
+    async fn delete(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Append-only history of the updates of end-to-end encrypted channels, kept
+/// as the ciphertext clients sent and never decoded. It outlives the channel's
+/// members so late joiners can still page through it, until it goes unused
+/// for longer than the store retains it.
+#[async_trait]
+pub trait EncryptedLog: Send + Sync {
+    /// Mark the channel as end-to-end encrypted, or keep marking it so
+    async fn declare(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    async fn is_encrypted(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>>;
+
+    async fn append(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        update: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Up to `count` updates from the `start`th one, oldest first
+    async fn get_updates(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        start: usize,
+        count: usize,
+    ) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>>;
+
+    /// Total size of the updates, which stands for the document size
+    async fn size(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<usize, Box<dyn std::error::Error>>;
+}
+
+#[cfg(test)]
+pub mod memory;
 pub mod redis;
-pub use redis::RedisDocumentStorage;
+pub use redis::{
+    RedisDocumentStorage, RedisEncryptedLog, RedisMembershipIndex, RedisPatchLog, RedisRelayStore,
+};
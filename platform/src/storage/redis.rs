@@ -1,6 +1,9 @@
-use super::DocumentStorage;
+use super::{DocumentStorage, EncryptedLog, LoggedPatch, MembershipIndex, PatchLog, RelayStore};
 use async_trait::async_trait;
-use redis::aio::ConnectionManager;
+use redis::{Script, aio::ConnectionManager};
+use serde_json::Value as JsonValue;
+
+use crate::observability::metrics::REDIS_COMMAND_SECONDS;
 
 pub struct RedisDocumentStorage {
     redis: ConnectionManager,
@@ -25,6 +28,9 @@ impl DocumentStorage for RedisDocumentStorage {
     ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
         let key = Self::get_key(app_id, channel_id);
         let mut conn = self.redis.clone();
+        let _timer = REDIS_COMMAND_SECONDS
+            .with_label_values(&["get_document"])
+            .start_timer();
 
         let data: Option<Vec<u8>> = redis::cmd("GET").arg(&key).query_async(&mut conn).await?;
 
@@ -39,6 +45,9 @@ impl DocumentStorage for RedisDocumentStorage {
     ) -> Result<(), Box<dyn std::error::Error>> {
         let key = Self::get_key(app_id, channel_id);
         let mut conn = self.redis.clone();
+        let _timer = REDIS_COMMAND_SECONDS
+            .with_label_values(&["save_document"])
+            .start_timer();
 
         redis::cmd("SET")
             .arg(&key)
@@ -56,6 +65,9 @@ impl DocumentStorage for RedisDocumentStorage {
     ) -> Result<(), Box<dyn std::error::Error>> {
         let key = Self::get_key(app_id, channel_id);
         let mut conn = self.redis.clone();
+        let _timer = REDIS_COMMAND_SECONDS
+            .with_label_values(&["delete_document"])
+            .start_timer();
 
         redis::cmd("DEL")
             .arg(&key)
@@ -65,3 +77,934 @@ impl DocumentStorage for RedisDocumentStorage {
         Ok(())
     }
 }
+
+// How many broadcasts each channel keeps for resuming clients
+const PATCH_LOG_SIZE: usize = 128;
+
+// A channel's history is dropped after this long without patches, far longer
+// than a resume token is valid. Its sequence counter never expires, like the
+// document, so numbers do not restart while clients still hold old ones.
+const PATCH_LOG_TTL_SECONDS: u64 = 3600;
+
+// Number the merged patches and append the broadcast to the channel's stream.
+// Entry ids are the last sequence number of each broadcast, its first one is
+// kept alongside the payload. ARGV: payload, patch count, log size, log ttl.
+// PERSIST clears the TTL counters were once given.
+const APPEND_SCRIPT: &str = r#"
+local count = tonumber(ARGV[2])
+local last = redis.call('INCRBY', KEYS[1], count)
+local first = last - count + 1
+
+redis.call('XADD', KEYS[2], 'MAXLEN', ARGV[3], last .. '-0', 'first', first, 'p', ARGV[1])
+redis.call('PERSIST', KEYS[1])
+redis.call('EXPIRE', KEYS[2], ARGV[4])
+
+return first
+"#;
+
+// Broadcasts holding patches after ARGV[1]: '1' followed by (last seq,
+// payload) pairs, or '0' alone when the oldest of them already left the
+// stream or the counter was reset
+const SINCE_SCRIPT: &str = r#"
+local last = tonumber(redis.call('GET', KEYS[1]) or '0')
+local after = tonumber(ARGV[1])
+
+if after > last then
+    return {'0'}
+end
+
+if after == last then
+    return {'1'}
+end
+
+local entries = redis.call('XRANGE', KEYS[2], (after + 1) .. '-0', '+')
+
+if #entries == 0 or tonumber(entries[1][2][2]) > after + 1 then
+    return {'0'}
+end
+
+local result = {'1'}
+
+for _, entry in ipairs(entries) do
+    table.insert(result, string.match(entry[1], '^%d+'))
+    table.insert(result, entry[2][4])
+end
+
+return result
+"#;
+
+pub struct RedisPatchLog {
+    redis: ConnectionManager,
+    append_script: Script,
+    since_script: Script,
+}
+
+impl RedisPatchLog {
+    pub fn new(redis: ConnectionManager) -> Self {
+        Self {
+            redis,
+            append_script: Script::new(APPEND_SCRIPT),
+            since_script: Script::new(SINCE_SCRIPT),
+        }
+    }
+
+    fn get_seq_key(app_id: &str, channel_id: &str) -> String {
+        format!("{app_id}:seq:{channel_id}")
+    }
+
+    fn get_log_key(app_id: &str, channel_id: &str) -> String {
+        format!("{app_id}:log:{channel_id}")
+    }
+}
+
+#[async_trait]
+impl PatchLog for RedisPatchLog {
+    async fn append(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        count: u64,
+        payload: &[u8],
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+        let _timer = REDIS_COMMAND_SECONDS
+            .with_label_values(&["append_patch"])
+            .start_timer();
+
+        let seq: u64 = self
+            .append_script
+            .key(Self::get_seq_key(app_id, channel_id))
+            .key(Self::get_log_key(app_id, channel_id))
+            .arg(payload)
+            .arg(count)
+            .arg(PATCH_LOG_SIZE)
+            .arg(PATCH_LOG_TTL_SECONDS)
+            .invoke_async(&mut conn)
+            .await?;
+
+        Ok(seq)
+    }
+
+    async fn last_seq(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        let seq: Option<u64> = redis::cmd("GET")
+            .arg(Self::get_seq_key(app_id, channel_id))
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(seq.unwrap_or_default())
+    }
+
+    async fn since(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        seq: u64,
+    ) -> Result<Option<Vec<LoggedPatch>>, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+        let _timer = REDIS_COMMAND_SECONDS
+            .with_label_values(&["patches_since"])
+            .start_timer();
+
+        let reply: Vec<Vec<u8>> = self
+            .since_script
+            .key(Self::get_seq_key(app_id, channel_id))
+            .key(Self::get_log_key(app_id, channel_id))
+            .arg(seq)
+            .invoke_async(&mut conn)
+            .await?;
+
+        let Some((status, entries)) = reply.split_first() else {
+            return Err("Empty patch log reply".into());
+        };
+
+        if status.as_slice() != b"1" {
+            return Ok(None);
+        }
+
+        let patches = entries
+            .chunks_exact(2)
+            .map(|entry| {
+                let seq = std::str::from_utf8(&entry[0])?.parse::<u64>()?;
+
+                Ok(LoggedPatch {
+                    seq,
+                    payload: entry[1].clone(),
+                })
+            })
+            .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+
+        Ok(Some(patches))
+    }
+
+    async fn delete(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        // The counter moves on rather than going away, so resume tokens from
+        // before the delete see a gap instead of matching new patches
+        redis::pipe()
+            .atomic()
+            .cmd("DEL")
+            .arg(Self::get_log_key(app_id, channel_id))
+            .ignore()
+            .cmd("INCR")
+            .arg(Self::get_seq_key(app_id, channel_id))
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+// Membership entries are dropped after this long without a node refreshing
+// them, longer than a dead node goes before the reaper removes its members
+const MEMBERSHIP_TTL_SECONDS: u64 = 600;
+
+// Add the members a channel had before the index unless it is already seeded.
+// KEYS: members, seeded marker. ARGV: ttl, then user id and metadata pairs.
+const SEED_SCRIPT: &str = r#"
+if redis.call('SET', KEYS[2], 1, 'NX', 'EX', ARGV[1]) then
+  for i = 2, #ARGV, 2 do
+    redis.call('HSETNX', KEYS[1], ARGV[i], ARGV[i + 1])
+  end
+  redis.call('EXPIRE', KEYS[1], ARGV[1])
+end
+"#;
+
+pub struct RedisMembershipIndex {
+    redis: ConnectionManager,
+    seed_script: Script,
+}
+
+impl RedisMembershipIndex {
+    pub fn new(redis: ConnectionManager) -> Self {
+        Self {
+            redis,
+            seed_script: Script::new(SEED_SCRIPT),
+        }
+    }
+
+    fn get_key(app_id: &str, channel_id: &str) -> String {
+        format!("{app_id}:members:{channel_id}")
+    }
+
+    fn get_seeded_key(app_id: &str, channel_id: &str) -> String {
+        format!("{app_id}:members:{channel_id}:seeded")
+    }
+}
+
+#[async_trait]
+impl MembershipIndex for RedisMembershipIndex {
+    async fn add_member(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        user_id: &str,
+        metadata: &JsonValue,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let key = Self::get_key(app_id, channel_id);
+        let mut conn = self.redis.clone();
+        let _timer = REDIS_COMMAND_SECONDS
+            .with_label_values(&["add_member"])
+            .start_timer();
+
+        redis::pipe()
+            .atomic()
+            .cmd("HSET")
+            .arg(&key)
+            .arg(user_id)
+            .arg(serde_json::to_string(metadata)?)
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(MEMBERSHIP_TTL_SECONDS)
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn remove_member(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        user_id: &str,
+    ) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+        let key = Self::get_key(app_id, channel_id);
+        let mut conn = self.redis.clone();
+        let _timer = REDIS_COMMAND_SECONDS
+            .with_label_values(&["remove_member"])
+            .start_timer();
+
+        let (remaining, seeded): (usize, bool) = redis::pipe()
+            .atomic()
+            .cmd("HDEL")
+            .arg(&key)
+            .arg(user_id)
+            .ignore()
+            .cmd("HLEN")
+            .arg(&key)
+            .cmd("EXISTS")
+            .arg(Self::get_seeded_key(app_id, channel_id))
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(seeded.then_some(remaining))
+    }
+
+    async fn get_member_ids(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+        let _timer = REDIS_COMMAND_SECONDS
+            .with_label_values(&["get_member_ids"])
+            .start_timer();
+
+        let member_ids: Vec<String> = redis::cmd("HKEYS")
+            .arg(Self::get_key(app_id, channel_id))
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(member_ids)
+    }
+
+    async fn seed(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        members: &[(String, JsonValue)],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+        let _timer = REDIS_COMMAND_SECONDS
+            .with_label_values(&["seed_members"])
+            .start_timer();
+
+        let mut invocation = self.seed_script.prepare_invoke();
+        invocation
+            .key(Self::get_key(app_id, channel_id))
+            .key(Self::get_seeded_key(app_id, channel_id))
+            .arg(MEMBERSHIP_TTL_SECONDS);
+
+        for (user_id, metadata) in members {
+            invocation
+                .arg(user_id)
+                .arg(serde_json::to_string(metadata)?);
+        }
+
+        invocation.invoke_async::<()>(&mut conn).await?;
+
+        Ok(())
+    }
+
+    async fn is_seeded(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        let seeded: bool = redis::cmd("EXISTS")
+            .arg(Self::get_seeded_key(app_id, channel_id))
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(seeded)
+    }
+
+    async fn refresh(
+        &self,
+        channels: &[(String, String)],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+        let mut pipe = redis::pipe();
+
+        for (app_id, channel_id) in channels {
+            pipe.cmd("EXPIRE")
+                .arg(Self::get_key(app_id, channel_id))
+                .arg(MEMBERSHIP_TTL_SECONDS)
+                .ignore()
+                .cmd("EXPIRE")
+                .arg(Self::get_seeded_key(app_id, channel_id))
+                .arg(MEMBERSHIP_TTL_SECONDS)
+                .ignore();
+        }
+
+        pipe.query_async::<()>(&mut conn).await?;
+
+        Ok(())
+    }
+}
+
+pub struct RedisRelayStore {
+    redis: ConnectionManager,
+}
+
+impl RedisRelayStore {
+    pub fn new(redis: ConnectionManager) -> Self {
+        Self { redis }
+    }
+
+    // Holds the last relayed patch, empty until one is kept. The key existing
+    // is what marks the channel as a relay.
+    fn get_key(app_id: &str, channel_id: &str) -> String {
+        format!("{app_id}:relay:{channel_id}")
+    }
+}
+
+#[async_trait]
+impl RelayStore for RedisRelayStore {
+    async fn declare(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+        let _timer = REDIS_COMMAND_SECONDS
+            .with_label_values(&["declare_relay"])
+            .start_timer();
+
+        redis::cmd("SET")
+            .arg(Self::get_key(app_id, channel_id))
+            .arg("")
+            .arg("NX")
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn is_relay(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        let exists: bool = redis::cmd("EXISTS")
+            .arg(Self::get_key(app_id, channel_id))
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(exists)
+    }
+
+    async fn save_last(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        payload: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+        let _timer = REDIS_COMMAND_SECONDS
+            .with_label_values(&["save_last_relayed"])
+            .start_timer();
+
+        redis::cmd("SET")
+            .arg(Self::get_key(app_id, channel_id))
+            .arg(payload)
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_last(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        let payload: Option<Vec<u8>> = redis::cmd("GET")
+            .arg(Self::get_key(app_id, channel_id))
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(payload.filter(|payload| !payload.is_empty()))
+    }
+
+    async fn delete(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        redis::cmd("DEL")
+            .arg(Self::get_key(app_id, channel_id))
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+// The encrypted log is the only copy of an encrypted channel, so it outlives
+// its members for late joiners and is dropped this long after it was last
+// declared or written to. Its size is capped by the app's document size limit.
+const ENCRYPTED_LOG_TTL_SECONDS: u64 = 30 * 24 * 3600;
+
+pub struct RedisEncryptedLog {
+    redis: ConnectionManager,
+}
+
+impl RedisEncryptedLog {
+    pub fn new(redis: ConnectionManager) -> Self {
+        Self { redis }
+    }
+
+    // Total size of the updates. The key existing is what marks the channel
+    // as encrypted, the log itself only exists once it has an update.
+    fn get_size_key(app_id: &str, channel_id: &str) -> String {
+        format!("{app_id}:e2ee:{channel_id}")
+    }
+
+    fn get_log_key(app_id: &str, channel_id: &str) -> String {
+        format!("{app_id}:e2ee_log:{channel_id}")
+    }
+}
+
+#[async_trait]
+impl EncryptedLog for RedisEncryptedLog {
+    async fn declare(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+        let _timer = REDIS_COMMAND_SECONDS
+            .with_label_values(&["declare_encrypted"])
+            .start_timer();
+
+        redis::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(Self::get_size_key(app_id, channel_id))
+            .arg(0)
+            .arg("NX")
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(Self::get_size_key(app_id, channel_id))
+            .arg(ENCRYPTED_LOG_TTL_SECONDS)
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(Self::get_log_key(app_id, channel_id))
+            .arg(ENCRYPTED_LOG_TTL_SECONDS)
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn is_encrypted(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        let exists: bool = redis::cmd("EXISTS")
+            .arg(Self::get_size_key(app_id, channel_id))
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(exists)
+    }
+
+    async fn append(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        update: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+        let _timer = REDIS_COMMAND_SECONDS
+            .with_label_values(&["append_encrypted"])
+            .start_timer();
+
+        redis::pipe()
+            .atomic()
+            .cmd("RPUSH")
+            .arg(Self::get_log_key(app_id, channel_id))
+            .arg(update)
+            .ignore()
+            .cmd("INCRBY")
+            .arg(Self::get_size_key(app_id, channel_id))
+            .arg(update.len())
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(Self::get_size_key(app_id, channel_id))
+            .arg(ENCRYPTED_LOG_TTL_SECONDS)
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(Self::get_log_key(app_id, channel_id))
+            .arg(ENCRYPTED_LOG_TTL_SECONDS)
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_updates(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        start: usize,
+        count: usize,
+    ) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+        let _timer = REDIS_COMMAND_SECONDS
+            .with_label_values(&["get_encrypted_updates"])
+            .start_timer();
+
+        if count == 0 {
+            return Ok(vec![]);
+        }
+
+        let updates: Vec<Vec<u8>> = redis::cmd("LRANGE")
+            .arg(Self::get_log_key(app_id, channel_id))
+            .arg(start)
+            .arg(start + count - 1)
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(updates)
+    }
+
+    async fn size(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        let size: Option<usize> = redis::cmd("GET")
+            .arg(Self::get_size_key(app_id, channel_id))
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(size.unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    async fn redis() -> ConnectionManager {
+        let url =
+            std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+
+        ConnectionManager::new(redis::Client::open(url).unwrap())
+            .await
+            .unwrap()
+    }
+
+    // Each test uses an app of its own, so tests can share a Redis
+    fn app_id() -> String {
+        Uuid::new_v4().simple().to_string()
+    }
+
+    async fn ttl(redis: &ConnectionManager, key: &str) -> i64 {
+        redis::cmd("TTL")
+            .arg(key)
+            .query_async(&mut redis.clone())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn the_patch_sequence_outlives_the_log() {
+        let redis = redis().await;
+        let patch_log = RedisPatchLog::new(redis.clone());
+        let app_id = app_id();
+        let seq_key = RedisPatchLog::get_seq_key(&app_id, "doc");
+
+        // Counters used to expire along with the log
+        redis::cmd("SET")
+            .arg(&seq_key)
+            .arg(4)
+            .arg("EX")
+            .arg(60)
+            .query_async::<()>(&mut redis.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(patch_log.append(&app_id, "doc", 2, b"p").await.unwrap(), 5);
+        assert_eq!(ttl(&redis, &seq_key).await, -1);
+        assert!(ttl(&redis, &RedisPatchLog::get_log_key(&app_id, "doc")).await > 0);
+
+        patch_log.delete(&app_id, "doc").await.unwrap();
+
+        assert_eq!(patch_log.last_seq(&app_id, "doc").await.unwrap(), 7);
+        assert_eq!(ttl(&redis, &seq_key).await, -1);
+        assert!(patch_log.since(&app_id, "doc", 6).await.unwrap().is_none());
+    }
+
+    async fn members(index: &RedisMembershipIndex, app_id: &str) -> Vec<String> {
+        let mut members = index.get_member_ids(app_id, "room").await.unwrap();
+        members.sort();
+        members
+    }
+
+    fn member(user_id: &str) -> (String, JsonValue) {
+        (user_id.to_string(), serde_json::json!({ "name": user_id }))
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn channels_are_seeded_once() {
+        let index = RedisMembershipIndex::new(redis().await);
+        let app_id = app_id();
+
+        assert!(!index.is_seeded(&app_id, "room").await.unwrap());
+
+        index
+            .seed(&app_id, "room", &[member("alice")])
+            .await
+            .unwrap();
+        index.seed(&app_id, "room", &[member("bob")]).await.unwrap();
+
+        assert!(index.is_seeded(&app_id, "room").await.unwrap());
+        assert_eq!(members(&index, &app_id).await, ["alice"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn seeding_keeps_members_that_joined_first() {
+        let redis = redis().await;
+        let index = RedisMembershipIndex::new(redis.clone());
+        let app_id = app_id();
+        let joined = serde_json::json!({ "name": "Carol" });
+
+        index
+            .add_member(&app_id, "room", "carol", &joined)
+            .await
+            .unwrap();
+        index
+            .seed(&app_id, "room", &[member("alice"), member("carol")])
+            .await
+            .unwrap();
+
+        let metadata: String = redis::cmd("HGET")
+            .arg(RedisMembershipIndex::get_key(&app_id, "room"))
+            .arg("carol")
+            .query_async(&mut redis.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(members(&index, &app_id).await, ["alice", "carol"]);
+        assert_eq!(
+            serde_json::from_str::<JsonValue>(&metadata).unwrap(),
+            joined
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn expired_channels_are_seeded_again() {
+        let redis = redis().await;
+        let index = RedisMembershipIndex::new(redis.clone());
+        let app_id = app_id();
+
+        index
+            .seed(&app_id, "room", &[member("alice")])
+            .await
+            .unwrap();
+
+        let keys = [
+            RedisMembershipIndex::get_key(&app_id, "room"),
+            RedisMembershipIndex::get_seeded_key(&app_id, "room"),
+        ];
+        for key in &keys {
+            assert!(ttl(&redis, key).await > 0);
+
+            redis::cmd("PEXPIRE")
+                .arg(key)
+                .arg(1)
+                .query_async::<()>(&mut redis.clone())
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        assert!(!index.is_seeded(&app_id, "room").await.unwrap());
+
+        index.seed(&app_id, "room", &[member("bob")]).await.unwrap();
+
+        assert!(index.is_seeded(&app_id, "room").await.unwrap());
+        assert_eq!(members(&index, &app_id).await, ["bob"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn removals_count_members_once_seeded() {
+        let redis = redis().await;
+        let index = RedisMembershipIndex::new(redis.clone());
+        let app_id = app_id();
+        let (alice, bob) = (member("alice"), member("bob"));
+
+        index
+            .add_member(&app_id, "room", &alice.0, &alice.1)
+            .await
+            .unwrap();
+        index
+            .add_member(&app_id, "room", &bob.0, &bob.1)
+            .await
+            .unwrap();
+
+        // Members from before the index may be missing, the count means nothing
+        assert_eq!(
+            index.remove_member(&app_id, "room", "alice").await.unwrap(),
+            None
+        );
+
+        index.seed(&app_id, "room", &[]).await.unwrap();
+        index
+            .add_member(&app_id, "room", &alice.0, &alice.1)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            index.remove_member(&app_id, "room", "alice").await.unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            index.remove_member(&app_id, "room", "bob").await.unwrap(),
+            Some(0)
+        );
+        assert!(members(&index, &app_id).await.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn declared_relays_have_no_last_patch() {
+        let relays = RedisRelayStore::new(redis().await);
+        let app_id = app_id();
+
+        assert!(!relays.is_relay(&app_id, "cursor").await.unwrap());
+
+        relays.declare(&app_id, "cursor").await.unwrap();
+
+        assert!(relays.is_relay(&app_id, "cursor").await.unwrap());
+        assert_eq!(relays.get_last(&app_id, "cursor").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn declaring_again_keeps_the_last_patch() {
+        let relays = RedisRelayStore::new(redis().await);
+        let app_id = app_id();
+
+        relays.declare(&app_id, "cursor").await.unwrap();
+        relays.save_last(&app_id, "cursor", b"first").await.unwrap();
+        relays
+            .save_last(&app_id, "cursor", b"second")
+            .await
+            .unwrap();
+        relays.declare(&app_id, "cursor").await.unwrap();
+
+        assert_eq!(
+            relays.get_last(&app_id, "cursor").await.unwrap(),
+            Some(b"second".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn deleted_relays_are_forgotten() {
+        let relays = RedisRelayStore::new(redis().await);
+        let app_id = app_id();
+
+        relays.declare(&app_id, "cursor").await.unwrap();
+        relays.save_last(&app_id, "cursor", b"patch").await.unwrap();
+        relays.delete(&app_id, "cursor").await.unwrap();
+
+        assert!(!relays.is_relay(&app_id, "cursor").await.unwrap());
+        assert_eq!(relays.get_last(&app_id, "cursor").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn encrypted_updates_are_paged_oldest_first() {
+        let log = RedisEncryptedLog::new(redis().await);
+        let app_id = app_id();
+
+        log.declare(&app_id, "secret").await.unwrap();
+        for update in [b"one", b"two", b"six"] {
+            log.append(&app_id, "secret", update).await.unwrap();
+        }
+
+        assert_eq!(
+            log.get_updates(&app_id, "secret", 0, 2).await.unwrap(),
+            [b"one".to_vec(), b"two".to_vec()]
+        );
+        assert_eq!(
+            log.get_updates(&app_id, "secret", 2, 2).await.unwrap(),
+            [b"six".to_vec()]
+        );
+        assert!(
+            log.get_updates(&app_id, "secret", 3, 2)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            log.get_updates(&app_id, "secret", 0, 0)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(log.size(&app_id, "secret").await.unwrap(), 9);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Redis at REDIS_URL"]
+    async fn encrypted_logs_are_retained_past_their_last_write() {
+        let redis = redis().await;
+        let log = RedisEncryptedLog::new(redis.clone());
+        let app_id = app_id();
+        let size_key = RedisEncryptedLog::get_size_key(&app_id, "secret");
+        let log_key = RedisEncryptedLog::get_log_key(&app_id, "secret");
+
+        log.declare(&app_id, "secret").await.unwrap();
+
+        // Nothing was written yet, only the marker expires
+        assert!(ttl(&redis, &size_key).await > 0);
+        assert_eq!(ttl(&redis, &log_key).await, -2);
+
+        log.append(&app_id, "secret", b"update").await.unwrap();
+
+        for key in [&size_key, &log_key] {
+            let ttl = ttl(&redis, key).await;
+            assert!(ttl > 0 && ttl <= ENCRYPTED_LOG_TTL_SECONDS as i64);
+        }
+
+        // Declaring again, as every join does, keeps the log
+        log.declare(&app_id, "secret").await.unwrap();
+
+        assert!(log.is_encrypted(&app_id, "secret").await.unwrap());
+        assert_eq!(
+            log.get_updates(&app_id, "secret", 0, 10).await.unwrap(),
+            [b"update".to_vec()]
+        );
+        assert_eq!(log.size(&app_id, "secret").await.unwrap(), 6);
+    }
+}
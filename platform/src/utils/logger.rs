@@ -1,10 +1,32 @@
+use opentelemetry_sdk::trace::SdkTracerProvider;
 use tracing_subscriber::{EnvFilter, fmt, prelude::*, registry};
 
-pub fn setup_logging() -> Result<(), Box<dyn std::error::Error>> {
+use super::Config;
+use crate::observability::telemetry;
+
+/// Install the log subscriber. When a tracing exporter is configured the
+/// returned provider must be shut down on exit to flush pending spans.
+pub fn setup_logging(
+    config: &Config,
+) -> Result<Option<SdkTracerProvider>, Box<dyn std::error::Error>> {
     let filter_layer = EnvFilter::from_default_env();
     let fmt_layer = fmt::layer().with_target(false).with_line_number(true);
 
-    registry().with(filter_layer).with(fmt_layer).init();
+    let provider = telemetry::setup_tracer_provider(
+        config.tracing_exporter,
+        &config.tracing_otlp_endpoint,
+        &config.tracing_file_path,
+    )?;
+
+    let otel_layer = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(telemetry::tracer(provider)));
+
+    registry()
+        .with(filter_layer)
+        .with(fmt_layer)
+        .with(otel_layer)
+        .init();
 
-    Ok(())
+    Ok(provider)
 }
@@ -2,8 +2,10 @@ mod auth_guard;
 mod config;
 mod logger;
 mod nats;
+mod shutdown;
 
 pub use auth_guard::*;
 pub use config::*;
 pub use logger::*;
 pub use nats::*;
+pub use shutdown::*;
@@ -0,0 +1,5 @@
+pub mod origins;
+pub mod repository;
+
+pub use origins::OriginPolicy;
+pub use repository::{AppRepository, AppSettings, VerificationKey};
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, interval};
+use tracing::error;
+
+use super::AppRepository;
+
+/// Per-app origin allow-lists, kept in memory so they can be checked from the
+/// synchronous handshake callback and refreshed in the background
+#[derive(Debug)]
+pub struct OriginPolicy {
+    repository: Arc<AppRepository>,
+    allowed_origins: RwLock<HashMap<String, Vec<String>>>,
+}
+
+impl OriginPolicy {
+    pub fn new(repository: Arc<AppRepository>) -> Self {
+        Self {
+            repository,
+            allowed_origins: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Lowercase and drop a trailing slash so `https://App.io/` matches `https://app.io`
+    pub fn normalize(origin: &str) -> String {
+        origin.trim().trim_end_matches('/').to_lowercase()
+    }
+
+    pub async fn refresh(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let allowed_origins = self
+            .repository
+            .list_allowed_origins()
+            .await?
+            .into_iter()
+            .map(|(app_id, origins)| {
+                let origins = origins.into_iter().map(|origin| Self::normalize(&origin));
+                (app_id, origins.collect())
+            })
+            .collect();
+
+        *self.allowed_origins.write().map_err(|e| e.to_string())? = allowed_origins;
+
+        Ok(())
+    }
+
+    /// Reload the allow-lists every `period`. Call `refresh` once before
+    /// accepting connections so the first period is covered.
+    pub fn start_refresh(self: Arc<Self>, period: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+            ticker.tick().await;
+
+            loop {
+                ticker.tick().await;
+
+                if let Err(e) = self.refresh().await {
+                    error!("Failed to refresh allowed origins: {e}");
+                }
+            }
+        })
+    }
+
+    /// Check against the cached allow-lists, for the handshake callback. Apps
+    /// missing from the cache are checked again once their settings are loaded.
+    pub fn is_allowed(&self, app_id: &str, origin: Option<&str>) -> bool {
+        // A poisoned lock means the lists may be half written, refuse rather than guess
+        let Ok(allowed_origins) = self.allowed_origins.read() else {
+            return false;
+        };
+
+        allowed_origins
+            .get(app_id)
+            .is_none_or(|allowed| Self::matches(allowed, origin))
+    }
+
+    /// An empty allow-list accepts any origin. Otherwise the origin must match
+    /// an entry exactly or through a `*.` subdomain wildcard.
+    pub fn matches(allowed: &[String], origin: Option<&str>) -> bool {
+        if allowed.is_empty() {
+            return true;
+        }
+
+        let Some(origin) = origin.map(Self::normalize) else {
+            return false;
+        };
+
+        allowed
+            .iter()
+            .map(|entry| Self::normalize(entry))
+            .any(|entry| match entry.split_once("://*.") {
+                Some((scheme, domain)) => origin
+                    .strip_prefix(scheme)
+                    .and_then(|rest| rest.strip_prefix("://"))
+                    .is_some_and(|host| host.ends_with(&format!(".{domain}"))),
+                None => entry == origin,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::PgPool;
+
+    fn allowed(origins: &[&str]) -> Vec<String> {
+        origins.iter().map(|origin| origin.to_string()).collect()
+    }
+
+    // The repository is only used by `refresh`, the pool never connects
+    fn policy(allowed_origins: &[(&str, &[&str])]) -> OriginPolicy {
+        let pool = PgPool::connect_lazy("postgres://localhost/unused").unwrap();
+        let policy = OriginPolicy::new(Arc::new(AppRepository::new(pool)));
+
+        *policy.allowed_origins.write().unwrap() = allowed_origins
+            .iter()
+            .map(|(app_id, origins)| (app_id.to_string(), allowed(origins)))
+            .collect();
+
+        policy
+    }
+
+    #[test]
+    fn an_empty_allow_list_accepts_any_origin() {
+        assert!(OriginPolicy::matches(&[], Some("https://evil.io")));
+        assert!(OriginPolicy::matches(&[], None));
+    }
+
+    #[test]
+    fn exact_entries_match_after_normalization() {
+        let allowed = allowed(&["https://App.io/"]);
+
+        assert!(OriginPolicy::matches(&allowed, Some("https://app.io")));
+        assert!(OriginPolicy::matches(&allowed, Some("HTTPS://APP.IO/")));
+        assert!(!OriginPolicy::matches(&allowed, Some("http://app.io")));
+        assert!(!OriginPolicy::matches(
+            &allowed,
+            Some("https://app.io.evil.io")
+        ));
+        assert!(!OriginPolicy::matches(&allowed, Some("https://sub.app.io")));
+    }
+
+    #[test]
+    fn wildcard_entries_match_subdomains_only() {
+        let allowed = allowed(&["https://*.app.io"]);
+
+        assert!(OriginPolicy::matches(&allowed, Some("https://www.app.io")));
+        assert!(OriginPolicy::matches(&allowed, Some("https://a.b.app.io")));
+        assert!(!OriginPolicy::matches(&allowed, Some("https://app.io")));
+        assert!(!OriginPolicy::matches(&allowed, Some("https://evilapp.io")));
+        assert!(!OriginPolicy::matches(&allowed, Some("http://www.app.io")));
+    }
+
+    #[test]
+    fn a_missing_origin_is_refused_by_a_non_empty_allow_list() {
+        assert!(!OriginPolicy::matches(&allowed(&["https://app.io"]), None));
+    }
+
+    #[tokio::test]
+    async fn handshakes_are_checked_against_the_cached_lists() {
+        let policy = policy(&[("restricted", &["https://app.io"]), ("open", &[])]);
+
+        assert!(policy.is_allowed("restricted", Some("https://app.io")));
+        assert!(!policy.is_allowed("restricted", Some("https://evil.io")));
+        assert!(!policy.is_allowed("restricted", None));
+        assert!(policy.is_allowed("open", Some("https://evil.io")));
+        // Left to the auth middleware once the settings are loaded
+        assert!(policy.is_allowed("uncached", Some("https://evil.io")));
+    }
+}
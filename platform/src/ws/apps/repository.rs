@@ -0,0 +1,191 @@
+use jsonwebtoken::{Algorithm, jwk::Jwk};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::str::FromStr;
+use tracing::debug;
+
+/// Per-app connection settings managed from the api
+#[derive(Debug, Clone)]
+pub struct AppSettings {
+    pub allow_query_token: bool,
+    /// Whether channel members are projected into the documents
+    pub members_in_document: bool,
+    /// Channels named with one of these prefixes relay patches verbatim
+    pub relay_channel_prefixes: Vec<String>,
+    /// Whether relay channels keep their last patch for joiners
+    pub relay_keep_last: bool,
+    /// Whether clients may declare relay channels outside the relay prefixes
+    pub allow_client_relay: bool,
+    /// Origins allowed to connect, any origin when empty
+    pub allowed_origins: Vec<String>,
+}
+
+/// Public key that verifies tokens signed with a given `kid`
+#[derive(Debug, Clone)]
+pub struct VerificationKey {
+    /// Set for keys registered by an app, which may only sign its own tokens
+    pub app_id: Option<String>,
+    pub algorithm: Algorithm,
+    pub jwk: Jwk,
+}
+
+#[derive(Debug, Clone)]
+pub struct AppRepository {
+    pool: PgPool,
+}
+
+impl AppRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Load the connection settings of an app, `None` if it does not exist
+    pub async fn get_settings(
+        &self,
+        app_id: &str,
+    ) -> Result<Option<AppSettings>, Box<dyn std::error::Error>> {
+        debug!("Loading settings for app {app_id}");
+
+        let row = sqlx::query!(
+            r#"
+            SELECT allow_query_token, members_in_document, relay_channel_prefixes, relay_keep_last,
+                allow_client_relay, allowed_origins
+            FROM apps
+            WHERE app_id = $1
+            "#,
+            app_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| AppSettings {
+            allow_query_token: row.allow_query_token,
+            members_in_document: row.members_in_document,
+            relay_channel_prefixes: row.relay_channel_prefixes,
+            relay_keep_last: row.relay_keep_last,
+            allow_client_relay: row.allow_client_relay,
+            allowed_origins: row.allowed_origins,
+        }))
+    }
+
+    /// Origin allow-lists of every app that restricts origins
+    pub async fn list_allowed_origins(
+        &self,
+    ) -> Result<HashMap<String, Vec<String>>, Box<dyn std::error::Error>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT app_id, allowed_origins
+            FROM apps
+            WHERE cardinality(allowed_origins) > 0
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.app_id, row.allowed_origins))
+            .collect())
+    }
+
+    /// Load the key a token's `kid` refers to, `None` if it is unknown or expired
+    pub async fn get_verification_key(
+        &self,
+        kid: &str,
+    ) -> Result<Option<VerificationKey>, Box<dyn std::error::Error>> {
+        debug!("Loading signing key {kid}");
+
+        let row = sqlx::query!(
+            r#"
+            SELECT app_id, algorithm AS "algorithm!", public_jwk AS "public_jwk!"
+            FROM signing_keys_public
+            WHERE kid = $1
+            AND (expires_at IS NULL OR expires_at > NOW())
+            "#,
+            kid
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        Ok(Some(VerificationKey {
+            app_id: row.app_id,
+            algorithm: Algorithm::from_str(&row.algorithm)?,
+            jwk: serde_json::from_value(row.public_jwk)?,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    async fn insert_key(pool: &PgPool, kid: &str, app_id: Option<&str>, expired: bool) {
+        // Only platform keys have a private key, which the platform never reads
+        let private_key = app_id.is_none().then_some(vec![0u8]);
+
+        sqlx::query(
+            r#"
+            INSERT INTO signing_keys (kid, app_id, algorithm, public_jwk, private_key, expires_at)
+            VALUES ($1, $2, 'EdDSA', $3, $4,
+                CASE WHEN $5 THEN NOW() - INTERVAL '1 second' END)
+            "#,
+        )
+        .bind(kid)
+        .bind(app_id)
+        .bind(json!({ "kty": "OKP", "crv": "Ed25519", "x": "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo" }))
+        .bind(private_key)
+        .bind(expired)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test(migrator = "db::MIGRATOR")]
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    async fn keys_are_looked_up_by_kid(pool: PgPool) {
+        sqlx::query(
+            r#"
+            WITH organization AS (
+                INSERT INTO organizations (name) VALUES ('Test') RETURNING id
+            )
+            INSERT INTO apps (app_id, app_secret_hash, name, organization_id)
+            SELECT 'test_app', 'hash', 'Test', id FROM organization
+            "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        insert_key(&pool, "platform", None, false).await;
+        insert_key(&pool, "app", Some("test_app"), false).await;
+        insert_key(&pool, "expired", None, true).await;
+
+        let repository = AppRepository::new(pool);
+
+        let platform_key = repository.get_verification_key("platform").await.unwrap();
+        let platform_key = platform_key.expect("platform key is found");
+        assert_eq!(platform_key.app_id, None);
+        assert_eq!(platform_key.algorithm, Algorithm::EdDSA);
+
+        let app_key = repository.get_verification_key("app").await.unwrap();
+        assert_eq!(
+            app_key.and_then(|key| key.app_id).as_deref(),
+            Some("test_app")
+        );
+
+        for kid in ["expired", "unknown"] {
+            assert!(
+                repository
+                    .get_verification_key(kid)
+                    .await
+                    .unwrap()
+                    .is_none()
+            );
+        }
+    }
+}
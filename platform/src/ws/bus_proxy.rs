@@ -5,85 +5,505 @@ use async_trait::async_trait;
 
 use crate::{
     messaging::{BroadcastMessage, MessagingResult, bus::MessagePublisher},
-    storage::DocumentStorage,
+    observability::{
+        metrics::{BUS_PROXY_BATCH_SENDERS, BUS_PROXY_BATCH_SIZE, BUS_PROXY_FLUSH_SECONDS},
+        telemetry::{self, TraceContext},
+    },
+    storage::{DocumentStorage, EncryptedLog, PatchLog, RelayStore},
 };
 
-use super::crdt::CrdtDocument;
+use super::{
+    crdt::CrdtDocument,
+    document_cache::DocumentCache,
+    metrics::{DataTransferMetric, MetricsCollector, data_transfer::MessageType},
+    ownership::ChannelOwnership,
+    quota::QuotaEnforcer,
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use tokio::sync::Mutex;
+use thiserror::Error;
+use tokio::sync::{Mutex, oneshot};
 use tokio::task::JoinHandle;
-use tokio::time::{Duration, Instant, sleep};
+use tokio::time::{Duration, Instant, interval, sleep, timeout};
+use tracing::{Instrument, error, info_span, warn};
+use uuid::Uuid;
+use yrs::StateVector;
+
+// How long a node waits for the owner of a channel to merge a forwarded patch,
+// covering the owner's batching and a handoff in progress
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Why a patch published with `publish_acked` was not saved
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PatchRejected {
+    #[error("Patch could not be applied to the document")]
+    Invalid,
+    #[error("Patch could not be saved")]
+    NotSaved,
+    #[error("Channel owner did not answer, the patch may not be saved")]
+    OwnerUnavailable,
+}
+
+/// Sequence number of a saved patch in its channel
+pub type PatchOutcome = Result<u64, PatchRejected>;
 
-// BusProxy: batches Patch messages per (app_id, channel_id)
+/// Publishes the patches clients send, reporting whether each was saved
+#[async_trait]
+pub trait PatchPublisher: Send + Sync {
+    /// Publish a document patch, resolved once its batch is saved
+    async fn publish_acked(
+        &self,
+        message: BroadcastMessage,
+        connection_id: Uuid,
+    ) -> oneshot::Receiver<PatchOutcome>;
+    /// Publish a patch of a relay or encrypted channel as sent
+    async fn publish_relayed(
+        &self,
+        message: BroadcastMessage,
+        connection_id: Uuid,
+        retention: RelayRetention,
+    ) -> oneshot::Receiver<PatchOutcome>;
+}
+
+/// What a channel keeps of the patches it relays, besides its patch log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayRetention {
+    /// Nothing, joiners only receive what is relayed after them
+    Nothing,
+    /// The last patch, sent to joiners
+    Last,
+    /// Every patch in the encrypted log, sent to joiners in order
+    All,
+}
+
+// A patch handed to the node owning its channel
+#[derive(Serialize, Deserialize)]
+struct ForwardedPatch {
+    message: BroadcastMessage,
+    connection_id: Option<String>,
+}
+
+// BusProxy: batches Patch messages per (app_id, channel_id), on the node
+// owning the channel
 pub struct BusProxy {
     publisher: Arc<dyn MessagePublisher>,
     storage: Arc<dyn DocumentStorage>,
+    patch_log: Arc<dyn PatchLog>,
+    relay_store: Arc<dyn RelayStore>,
+    encrypted_log: Arc<dyn EncryptedLog>,
+    document_cache: Arc<DocumentCache>,
+    metrics_collector: Arc<MetricsCollector>,
+    quota_enforcer: Arc<QuotaEnforcer>,
+    ownership: Arc<ChannelOwnership>,
     batches: Arc<Mutex<HashMap<(String, String), PatchBatch>>>,
 }
 
+struct BatchedPatch {
+    payload: Vec<u8>,
+    senders: Vec<String>,
+    // Connection the patch was sent on, `None` for patches the server makes
+    connection_id: Option<Uuid>,
+    // Where to report the outcome, when the sender asked for it
+    ack: Option<oneshot::Sender<PatchOutcome>>,
+}
+
+impl BatchedPatch {
+    fn sent_by(&self, user_id: &str) -> bool {
+        self.senders.iter().any(|sender| sender == user_id)
+    }
+}
+
 struct PatchBatch {
-    patches: Vec<Vec<u8>>,
+    patches: Vec<BatchedPatch>,
+    // Recipients of every batched patch
     recipients: Vec<String>,
+    // Context of every batched patch, the first one parents the flush span
+    trace_contexts: Vec<TraceContext>,
     debounce_handle: Option<JoinHandle<()>>,
     max_wait_handle: Option<JoinHandle<()>>,
     last_patch_time: Instant,
 }
 
+impl PatchBatch {
+    fn has_several_senders(&self) -> bool {
+        let mut senders = self.patches.iter().flat_map(|patch| &patch.senders);
+
+        match senders.next() {
+            Some(first) => senders.any(|sender| sender != first),
+            None => false,
+        }
+    }
+
+    // Bytes and recipients to bill each connection whose patches were
+    // applied. Recipients download the published diff rather than the
+    // patches, so it is split between them by the size of their patches.
+    fn transfers(
+        &self,
+        applied: &[bool],
+        recipients: &[String],
+        published_size: usize,
+    ) -> HashMap<Uuid, (usize, usize)> {
+        let applied_size = self
+            .patches
+            .iter()
+            .zip(applied)
+            .filter(|(_, applied)| **applied)
+            .map(|(patch, _)| patch.payload.len())
+            .sum::<usize>()
+            .max(1);
+
+        let mut transfers: HashMap<Uuid, (usize, usize)> = HashMap::new();
+
+        for (patch, applied) in self.patches.iter().zip(applied) {
+            let Some(connection_id) = patch.connection_id.filter(|_| *applied) else {
+                continue;
+            };
+
+            let recipient_count = recipients
+                .iter()
+                .filter(|recipient| !patch.senders.contains(recipient))
+                .count();
+
+            let transfer = transfers.entry(connection_id).or_default();
+            transfer.0 += published_size * patch.payload.len() / applied_size;
+            transfer.1 = transfer.1.max(recipient_count);
+        }
+
+        transfers
+    }
+}
+
 impl BusProxy {
-    pub fn new(publisher: Arc<dyn MessagePublisher>, storage: Arc<dyn DocumentStorage>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        publisher: Arc<dyn MessagePublisher>,
+        storage: Arc<dyn DocumentStorage>,
+        patch_log: Arc<dyn PatchLog>,
+        relay_store: Arc<dyn RelayStore>,
+        encrypted_log: Arc<dyn EncryptedLog>,
+        document_cache: Arc<DocumentCache>,
+        metrics_collector: Arc<MetricsCollector>,
+        quota_enforcer: Arc<QuotaEnforcer>,
+        ownership: Arc<ChannelOwnership>,
+    ) -> Self {
         Self {
             publisher,
             storage,
+            patch_log,
+            relay_store,
+            encrypted_log,
+            document_cache,
+            metrics_collector,
+            quota_enforcer,
+            ownership,
             batches: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    // Log the patch for resuming clients, keep it for joiners and publish it
+    async fn relay(
+        &self,
+        mut message: BroadcastMessage,
+        connection_id: Uuid,
+        retention: RelayRetention,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let BroadcastMessage::Patch {
+            app_id,
+            channel_id,
+            recipients,
+            payload,
+            seq,
+            relay,
+            ..
+        } = &mut message
+        else {
+            return Err("Only patches are relayed".into());
+        };
+
+        // Before logging, so the updates read after a sequence number always
+        // hold the patches up to it
+        if retention == RelayRetention::All {
+            self.encrypted_log
+                .append(app_id, channel_id, payload)
+                .await?;
+        }
+
+        let logged_seq = self
+            .patch_log
+            .append(app_id, channel_id, 1, payload)
+            .await?;
+
+        if retention == RelayRetention::Last {
+            self.relay_store
+                .save_last(app_id, channel_id, payload)
+                .await?;
+        }
+
+        *seq = Some(logged_seq);
+        *relay = true;
+
+        let metric = DataTransferMetric::new(
+            channel_id.clone(),
+            connection_id,
+            MessageType::Patch,
+            payload.len(),
+            recipients.len(),
+        );
+
+        let app_id = app_id.clone();
+
+        self.publisher.publish(message).await?;
+
+        self.record_transfer(&app_id, metric).await;
+
+        Ok(logged_seq)
+    }
+
+    // Merge the batched patches into the stored document, log what they
+    // changed for resuming clients and publish it. Returns the sequence number
+    // of each patch, `None` for those that could not be applied.
+    async fn publish_batch(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        batch: &PatchBatch,
+    ) -> Result<Vec<Option<u64>>, Box<dyn std::error::Error>> {
+        let _flush_timer = BUS_PROXY_FLUSH_SECONDS.start_timer();
+        BUS_PROXY_BATCH_SIZE.observe(batch.patches.len() as f64);
+
+        // Merge patches using CRDT, into the cached document while it holds
+        // every patch logged so far. Taken out until saved, so a failure
+        // leaves it to be loaded again.
+        let prev_seq = self.patch_log.last_seq(app_id, channel_id).await?;
+        let mut crdt = self
+            .document_cache
+            .take(app_id, channel_id, prev_seq)
+            .await?;
+        let prev_state_vector = crdt.state_vector().await;
+
+        // Only needed to leave each sender's patches out of what it receives
+        let state_update = if batch.has_several_senders() {
+            Some(crdt.get_state_as_update().await)
+        } else {
+            None
+        };
+
+        let mut applied = Vec::with_capacity(batch.patches.len());
+
+        for patch in &batch.patches {
+            applied.push(crdt.apply_delta(&patch.payload).await.is_ok());
+        }
+
+        let count = applied.iter().filter(|applied| **applied).count() as u64;
+
+        if count == 0 {
+            return Ok(vec![None; applied.len()]);
+        }
+
+        let document = crdt.get_state_as_update().await;
+        // Recipients already hold the document as it was before the batch
+        let merged_update = crdt.to_update(&prev_state_vector).await;
+
+        let mut senders = batch
+            .patches
+            .iter()
+            .zip(&applied)
+            .filter(|(_, applied)| **applied)
+            .flat_map(|(patch, _)| patch.senders.iter().cloned())
+            .collect::<Vec<String>>();
+        senders.sort();
+        senders.dedup();
+        BUS_PROXY_BATCH_SENDERS.observe(senders.len() as f64);
+
+        // A sole sender already has everything in the batch
+        let mut without_sender = HashMap::new();
+
+        if let Some(state_update) = state_update.filter(|_| senders.len() > 1) {
+            for sender in &senders {
+                let update = self
+                    .merge_without(&state_update, &prev_state_vector, batch, &applied, sender)
+                    .await
+                    .unwrap_or_else(|| merged_update.clone());
+
+                without_sender.insert(sender.clone(), update);
+            }
+        }
+
+        // Senders receive what the others contributed
+        let mut recipients = batch.recipients.clone();
+        recipients.extend(senders.iter().cloned());
+        recipients.sort();
+        recipients.dedup();
+
+        self.storage
+            .save_document(app_id, channel_id, &document)
+            .await?;
+
+        // Logged after the save, so a document read before a sequence number
+        // always contains the patches up to it
+        let first_seq = self
+            .patch_log
+            .append(app_id, channel_id, count, &merged_update)
+            .await?;
+        let last_seq = first_seq + count - 1;
+
+        // Another node logging patches in between leaves the document behind
+        let complete_seq = (first_seq == prev_seq + 1).then_some(last_seq);
+        self.document_cache
+            .put(app_id, channel_id, crdt, document.len(), complete_seq)
+            .await;
+
+        let published_size = merged_update.len();
+
+        // Publish merged patch
+        let merged_message = BroadcastMessage::Patch {
+            app_id: app_id.to_string(),
+            channel_id: channel_id.to_string(),
+            senders,
+            recipients: recipients.clone(),
+            payload: merged_update,
+            without_sender,
+            seq: Some(last_seq),
+            relay: false,
+            trace_context: TraceContext::new(),
+        };
+
+        self.publisher.publish(merged_message).await?;
+
+        self.record_transfers(
+            app_id,
+            channel_id,
+            batch,
+            &applied,
+            &recipients,
+            published_size,
+        )
+        .await;
+
+        let mut next_seq = first_seq;
+
+        Ok(applied
+            .into_iter()
+            .map(|applied| {
+                applied.then(|| {
+                    next_seq += 1;
+                    next_seq - 1
+                })
+            })
+            .collect())
+    }
+
+    // Merge the applied patches of everyone but `sender` into the state the
+    // batch started from, `None` if a patch no longer applies on its own
+    async fn merge_without(
+        &self,
+        state_update: &[u8],
+        prev_state_vector: &StateVector,
+        batch: &PatchBatch,
+        applied: &[bool],
+        sender: &str,
+    ) -> Option<Vec<u8>> {
+        let mut crdt = CrdtDocument::from_update(state_update).await;
+
+        for (patch, applied) in batch.patches.iter().zip(applied) {
+            if *applied && !patch.sent_by(sender) {
+                crdt.apply_delta(&patch.payload).await.ok()?;
+            }
+        }
+
+        Some(crdt.to_update(prev_state_vector).await)
+    }
+
+    // Record the transfer of the published diff against the connections whose
+    // patches it carries, now that it reached the other recipients
+    async fn record_transfers(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        batch: &PatchBatch,
+        applied: &[bool],
+        recipients: &[String],
+        published_size: usize,
+    ) {
+        let transfers = batch.transfers(applied, recipients, published_size);
+
+        for (connection_id, (message_size, recipient_count)) in transfers {
+            let metric = DataTransferMetric::new(
+                channel_id.to_string(),
+                connection_id,
+                MessageType::Patch,
+                message_size,
+                recipient_count,
+            );
+
+            self.record_transfer(app_id, metric).await;
+        }
+    }
+
+    // Bill a transfer and charge it against the app's monthly cap, which the
+    // sender was checked against before its patch was accepted
+    async fn record_transfer(&self, app_id: &str, metric: DataTransferMetric) {
+        let bytes = metric.total_bytes_transferred() as usize;
+        let channel_id = metric.channel_id.clone();
+
+        if let Err(e) = self.quota_enforcer.charge_transfer(app_id, bytes).await {
+            error!("Failed to charge transfer for app {app_id}: {e}");
+        }
+
+        if let Err(e) = self.metrics_collector.record_data_transfer(metric).await {
+            error!("Failed to record transfer for channel {channel_id}: {e}");
+        }
+    }
+
     async fn flush_batch(&self, app_id: String, channel_id: String) {
+        // Before locking, other channels keep batching meanwhile
+        self.ownership.wait_for_handoff(&app_id, &channel_id).await;
+
         let key = (app_id.clone(), channel_id.clone());
         let mut batches = self.batches.lock().await;
 
         if let Some(batch) = batches.get_mut(&key) {
             if !batch.patches.is_empty() {
-                // Merge patches using CRDT
-                let state_update = self
-                    .storage
-                    .get_document(&app_id, &channel_id)
-                    .await
-                    .ok()
-                    .flatten()
-                    .unwrap_or_default();
+                let span = info_span!(
+                    "bus_proxy.flush",
+                    app_id = %app_id,
+                    channel_id = %channel_id,
+                    batch_size = batch.patches.len()
+                );
 
-                let mut crdt = CrdtDocument::from_update(&state_update).await;
+                if let Some((first, rest)) = batch.trace_contexts.split_first() {
+                    telemetry::set_parent(&span, first);
 
-                for patch in &batch.patches {
-                    let _ = crdt.apply_delta(patch).await;
+                    for context in rest {
+                        telemetry::add_link(&span, context);
+                    }
                 }
 
-                let merged_update = crdt.get_state_as_update().await;
-
-                // Deduplicate recipients
-                let mut recipients = batch.recipients.clone();
-                recipients.sort();
-                recipients.dedup();
-
-                // Publish merged patch
-                let merged_message = BroadcastMessage::Patch {
-                    app_id: app_id.clone(),
-                    channel_id: channel_id.clone(),
-                    sender: "batched".to_string(),
-                    recipients,
-                    payload: merged_update.clone(),
+                let outcomes = match self
+                    .publish_batch(&app_id, &channel_id, batch)
+                    .instrument(span)
+                    .await
+                {
+                    Ok(seqs) => seqs
+                        .into_iter()
+                        .map(|seq| seq.ok_or(PatchRejected::Invalid))
+                        .collect(),
+                    Err(e) => {
+                        error!("Failed to publish batch for channel {channel_id}: {e}");
+                        vec![Err(PatchRejected::NotSaved); batch.patches.len()]
+                    }
                 };
 
-                let _ = self
-                    .storage
-                    .save_document(&app_id, &channel_id, &merged_update)
-                    .await;
-
-                let _ = self.publisher.publish(merged_message).await;
+                // Senders that went away in the meantime no longer care
+                for (patch, outcome) in batch.patches.drain(..).zip(outcomes) {
+                    if let Some(ack) = patch.ack {
+                        let _ = ack.send(outcome);
+                    }
+                }
 
-                batch.patches.clear();
                 batch.recipients.clear();
+                batch.trace_contexts.clear();
             }
 
             if let Some(handle) = batch.debounce_handle.take() {
@@ -95,32 +515,237 @@ impl BusProxy {
             }
         }
     }
-}
 
-#[async_trait]
-impl MessagePublisher for BusProxy {
-    async fn publish(&self, message: BroadcastMessage) -> MessagingResult<()> {
+    async fn enqueue(
+        &self,
+        message: BroadcastMessage,
+        connection_id: Option<Uuid>,
+        ack: Option<oneshot::Sender<PatchOutcome>>,
+    ) {
+        let BroadcastMessage::Patch {
+            app_id, channel_id, ..
+        } = &message
+        else {
+            error!("Only patches are batched");
+            return;
+        };
+
+        match self.ownership.remote_owner(app_id, channel_id) {
+            Some(owner) => self.forward(owner, message, connection_id, ack).await,
+            None => self.enqueue_local(message, connection_id, ack).await,
+        }
+    }
+
+    // Hand the patch to the node owning its channel, which replies with the
+    // outcome once merged. Never merged here when the owner does not answer:
+    // it may still hold the channel's document, so the patch is rejected for
+    // the client to send again, which is harmless if the owner merged it.
+    async fn forward(
+        &self,
+        owner: Uuid,
+        message: BroadcastMessage,
+        connection_id: Option<Uuid>,
+        ack: Option<oneshot::Sender<PatchOutcome>>,
+    ) {
+        let forwarded = ForwardedPatch {
+            message,
+            connection_id: connection_id.map(|connection_id| connection_id.to_string()),
+        };
+
+        let nats = self.ownership.nats();
+        let inbox = nats.new_inbox();
+
+        // Sent before returning, so patches reach the owner in order
+        let sent = async {
+            let replies = nats.subscribe(inbox.clone()).await?;
+            let payload = serde_json::to_vec(&forwarded)?;
+
+            nats.publish_with_reply(
+                ChannelOwnership::get_patch_subject(&owner),
+                inbox,
+                payload.into(),
+            )
+            .await?;
+
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(replies)
+        }
+        .await;
+
+        let mut replies = match sent {
+            Ok(replies) => replies,
+            Err(e) => {
+                warn!("Failed to forward patch to node {owner}: {e}");
+
+                if let Some(ack) = ack {
+                    let _ = ack.send(Err(PatchRejected::OwnerUnavailable));
+                }
+
+                return;
+            }
+        };
+
+        tokio::spawn(async move {
+            let outcome = match timeout(FORWARD_TIMEOUT, replies.next()).await {
+                Ok(Some(reply)) => serde_json::from_slice::<PatchOutcome>(&reply.payload).ok(),
+                _ => None,
+            };
+
+            let outcome = outcome.unwrap_or_else(|| {
+                warn!("Node {owner} did not answer a forwarded patch");
+                Err(PatchRejected::OwnerUnavailable)
+            });
+
+            if let Some(ack) = ack {
+                let _ = ack.send(outcome);
+            }
+        });
+    }
+
+    /// Merge the patches other nodes forward to this one, replying with the
+    /// outcome of each once saved
+    pub async fn serve_forwarded(
+        self: Arc<Self>,
+    ) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
+        let nats = self.ownership.nats().clone();
+        let mut requests = nats
+            .subscribe(ChannelOwnership::get_patch_subject(
+                &self.ownership.node_id(),
+            ))
+            .await?;
+
+        Ok(tokio::spawn(async move {
+            while let Some(request) = requests.next().await {
+                let forwarded: ForwardedPatch = match serde_json::from_slice(&request.payload) {
+                    Ok(forwarded) => forwarded,
+                    Err(e) => {
+                        error!("Invalid forwarded patch: {e}");
+                        continue;
+                    }
+                };
+
+                let connection_id = forwarded
+                    .connection_id
+                    .and_then(|connection_id| connection_id.parse().ok());
+                let (tx, rx) = oneshot::channel();
+
+                // Merged even if this node sees another owner by now, so
+                // patches never bounce between nodes
+                self.enqueue_local(forwarded.message, connection_id, Some(tx))
+                    .await;
+
+                let Some(reply) = request.reply else {
+                    continue;
+                };
+
+                let nats = nats.clone();
+
+                tokio::spawn(async move {
+                    let outcome = rx.await.unwrap_or(Err(PatchRejected::NotSaved));
+
+                    let sent = match serde_json::to_vec(&outcome) {
+                        Ok(payload) => nats
+                            .publish(reply, payload.into())
+                            .await
+                            .map_err(|e| e.to_string()),
+                        Err(e) => Err(e.to_string()),
+                    };
+
+                    if let Err(e) = sent {
+                        error!("Failed to reply to a forwarded patch: {e}");
+                    }
+                });
+            }
+        }))
+    }
+
+    /// Follow ring changes every `period`. The batches of channels this node
+    /// gave up are flushed and their documents dropped, then the other nodes
+    /// are told the handoff is done.
+    pub fn start_handoff(self: Arc<Self>, period: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+
+            loop {
+                ticker.tick().await;
+
+                let changed = match self.ownership.refresh().await {
+                    Ok(changed) => changed,
+                    Err(e) => {
+                        error!("Failed to refresh channel owners: {e}");
+                        false
+                    }
+                };
+
+                if changed {
+                    self.hand_off().await;
+                }
+            }
+        })
+    }
+
+    async fn hand_off(&self) {
+        let moved = self
+            .batches
+            .lock()
+            .await
+            .keys()
+            .filter(|(app_id, channel_id)| {
+                self.ownership.remote_owner(app_id, channel_id).is_some()
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for (app_id, channel_id) in moved {
+            self.flush_batch(app_id, channel_id).await;
+        }
+
+        self.document_cache
+            .retain(|app_id, channel_id| self.ownership.remote_owner(app_id, channel_id).is_none())
+            .await;
+
+        if let Err(e) = self.ownership.announce_handoff().await {
+            error!("Failed to announce handoff: {e}");
+        }
+    }
+
+    async fn enqueue_local(
+        &self,
+        message: BroadcastMessage,
+        connection_id: Option<Uuid>,
+        ack: Option<oneshot::Sender<PatchOutcome>>,
+    ) {
         match message {
             BroadcastMessage::Patch {
                 app_id,
                 channel_id,
-                sender: _,
+                senders,
                 recipients,
                 payload,
+                without_sender: _,
+                seq: _,
+                relay: _,
+                trace_context,
             } => {
                 let key = (app_id.clone(), channel_id.clone());
                 let mut batches = self.batches.lock().await;
 
                 let batch = batches.entry(key.clone()).or_insert_with(|| PatchBatch {
                     patches: vec![],
-                    recipients: recipients.clone(),
+                    recipients: vec![],
+                    trace_contexts: vec![],
                     debounce_handle: None,
                     max_wait_handle: None,
                     last_patch_time: Instant::now(),
                 });
 
-                batch.patches.push(payload);
-                batch.recipients = recipients;
+                batch.patches.push(BatchedPatch {
+                    payload,
+                    senders,
+                    connection_id,
+                    ack,
+                });
+                batch.recipients.extend(recipients);
+                batch.trace_contexts.push(trace_context);
                 batch.last_patch_time = Instant::now();
 
                 // Debounce timer (50ms)
@@ -139,11 +764,10 @@ impl MessagePublisher for BusProxy {
 
                     if let Some(batch) =
                         batches.get_mut(&(app_id_clone.clone(), channel_id_clone.clone()))
+                        && batch.last_patch_time.elapsed() >= Duration::from_millis(50)
                     {
-                        if batch.last_patch_time.elapsed() >= Duration::from_millis(50) {
-                            drop(batches); // unlock before flush
-                            this.flush_batch(app_id_clone, channel_id_clone).await;
-                        }
+                        drop(batches); // unlock before flush
+                        this.flush_batch(app_id_clone, channel_id_clone).await;
                     }
                 }));
 
@@ -158,10 +782,66 @@ impl MessagePublisher for BusProxy {
                         this.flush_batch(app_id_clone, channel_id_clone).await;
                     }));
                 }
-
-                Ok(())
             }
+            BroadcastMessage::KeyExchange { .. } => {
+                error!("Only patches are batched");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl PatchPublisher for BusProxy {
+    /// Publish a patch sent on `connection_id` and learn once its batch is
+    /// saved, with the sequence number the patch got, or why it was not. The
+    /// transfer is recorded against the connection once the batch is published.
+    async fn publish_acked(
+        &self,
+        message: BroadcastMessage,
+        connection_id: Uuid,
+    ) -> oneshot::Receiver<PatchOutcome> {
+        let (tx, rx) = oneshot::channel();
+
+        self.enqueue(message, Some(connection_id), Some(tx)).await;
+
+        rx
+    }
+
+    /// Publish a patch of a relay or encrypted channel as sent, without
+    /// batching or merging it, and learn its sequence number. The channel
+    /// keeps it as `retention` says.
+    async fn publish_relayed(
+        &self,
+        message: BroadcastMessage,
+        connection_id: Uuid,
+        retention: RelayRetention,
+    ) -> oneshot::Receiver<PatchOutcome> {
+        let (tx, rx) = oneshot::channel();
+
+        let outcome = self
+            .relay(message, connection_id, retention)
+            .await
+            .map_err(|e| {
+                error!("Failed to relay patch: {e}");
+                PatchRejected::NotSaved
+            });
+
+        let _ = tx.send(outcome);
+
+        rx
+    }
+}
+
+#[async_trait]
+impl MessagePublisher for BusProxy {
+    async fn publish(&self, message: BroadcastMessage) -> MessagingResult<()> {
+        match message {
+            BroadcastMessage::Patch { .. } => self.enqueue(message, None, None).await,
+            // Nothing to merge, published as is
+            BroadcastMessage::KeyExchange { .. } => return self.publisher.publish(message).await,
         }
+
+        Ok(())
     }
 }
 
@@ -170,7 +850,419 @@ impl Clone for BusProxy {
         Self {
             publisher: self.publisher.clone(),
             storage: self.storage.clone(),
+            patch_log: self.patch_log.clone(),
+            relay_store: self.relay_store.clone(),
+            encrypted_log: self.encrypted_log.clone(),
+            document_cache: self.document_cache.clone(),
+            metrics_collector: self.metrics_collector.clone(),
+            quota_enforcer: self.quota_enforcer.clone(),
+            ownership: self.ownership.clone(),
             batches: self.batches.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::memory::{
+            MemoryDocumentStorage, MemoryEncryptedLog, MemoryPatchLog, MemoryRelayStore,
+        },
+        ws::{
+            metrics::collector::MetricsEvent,
+            quota::{QuotaRepositoryTrait, Quotas, store::MemoryQuotaStore},
+        },
+    };
+    use prometheus::{Encoder, TextEncoder};
+    use serde_json::{Value as JsonValue, json};
+    use std::sync::Mutex as StdMutex;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::sync::mpsc;
+
+    const APP_ID: &str = "app";
+
+    #[derive(Default)]
+    struct RecordingPublisher {
+        messages: StdMutex<Vec<BroadcastMessage>>,
+    }
+
+    #[async_trait]
+    impl MessagePublisher for RecordingPublisher {
+        async fn publish(&self, message: BroadcastMessage) -> MessagingResult<()> {
+            self.messages.lock().unwrap().push(message);
+            Ok(())
+        }
+    }
+
+    struct Unlimited;
+
+    #[async_trait]
+    impl QuotaRepositoryTrait for Unlimited {
+        async fn get_quotas(
+            &self,
+            _app_id: &str,
+        ) -> Result<Option<Quotas>, Box<dyn std::error::Error>> {
+            Ok(None)
+        }
+    }
+
+    // Documents that cannot be saved while `failing` is set
+    #[derive(Default)]
+    struct FlakyStorage {
+        documents: MemoryDocumentStorage,
+        failing: AtomicBool,
+    }
+
+    #[async_trait]
+    impl DocumentStorage for FlakyStorage {
+        async fn get_document(
+            &self,
+            app_id: &str,
+            channel_id: &str,
+        ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+            self.documents.get_document(app_id, channel_id).await
+        }
+
+        async fn save_document(
+            &self,
+            app_id: &str,
+            channel_id: &str,
+            update: &[u8],
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err("Storage unavailable".into());
+            }
+
+            self.documents
+                .save_document(app_id, channel_id, update)
+                .await
+        }
+
+        async fn delete_document(
+            &self,
+            app_id: &str,
+            channel_id: &str,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            self.documents.delete_document(app_id, channel_id).await
+        }
+    }
+
+    struct Proxy {
+        proxy: Arc<BusProxy>,
+        publisher: Arc<RecordingPublisher>,
+        // Kept so recording transfers does not fail
+        _metrics: mpsc::UnboundedReceiver<MetricsEvent>,
+    }
+
+    // A proxy alone in its ring, merging every channel itself
+    async fn proxy(storage: Arc<dyn DocumentStorage>) -> Proxy {
+        let publisher = Arc::new(RecordingPublisher::default());
+        let (metrics_collector, metrics) = MetricsCollector::detached();
+        let quota_enforcer =
+            QuotaEnforcer::with_store(Arc::new(MemoryQuotaStore::default()), Arc::new(Unlimited));
+
+        let proxy = BusProxy::new(
+            publisher.clone(),
+            storage.clone(),
+            Arc::new(MemoryPatchLog::default()),
+            Arc::new(MemoryRelayStore::default()),
+            Arc::new(MemoryEncryptedLog::default()),
+            Arc::new(DocumentCache::new(
+                storage,
+                usize::MAX,
+                Duration::from_secs(60),
+            )),
+            Arc::new(metrics_collector),
+            Arc::new(quota_enforcer),
+            Arc::new(ChannelOwnership::alone().await),
+        );
+
+        Proxy {
+            proxy: Arc::new(proxy),
+            publisher,
+            _metrics: metrics,
+        }
+    }
+
+    // Patch setting `key` of the shared state, made by a client of its own
+    async fn update(key: &str, value: JsonValue) -> Vec<u8> {
+        let mut crdt = CrdtDocument::new().await;
+        crdt.insert_value(&["state", key], value).await.unwrap();
+        crdt.get_state_as_update().await
+    }
+
+    fn message(channel_id: &str, sender: &str, payload: Vec<u8>) -> BroadcastMessage {
+        BroadcastMessage::Patch {
+            app_id: APP_ID.to_string(),
+            channel_id: channel_id.to_string(),
+            senders: vec![sender.to_string()],
+            recipients: vec![],
+            payload,
+            without_sender: HashMap::new(),
+            seq: None,
+            relay: false,
+            trace_context: TraceContext::new(),
+        }
+    }
+
+    // Value of a series in what `/metrics` serves, 0 before it is first set
+    fn scrape(series: &str) -> f64 {
+        let mut body = vec![];
+        TextEncoder::new()
+            .encode(&prometheus::gather(), &mut body)
+            .unwrap();
+
+        String::from_utf8(body)
+            .unwrap()
+            .lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+            .map(|value| value.parse().unwrap())
+            .unwrap_or_default()
+    }
+
+    fn patch(payload: Vec<u8>, sender: &str, connection_id: Option<Uuid>) -> BatchedPatch {
+        BatchedPatch {
+            payload,
+            senders: vec![sender.to_string()],
+            connection_id,
+            ack: None,
+        }
+    }
+
+    fn batch(patches: Vec<BatchedPatch>) -> PatchBatch {
+        PatchBatch {
+            patches,
+            recipients: vec![],
+            trace_contexts: vec![],
+            debounce_handle: None,
+            max_wait_handle: None,
+            last_patch_time: Instant::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn transfers_bill_the_published_diff_not_the_patch() {
+        let mut crdt = CrdtDocument::new().await;
+        for i in 0..100 {
+            crdt.insert_value(&["state", &format!("entry-{i}")], json!(i))
+                .await
+                .unwrap();
+        }
+        let document = crdt.get_state_as_update().await;
+
+        // A client that sends its whole state along with a small edit
+        crdt.insert_value(&["state", "entry-new"], json!(true))
+            .await
+            .unwrap();
+        let payload = crdt.get_state_as_update().await;
+
+        let mut merged = CrdtDocument::from_update(&document).await;
+        let state_vector = merged.state_vector().await;
+        merged.apply_delta(&payload).await.unwrap();
+        let diff = merged.to_update(&state_vector).await;
+
+        let connection_id = Uuid::new_v4();
+        let batch = batch(vec![patch(payload.clone(), "alice", Some(connection_id))]);
+        let recipients = ["alice", "bob", "carol"].map(str::to_string);
+
+        let transfers = batch.transfers(&[true], &recipients, diff.len());
+
+        assert!(diff.len() < payload.len());
+        assert_eq!(transfers[&connection_id], (diff.len(), 2));
+    }
+
+    #[test]
+    fn transfers_split_the_diff_between_applied_client_patches() {
+        let (alice, bob, rejected) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let batch = batch(vec![
+            patch(vec![0; 300], "alice", Some(alice)),
+            patch(vec![0; 100], "bob", Some(bob)),
+            // Made by the server, its share is not billed
+            patch(vec![0; 100], "carol", None),
+            patch(vec![0; 100], "dave", Some(rejected)),
+        ]);
+        let recipients = ["alice", "bob", "carol"].map(str::to_string);
+
+        let transfers = batch.transfers(&[true, true, true, false], &recipients, 100);
+
+        assert_eq!(transfers[&alice], (60, 2));
+        assert_eq!(transfers[&bob], (20, 2));
+        assert!(!transfers.contains_key(&rejected));
+    }
+
+    #[tokio::test]
+    async fn a_flush_shows_in_the_scraped_metrics() {
+        let proxy = proxy(Arc::new(MemoryDocumentStorage::default())).await;
+        let series = [
+            "platform_bus_proxy_batch_size_count",
+            "platform_bus_proxy_batch_senders_count",
+            "platform_bus_proxy_flush_seconds_count",
+            "platform_document_cache_lookups_total{result=\"miss\"}",
+        ];
+        let before = series.map(scrape);
+
+        let payload = update("title", json!("Draft")).await;
+        let outcome = proxy
+            .proxy
+            .publish_acked(message("metrics", "alice", payload), Uuid::new_v4())
+            .await
+            .await
+            .unwrap();
+
+        assert_eq!(outcome, Ok(1));
+        assert_eq!(proxy.publisher.messages.lock().unwrap().len(), 1);
+
+        // Other tests flush meanwhile, so only a lower bound holds
+        for (series, before) in series.iter().zip(before) {
+            assert!(
+                scrape(series) >= before + 1.0,
+                "{series} was not incremented"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn patches_of_a_batch_are_acked_with_consecutive_sequences() {
+        let proxy = proxy(Arc::new(MemoryDocumentStorage::default())).await;
+
+        // Enqueued within the debounce delay, so flushed as one batch
+        let mut outcomes = vec![];
+        for (sender, payload) in [
+            ("alice", update("title", json!("Draft")).await),
+            // Not an update at all
+            ("bob", vec![]),
+            ("carol", update("done", json!(false)).await),
+        ] {
+            outcomes.push(
+                proxy
+                    .proxy
+                    .publish_acked(message("acks", sender, payload), Uuid::new_v4())
+                    .await,
+            );
+        }
+
+        let mut acks = vec![];
+        for outcome in outcomes {
+            acks.push(outcome.await.unwrap());
+        }
+
+        assert_eq!(acks, [Ok(1), Err(PatchRejected::Invalid), Ok(2)]);
+        assert_eq!(proxy.publisher.messages.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn patches_that_cannot_be_saved_are_nacked_without_using_a_sequence() {
+        let storage = Arc::new(FlakyStorage::default());
+        storage.failing.store(true, Ordering::SeqCst);
+        let proxy = proxy(storage.clone()).await;
+
+        let outcome = proxy
+            .proxy
+            .publish_acked(
+                message("nacks", "alice", update("title", json!("Draft")).await),
+                Uuid::new_v4(),
+            )
+            .await
+            .await
+            .unwrap();
+
+        assert_eq!(outcome, Err(PatchRejected::NotSaved));
+        assert!(proxy.publisher.messages.lock().unwrap().is_empty());
+
+        // Sent again once storage is back, the patch gets the first sequence
+        storage.failing.store(false, Ordering::SeqCst);
+
+        let outcome = proxy
+            .proxy
+            .publish_acked(
+                message("nacks", "alice", update("title", json!("Draft")).await),
+                Uuid::new_v4(),
+            )
+            .await
+            .await
+            .unwrap();
+
+        assert_eq!(outcome, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn senders_of_a_batch_receive_each_others_changes() {
+        // Both clients start from the stored document, so they share its state map
+        let base = update("status", json!("open")).await;
+        let storage = Arc::new(MemoryDocumentStorage::default());
+        storage
+            .save_document(APP_ID, "shared", &base)
+            .await
+            .unwrap();
+        let proxy = proxy(storage).await;
+
+        let edit = |key: &'static str, value: JsonValue| {
+            let base = base.clone();
+            async move {
+                let mut crdt = CrdtDocument::from_update(&base).await;
+                crdt.insert_value(&["state", key], value).await.unwrap();
+                crdt.get_state_as_update().await
+            }
+        };
+
+        // Enqueued within the debounce delay, so flushed as one batch
+        let alice = proxy
+            .proxy
+            .publish_acked(
+                message("shared", "alice", edit("title", json!("Draft")).await),
+                Uuid::new_v4(),
+            )
+            .await;
+        let bob = proxy
+            .proxy
+            .publish_acked(
+                message("shared", "bob", edit("owner", json!("bob")).await),
+                Uuid::new_v4(),
+            )
+            .await;
+
+        assert_eq!(alice.await.unwrap(), Ok(1));
+        assert_eq!(bob.await.unwrap(), Ok(2));
+
+        let messages = proxy.publisher.messages.lock().unwrap().clone();
+        let [
+            BroadcastMessage::Patch {
+                senders,
+                payload,
+                without_sender,
+                ..
+            },
+        ] = messages.as_slice()
+        else {
+            panic!("Expected a single merged patch, got {messages:?}");
+        };
+
+        // What a client holding the stored document sees after applying `update`
+        let apply = |update: Vec<u8>| {
+            let base = base.clone();
+            async move {
+                let mut crdt = CrdtDocument::from_update(&base).await;
+                crdt.apply_delta(&update).await.unwrap();
+                crdt.to_json().await
+            }
+        };
+
+        assert_eq!(senders, &["alice", "bob"]);
+        assert_eq!(without_sender.len(), 2);
+        assert_eq!(
+            apply(without_sender["alice"].clone()).await,
+            json!({"state": {"status": "open", "owner": "bob"}})
+        );
+        assert_eq!(
+            apply(without_sender["bob"].clone()).await,
+            json!({"state": {"status": "open", "title": "Draft"}})
+        );
+
+        // Everyone else gets both changes
+        assert_eq!(
+            apply(payload.clone()).await,
+            json!({"state": {"status": "open", "title": "Draft", "owner": "bob"}})
+        );
+    }
+}
@@ -1,20 +1,114 @@
 use async_trait::async_trait;
 use derive_builder::Builder;
-use futures_util::{StreamExt, stream::SplitSink};
+use futures_util::{
+    SinkExt, StreamExt,
+    stream::{SplitSink, SplitStream},
+};
 use std::sync::Arc;
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::Mutex,
     task::JoinHandle,
+    time::{Duration, timeout},
+};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{
+    HeaderValue, StatusCode,
+    header::{ORIGIN, SEC_WEBSOCKET_PROTOCOL},
 };
-use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, frame::coding::CloseCode};
 use tokio_tungstenite::{WebSocketStream, tungstenite::Message};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
+use url::Url;
 use uuid::Uuid;
 
+use super::apps::OriginPolicy;
+use super::crdt::UpdateEncoding;
 use super::dto::incoming_message::{IncomingMessage, parse_binary_message};
+use super::dto::outgoing_message::OutgoingMessage;
+use super::dto::protocol::{ProtocolVersion, encoding_from_subprotocols, token_from_subprotocols};
+use super::errors::{ClientError, ErrorCode};
+use super::middlewares::auth::peek_app_id;
+use super::quota::QuotaExceeded;
+use super::rate_limit::RateLimitDecision;
+use super::resume::ResumeStore;
+use crate::observability::metrics::{
+    ACTIVE_CHANNELS, ACTIVE_CONNECTIONS, MESSAGE_BYTES_TOTAL, MESSAGES_TOTAL,
+};
 
 pub type WsWrite = Arc<Mutex<(String, SplitSink<WebSocketStream<TcpStream>, Message>)>>;
+pub type WsRead = SplitStream<WebSocketStream<TcpStream>>;
+
+/// Where the client presented its token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenSource {
+    Query,
+    Subprotocol,
+    Message,
+}
+
+/// How a channel treats the patches sent on it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChannelMode {
+    /// Yjs updates merged into the channel's document
+    #[default]
+    Document,
+    /// Opaque payloads relayed verbatim to the members
+    Relay,
+    /// Ciphertext relayed to the members and logged without being read
+    Encrypted,
+}
+
+impl ChannelMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChannelMode::Document => "document",
+            ChannelMode::Relay => "relay",
+            ChannelMode::Encrypted => "encrypted",
+        }
+    }
+}
+
+/// What the middlewares learn about a connection from its handshake
+#[derive(Debug, Clone, Default)]
+pub struct Handshake {
+    /// Connection URL with any token redacted, safe to log
+    pub url: String,
+    pub origin: Option<String>,
+    pub token: Option<(String, TokenSource)>,
+}
+
+/// Counts a connection and its channels in the active gauges for as long as
+/// its state lives, so they stay balanced however the connection ends
+#[derive(Debug, Default)]
+pub struct ActiveGauges {
+    connection: bool,
+    channels: i64,
+}
+
+impl ActiveGauges {
+    pub fn open_connection(&mut self) {
+        if !self.connection {
+            self.connection = true;
+            ACTIVE_CONNECTIONS.inc();
+        }
+    }
+
+    pub fn add_channel(&mut self) {
+        self.channels += 1;
+        ACTIVE_CHANNELS.inc();
+    }
+}
+
+impl Drop for ActiveGauges {
+    fn drop(&mut self) {
+        if self.connection {
+            ACTIVE_CONNECTIONS.dec();
+        }
+
+        ACTIVE_CHANNELS.sub(self.channels);
+    }
+}
 
 #[derive(Debug, Default)]
 pub struct ConnectionState {
@@ -23,6 +117,25 @@ pub struct ConnectionState {
     pub broadcast_task: Option<JoinHandle<()>>,
     pub channel_ids: std::collections::HashSet<String>,
     pub connection_id: Uuid,
+    pub protocol_version: ProtocolVersion,
+    /// Whether the client's last frame was binary, replies use the same mode
+    pub binary: bool,
+    pub update_encoding: UpdateEncoding,
+    pub resume_token: Option<String>,
+    /// Custom claims of the token, kept as the user's member metadata
+    pub custom: serde_json::Value,
+    /// Whether the app projects channel members into their documents
+    pub members_in_document: bool,
+    /// Channels named with one of these prefixes relay patches verbatim
+    pub relay_channel_prefixes: Vec<String>,
+    /// Whether relay channels keep their last patch for joiners
+    pub relay_keep_last: bool,
+    /// Whether clients may declare relay channels outside the relay prefixes
+    pub allow_client_relay: bool,
+    /// Mode of each joined channel
+    pub channel_modes: std::collections::HashMap<String, ChannelMode>,
+    /// Only counted for connections accepted by this node
+    pub gauges: ActiveGauges,
 }
 
 #[derive(Builder, Clone)]
@@ -37,6 +150,16 @@ pub struct WsConnection {
     message_handler: Arc<dyn MessageHandler>,
     #[builder(setter(custom))]
     session_handler: Arc<dyn SessionHandler>,
+    #[builder(setter(custom), default)]
+    rate_limiter: Option<Arc<dyn RateLimiter>>,
+    #[builder(setter(custom), default)]
+    origin_policy: Option<Arc<OriginPolicy>>,
+    /// Issues resume tokens to clients whose protocol version has sequence numbers
+    #[builder(setter(custom), default)]
+    resume_store: Option<Arc<ResumeStore>>,
+    /// How long a client without a handshake token has to send an Auth message
+    #[builder(default = "Duration::from_secs(10)")]
+    auth_timeout: Duration,
 }
 
 impl WsConnection {
@@ -45,11 +168,62 @@ impl WsConnection {
         stream: TcpStream,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let peer_addr = stream.peer_addr()?.to_string();
-        let mut url = String::new();
+        let mut handshake = Handshake::default();
+        let mut protocol_version = ProtocolVersion::default();
+        let mut update_encoding = UpdateEncoding::default();
         let state = Arc::new(Mutex::new(ConnectionState::default()));
 
-        // Use a custom handshake callback to extract the URL with query parameters
-        let callback = |req: &Request, response: Response| {
+        // Use a custom handshake callback to extract the URL with query parameters,
+        // its error type is set by tungstenite
+        #[allow(clippy::result_large_err)]
+        let callback = |req: &Request, mut response: Response| {
+            let offered = req
+                .headers()
+                .get(SEC_WEBSOCKET_PROTOCOL)
+                .and_then(|h| h.to_str().ok());
+
+            let subprotocol_token = token_from_subprotocols(offered);
+
+            let subprotocol_encoding = match encoding_from_subprotocols(offered) {
+                Ok(encoding) => encoding,
+                Err(e) => {
+                    warn!("Rejecting handshake from {peer_addr}: {e}");
+                    return Err(Self::reject_handshake(StatusCode::BAD_REQUEST, e));
+                }
+            };
+
+            // Refuse the upgrade rather than guess at a framing the client did not ask for
+            match ProtocolVersion::negotiate(offered) {
+                Ok(Some(version)) => {
+                    protocol_version = version;
+                    response.headers_mut().insert(
+                        SEC_WEBSOCKET_PROTOCOL,
+                        HeaderValue::from_static(version.subprotocol()),
+                    );
+                }
+                // Browsers fail the handshake when none of the offered
+                // subprotocols is selected, and the token and encoding must
+                // not be echoed
+                Ok(None) if subprotocol_token.is_some() || subprotocol_encoding.is_some() => {
+                    warn!(
+                        "Rejecting handshake from {peer_addr}: token or encoding offered without a version"
+                    );
+                    return Err(Self::reject_handshake(
+                        StatusCode::BAD_REQUEST,
+                        "A protocol version must be offered with the auth and encoding subprotocols",
+                    ));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Rejecting handshake from {peer_addr}: {e}");
+                    return Err(Self::reject_handshake(StatusCode::BAD_REQUEST, e));
+                }
+            }
+
+            if let Some(encoding) = subprotocol_encoding {
+                update_encoding = encoding;
+            }
+
             // Extract the full URL from the request
             let path = req
                 .uri()
@@ -65,8 +239,34 @@ impl WsConnection {
                 .unwrap_or(&peer_addr);
 
             // Build the full URL
-            url = format!("wss://{host}{path}");
-            info!("WebSocket connection URL: {url}");
+            let url = format!("wss://{host}{path}");
+
+            handshake.url = redact_token(&url);
+            handshake.token = handshake_token(offered, &url);
+
+            info!("WebSocket connection URL: {}", handshake.url);
+
+            handshake.origin = req
+                .headers()
+                .get(ORIGIN)
+                .and_then(|h| h.to_str().ok())
+                .map(str::to_string);
+
+            // The token is only verified by the auth middleware, a forged app
+            // id gets past this check but not past authentication
+            if let (Some(origin_policy), Some((token, _))) = (&self.origin_policy, &handshake.token)
+                && let Some(app_id) = peek_app_id(token)
+                && !origin_policy.is_allowed(&app_id, handshake.origin.as_deref())
+            {
+                warn!(
+                    "Rejecting handshake from {peer_addr}: origin {:?} not allowed for app {app_id}",
+                    handshake.origin
+                );
+                return Err(Self::reject_handshake(
+                    StatusCode::FORBIDDEN,
+                    "Origin not allowed",
+                ));
+            }
 
             // Return the response to continue with the handshake
             Ok(response)
@@ -74,21 +274,32 @@ impl WsConnection {
 
         let connection_id = Uuid::new_v4();
 
+        info!("New connection ID: {connection_id} from {peer_addr}");
+
+        // Accept the connection with the callback
+        let ws_stream = tokio_tungstenite::accept_hdr_async(stream, callback).await?;
+
         {
             let mut state = state.lock().await;
             state.connection_id = connection_id;
+            state.protocol_version = protocol_version;
+            state.update_encoding = update_encoding;
         }
 
-        info!("New connection ID: {connection_id} from {peer_addr}");
-
-        // Accept the connection with the callback
-        let ws_stream = tokio_tungstenite::accept_hdr_async(stream, callback).await?;
         let (write, mut read) = ws_stream.split();
         let write: WsWrite = Arc::new(Mutex::new((connection_id.to_string(), write)));
 
+        if handshake.token.is_none()
+            && let Some((token, binary)) = self.read_auth_message(&mut read, protocol_version).await
+        {
+            // The auth reply goes out in the mode the client authenticated in
+            state.lock().await.binary = binary;
+            handshake.token = Some((token, TokenSource::Message));
+        }
+
         // Run all middlewares in sequence
         for middleware in &self.middlewares {
-            middleware.run(&write, &url, state.clone()).await?;
+            middleware.run(&write, &handshake, state.clone()).await?;
         }
 
         let app_id: String;
@@ -100,66 +311,420 @@ impl WsConnection {
             user_id = state.user_id.clone();
         }
 
-        self.session_handler
+        // Errors are not Send, they must not be held across the awaits below
+        let rejected = match self
+            .session_handler
             .add(&app_id, &user_id, &connection_id)
+            .await
+        {
+            Ok(()) => None,
+            Err(e) => Some(
+                e.downcast::<QuotaExceeded>()
+                    .map(|quota| *quota)
+                    .map_err(|e| e.to_string()),
+            ),
+        };
+
+        if let Some(quota) = rejected {
+            if let Some(task) = state.lock().await.broadcast_task.take() {
+                task.abort();
+            }
+
+            let quota = quota?;
+            let binary = state.lock().await.binary;
+
+            warn!("Rejecting connection {connection_id}: {quota}");
+            Self::send_error(
+                &write,
+                protocol_version,
+                binary,
+                OutgoingMessage::error(quota.into(), quota),
+            )
             .await?;
+            Self::send_close(&write, CloseCode::Library(quota.close_code()), quota).await?;
+
+            return Ok(());
+        }
+
+        state.lock().await.gauges.open_connection();
+
+        // Issued on connect, so clients that only listen can resume too. It
+        // goes out in the mode the client authenticated in, text otherwise.
+        if protocol_version.has_sequence_numbers()
+            && let Err(e) = self.send_resume_token(&write, state.clone()).await
+        {
+            error!("Error issuing resume token for connection {connection_id}: {e}");
+        }
 
         // Handle messages after middleware processing
         while let Some(msg) = read.next().await {
-            match msg {
-                Ok(msg) => match msg {
-                    Message::Text(text) => {
-                        if text.is_empty() {
-                            continue;
-                        }
-
-                        match serde_json::from_str::<IncomingMessage>(&text) {
-                            Ok(message) => {
-                                self.message_handler
-                                    .handle(&write, message, state.clone())
-                                    .await?
-                            }
-                            Err(e) => {
-                                error!("Error parsing message: {e}");
-                            }
-                        }
-                    }
-                    Message::Binary(binary) => match parse_binary_message(&binary) {
-                        Ok(message) => {
-                            self.message_handler
-                                .handle(&write, message, state.clone())
-                                .await?
-                        }
-                        Err(e) => {
-                            error!("Error parsing binary message: {e}");
-                        }
-                    },
-                    _ => continue,
-                },
+            let msg = match msg {
+                Ok(msg) => msg,
                 Err(e) => {
                     error!("Error reading message: {e}");
                     break;
                 }
+            };
+
+            // Errors are reported in the encoding the client used
+            let (binary, message_size) = match &msg {
+                Message::Text(text) if !text.is_empty() => (false, text.len()),
+                Message::Binary(binary) => (true, binary.len()),
+                _ => continue,
+            };
+
+            match self
+                .check_rate_limit(
+                    &write,
+                    protocol_version,
+                    binary,
+                    &app_id,
+                    &user_id,
+                    &connection_id,
+                    message_size,
+                )
+                .await
+            {
+                RateLimitDecision::Allow => {}
+                RateLimitDecision::Reject(_) => continue,
+                RateLimitDecision::Close(_) => break,
+            }
+
+            state.lock().await.binary = binary;
+
+            let message = match msg {
+                Message::Text(text) => {
+                    serde_json::from_str::<IncomingMessage>(&text).map_err(|e| e.to_string())
+                }
+                Message::Binary(binary) => parse_binary_message(&binary, protocol_version),
+                _ => continue,
+            };
+
+            let message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    debug!("Error parsing message: {e}");
+
+                    let frame = OutgoingMessage::error(ErrorCode::InvalidMessage, e);
+
+                    if let Err(e) = Self::send_error(&write, protocol_version, binary, frame).await
+                    {
+                        error!("Error sending error frame: {e}");
+                        break;
+                    }
+
+                    continue;
+                }
+            };
+
+            let message_type = message.message_type();
+            MESSAGES_TOTAL
+                .with_label_values(&["in", message_type])
+                .inc();
+            MESSAGE_BYTES_TOTAL
+                .with_label_values(&["in", message_type])
+                .inc_by(message_size as u64);
+
+            let channel_id = message.channel_id().map(str::to_string);
+            let request_id = message.request_id().map(str::to_string);
+            let joins = matches!(
+                message,
+                IncomingMessage::Init { .. } | IncomingMessage::Resume { .. }
+            );
+
+            let Err(e) = self
+                .message_handler
+                .handle(&write, message, state.clone())
+                .await
+            else {
+                // Recorded so the channel can be cleaned up if this node dies
+                if let (true, Some(channel_id)) = (joins, &channel_id)
+                    && let Err(e) = self.session_handler.join(&connection_id, channel_id).await
+                {
+                    error!("Error registering channel {channel_id}: {e}");
+                }
+
+                continue;
+            };
+
+            // Client errors only fail the request, anything else closes the connection
+            let (frame, close) = match e.downcast::<ClientError>() {
+                Ok(client_error) => {
+                    debug!("Rejected message on connection {connection_id}: {client_error}");
+
+                    let frame = OutgoingMessage::Error {
+                        code: client_error.code,
+                        message: client_error.message,
+                        channel_id: client_error.channel_id.or(channel_id),
+                        request_id,
+                    };
+
+                    (frame, None)
+                }
+                Err(e) => match e.downcast::<QuotaExceeded>() {
+                    Ok(quota) => {
+                        warn!("Closing connection {connection_id}: {quota}");
+
+                        let frame = OutgoingMessage::Error {
+                            code: (*quota).into(),
+                            message: quota.to_string(),
+                            channel_id,
+                            request_id,
+                        };
+
+                        (
+                            frame,
+                            Some((CloseCode::Library(quota.close_code()), quota.to_string())),
+                        )
+                    }
+                    Err(e) => {
+                        error!("Error handling message: {e}");
+
+                        let frame = OutgoingMessage::Error {
+                            code: ErrorCode::Internal,
+                            message: "Internal error".to_string(),
+                            channel_id,
+                            request_id,
+                        };
+
+                        (
+                            frame,
+                            Some((CloseCode::Error, "Internal error".to_string())),
+                        )
+                    }
+                },
+            };
+
+            // Leave the loop on failure so the session is still cleaned up
+            if let Err(e) = Self::send_error(&write, protocol_version, binary, frame).await {
+                error!("Error sending error frame: {e}");
+                break;
+            }
+
+            if let Some((code, reason)) = close {
+                if let Err(e) = Self::send_close(&write, code, reason).await {
+                    error!("Error sending close frame: {e}");
+                }
+
+                break;
             }
         }
 
+        // Cleanup runs to the end, a failing step only skips what depends on it
+        self.suspend_resume_token(state.clone()).await;
+
         // Abort the broadcast task when the connection is closed
         if let Some(task) = state.lock().await.broadcast_task.take() {
             debug!("Aborting broadcast task");
             task.abort();
         }
 
-        self.session_handler
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.release(&connection_id).await;
+        }
+
+        if let Err(e) = self
+            .session_handler
             .remove(&app_id, &user_id, &connection_id)
-            .await?;
+            .await
+        {
+            error!("Error removing connection {connection_id} from its session: {e}");
+        }
 
-        if self.session_handler.count(&app_id, &user_id).await? == 0 {
-            self.message_handler.on_close(state.clone()).await?;
+        let remaining = match self.session_handler.count(&app_id, &user_id).await {
+            Ok(remaining) => remaining,
+            Err(e) => {
+                error!("Error counting connections of user {user_id}: {e}");
+                return Ok(());
+            }
+        };
+
+        if remaining == 0
+            && let Err(e) = self.message_handler.on_close(state.clone()).await
+        {
+            error!("Error closing connection {connection_id}: {e}");
         }
 
         Ok(())
     }
 
+    /// Consult the rate limiter for an incoming message, notifying the client
+    /// with an error frame on rejection and a close frame on repeated violations.
+    /// A failing limiter lets the message through, a failed notification closes
+    /// the connection so it is still cleaned up.
+    #[allow(clippy::too_many_arguments)]
+    async fn check_rate_limit(
+        &self,
+        write: &WsWrite,
+        protocol_version: ProtocolVersion,
+        binary: bool,
+        app_id: &str,
+        user_id: &str,
+        connection_id: &Uuid,
+        message_size: usize,
+    ) -> RateLimitDecision {
+        let Some(rate_limiter) = &self.rate_limiter else {
+            return RateLimitDecision::Allow;
+        };
+
+        let decision = match rate_limiter
+            .check(app_id, user_id, connection_id, message_size)
+            .await
+        {
+            Ok(decision) => decision,
+            Err(e) => {
+                error!("Error checking rate limit for connection {connection_id}: {e}");
+                return RateLimitDecision::Allow;
+            }
+        };
+
+        match decision {
+            RateLimitDecision::Allow => decision,
+            RateLimitDecision::Reject(scope) => {
+                let frame = OutgoingMessage::error(
+                    ErrorCode::RateLimited,
+                    format!("Rate limit exceeded for {}", scope.as_str()),
+                );
+
+                match Self::send_error(write, protocol_version, binary, frame).await {
+                    Ok(()) => decision,
+                    Err(e) => {
+                        error!("Error sending error frame: {e}");
+                        RateLimitDecision::Close(scope)
+                    }
+                }
+            }
+            RateLimitDecision::Close(_) => {
+                if let Err(e) =
+                    Self::send_close(write, CloseCode::Policy, "Rate limit exceeded").await
+                {
+                    error!("Error sending close frame: {e}");
+                }
+
+                decision
+            }
+        }
+    }
+
+    /// Issue a resume token for the connection and send it to the client in
+    /// the frame mode it uses
+    async fn send_resume_token(
+        &self,
+        write: &WsWrite,
+        state: Arc<Mutex<ConnectionState>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(resume_store) = &self.resume_store else {
+            return Ok(());
+        };
+
+        let mut state = state.lock().await;
+        let token = resume_store.issue(&state.app_id, &state.user_id).await?;
+        let message = OutgoingMessage::ResumeToken {
+            token: token.clone(),
+        }
+        .encode(state.protocol_version, state.binary)?;
+
+        write.lock().await.1.send(message).await?;
+        state.resume_token = Some(token);
+
+        Ok(())
+    }
+
+    /// Let the connection's resume token outlive it for the resume window
+    async fn suspend_resume_token(&self, state: Arc<Mutex<ConnectionState>>) {
+        let Some(resume_store) = &self.resume_store else {
+            return;
+        };
+
+        let Some(token) = state.lock().await.resume_token.clone() else {
+            return;
+        };
+
+        if let Err(e) = resume_store.suspend(&token).await {
+            error!("Error suspending resume token: {e}");
+        }
+    }
+
+    /// Send an error frame, as binary if the client speaks the binary protocol
+    /// and its version has binary error frames
+    async fn send_error(
+        write: &WsWrite,
+        protocol_version: ProtocolVersion,
+        binary: bool,
+        frame: OutgoingMessage,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let message = frame.encode(protocol_version, binary)?;
+
+        write.lock().await.1.send(message).await?;
+
+        Ok(())
+    }
+
+    /// Wait for an Auth message from a client that did not send a token with
+    /// the handshake. Anything else, or nothing within `auth_timeout`, yields
+    /// no token and lets the auth middleware reject the connection. The token
+    /// comes with whether the message was a binary frame.
+    async fn read_auth_message(
+        &self,
+        read: &mut WsRead,
+        protocol_version: ProtocolVersion,
+    ) -> Option<(String, bool)> {
+        let first_message = async {
+            while let Some(Ok(msg)) = read.next().await {
+                match msg {
+                    Message::Text(text) => {
+                        return serde_json::from_str::<IncomingMessage>(&text)
+                            .ok()
+                            .map(|message| (message, false));
+                    }
+                    Message::Binary(binary) => {
+                        return parse_binary_message(&binary, protocol_version)
+                            .ok()
+                            .map(|message| (message, true));
+                    }
+                    _ => {}
+                }
+            }
+
+            None
+        };
+
+        match timeout(self.auth_timeout, first_message).await {
+            Ok(Some((IncomingMessage::Auth { token }, binary))) => Some((token, binary)),
+            Ok(_) => None,
+            Err(_) => {
+                debug!("Timed out waiting for an auth message");
+                None
+            }
+        }
+    }
+
+    fn reject_handshake(status: StatusCode, reason: impl ToString) -> ErrorResponse {
+        let mut response = ErrorResponse::new(Some(reason.to_string()));
+        *response.status_mut() = status;
+        response
+    }
+
+    async fn send_close(
+        write: &WsWrite,
+        code: CloseCode,
+        reason: impl ToString,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let close_frame = CloseFrame {
+            code,
+            reason: reason.to_string().into(),
+        };
+
+        write
+            .lock()
+            .await
+            .1
+            .send(Message::Close(Some(close_frame)))
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
         let listener = TcpListener::bind(format!("0.0.0.0:{}", self.port)).await?;
 
@@ -186,6 +751,47 @@ impl WsConnection {
     }
 }
 
+/// The token offered in the handshake, from an auth subprotocol or else the
+/// `token` query parameter
+fn handshake_token(offered: Option<&str>, url: &str) -> Option<(String, TokenSource)> {
+    let query_token = Url::parse(url).ok().and_then(|url| {
+        url.query_pairs()
+            .find(|(key, _)| key == "token")
+            .map(|(_, value)| value.to_string())
+    });
+
+    token_from_subprotocols(offered)
+        .map(|token| (token, TokenSource::Subprotocol))
+        .or(query_token.map(|token| (token, TokenSource::Query)))
+}
+
+/// Replace the value of a `token` query parameter so the URL can be logged
+fn redact_token(url: &str) -> String {
+    let Ok(mut parsed) = Url::parse(url) else {
+        return url.to_string();
+    };
+
+    if !parsed.query_pairs().any(|(key, _)| key == "token") {
+        return url.to_string();
+    }
+
+    let pairs = parsed
+        .query_pairs()
+        .map(|(key, value)| {
+            let value = if key == "token" {
+                "REDACTED".to_string()
+            } else {
+                value.into_owned()
+            };
+
+            (key.into_owned(), value)
+        })
+        .collect::<Vec<_>>();
+
+    parsed.query_pairs_mut().clear().extend_pairs(pairs);
+    parsed.to_string()
+}
+
 impl WsConnectionBuilder {
     pub fn middleware<T: Middleware + 'static>(&mut self, middleware: T) -> &mut Self {
         let middleware = Arc::new(middleware);
@@ -211,6 +817,21 @@ impl WsConnectionBuilder {
         self.session_handler = Some(session_handler);
         self
     }
+
+    pub fn rate_limiter(&mut self, rate_limiter: Arc<dyn RateLimiter>) -> &mut Self {
+        self.rate_limiter = Some(Some(rate_limiter));
+        self
+    }
+
+    pub fn origin_policy(&mut self, origin_policy: Arc<OriginPolicy>) -> &mut Self {
+        self.origin_policy = Some(Some(origin_policy));
+        self
+    }
+
+    pub fn resume_store(&mut self, resume_store: Arc<ResumeStore>) -> &mut Self {
+        self.resume_store = Some(Some(resume_store));
+        self
+    }
 }
 
 #[async_trait]
@@ -218,7 +839,7 @@ pub trait Middleware: Send + Sync {
     async fn run(
         &self,
         write: &WsWrite,
-        url: &str,
+        handshake: &Handshake,
         state: Arc<Mutex<ConnectionState>>,
     ) -> Result<(), Box<dyn std::error::Error>>;
 }
@@ -245,6 +866,11 @@ pub trait SessionHandler: Send + Sync {
         user_id: &str,
         connection_id: &Uuid,
     ) -> Result<(), Box<dyn std::error::Error>>;
+    async fn join(
+        &self,
+        connection_id: &Uuid,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>>;
     async fn remove(
         &self,
         app_id: &str,
@@ -254,3 +880,60 @@ pub trait SessionHandler: Send + Sync {
     async fn count(&self, app_id: &str, user_id: &str)
     -> Result<usize, Box<dyn std::error::Error>>;
 }
+
+#[async_trait]
+pub trait RateLimiter: Send + Sync {
+    async fn check(
+        &self,
+        app_id: &str,
+        user_id: &str,
+        connection_id: &Uuid,
+        message_size: usize,
+    ) -> Result<RateLimitDecision, Box<dyn std::error::Error>>;
+    async fn release(&self, connection_id: &Uuid);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_token_hides_the_token_and_keeps_other_parameters() {
+        let redacted = redact_token("wss://ws.example.com/?app=demo&token=secret.jwt.value&v=2");
+
+        assert!(!redacted.contains("secret.jwt.value"));
+        assert_eq!(
+            redacted,
+            "wss://ws.example.com/?app=demo&token=REDACTED&v=2"
+        );
+    }
+
+    #[test]
+    fn redact_token_leaves_urls_without_a_token_alone() {
+        let url = "wss://ws.example.com/channels?app=demo";
+
+        assert_eq!(redact_token(url), url);
+    }
+
+    #[test]
+    fn handshake_token_prefers_the_subprotocol() {
+        let offered = "shallabuf.v2, shallabuf.auth.from-protocol";
+
+        assert_eq!(
+            handshake_token(Some(offered), "wss://ws.example.com/?token=from-query"),
+            Some(("from-protocol".to_string(), TokenSource::Subprotocol))
+        );
+    }
+
+    #[test]
+    fn handshake_token_falls_back_to_the_query() {
+        assert_eq!(
+            handshake_token(
+                Some("shallabuf.v2"),
+                "wss://ws.example.com/?token=from-query"
+            ),
+            Some(("from-query".to_string(), TokenSource::Query))
+        );
+        assert_eq!(handshake_token(None, "wss://ws.example.com/"), None);
+    }
+}
@@ -1,9 +1,16 @@
 use async_trait::async_trait;
-use serde_json::Value as JsonValue;
+use base64::Engine as _;
+use serde_json::{Value as JsonValue, json};
 use tracing::error;
-use yrs::{Any, AsyncTransact, Map, MapPrelim, Update};
-use yrs::{In, Out, updates::decoder::Decode};
+use yrs::encoding::read::Error as DecodeError;
+use yrs::{Any, AsyncTransact, Map, MapPrelim, MapRef, TransactionMut, Update};
+use yrs::{Array, ArrayPrelim, ArrayRef, GetString, TextPrelim};
+use yrs::{In, Out, updates::decoder::Decode, updates::encoder::Encode};
 use yrs::{ReadTxn, StateVector};
+use yrs::{
+    Text, Xml, XmlElementPrelim, XmlElementRef, XmlFragment, XmlFragmentPrelim, XmlOut,
+    XmlTextPrelim, XmlTextRef,
+};
 
 #[async_trait]
 pub trait Crdt: Send + Sync {
@@ -13,7 +20,25 @@ pub trait Crdt: Send + Sync {
     async fn to_update(&self, prev_state_vector: &StateVector) -> Vec<u8>;
     async fn get_state_as_update(&self) -> Vec<u8>;
     async fn apply_delta(&mut self, delta: &[u8]) -> Result<(), Box<dyn std::error::Error>>;
-    async fn insert_value(&mut self, path: &[&str], value: JsonValue);
+    /// Insert plain JSON, without shared type declarations
+    async fn insert_value(
+        &mut self,
+        path: &[&str],
+        value: JsonValue,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.insert(path, CrdtValue::from(value)).await
+    }
+    /// Insert a value that may contain shared types such as collaborative text
+    async fn insert(
+        &mut self,
+        path: &[&str],
+        value: CrdtValue,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    /// The whole document as JSON, in the shape `CrdtValue::from_schema` reads
+    async fn to_json(&self) -> JsonValue;
+    /// The value at `path` as JSON, `None` if there is nothing there. Numeric
+    /// segments index into arrays and the children of XML nodes.
+    async fn get_value(&self, path: &[&str]) -> Option<JsonValue>;
     async fn get_members(&self) -> Vec<String>;
     async fn remove_member(&mut self, member: &str);
 }
@@ -22,53 +47,466 @@ pub struct CrdtDocument {
     doc: yrs::Doc,
 }
 
-enum CrdtValue {
-    Map(MapPrelim),
+/// Binary encoding of Yjs updates. Documents are stored and merged as v2,
+/// clients such as y-websocket that speak v1 are transcoded at the edge.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UpdateEncoding {
+    V1,
+    #[default]
+    V2,
+}
+
+impl UpdateEncoding {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "v1" => Some(UpdateEncoding::V1),
+            "v2" => Some(UpdateEncoding::V2),
+            _ => None,
+        }
+    }
+
+    /// Re-encode an update sent by a client as v2
+    pub fn canonicalize(self, update: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        match self {
+            UpdateEncoding::V1 => Ok(Update::decode_v1(update)?.encode_v2()),
+            UpdateEncoding::V2 => Ok(update.to_vec()),
+        }
+    }
+
+    /// Re-encode a v2 update for a client
+    pub fn transcode(self, update: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        match self {
+            UpdateEncoding::V1 => Ok(Update::decode_v2(update)?.encode_v1()),
+            UpdateEncoding::V2 => Ok(update.to_vec()),
+        }
+    }
+}
+
+// Key marking a JSON object as a shared type declaration, e.g.
+// `{"$type": "text", "value": "Hello"}`
+const SCHEMA_TYPE_KEY: &str = "$type";
+
+// Prefix escaping keys that would read as `SCHEMA_TYPE_KEY`, `$$type` is the
+// literal key `$type`, `$$$type` the literal key `$$type` and so on
+const SCHEMA_ESCAPE: char = '$';
+
+/// A value to insert into a document. `Map`, `Array`, `Text` and
+/// `XmlFragment` become shared types that clients edit collaboratively, the
+/// others are plain values.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CrdtValue {
+    Map(Vec<(String, CrdtValue)>),
     Array(Vec<CrdtValue>),
+    /// Collaborative text (`Y.Text`)
+    Text(String),
+    /// Rich text as edited by ProseMirror or TipTap (`Y.XmlFragment`)
+    XmlFragment(Vec<XmlNode>),
     String(String),
     Number(f64),
     Bool(bool),
     Null,
 }
 
-impl From<CrdtValue> for In {
-    fn from(value: CrdtValue) -> Self {
-        match value {
-            CrdtValue::Map(map) => In::Map(map),
-            CrdtValue::Array(items) => In::Array(items.into_iter().map(In::from).collect()),
-            CrdtValue::String(s) => In::Any(Any::String(s.into())),
-            CrdtValue::Number(n) => In::Any(Any::Number(n)),
-            CrdtValue::Bool(b) => In::Any(Any::Bool(b)),
-            CrdtValue::Null => In::Any(Any::Null),
-        }
-    }
+#[derive(Debug, Clone, PartialEq)]
+pub enum XmlNode {
+    Element {
+        tag: String,
+        attributes: Vec<(String, String)>,
+        children: Vec<XmlNode>,
+    },
+    Text(String),
 }
 
-impl CrdtDocument {
-    fn json_to_yrs(value: JsonValue) -> CrdtValue {
+impl From<JsonValue> for CrdtValue {
+    fn from(value: JsonValue) -> Self {
         match value {
             JsonValue::Null => CrdtValue::Null,
             JsonValue::Bool(b) => CrdtValue::Bool(b),
             JsonValue::Number(n) => CrdtValue::Number(n.as_f64().unwrap()),
             JsonValue::String(s) => CrdtValue::String(s),
-            JsonValue::Array(arr) => {
-                let mut items = Vec::with_capacity(arr.len());
+            JsonValue::Array(arr) => CrdtValue::Array(arr.into_iter().map(Self::from).collect()),
+            JsonValue::Object(obj) => CrdtValue::Map(
+                obj.into_iter()
+                    .map(|(key, value)| (key, Self::from(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl CrdtValue {
+    /// Read JSON in which objects with a `$type` key declare shared types:
+    ///
+    /// - `{"$type": "text", "value": "..."}`
+    /// - `{"$type": "array", "items": [...]}`
+    /// - `{"$type": "map", "entries": {...}}`, for maps with a `$type` key
+    /// - `{"$type": "xmlFragment", "children": [...]}`, where children are
+    ///   `{"$type": "xmlElement", "tag": "...", "attributes": {...}, "children": [...]}`,
+    ///   `{"$type": "xmlText", "value": "..."}` or plain strings
+    ///
+    /// Everything else is read as with `From<JsonValue>`, except that arrays
+    /// and objects may nest declarations. Objects holding data under a
+    /// literal `$type` key, which were stored as is before declarations, now
+    /// write it `$$type` or wrap themselves in a `map` declaration.
+    pub fn from_schema(value: JsonValue) -> Result<Self, String> {
+        match value {
+            JsonValue::Array(arr) => Ok(CrdtValue::Array(
+                arr.into_iter()
+                    .map(Self::from_schema)
+                    .collect::<Result<_, _>>()?,
+            )),
+            JsonValue::Object(mut obj) => {
+                let Some(schema_type) = obj.remove(SCHEMA_TYPE_KEY) else {
+                    return Self::entries_from_schema(obj);
+                };
+
+                match schema_type.as_str() {
+                    Some("text") => Ok(CrdtValue::Text(string_field(&mut obj, "value")?)),
+                    Some("array") => Self::from_schema(array_field(&mut obj, "items")?),
+                    Some("map") => match obj.remove("entries") {
+                        Some(JsonValue::Object(entries)) => Self::entries_from_schema(entries),
+                        None => Ok(CrdtValue::Map(vec![])),
+                        Some(_) => Err("\"entries\" must be an object".to_string()),
+                    },
+                    Some("xmlFragment") => Ok(CrdtValue::XmlFragment(
+                        XmlNode::children_from_schema(array_field(&mut obj, "children")?)?,
+                    )),
+                    _ => Err(format!("Unknown {SCHEMA_TYPE_KEY}: {schema_type}")),
+                }
+            }
+            value => Ok(Self::from(value)),
+        }
+    }
+
+    fn entries_from_schema(obj: serde_json::Map<String, JsonValue>) -> Result<Self, String> {
+        Ok(CrdtValue::Map(
+            obj.into_iter()
+                .map(|(key, value)| Ok((unescape_key(key), Self::from_schema(value)?)))
+                .collect::<Result<_, String>>()?,
+        ))
+    }
+
+    /// The value as stored inside a shared type, which only plain values are
+    fn into_any(self) -> Result<Any, String> {
+        match self {
+            CrdtValue::String(s) => Ok(Any::String(s.into())),
+            CrdtValue::Number(n) => Ok(Any::Number(n)),
+            CrdtValue::Bool(b) => Ok(Any::Bool(b)),
+            CrdtValue::Null => Ok(Any::Null),
+            value => Err(format!("{value:?} is a shared type, not a plain value")),
+        }
+    }
+}
+
+impl XmlNode {
+    fn children_from_schema(children: JsonValue) -> Result<Vec<Self>, String> {
+        let JsonValue::Array(children) = children else {
+            return Err("\"children\" must be an array".to_string());
+        };
+
+        children.into_iter().map(Self::from_schema).collect()
+    }
+
+    fn from_schema(value: JsonValue) -> Result<Self, String> {
+        let mut obj = match value {
+            JsonValue::String(text) => return Ok(XmlNode::Text(text)),
+            JsonValue::Object(obj) => obj,
+            value => return Err(format!("Invalid XML node: {value}")),
+        };
+
+        match obj
+            .remove(SCHEMA_TYPE_KEY)
+            .as_ref()
+            .and_then(JsonValue::as_str)
+        {
+            Some("xmlText") => Ok(XmlNode::Text(string_field(&mut obj, "value")?)),
+            Some("xmlElement") => {
+                let JsonValue::String(tag) = obj.remove("tag").unwrap_or_default() else {
+                    return Err("XML elements need a \"tag\"".to_string());
+                };
+
+                let attributes = match obj.remove("attributes") {
+                    Some(JsonValue::Object(attributes)) => attributes
+                        .into_iter()
+                        .map(|(name, value)| match value {
+                            JsonValue::String(value) => (name, value),
+                            value => (name, value.to_string()),
+                        })
+                        .collect(),
+                    None => vec![],
+                    Some(_) => return Err("\"attributes\" must be an object".to_string()),
+                };
+
+                Ok(XmlNode::Element {
+                    tag,
+                    attributes,
+                    children: Self::children_from_schema(array_field(&mut obj, "children")?)?,
+                })
+            }
+            _ => Err(format!(
+                "XML nodes need a {SCHEMA_TYPE_KEY} of xmlElement or xmlText"
+            )),
+        }
+    }
+}
+
+// Whether the key is the schema type key behind any number of escapes
+fn is_type_key(key: &str) -> bool {
+    key.strip_suffix(SCHEMA_TYPE_KEY)
+        .is_some_and(|escapes| escapes.chars().all(|c| c == SCHEMA_ESCAPE))
+}
+
+// Drop the escape of a key that reads as the schema type key without it
+fn unescape_key(key: String) -> String {
+    match key.strip_prefix(SCHEMA_ESCAPE) {
+        Some(unescaped) if is_type_key(unescaped) => unescaped.to_string(),
+        _ => key,
+    }
+}
+
+// Escape a key that would read as the schema type key, the inverse of
+// `unescape_key`
+fn escape_key(key: &str) -> String {
+    if is_type_key(key) {
+        format!("{SCHEMA_ESCAPE}{key}")
+    } else {
+        key.to_string()
+    }
+}
+
+fn string_field(
+    obj: &mut serde_json::Map<String, JsonValue>,
+    name: &str,
+) -> Result<String, String> {
+    match obj.remove(name) {
+        Some(JsonValue::String(value)) => Ok(value),
+        None => Ok(String::new()),
+        Some(_) => Err(format!("\"{name}\" must be a string")),
+    }
+}
+
+fn array_field(
+    obj: &mut serde_json::Map<String, JsonValue>,
+    name: &str,
+) -> Result<JsonValue, String> {
+    match obj.remove(name) {
+        Some(value @ JsonValue::Array(_)) => Ok(value),
+        None => Ok(JsonValue::Array(vec![])),
+        Some(_) => Err(format!("\"{name}\" must be an array")),
+    }
+}
+
+impl CrdtDocument {
+    /// Shared types are read in the shape `CrdtValue::from_schema` reads, so
+    /// the JSON can be inserted back as is: text as a `text` declaration, XML
+    /// as `xmlFragment`, `xmlElement` and `xmlText` declarations, maps as
+    /// objects with escaped `$type` keys and arrays as arrays. Formatting of
+    /// text is dropped.
+    fn out_to_json<T: ReadTxn>(txn: &T, value: &Out) -> JsonValue {
+        match value {
+            Out::Any(any) => Self::any_to_json(any),
+            Out::YText(text) => json!({
+                SCHEMA_TYPE_KEY: "text",
+                "value": text.get_string(txn),
+            }),
+            Out::YArray(array) => JsonValue::Array(
+                array
+                    .iter(txn)
+                    .map(|item| Self::out_to_json(txn, &item))
+                    .collect(),
+            ),
+            Out::YMap(map) => JsonValue::Object(
+                map.iter(txn)
+                    .map(|(key, value)| (escape_key(key), Self::out_to_json(txn, &value)))
+                    .collect(),
+            ),
+            Out::YXmlFragment(fragment) => json!({
+                SCHEMA_TYPE_KEY: "xmlFragment",
+                "children": Self::xml_children_to_json(txn, fragment),
+            }),
+            Out::YXmlElement(element) => Self::xml_element_to_json(txn, element),
+            Out::YXmlText(text) => Self::xml_text_to_json(txn, text),
+            _ => JsonValue::Null,
+        }
+    }
+
+    fn any_to_json(any: &Any) -> JsonValue {
+        match any {
+            Any::Null | Any::Undefined => JsonValue::Null,
+            Any::Bool(b) => JsonValue::Bool(*b),
+            // Yjs stores every number as a float
+            Any::Number(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => {
+                JsonValue::from(*n as i64)
+            }
+            Any::Number(n) => serde_json::Number::from_f64(*n)
+                .map(JsonValue::Number)
+                .unwrap_or(JsonValue::Null),
+            Any::BigInt(n) => JsonValue::from(*n),
+            Any::String(s) => JsonValue::String(s.to_string()),
+            Any::Buffer(bytes) => {
+                JsonValue::String(base64::engine::general_purpose::STANDARD.encode(bytes))
+            }
+            Any::Array(items) => JsonValue::Array(items.iter().map(Self::any_to_json).collect()),
+            Any::Map(entries) => JsonValue::Object(
+                entries
+                    .iter()
+                    .map(|(key, value)| (escape_key(key), Self::any_to_json(value)))
+                    .collect(),
+            ),
+        }
+    }
+
+    // Nested fragments, which Yjs editors do not write, are flattened into
+    // their parent as elements cannot hold them
+    fn xml_children_to_json<T: ReadTxn, F: XmlFragment>(txn: &T, parent: &F) -> Vec<JsonValue> {
+        parent
+            .children(txn)
+            .flat_map(|child| match child {
+                XmlOut::Element(element) => vec![Self::xml_element_to_json(txn, &element)],
+                XmlOut::Fragment(fragment) => Self::xml_children_to_json(txn, &fragment),
+                XmlOut::Text(text) => vec![Self::xml_text_to_json(txn, &text)],
+            })
+            .collect()
+    }
+
+    fn xml_element_to_json<T: ReadTxn>(txn: &T, element: &XmlElementRef) -> JsonValue {
+        let attributes = element
+            .attributes(txn)
+            .map(|(name, value)| (name.to_string(), JsonValue::String(value)))
+            .collect::<serde_json::Map<_, _>>();
+
+        json!({
+            SCHEMA_TYPE_KEY: "xmlElement",
+            "tag": element.tag().to_string(),
+            "attributes": attributes,
+            "children": Self::xml_children_to_json(txn, element),
+        })
+    }
 
-                for item in arr {
-                    items.push(Self::json_to_yrs(item));
+    // The text without the markup of its formatting
+    fn xml_text_to_json<T: ReadTxn>(txn: &T, text: &XmlTextRef) -> JsonValue {
+        let value = text
+            .diff(txn, |_| ())
+            .into_iter()
+            .filter_map(|chunk| match chunk.insert {
+                Out::Any(Any::String(s)) => Some(s.to_string()),
+                _ => None,
+            })
+            .collect::<String>();
+
+        json!({
+            SCHEMA_TYPE_KEY: "xmlText",
+            "value": value,
+        })
+    }
+
+    // The plain value at `path` inside a value stored as is
+    fn any_at<'a>(mut any: &'a Any, path: &[&str]) -> Option<&'a Any> {
+        for key in path {
+            any = match any {
+                Any::Map(entries) => entries.get(*key)?,
+                Any::Array(items) => items.get(key.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+
+        Some(any)
+    }
+
+    fn insert_into_map(
+        txn: &mut TransactionMut,
+        map: &MapRef,
+        key: &str,
+        value: CrdtValue,
+    ) -> Result<(), String> {
+        match value {
+            CrdtValue::Map(entries) => {
+                let child = map.insert(txn, key, MapPrelim::default());
+
+                for (key, value) in entries {
+                    Self::insert_into_map(txn, &child, &key, value)?;
+                }
+            }
+            CrdtValue::Array(items) => {
+                let child = map.insert(txn, key, ArrayPrelim::default());
+
+                for item in items {
+                    Self::push_into_array(txn, &child, item)?;
                 }
+            }
+            CrdtValue::Text(text) => {
+                map.insert(txn, key, TextPrelim::new(text));
+            }
+            CrdtValue::XmlFragment(children) => {
+                let fragment = map.insert(txn, key, XmlFragmentPrelim::default());
+                Self::push_xml_children(txn, &fragment, children);
+            }
+            value => {
+                map.insert(txn, key, In::Any(value.into_any()?));
+            }
+        }
+
+        Ok(())
+    }
 
-                CrdtValue::Array(items)
+    fn push_into_array(
+        txn: &mut TransactionMut,
+        array: &ArrayRef,
+        value: CrdtValue,
+    ) -> Result<(), String> {
+        match value {
+            CrdtValue::Map(entries) => {
+                let child = array.push_back(txn, MapPrelim::default());
+
+                for (key, value) in entries {
+                    Self::insert_into_map(txn, &child, &key, value)?;
+                }
             }
-            JsonValue::Object(obj) => {
-                let mut map = MapPrelim::default();
+            CrdtValue::Array(items) => {
+                let child = array.push_back(txn, ArrayPrelim::default());
 
-                for (k, v) in obj {
-                    let any = Self::json_to_yrs(v);
-                    map.insert(k.into(), any.into());
+                for item in items {
+                    Self::push_into_array(txn, &child, item)?;
                 }
+            }
+            CrdtValue::Text(text) => {
+                array.push_back(txn, TextPrelim::new(text));
+            }
+            CrdtValue::XmlFragment(children) => {
+                let fragment = array.push_back(txn, XmlFragmentPrelim::default());
+                Self::push_xml_children(txn, &fragment, children);
+            }
+            value => {
+                array.push_back(txn, In::Any(value.into_any()?));
+            }
+        }
+
+        Ok(())
+    }
 
-                CrdtValue::Map(map)
+    fn push_xml_children<F: XmlFragment>(
+        txn: &mut TransactionMut,
+        parent: &F,
+        children: Vec<XmlNode>,
+    ) {
+        for child in children {
+            match child {
+                XmlNode::Element {
+                    tag,
+                    attributes,
+                    children,
+                } => {
+                    let element = parent.push_back(txn, XmlElementPrelim::empty(tag));
+
+                    for (name, value) in attributes {
+                        element.insert_attribute(txn, name, value);
+                    }
+
+                    Self::push_xml_children(txn, &element, children);
+                }
+                XmlNode::Text(text) => {
+                    parent.push_back(txn, XmlTextPrelim::new(text));
+                }
             }
         }
     }
@@ -131,7 +569,11 @@ impl Crdt for CrdtDocument {
         }
     }
 
-    async fn insert_value(&mut self, path: &[&str], value: JsonValue) {
+    async fn insert(
+        &mut self,
+        path: &[&str],
+        value: CrdtValue,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         let mut map = self.doc.get_or_insert_map("root");
         let mut txn = self.doc.transact_mut().await;
 
@@ -140,11 +582,41 @@ impl Crdt for CrdtDocument {
         }
 
         if let Some(last_key) = path.last() {
-            let crdt_value = Self::json_to_yrs(value);
-            map.insert(&mut txn, last_key.to_string(), In::from(crdt_value));
+            Self::insert_into_map(&mut txn, &map, last_key, value)?;
         }
 
         txn.commit();
+
+        Ok(())
+    }
+
+    async fn to_json(&self) -> JsonValue {
+        let txn = self.doc.transact().await;
+
+        match txn.get_map("root") {
+            Some(root) => Self::out_to_json(&txn, &Out::YMap(root)),
+            None => json!({}),
+        }
+    }
+
+    async fn get_value(&self, path: &[&str]) -> Option<JsonValue> {
+        let txn = self.doc.transact().await;
+        let mut value = Out::YMap(txn.get_map("root")?);
+
+        for (depth, key) in path.iter().enumerate() {
+            value = match value {
+                Out::YMap(map) => map.get(&txn, key)?,
+                Out::YArray(array) => array.get(&txn, key.parse().ok()?)?,
+                Out::YXmlFragment(fragment) => fragment.get(&txn, key.parse().ok()?)?.into(),
+                Out::YXmlElement(element) => element.get(&txn, key.parse().ok()?)?.into(),
+                Out::Any(any) => {
+                    return Self::any_at(&any, &path[depth..]).map(Self::any_to_json);
+                }
+                _ => return None,
+            };
+        }
+
+        Some(Self::out_to_json(&txn, &value))
     }
 
     async fn get_members(&self) -> Vec<String> {
@@ -181,3 +653,332 @@ impl Crdt for CrdtDocument {
         txn.commit();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Crdt, CrdtDocument, CrdtValue, UpdateEncoding};
+    use serde_json::json;
+    use yrs::updates::{decoder::Decode, encoder::Encode};
+    use yrs::{
+        Any, Array, ArrayPrelim, Doc, GetString, Map, MapPrelim, MapRef, Out, ReadTxn, StateVector,
+        TextPrelim, Transact, Update, Xml, XmlElementPrelim, XmlFragment, XmlFragmentPrelim,
+        XmlTextPrelim,
+    };
+
+    fn schema() -> serde_json::Value {
+        json!({
+            "title": {"$type": "text", "value": "Hello"},
+            "tags": {"$type": "array", "items": ["a", {"$type": "text", "value": "b"}]},
+            "meta": {"$type": "map", "entries": {"$type": "data", "count": 2}},
+            "body": {"$type": "xmlFragment", "children": [
+                {"$type": "xmlElement", "tag": "p", "attributes": {"class": "lead"}, "children": [
+                    {"$type": "xmlText", "value": "Hi"}
+                ]}
+            ]},
+            "done": false
+        })
+    }
+
+    // The document `schema` declares, built with yrs as a Yjs client would
+    fn yrs_update() -> Vec<u8> {
+        let doc = Doc::new();
+        let root = doc.get_or_insert_map("root");
+        let mut txn = doc.transact_mut();
+
+        let state = root.insert(&mut txn, "state", MapPrelim::default());
+        state.insert(&mut txn, "title", TextPrelim::new("Hello"));
+
+        let tags = state.insert(&mut txn, "tags", ArrayPrelim::default());
+        tags.push_back(&mut txn, "a");
+        tags.push_back(&mut txn, TextPrelim::new("b"));
+
+        let meta = state.insert(&mut txn, "meta", MapPrelim::default());
+        meta.insert(&mut txn, "$type", "data");
+        meta.insert(&mut txn, "count", 2);
+
+        let body = state.insert(&mut txn, "body", XmlFragmentPrelim::default());
+        let p = body.push_back(&mut txn, XmlElementPrelim::empty("p"));
+        p.insert_attribute(&mut txn, "class", "lead");
+        p.push_back(&mut txn, XmlTextPrelim::new("Hi"));
+
+        state.insert(&mut txn, "done", false);
+
+        txn.encode_state_as_update_v2(&StateVector::default())
+    }
+
+    async fn schema_update(schema: serde_json::Value) -> Vec<u8> {
+        let mut crdt = CrdtDocument::new().await;
+        crdt.insert(&["state"], CrdtValue::from_schema(schema).unwrap())
+            .await
+            .unwrap();
+
+        crdt.get_state_as_update().await
+    }
+
+    fn state(root: &MapRef, txn: &impl ReadTxn) -> MapRef {
+        match root.get(txn, "state") {
+            Some(Out::YMap(state)) => state,
+            other => panic!("Expected a map, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn from_schema_builds_the_shared_types_yrs_reads() {
+        let update = schema_update(schema()).await;
+
+        let doc = Doc::new();
+        doc.transact_mut()
+            .apply_update(Update::decode_v2(&update).unwrap())
+            .unwrap();
+
+        let root = doc.get_or_insert_map("root");
+        let txn = doc.transact();
+        let state = state(&root, &txn);
+
+        let Some(Out::YText(title)) = state.get(&txn, "title") else {
+            panic!("Expected text");
+        };
+        assert_eq!(title.get_string(&txn), "Hello");
+
+        let Some(Out::YArray(tags)) = state.get(&txn, "tags") else {
+            panic!("Expected an array");
+        };
+        assert_eq!(tags.get(&txn, 0), Some(Out::Any(Any::from("a"))));
+        let Some(Out::YText(tag)) = tags.get(&txn, 1) else {
+            panic!("Expected text");
+        };
+        assert_eq!(tag.get_string(&txn), "b");
+
+        let Some(Out::YMap(meta)) = state.get(&txn, "meta") else {
+            panic!("Expected a map");
+        };
+        assert_eq!(meta.get(&txn, "$type"), Some(Out::Any(Any::from("data"))));
+
+        let Some(Out::YXmlFragment(body)) = state.get(&txn, "body") else {
+            panic!("Expected an XML fragment");
+        };
+        assert_eq!(body.get_string(&txn), "<p class=\"lead\">Hi</p>");
+
+        assert_eq!(state.get(&txn, "done"), Some(Out::Any(Any::Bool(false))));
+    }
+
+    #[tokio::test]
+    async fn from_schema_matches_the_document_built_with_yrs() {
+        let expected = CrdtDocument::from_update(&yrs_update()).await;
+        let actual = CrdtDocument::from_update(&schema_update(schema()).await).await;
+
+        assert_eq!(actual.to_json().await, expected.to_json().await);
+    }
+
+    #[tokio::test]
+    async fn canonicalized_v1_updates_of_yrs_match_from_schema() {
+        let v1 = Update::decode_v2(&yrs_update()).unwrap().encode_v1();
+        let v2 = UpdateEncoding::V1.canonicalize(&v1).unwrap();
+
+        let expected = CrdtDocument::from_update(&v2).await;
+        let actual = CrdtDocument::from_update(&schema_update(schema()).await).await;
+
+        assert_eq!(actual.to_json().await, expected.to_json().await);
+    }
+
+    #[tokio::test]
+    async fn v2_updates_round_trip() {
+        let update = yrs_update();
+
+        assert_eq!(UpdateEncoding::V2.canonicalize(&update).unwrap(), update);
+        assert_eq!(UpdateEncoding::V2.transcode(&update).unwrap(), update);
+
+        // Through v1 and back, the update still builds the same document
+        let v1 = UpdateEncoding::V1.transcode(&update).unwrap();
+        let v2 = UpdateEncoding::V1.canonicalize(&v1).unwrap();
+
+        let expected = CrdtDocument::from_update(&update).await;
+        let actual = CrdtDocument::from_update(&v2).await;
+
+        assert_eq!(actual.to_json().await, expected.to_json().await);
+    }
+
+    // A client's whole state in `encoding`
+    fn client_state(doc: &Doc, encoding: UpdateEncoding) -> Vec<u8> {
+        let txn = doc.transact();
+
+        match encoding {
+            UpdateEncoding::V1 => txn.encode_state_as_update_v1(&StateVector::default()),
+            UpdateEncoding::V2 => txn.encode_state_as_update_v2(&StateVector::default()),
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_and_v2_clients_share_a_document() {
+        let clients = [
+            (Doc::new(), UpdateEncoding::V1),
+            (Doc::new(), UpdateEncoding::V2),
+        ];
+        let mut server = CrdtDocument::new().await;
+
+        // Each client edits a key of its own and sends it in its encoding
+        for ((doc, encoding), (key, value)) in
+            clients.iter().zip([("title", "Draft"), ("owner", "bob")])
+        {
+            doc.get_or_insert_map("root")
+                .insert(&mut doc.transact_mut(), key, value);
+
+            let update = encoding
+                .canonicalize(&client_state(doc, *encoding))
+                .unwrap();
+            server.apply_delta(&update).await.unwrap();
+        }
+
+        let expected = json!({"title": "Draft", "owner": "bob"});
+        assert_eq!(server.to_json().await, expected);
+
+        // The merged document is sent back to each client in its encoding
+        let merged = server.get_state_as_update().await;
+
+        for (doc, encoding) in &clients {
+            let update = encoding.transcode(&merged).unwrap();
+            let update = match encoding {
+                UpdateEncoding::V1 => Update::decode_v1(&update).unwrap(),
+                UpdateEncoding::V2 => Update::decode_v2(&update).unwrap(),
+            };
+            doc.transact_mut().apply_update(update).unwrap();
+
+            let state = client_state(doc, UpdateEncoding::V2);
+            assert_eq!(
+                CrdtDocument::from_update(&state).await.to_json().await,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn escaped_type_keys_are_read_as_data() {
+        let CrdtValue::Map(mut entries) =
+            CrdtValue::from_schema(json!({"$$type": "text", "$$$type": 1})).unwrap()
+        else {
+            panic!("Expected a map");
+        };
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        assert_eq!(
+            entries,
+            vec![
+                ("$$type".to_string(), CrdtValue::Number(1.0)),
+                ("$type".to_string(), CrdtValue::String("text".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_types_are_rejected() {
+        assert!(CrdtValue::from_schema(json!({"$type": "counter"})).is_err());
+    }
+
+    #[test]
+    fn shared_types_are_not_plain_values() {
+        assert!(CrdtValue::Text("Hello".to_string()).into_any().is_err());
+        assert_eq!(CrdtValue::Null.into_any(), Ok(Any::Null));
+    }
+
+    async fn fixture(update_v1: &[u8]) -> CrdtDocument {
+        let update = UpdateEncoding::V1.canonicalize(update_v1).unwrap();
+
+        CrdtDocument::from_update(&update).await
+    }
+
+    // Updates in the v1 format Yjs clients send: a rich text editor with
+    // formatted text, and a todo list with plain values and members
+    const EDITOR: &[u8] = include_bytes!("fixtures/editor.v1.bin");
+    const TODOS: &[u8] = include_bytes!("fixtures/todos.v1.bin");
+
+    #[tokio::test]
+    async fn to_json_reads_an_editor_document() {
+        let crdt = fixture(EDITOR).await;
+
+        assert_eq!(
+            crdt.to_json().await,
+            json!({
+                "state": {
+                    "title": {"$type": "text", "value": "Meeting notes"},
+                    "doc": {"$type": "xmlFragment", "children": [
+                        {"$type": "xmlElement", "tag": "heading", "attributes": {"level": "1"}, "children": [
+                            {"$type": "xmlText", "value": "Agenda"}
+                        ]},
+                        {"$type": "xmlElement", "tag": "paragraph", "attributes": {}, "children": [
+                            {"$type": "xmlText", "value": "Hello world"}
+                        ]}
+                    ]}
+                }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn to_json_reads_a_todo_list() {
+        let crdt = fixture(TODOS).await;
+
+        assert_eq!(
+            crdt.to_json().await,
+            json!({
+                "state": {
+                    "todos": [
+                        {"title": {"$type": "text", "value": "Write tests"}, "done": true},
+                        {"title": {"$type": "text", "value": "Ship"}, "done": false}
+                    ],
+                    "settings": {"$$type": "plain", "tags": ["a", "b"]},
+                    "count": 3
+                },
+                "members": {"alice": {"name": "Alice"}}
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn get_value_steps_into_arrays_and_xml_children() {
+        let todos = fixture(TODOS).await;
+        let editor = fixture(EDITOR).await;
+
+        assert_eq!(
+            todos.get_value(&["state", "todos", "1", "done"]).await,
+            Some(json!(false))
+        );
+        assert_eq!(
+            todos.get_value(&["state", "settings", "tags", "0"]).await,
+            Some(json!("a"))
+        );
+        assert_eq!(
+            editor.get_value(&["state", "doc", "1", "0"]).await,
+            Some(json!({"$type": "xmlText", "value": "Hello world"}))
+        );
+        assert_eq!(
+            editor.get_value(&["state", "doc", "0"]).await.unwrap()["attributes"],
+            json!({"level": "1"})
+        );
+    }
+
+    #[tokio::test]
+    async fn get_value_is_none_off_the_document() {
+        let crdt = fixture(TODOS).await;
+
+        assert_eq!(crdt.get_value(&["state", "todos", "2"]).await, None);
+        assert_eq!(crdt.get_value(&["state", "todos", "first"]).await, None);
+        assert_eq!(crdt.get_value(&["state", "count", "0"]).await, None);
+        assert_eq!(crdt.get_value(&["missing"]).await, None);
+    }
+
+    #[tokio::test]
+    async fn to_json_round_trips_through_from_schema() {
+        for update in [EDITOR, TODOS] {
+            let json = fixture(update).await.to_json().await;
+
+            let mut crdt = CrdtDocument::new().await;
+            for (key, value) in json.as_object().unwrap().clone() {
+                crdt.insert(&[&key], CrdtValue::from_schema(value).unwrap())
+                    .await
+                    .unwrap();
+            }
+
+            assert_eq!(crdt.to_json().await, json);
+        }
+    }
+}
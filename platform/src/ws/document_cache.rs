@@ -0,0 +1,488 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::sync::{Mutex, broadcast, broadcast::error::RecvError};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant, interval};
+use tracing::{error, warn};
+
+use crate::{
+    messaging::BroadcastMessage,
+    observability::metrics::{
+        DOCUMENT_CACHE_BYTES, DOCUMENT_CACHE_EVICTIONS_TOTAL, DOCUMENT_CACHE_LOOKUPS_TOTAL,
+    },
+    storage::DocumentStorage,
+};
+
+use super::crdt::{Crdt, CrdtDocument};
+
+/// Decoded documents of the channels active on this node, so patches do not
+/// load and decode the whole document each time they are merged. Documents
+/// follow the node's own flushes and the patches other nodes publish on the
+/// bus. The least recently used ones are dropped over the memory limit, and
+/// any once their channel goes idle.
+pub struct DocumentCache {
+    storage: Arc<dyn DocumentStorage>,
+    documents: Mutex<HashMap<(String, String), CachedDocument>>,
+    max_bytes: usize,
+    idle_timeout: Duration,
+}
+
+struct CachedDocument {
+    // Locked on its own, so applying a patch does not hold up other channels.
+    // Emptied by `take`, which may win the race against a patch being applied.
+    crdt: Arc<Mutex<Option<CrdtDocument>>>,
+    // Encoded size when loaded or saved, plus the patches applied since
+    size: usize,
+    // Sequence number the document holds every patch up to. Unknown once it
+    // took a patch from the bus, as it may have missed others.
+    seq: Option<u64>,
+    last_used: Instant,
+}
+
+impl DocumentCache {
+    pub fn new(
+        storage: Arc<dyn DocumentStorage>,
+        max_bytes: usize,
+        idle_timeout: Duration,
+    ) -> Self {
+        Self {
+            storage,
+            documents: Mutex::new(HashMap::new()),
+            max_bytes,
+            idle_timeout,
+        }
+    }
+
+    fn get_key(app_id: &str, channel_id: &str) -> (String, String) {
+        (app_id.to_string(), channel_id.to_string())
+    }
+
+    /// Encoded size of the channel's document, `None` if there is none. A
+    /// document loaded for this is not cached: the flush that follows takes it
+    /// at a known sequence number, which the load cannot tell.
+    pub async fn size(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+        let key = Self::get_key(app_id, channel_id);
+
+        if let Some(document) = self.documents.lock().await.get_mut(&key) {
+            DOCUMENT_CACHE_LOOKUPS_TOTAL
+                .with_label_values(&["hit"])
+                .inc();
+            document.last_used = Instant::now();
+
+            return Ok(Some(document.size));
+        }
+
+        DOCUMENT_CACHE_LOOKUPS_TOTAL
+            .with_label_values(&["miss"])
+            .inc();
+
+        // Loaded without holding the lock, other channels keep being served
+        let update = self.storage.get_document(app_id, channel_id).await?;
+
+        Ok(update.map(|update| update.len()))
+    }
+
+    /// Take the document out of the cache to change it, loading it again
+    /// unless the cached one holds every patch up to `seq`. An empty document
+    /// if there is none.
+    pub async fn take(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        seq: u64,
+    ) -> Result<CrdtDocument, Box<dyn std::error::Error>> {
+        let cached = self
+            .documents
+            .lock()
+            .await
+            .remove(&Self::get_key(app_id, channel_id));
+
+        if let Some(document) = cached {
+            DOCUMENT_CACHE_BYTES.sub(document.size as i64);
+
+            if document.seq == Some(seq)
+                && let Some(crdt) = document.crdt.lock().await.take()
+            {
+                DOCUMENT_CACHE_LOOKUPS_TOTAL
+                    .with_label_values(&["hit"])
+                    .inc();
+
+                return Ok(crdt);
+            }
+        }
+
+        DOCUMENT_CACHE_LOOKUPS_TOTAL
+            .with_label_values(&["miss"])
+            .inc();
+
+        let update = self
+            .storage
+            .get_document(app_id, channel_id)
+            .await?
+            .unwrap_or_default();
+
+        Ok(CrdtDocument::from_update(&update).await)
+    }
+
+    /// Put back a document taken out with `take` once it is saved, holding
+    /// every patch up to `seq` when known
+    pub async fn put(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        crdt: CrdtDocument,
+        size: usize,
+        seq: Option<u64>,
+    ) {
+        self.insert(Self::get_key(app_id, channel_id), crdt, size, seq)
+            .await;
+    }
+
+    /// Forget the document, when it is deleted or could not be saved
+    pub async fn remove(&self, app_id: &str, channel_id: &str) {
+        let removed = self
+            .documents
+            .lock()
+            .await
+            .remove(&Self::get_key(app_id, channel_id));
+
+        if let Some(document) = removed {
+            DOCUMENT_CACHE_BYTES.sub(document.size as i64);
+        }
+    }
+
+    /// Keep only the documents of the channels `keep` accepts
+    pub async fn retain(&self, keep: impl Fn(&str, &str) -> bool) {
+        self.documents
+            .lock()
+            .await
+            .retain(|(app_id, channel_id), document| {
+                let kept = keep(app_id, channel_id);
+
+                if !kept {
+                    DOCUMENT_CACHE_BYTES.sub(document.size as i64);
+                }
+
+                kept
+            });
+    }
+
+    async fn insert(
+        &self,
+        key: (String, String),
+        crdt: CrdtDocument,
+        size: usize,
+        seq: Option<u64>,
+    ) {
+        let mut documents = self.documents.lock().await;
+
+        let document = CachedDocument {
+            crdt: Arc::new(Mutex::new(Some(crdt))),
+            size,
+            seq,
+            last_used: Instant::now(),
+        };
+
+        if let Some(previous) = documents.insert(key.clone(), document) {
+            DOCUMENT_CACHE_BYTES.sub(previous.size as i64);
+        }
+
+        DOCUMENT_CACHE_BYTES.add(size as i64);
+
+        let mut total: usize = documents.values().map(|document| document.size).sum();
+
+        // Over the limit, drop the least recently used documents but the new one
+        while total > self.max_bytes {
+            let Some(oldest) = documents
+                .iter()
+                .filter(|(other, _)| **other != key)
+                .min_by_key(|(_, document)| document.last_used)
+                .map(|(other, _)| other.clone())
+            else {
+                break;
+            };
+
+            if let Some(evicted) = documents.remove(&oldest) {
+                total -= evicted.size;
+                DOCUMENT_CACHE_BYTES.sub(evicted.size as i64);
+                DOCUMENT_CACHE_EVICTIONS_TOTAL
+                    .with_label_values(&["memory"])
+                    .inc();
+            }
+        }
+    }
+
+    // Apply a patch published on the bus to the cached document, if any
+    async fn apply(&self, app_id: &str, channel_id: &str, payload: &[u8], seq: Option<u64>) {
+        let key = Self::get_key(app_id, channel_id);
+
+        let crdt = {
+            let documents = self.documents.lock().await;
+
+            let Some(document) = documents.get(&key) else {
+                return;
+            };
+
+            // This node's own flush coming back, the document already has it
+            if seq.is_some() && seq == document.seq {
+                return;
+            }
+
+            document.crdt.clone()
+        };
+
+        let applied = match crdt.lock().await.as_mut() {
+            // As a string, the error is held across the lock below
+            Some(document) => document
+                .apply_delta(payload)
+                .await
+                .map_err(|e| e.to_string()),
+            // Taken meanwhile, the cache no longer holds it
+            None => return,
+        };
+
+        let mut documents = self.documents.lock().await;
+
+        // Replaced or dropped meanwhile, the entry is no longer this document
+        let Some(document) = documents
+            .get_mut(&key)
+            .filter(|document| Arc::ptr_eq(&document.crdt, &crdt))
+        else {
+            return;
+        };
+
+        if let Err(e) = applied {
+            warn!("Dropping cached document of channel {channel_id}: {e}");
+
+            if let Some(removed) = documents.remove(&key) {
+                DOCUMENT_CACHE_BYTES.sub(removed.size as i64);
+            }
+
+            return;
+        }
+
+        document.size += payload.len();
+        document.seq = None;
+        DOCUMENT_CACHE_BYTES.add(payload.len() as i64);
+    }
+
+    fn evict_idle(&self, documents: &mut HashMap<(String, String), CachedDocument>) {
+        documents.retain(|_, document| {
+            let idle = document.last_used.elapsed() >= self.idle_timeout;
+
+            if idle {
+                DOCUMENT_CACHE_BYTES.sub(document.size as i64);
+                DOCUMENT_CACHE_EVICTIONS_TOTAL
+                    .with_label_values(&["idle"])
+                    .inc();
+            }
+
+            !idle
+        });
+    }
+
+    /// Keep cached documents up to date with the patches delivered on this
+    /// node. Everything is dropped when patches were missed.
+    pub fn follow(self: Arc<Self>, tx: &broadcast::Sender<BroadcastMessage>) -> JoinHandle<()> {
+        let mut rx = tx.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(BroadcastMessage::Patch {
+                        app_id,
+                        channel_id,
+                        payload,
+                        seq,
+                        relay: false,
+                        ..
+                    }) => {
+                        self.apply(&app_id, &channel_id, &payload, seq).await;
+                    }
+                    // Relay and encrypted channels have no document here
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        error!("Document cache missed {skipped} patches, dropping it");
+
+                        let mut documents = self.documents.lock().await;
+                        documents.clear();
+                        DOCUMENT_CACHE_BYTES.set(0);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+
+    /// Drop the documents of channels idle for longer than the idle timeout
+    pub fn start(self: Arc<Self>, period: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+
+            loop {
+                ticker.tick().await;
+
+                let mut documents = self.documents.lock().await;
+                self.evict_idle(&mut documents);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryDocumentStorage;
+    use serde_json::{Value as JsonValue, json};
+
+    const APP_ID: &str = "app";
+
+    fn cache(max_bytes: usize) -> (DocumentCache, Arc<MemoryDocumentStorage>) {
+        let storage = Arc::new(MemoryDocumentStorage::default());
+        let cache = DocumentCache::new(storage.clone(), max_bytes, Duration::from_secs(60));
+
+        (cache, storage)
+    }
+
+    async fn document(title: &str) -> CrdtDocument {
+        let mut crdt = CrdtDocument::new().await;
+        crdt.insert_value(&["title"], json!(title)).await.unwrap();
+        crdt
+    }
+
+    async fn cached(cache: &DocumentCache) -> Vec<String> {
+        let mut channels = cache
+            .documents
+            .lock()
+            .await
+            .keys()
+            .map(|(_, channel_id)| channel_id.clone())
+            .collect::<Vec<_>>();
+        channels.sort();
+        channels
+    }
+
+    async fn total_size(cache: &DocumentCache) -> usize {
+        cache
+            .documents
+            .lock()
+            .await
+            .values()
+            .map(|document| document.size)
+            .sum()
+    }
+
+    async fn title(crdt: &CrdtDocument) -> JsonValue {
+        crdt.get_value(&["title"]).await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_least_recently_used_documents_go_over_the_limit() {
+        let (cache, _) = cache(25);
+
+        for channel_id in ["a", "b"] {
+            cache
+                .put(APP_ID, channel_id, document(channel_id).await, 10, Some(1))
+                .await;
+            tokio::time::advance(Duration::from_secs(1)).await;
+        }
+
+        // Looking `a` up makes `b` the least recently used
+        assert_eq!(cache.size(APP_ID, "a").await.unwrap(), Some(10));
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        cache
+            .put(APP_ID, "c", document("c").await, 10, Some(1))
+            .await;
+
+        assert_eq!(cached(&cache).await, ["a", "c"]);
+        assert_eq!(total_size(&cache).await, 20);
+    }
+
+    #[tokio::test]
+    async fn the_newest_document_stays_even_over_the_limit() {
+        let (cache, _) = cache(5);
+
+        cache
+            .put(APP_ID, "a", document("a").await, 10, Some(1))
+            .await;
+
+        assert_eq!(cached(&cache).await, ["a"]);
+    }
+
+    #[tokio::test]
+    async fn documents_behind_the_sequence_are_loaded_again() {
+        let (cache, storage) = cache(usize::MAX);
+        let stored = document("stored").await.get_state_as_update().await;
+        storage.save_document(APP_ID, "doc", &stored).await.unwrap();
+
+        cache
+            .put(APP_ID, "doc", document("cached").await, 10, Some(3))
+            .await;
+        let taken = cache.take(APP_ID, "doc", 4).await.unwrap();
+        assert_eq!(title(&taken).await, json!("stored"));
+        assert!(cached(&cache).await.is_empty());
+
+        cache
+            .put(APP_ID, "doc", document("cached").await, 10, Some(4))
+            .await;
+        let taken = cache.take(APP_ID, "doc", 4).await.unwrap();
+        assert_eq!(title(&taken).await, json!("cached"));
+    }
+
+    #[tokio::test]
+    async fn patches_from_the_bus_make_the_sequence_unknown() {
+        let (cache, storage) = cache(usize::MAX);
+        let stored = document("stored").await.get_state_as_update().await;
+        storage.save_document(APP_ID, "doc", &stored).await.unwrap();
+
+        cache
+            .put(APP_ID, "doc", document("cached").await, 10, Some(4))
+            .await;
+
+        // Another node's patch, this node may have missed others
+        let patch = document("remote").await.get_state_as_update().await;
+        cache.apply(APP_ID, "doc", &patch, Some(6)).await;
+
+        let taken = cache.take(APP_ID, "doc", 4).await.unwrap();
+        assert_eq!(title(&taken).await, json!("stored"));
+    }
+
+    #[tokio::test]
+    async fn sizes_follow_saves_and_patches() {
+        let (cache, storage) = cache(usize::MAX);
+        let stored = document("stored").await.get_state_as_update().await;
+        storage.save_document(APP_ID, "doc", &stored).await.unwrap();
+
+        // Read from storage without caching it
+        assert_eq!(cache.size(APP_ID, "doc").await.unwrap(), Some(stored.len()));
+        assert_eq!(cache.size(APP_ID, "missing").await.unwrap(), None);
+        assert!(cached(&cache).await.is_empty());
+
+        cache
+            .put(APP_ID, "doc", document("cached").await, 10, Some(1))
+            .await;
+        assert_eq!(cache.size(APP_ID, "doc").await.unwrap(), Some(10));
+
+        // This node's own flush coming back is already counted
+        cache.apply(APP_ID, "doc", b"ignored", Some(1)).await;
+        assert_eq!(cache.size(APP_ID, "doc").await.unwrap(), Some(10));
+
+        let patch = document("remote").await.get_state_as_update().await;
+        cache.apply(APP_ID, "doc", &patch, Some(2)).await;
+        assert_eq!(
+            cache.size(APP_ID, "doc").await.unwrap(),
+            Some(10 + patch.len())
+        );
+
+        // A patch the document cannot take drops it
+        cache.apply(APP_ID, "doc", &[], None).await;
+        assert!(cached(&cache).await.is_empty());
+        assert_eq!(total_size(&cache).await, 0);
+    }
+}
@@ -1,6 +1,8 @@
 use base64::Engine as _;
 use serde::Deserialize;
 
+use super::protocol::ProtocolVersion;
+
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum IncomingMessage {
@@ -9,17 +11,94 @@ pub enum IncomingMessage {
         channel_id: String,
         #[serde(rename = "initState")]
         init_state: Option<Vec<u8>>,
+        /// Relay patches verbatim instead of merging them into a document.
+        /// Only declared in JSON, binary clients and clients leaving it out
+        /// take the channel as it is.
+        #[serde(default)]
+        relay: Option<bool>,
+        /// Relay patches as ciphertext the server keeps without reading.
+        /// Only declared in JSON.
+        #[serde(default)]
+        encrypted: Option<bool>,
+        #[serde(rename = "requestId", default)]
+        request_id: Option<String>,
     },
     Patch {
         #[serde(rename = "channelId")]
         channel_id: String,
         #[serde(deserialize_with = "deserialize_delta")]
         delta: Vec<u8>,
+        #[serde(rename = "requestId", default)]
+        request_id: Option<String>,
+    },
+    /// Credentials sent as the first message when the token is not part of
+    /// the handshake
+    Auth { token: String },
+    /// Rejoin a channel after a reconnect, receiving only the patches
+    /// broadcast after `last_seq`
+    Resume {
+        #[serde(rename = "channelId")]
+        channel_id: String,
+        #[serde(rename = "resumeToken")]
+        resume_token: String,
+        #[serde(rename = "lastSeq")]
+        last_seq: u64,
+        #[serde(rename = "requestId", default)]
+        request_id: Option<String>,
+    },
+    /// Key material for the members of an encrypted channel, such as a
+    /// public key announced to every device or the channel key wrapped for a
+    /// single one. `recipient` is the connection id of that device, as other
+    /// devices learn it from the `senderConnection` of its key exchanges.
+    /// Relayed as is, JSON only.
+    KeyExchange {
+        #[serde(rename = "channelId")]
+        channel_id: String,
+        #[serde(default)]
+        recipient: Option<String>,
+        #[serde(deserialize_with = "deserialize_delta")]
+        payload: Vec<u8>,
+        #[serde(rename = "requestId", default)]
+        request_id: Option<String>,
     },
     #[serde(other)]
     Unknown,
 }
 
+impl IncomingMessage {
+    pub fn message_type(&self) -> &'static str {
+        match self {
+            IncomingMessage::Init { .. } => "init",
+            IncomingMessage::Patch { .. } => "patch",
+            IncomingMessage::Auth { .. } => "auth",
+            IncomingMessage::Resume { .. } => "resume",
+            IncomingMessage::KeyExchange { .. } => "key_exchange",
+            IncomingMessage::Unknown => "unknown",
+        }
+    }
+
+    pub fn channel_id(&self) -> Option<&str> {
+        match self {
+            IncomingMessage::Init { channel_id, .. }
+            | IncomingMessage::Patch { channel_id, .. }
+            | IncomingMessage::Resume { channel_id, .. }
+            | IncomingMessage::KeyExchange { channel_id, .. } => Some(channel_id),
+            IncomingMessage::Auth { .. } | IncomingMessage::Unknown => None,
+        }
+    }
+
+    /// Correlation id chosen by the client, echoed back in error frames
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            IncomingMessage::Init { request_id, .. }
+            | IncomingMessage::Patch { request_id, .. }
+            | IncomingMessage::Resume { request_id, .. }
+            | IncomingMessage::KeyExchange { request_id, .. } => request_id.as_deref(),
+            IncomingMessage::Auth { .. } | IncomingMessage::Unknown => None,
+        }
+    }
+}
+
 fn deserialize_delta<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -31,12 +110,11 @@ where
             let mut vec = vec![0; map.len()];
 
             for (key, value) in map {
-                if let Ok(index) = key.parse::<usize>() {
-                    if let Some(num) = value.as_u64() {
-                        if index < vec.len() {
-                            vec[index] = num as u8;
-                        }
-                    }
+                if let Ok(index) = key.parse::<usize>()
+                    && let Some(num) = value.as_u64()
+                    && index < vec.len()
+                {
+                    vec[index] = num as u8;
                 }
             }
 
@@ -49,41 +127,68 @@ where
     }
 }
 
-// Helper function to parse binary message
-pub fn parse_binary_message(data: &[u8]) -> Result<IncomingMessage, String> {
-    if data.len() < 5 {
-        return Err("Invalid message format: message too short".into());
+fn read_string(data: &[u8], offset: usize, name: &str) -> Result<(String, usize), String> {
+    if data.len() < offset + 4 {
+        return Err(format!("Invalid message format: missing {name} length"));
     }
 
-    // First byte is message type
-    let message_type = data[0];
+    // 4 bytes of length (little endian) followed by the string
+    let len = u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ]) as usize;
+
+    if len > 1024 {
+        return Err(format!("{name} length too large: {len}"));
+    }
 
-    // Next 4 bytes are channelId length (little endian)
-    let channel_id_len = u32::from_le_bytes([data[1], data[2], data[3], data[4]]) as usize;
+    let start = offset + 4;
 
-    if channel_id_len > 1024 {
-        return Err(format!("Channel ID length too large: {channel_id_len}"));
+    if data.len() < start + len {
+        return Err(format!(
+            "Invalid message format: {name} length exceeds message length"
+        ));
     }
 
-    if data.len() < 5 + channel_id_len {
-        return Err("Invalid message format: channel ID length exceeds message length".into());
+    let value = String::from_utf8(data[start..start + len].to_vec())
+        .map_err(|e| format!("Invalid {name}: {e}"))?;
+
+    Ok((value, start + len))
+}
+
+// Helper function to parse binary message. Since v2 frames carry a request id
+// after the channel id.
+pub fn parse_binary_message(
+    data: &[u8],
+    version: ProtocolVersion,
+) -> Result<IncomingMessage, String> {
+    if data.len() < 5 {
+        return Err("Invalid message format: message too short".into());
     }
 
-    // Extract channel ID
-    let channel_id = String::from_utf8(data[5..5 + channel_id_len].to_vec())
-        .map_err(|e| format!("Invalid channel ID: {e}"))?;
+    // First byte is message type
+    let message_type = data[0];
+
+    let (channel_id, offset) = read_string(data, 1, "Channel ID")?;
 
-    // The rest is the message data
-    let message_data = if data.len() > 5 + channel_id_len {
-        data[5 + channel_id_len..].to_vec()
-    } else {
-        Vec::new()
+    let (request_id, offset) = match version {
+        ProtocolVersion::V1 => (None, offset),
+        ProtocolVersion::V2 | ProtocolVersion::V3 => {
+            let (request_id, offset) = read_string(data, offset, "Request ID")?;
+            ((!request_id.is_empty()).then_some(request_id), offset)
+        }
     };
 
+    // The rest is the message data
+    let message_data = data[offset..].to_vec();
+
     match message_type {
         0 => Ok(IncomingMessage::Patch {
             channel_id,
             delta: message_data,
+            request_id,
         }),
         1 => Ok(IncomingMessage::Init {
             channel_id,
@@ -92,7 +197,181 @@ pub fn parse_binary_message(data: &[u8]) -> Result<IncomingMessage, String> {
             } else {
                 Some(message_data)
             },
+            relay: None,
+            encrypted: None,
+            request_id,
         }),
+        2 if version.has_binary_control_frames() => Ok(IncomingMessage::Auth {
+            token: String::from_utf8(message_data).map_err(|e| format!("Invalid token: {e}"))?,
+        }),
+        // The last seen sequence number (u64 LE) followed by the resume token
+        3 if version.has_sequence_numbers() => {
+            let Some((last_seq, resume_token)) = message_data.split_first_chunk::<8>() else {
+                return Err("Invalid message format: missing last sequence number".into());
+            };
+
+            Ok(IncomingMessage::Resume {
+                channel_id,
+                resume_token: String::from_utf8(resume_token.to_vec())
+                    .map_err(|e| format!("Invalid resume token: {e}"))?,
+                last_seq: u64::from_le_bytes(*last_seq),
+                request_id,
+            })
+        }
         _ => Err(format!("Unsupported message type: {}", message_type)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Build a frame the way clients do, with a request id from v2 on
+    fn client_frame(
+        message_type: u8,
+        channel_id: &str,
+        request_id: &str,
+        payload: &[u8],
+        version: ProtocolVersion,
+    ) -> Vec<u8> {
+        let mut frame = vec![message_type];
+
+        frame.extend_from_slice(&(channel_id.len() as u32).to_le_bytes());
+        frame.extend_from_slice(channel_id.as_bytes());
+
+        if version != ProtocolVersion::V1 {
+            frame.extend_from_slice(&(request_id.len() as u32).to_le_bytes());
+            frame.extend_from_slice(request_id.as_bytes());
+        }
+
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn patches_read_the_request_id_after_the_channel_id_from_v2_on() {
+        for version in [ProtocolVersion::V2, ProtocolVersion::V3] {
+            let frame = client_frame(0, "doc", "r1", &[1, 2, 3], version);
+
+            let Ok(IncomingMessage::Patch {
+                channel_id,
+                delta,
+                request_id,
+            }) = parse_binary_message(&frame, version)
+            else {
+                panic!("Expected a patch in {version:?}");
+            };
+
+            assert_eq!(channel_id, "doc");
+            assert_eq!(delta, [1, 2, 3]);
+            assert_eq!(request_id.as_deref(), Some("r1"));
+        }
+    }
+
+    #[test]
+    fn v1_frames_have_no_request_id() {
+        let frame = client_frame(0, "doc", "", &[1, 2, 3], ProtocolVersion::V1);
+
+        let Ok(IncomingMessage::Patch {
+            delta, request_id, ..
+        }) = parse_binary_message(&frame, ProtocolVersion::V1)
+        else {
+            panic!("Expected a patch");
+        };
+
+        assert_eq!(delta, [1, 2, 3]);
+        assert_eq!(request_id, None);
+    }
+
+    #[test]
+    fn empty_request_ids_are_none() {
+        let frame = client_frame(1, "doc", "", &[], ProtocolVersion::V2);
+
+        let Ok(IncomingMessage::Init {
+            init_state,
+            request_id,
+            relay,
+            encrypted,
+            ..
+        }) = parse_binary_message(&frame, ProtocolVersion::V2)
+        else {
+            panic!("Expected an init");
+        };
+
+        assert_eq!(init_state, None);
+        assert_eq!(request_id, None);
+        assert_eq!((relay, encrypted), (None, None));
+    }
+
+    #[test]
+    fn auth_frames_exist_from_v2_on() {
+        let v1 = client_frame(2, "", "", b"token", ProtocolVersion::V1);
+        assert!(parse_binary_message(&v1, ProtocolVersion::V1).is_err());
+
+        for version in [ProtocolVersion::V2, ProtocolVersion::V3] {
+            let frame = client_frame(2, "", "", b"token", version);
+
+            let Ok(IncomingMessage::Auth { token }) = parse_binary_message(&frame, version) else {
+                panic!("Expected an auth frame in {version:?}");
+            };
+
+            assert_eq!(token, "token");
+        }
+    }
+
+    #[test]
+    fn resume_frames_exist_from_v3_on() {
+        let payload = [&9u64.to_le_bytes()[..], b"resume"].concat();
+
+        let v2 = client_frame(3, "doc", "r1", &payload, ProtocolVersion::V2);
+        assert!(parse_binary_message(&v2, ProtocolVersion::V2).is_err());
+
+        let v3 = client_frame(3, "doc", "r1", &payload, ProtocolVersion::V3);
+
+        let Ok(IncomingMessage::Resume {
+            channel_id,
+            resume_token,
+            last_seq,
+            request_id,
+        }) = parse_binary_message(&v3, ProtocolVersion::V3)
+        else {
+            panic!("Expected a resume frame");
+        };
+
+        assert_eq!(channel_id, "doc");
+        assert_eq!(resume_token, "resume");
+        assert_eq!(last_seq, 9);
+        assert_eq!(request_id.as_deref(), Some("r1"));
+
+        let truncated = client_frame(3, "doc", "r1", &[1, 2], ProtocolVersion::V3);
+        assert!(parse_binary_message(&truncated, ProtocolVersion::V3).is_err());
+    }
+
+    #[test]
+    fn request_ids_longer_than_the_frame_are_rejected() {
+        let mut frame = client_frame(0, "doc", "r1", &[], ProtocolVersion::V2);
+        let request_id_offset = 1 + 4 + "doc".len();
+
+        frame[request_id_offset..request_id_offset + 4].copy_from_slice(&100u32.to_le_bytes());
+
+        assert!(parse_binary_message(&frame, ProtocolVersion::V2).is_err());
+    }
+
+    #[test]
+    fn json_patches_accept_base64_deltas_and_request_ids() {
+        let message: IncomingMessage = serde_json::from_str(
+            r#"{"type":"patch","channelId":"doc","delta":"AQID","requestId":"r1"}"#,
+        )
+        .unwrap();
+
+        let IncomingMessage::Patch {
+            delta, request_id, ..
+        } = message
+        else {
+            panic!("Expected a patch");
+        };
+
+        assert_eq!(delta, [1, 2, 3]);
+        assert_eq!(request_id.as_deref(), Some("r1"));
+    }
+}
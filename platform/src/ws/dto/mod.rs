@@ -1,5 +1,7 @@
 pub mod incoming_message;
 pub mod outgoing_message;
+pub mod protocol;
 
 pub use incoming_message::IncomingMessage;
 pub use outgoing_message::OutgoingMessage;
+pub use protocol::ProtocolVersion;
@@ -3,6 +3,9 @@ use std::error::Error;
 use std::fmt;
 use tokio_tungstenite::tungstenite::{Bytes, Message};
 
+use super::protocol::ProtocolVersion;
+use crate::ws::errors::ErrorCode;
+
 #[derive(Debug)]
 pub enum WsMessageError {
     Serialization(serde_json::Error),
@@ -28,6 +31,17 @@ pub trait ToWsMessage {
     fn to_ws_message(&self) -> Result<Message, WsMessageError>;
 }
 
+// Binary frames start with a type byte, then the channel id length (u32 LE),
+// the channel id and the payload. Sequenced Patch and Scan payloads start with
+// the sequence number (u64 LE).
+const PATCH_TYPE: u8 = 0;
+const SCAN_TYPE: u8 = 1;
+const ERROR_TYPE: u8 = 2;
+const AUTH_SUCCESS_TYPE: u8 = 3;
+const RESUME_TOKEN_TYPE: u8 = 4;
+const ACK_TYPE: u8 = 5;
+const NACK_TYPE: u8 = 6;
+
 #[derive(Debug, Serialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum OutgoingMessage {
@@ -36,48 +50,426 @@ pub enum OutgoingMessage {
         channel_id: String,
         #[serde(rename = "stateUpdate")]
         state_update: Vec<u8>,
+        /// Sequence number of the latest patch included in the state (v3)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
     },
     Patch {
         #[serde(rename = "channelId")]
         channel_id: String,
         payload: Vec<u8>,
+        /// Sequence number of the last patch merged into the payload (v3)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    /// Binary (v2) payload: code (u16 LE), request id length (u32 LE), request
+    /// id, then the UTF-8 message
+    Error {
+        code: ErrorCode,
+        message: String,
+        #[serde(rename = "channelId", skip_serializing_if = "Option::is_none")]
+        channel_id: Option<String>,
+        #[serde(rename = "requestId", skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+    #[serde(rename = "auth_success")]
+    AuthSuccess { message: String },
+    /// Presented with a Resume message after a reconnect (v3)
+    ResumeToken { token: String },
+    /// The client's patch was saved as `seq` of its channel (v3). Binary
+    /// payload: seq (u64 LE), then the request id
+    Ack {
+        #[serde(rename = "channelId")]
+        channel_id: String,
+        #[serde(rename = "requestId", skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        seq: u64,
+    },
+    /// The client's patch was not saved and should be sent again (v3). Binary
+    /// payload: request id length (u32 LE), request id, then the UTF-8 message
+    Nack {
+        #[serde(rename = "channelId")]
+        channel_id: String,
+        #[serde(rename = "requestId", skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        message: String,
+    },
+    /// Key material `sender` shares on an encrypted channel from the device
+    /// connected as `sender_connection`, which replies address. JSON only
+    KeyExchange {
+        #[serde(rename = "channelId")]
+        channel_id: String,
+        sender: String,
+        #[serde(rename = "senderConnection")]
+        sender_connection: String,
+        payload: Vec<u8>,
     },
 }
 
+impl OutgoingMessage {
+    pub fn error(code: ErrorCode, message: impl ToString) -> Self {
+        OutgoingMessage::Error {
+            code,
+            message: message.to_string(),
+            channel_id: None,
+            request_id: None,
+        }
+    }
+
+    /// JSON form of the frame, for clients speaking the text protocol
+    pub fn to_text_message(&self) -> Result<Message, WsMessageError> {
+        Ok(Message::Text(serde_json::to_string(self)?.into()))
+    }
+
+    /// Encode as binary when the client speaks binary and `version` has a
+    /// binary form of this frame, as JSON text otherwise
+    pub fn encode(
+        &self,
+        version: ProtocolVersion,
+        binary: bool,
+    ) -> Result<Message, WsMessageError> {
+        let has_binary_form = match self {
+            OutgoingMessage::Scan { .. }
+            | OutgoingMessage::Patch { .. }
+            | OutgoingMessage::ResumeToken { .. } => true,
+            OutgoingMessage::Error { .. }
+            | OutgoingMessage::AuthSuccess { .. }
+            | OutgoingMessage::Ack { .. }
+            | OutgoingMessage::Nack { .. } => version.has_binary_control_frames(),
+            OutgoingMessage::KeyExchange { .. } => false,
+        };
+
+        if binary && has_binary_form {
+            self.to_ws_message()
+        } else {
+            self.to_text_message()
+        }
+    }
+}
+
+fn encode_frame(message_type: u8, channel_id: &str, payload: &[u8]) -> Message {
+    let channel_id_bytes = channel_id.as_bytes();
+    let channel_id_len = channel_id_bytes.len() as u32;
+    let mut buffer = Vec::with_capacity(1 + 4 + channel_id_bytes.len() + payload.len());
+
+    buffer.push(message_type);
+    buffer.extend_from_slice(&channel_id_len.to_le_bytes());
+    buffer.extend_from_slice(channel_id_bytes);
+    buffer.extend_from_slice(payload);
+
+    Message::Binary(Bytes::from(buffer))
+}
+
+fn encode_sequenced_frame(
+    message_type: u8,
+    channel_id: &str,
+    seq: Option<u64>,
+    payload: &[u8],
+) -> Message {
+    match seq {
+        Some(seq) => encode_frame(
+            message_type,
+            channel_id,
+            &[&seq.to_le_bytes()[..], payload].concat(),
+        ),
+        None => encode_frame(message_type, channel_id, payload),
+    }
+}
+
 impl ToWsMessage for OutgoingMessage {
     fn to_ws_message(&self) -> Result<Message, WsMessageError> {
         match self {
             OutgoingMessage::Scan {
                 channel_id,
                 state_update,
+                seq,
+            } => Ok(encode_sequenced_frame(
+                SCAN_TYPE,
+                channel_id,
+                *seq,
+                state_update,
+            )),
+            OutgoingMessage::Patch {
+                channel_id,
+                payload,
+                seq,
+            } => Ok(encode_sequenced_frame(
+                PATCH_TYPE, channel_id, *seq, payload,
+            )),
+            OutgoingMessage::Error {
+                code,
+                message,
+                channel_id,
+                request_id,
             } => {
-                let channel_id_bytes = channel_id.as_bytes();
-                let channel_id_len = channel_id_bytes.len() as u32;
-                let mut buffer =
-                    Vec::with_capacity(1 + 4 + channel_id_bytes.len() + state_update.len());
+                let request_id = request_id.as_deref().unwrap_or_default().as_bytes();
+                let mut payload = Vec::with_capacity(2 + 4 + request_id.len() + message.len());
 
-                buffer.push(1); // Type byte for Scan
-                buffer.extend_from_slice(&channel_id_len.to_le_bytes());
-                buffer.extend_from_slice(channel_id_bytes);
-                buffer.extend_from_slice(state_update);
+                payload.extend_from_slice(&code.as_u16().to_le_bytes());
+                payload.extend_from_slice(&(request_id.len() as u32).to_le_bytes());
+                payload.extend_from_slice(request_id);
+                payload.extend_from_slice(message.as_bytes());
 
-                Ok(Message::Binary(Bytes::from(buffer)))
+                Ok(encode_frame(
+                    ERROR_TYPE,
+                    channel_id.as_deref().unwrap_or_default(),
+                    &payload,
+                ))
             }
-            OutgoingMessage::Patch {
+            OutgoingMessage::AuthSuccess { message } => {
+                Ok(encode_frame(AUTH_SUCCESS_TYPE, "", message.as_bytes()))
+            }
+            OutgoingMessage::ResumeToken { token } => {
+                Ok(encode_frame(RESUME_TOKEN_TYPE, "", token.as_bytes()))
+            }
+            OutgoingMessage::Ack {
                 channel_id,
-                payload,
+                request_id,
+                seq,
+            } => {
+                let request_id = request_id.as_deref().unwrap_or_default().as_bytes();
+
+                Ok(encode_frame(
+                    ACK_TYPE,
+                    channel_id,
+                    &[&seq.to_le_bytes()[..], request_id].concat(),
+                ))
+            }
+            OutgoingMessage::Nack {
+                channel_id,
+                request_id,
+                message,
             } => {
-                let channel_id_bytes = channel_id.as_bytes();
-                let channel_id_len = channel_id_bytes.len() as u32;
-                let mut buffer = Vec::with_capacity(1 + 4 + channel_id_bytes.len() + payload.len());
+                let request_id = request_id.as_deref().unwrap_or_default().as_bytes();
+                let mut payload = Vec::with_capacity(4 + request_id.len() + message.len());
+
+                payload.extend_from_slice(&(request_id.len() as u32).to_le_bytes());
+                payload.extend_from_slice(request_id);
+                payload.extend_from_slice(message.as_bytes());
+
+                Ok(encode_frame(NACK_TYPE, channel_id, &payload))
+            }
+            // No binary form
+            OutgoingMessage::KeyExchange { .. } => self.to_text_message(),
+        }
+    }
+}
 
-                buffer.push(0); // Type byte for Patch
-                buffer.extend_from_slice(&channel_id_len.to_le_bytes());
-                buffer.extend_from_slice(channel_id_bytes);
-                buffer.extend_from_slice(payload);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
 
-                Ok(Message::Binary(Bytes::from(buffer)))
+    const VERSIONS: [ProtocolVersion; 3] = [
+        ProtocolVersion::V1,
+        ProtocolVersion::V2,
+        ProtocolVersion::V3,
+    ];
+
+    // Split a binary frame the way clients do: type, channel id and payload
+    fn decode_frame(message: Message) -> (u8, String, Vec<u8>) {
+        let Message::Binary(data) = message else {
+            panic!("Expected a binary frame, got {message:?}");
+        };
+
+        let channel_id_len = u32::from_le_bytes(data[1..5].try_into().unwrap()) as usize;
+        let channel_id = String::from_utf8(data[5..5 + channel_id_len].to_vec()).unwrap();
+
+        (data[0], channel_id, data[5 + channel_id_len..].to_vec())
+    }
+
+    // A length-prefixed (u32 LE) string and what follows it
+    fn split_string(data: &[u8]) -> (String, &[u8]) {
+        let len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+
+        (
+            String::from_utf8(data[4..4 + len].to_vec()).unwrap(),
+            &data[4 + len..],
+        )
+    }
+
+    fn decode_text(message: Message) -> Value {
+        let Message::Text(text) = message else {
+            panic!("Expected a text frame, got {message:?}");
+        };
+
+        serde_json::from_str(&text).unwrap()
+    }
+
+    fn control_frames() -> Vec<OutgoingMessage> {
+        vec![
+            OutgoingMessage::Error {
+                code: ErrorCode::InvalidUpdate,
+                message: "Bad update".to_string(),
+                channel_id: Some("doc".to_string()),
+                request_id: Some("r1".to_string()),
+            },
+            OutgoingMessage::AuthSuccess {
+                message: "Welcome".to_string(),
+            },
+            OutgoingMessage::Ack {
+                channel_id: "doc".to_string(),
+                request_id: Some("r2".to_string()),
+                seq: 42,
+            },
+            OutgoingMessage::Nack {
+                channel_id: "doc".to_string(),
+                request_id: Some("r3".to_string()),
+                message: "Not saved".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn error_frames_carry_code_request_id_and_message() {
+        let (message_type, channel_id, payload) =
+            decode_frame(control_frames().remove(0).to_ws_message().unwrap());
+
+        assert_eq!(message_type, ERROR_TYPE);
+        assert_eq!(channel_id, "doc");
+        assert_eq!(
+            u16::from_le_bytes(payload[..2].try_into().unwrap()),
+            ErrorCode::InvalidUpdate.as_u16()
+        );
+
+        let (request_id, message) = split_string(&payload[2..]);
+
+        assert_eq!(request_id, "r1");
+        assert_eq!(message, b"Bad update");
+    }
+
+    #[test]
+    fn error_frames_without_channel_or_request_leave_them_empty() {
+        let frame = OutgoingMessage::error(ErrorCode::Unauthorized, "Denied");
+        let (message_type, channel_id, payload) = decode_frame(frame.to_ws_message().unwrap());
+
+        assert_eq!(message_type, ERROR_TYPE);
+        assert_eq!(channel_id, "");
+
+        let (request_id, message) = split_string(&payload[2..]);
+
+        assert_eq!(request_id, "");
+        assert_eq!(message, b"Denied");
+    }
+
+    #[test]
+    fn auth_success_frames_carry_the_message() {
+        let (message_type, channel_id, payload) =
+            decode_frame(control_frames().remove(1).to_ws_message().unwrap());
+
+        assert_eq!(message_type, AUTH_SUCCESS_TYPE);
+        assert_eq!(channel_id, "");
+        assert_eq!(payload, b"Welcome");
+    }
+
+    #[test]
+    fn resume_token_frames_carry_the_token() {
+        let frame = OutgoingMessage::ResumeToken {
+            token: "token".to_string(),
+        };
+        let (message_type, channel_id, payload) = decode_frame(frame.to_ws_message().unwrap());
+
+        assert_eq!(message_type, RESUME_TOKEN_TYPE);
+        assert_eq!(channel_id, "");
+        assert_eq!(payload, b"token");
+    }
+
+    #[test]
+    fn ack_frames_carry_the_sequence_then_the_request_id() {
+        let (message_type, channel_id, payload) =
+            decode_frame(control_frames().remove(2).to_ws_message().unwrap());
+
+        assert_eq!(message_type, ACK_TYPE);
+        assert_eq!(channel_id, "doc");
+        assert_eq!(u64::from_le_bytes(payload[..8].try_into().unwrap()), 42);
+        assert_eq!(&payload[8..], b"r2");
+    }
+
+    #[test]
+    fn nack_frames_carry_the_request_id_then_the_message() {
+        let (message_type, channel_id, payload) =
+            decode_frame(control_frames().remove(3).to_ws_message().unwrap());
+
+        assert_eq!(message_type, NACK_TYPE);
+        assert_eq!(channel_id, "doc");
+
+        let (request_id, message) = split_string(&payload);
+
+        assert_eq!(request_id, "r3");
+        assert_eq!(message, b"Not saved");
+    }
+
+    #[test]
+    fn control_frames_are_binary_from_v2_on() {
+        for version in VERSIONS {
+            for frame in control_frames() {
+                let message = frame.encode(version, true).unwrap();
+
+                assert_eq!(
+                    message.is_binary(),
+                    version.has_binary_control_frames(),
+                    "{frame:?} in {version:?}"
+                );
             }
         }
     }
+
+    #[test]
+    fn text_clients_get_json_in_every_version() {
+        let expected = [
+            json!({
+                "type": "error",
+                "code": 1003,
+                "message": "Bad update",
+                "channelId": "doc",
+                "requestId": "r1",
+            }),
+            json!({ "type": "auth_success", "message": "Welcome" }),
+            json!({ "type": "ack", "channelId": "doc", "requestId": "r2", "seq": 42 }),
+            json!({
+                "type": "nack",
+                "channelId": "doc",
+                "requestId": "r3",
+                "message": "Not saved",
+            }),
+        ];
+
+        for version in VERSIONS {
+            for (frame, expected) in control_frames().iter().zip(&expected) {
+                assert_eq!(
+                    decode_text(frame.encode(version, false).unwrap()),
+                    *expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn resume_tokens_are_binary_for_binary_clients_in_every_version() {
+        let frame = OutgoingMessage::ResumeToken {
+            token: "token".to_string(),
+        };
+
+        for version in VERSIONS {
+            assert!(frame.encode(version, true).unwrap().is_binary());
+            assert_eq!(
+                decode_text(frame.encode(version, false).unwrap()),
+                json!({ "type": "resumeToken", "token": "token" })
+            );
+        }
+    }
+
+    #[test]
+    fn sequenced_frames_put_the_sequence_before_the_payload() {
+        let frame = OutgoingMessage::Patch {
+            channel_id: "doc".to_string(),
+            payload: vec![1, 2, 3],
+            seq: Some(7),
+        };
+        let (message_type, _, payload) = decode_frame(frame.to_ws_message().unwrap());
+
+        assert_eq!(message_type, PATCH_TYPE);
+        assert_eq!(u64::from_le_bytes(payload[..8].try_into().unwrap()), 7);
+        assert_eq!(&payload[8..], [1, 2, 3]);
+    }
 }
@@ -0,0 +1,137 @@
+use crate::ws::crdt::UpdateEncoding;
+
+// Versions are negotiated through `Sec-WebSocket-Protocol`, offered as
+// `shallabuf.v1`, `shallabuf.v2`, ...
+const SUBPROTOCOL_PREFIX: &str = "shallabuf.v";
+
+// Browsers cannot set an Authorization header on WebSocket requests, so the
+// token may be offered as an extra `shallabuf.auth.<token>` subprotocol. It is
+// never echoed back.
+const AUTH_SUBPROTOCOL_PREFIX: &str = "shallabuf.auth.";
+
+// Clients such as y-websocket that only speak v1 updates offer an extra
+// `shallabuf.encoding.v1` subprotocol. It is never echoed back either.
+const ENCODING_SUBPROTOCOL_PREFIX: &str = "shallabuf.encoding.";
+
+/// Wire protocol spoken on a connection.
+///
+/// v1 is the original framing: binary Patch (0) and Scan (1) frames, control
+/// and error frames as JSON text. v2 adds a request id to incoming binary
+/// frames, an incoming binary Auth (2) frame and outgoing binary Error (2) and
+/// AuthSuccess (3) frames. v3 numbers broadcast patches per channel: Patch and
+/// Scan frames carry a sequence number, the server issues a ResumeToken (4) in
+/// reply to the client's first frame and a Resume (3) frame replays the patches
+/// missed since a sequence.
+/// Each patch sent by the client is answered with an Ack (5) carrying its
+/// sequence number once saved, or a Nack (6) if it was not.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolVersion {
+    #[default]
+    V1,
+    V2,
+    V3,
+}
+
+impl ProtocolVersion {
+    // Newest first, the first one offered by the client wins
+    const SUPPORTED: [ProtocolVersion; 3] = [
+        ProtocolVersion::V3,
+        ProtocolVersion::V2,
+        ProtocolVersion::V1,
+    ];
+
+    pub fn subprotocol(&self) -> &'static str {
+        match self {
+            ProtocolVersion::V1 => "shallabuf.v1",
+            ProtocolVersion::V2 => "shallabuf.v2",
+            ProtocolVersion::V3 => "shallabuf.v3",
+        }
+    }
+
+    /// Whether control and error frames have a binary encoding
+    pub fn has_binary_control_frames(&self) -> bool {
+        *self >= ProtocolVersion::V2
+    }
+
+    /// Whether patches carry their channel sequence number and connections
+    /// can be resumed
+    pub fn has_sequence_numbers(&self) -> bool {
+        *self >= ProtocolVersion::V3
+    }
+
+    /// Pick the newest supported version from a `Sec-WebSocket-Protocol`
+    /// header. Clients that offer no version get v1 and no subprotocol is
+    /// echoed back; offering only unknown versions is an error.
+    pub fn negotiate(offered: Option<&str>) -> Result<Option<Self>, String> {
+        let offered = offered
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|protocol| protocol.starts_with(SUBPROTOCOL_PREFIX))
+            .collect::<Vec<_>>();
+
+        if offered.is_empty() {
+            return Ok(None);
+        }
+
+        Self::SUPPORTED
+            .into_iter()
+            .find(|version| offered.contains(&version.subprotocol()))
+            .map(Some)
+            .ok_or_else(|| format!("Unsupported protocol version: {}", offered.join(", ")))
+    }
+}
+
+/// The token offered as an auth subprotocol, if any
+pub fn token_from_subprotocols(offered: Option<&str>) -> Option<String> {
+    offered
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .find_map(|protocol| protocol.strip_prefix(AUTH_SUBPROTOCOL_PREFIX))
+        .filter(|token| !token.is_empty())
+        .map(str::to_string)
+}
+
+/// The update encoding offered as an encoding subprotocol, if any. Offering
+/// an unknown encoding is an error.
+pub fn encoding_from_subprotocols(offered: Option<&str>) -> Result<Option<UpdateEncoding>, String> {
+    offered
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .find_map(|protocol| protocol.strip_prefix(ENCODING_SUBPROTOCOL_PREFIX))
+        .map(|encoding| {
+            UpdateEncoding::parse(encoding)
+                .ok_or_else(|| format!("Unsupported update encoding: {encoding}"))
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProtocolVersion, encoding_from_subprotocols, token_from_subprotocols};
+    use crate::ws::crdt::UpdateEncoding;
+
+    #[test]
+    fn extra_subprotocols_are_read_next_to_the_version() {
+        let offered = Some("shallabuf.v3, shallabuf.encoding.v1, shallabuf.auth.abc");
+
+        assert_eq!(
+            ProtocolVersion::negotiate(offered),
+            Ok(Some(ProtocolVersion::V3))
+        );
+        assert_eq!(
+            encoding_from_subprotocols(offered),
+            Ok(Some(UpdateEncoding::V1))
+        );
+        assert_eq!(token_from_subprotocols(offered), Some("abc".to_string()));
+    }
+
+    #[test]
+    fn encoding_defaults_to_none_and_rejects_unknown_encodings() {
+        assert_eq!(encoding_from_subprotocols(Some("shallabuf.v3")), Ok(None));
+        assert_eq!(encoding_from_subprotocols(None), Ok(None));
+        assert!(encoding_from_subprotocols(Some("shallabuf.v3, shallabuf.encoding.v9")).is_err());
+    }
+}
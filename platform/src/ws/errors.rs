@@ -0,0 +1,76 @@
+use serde::{Serialize, Serializer};
+use thiserror::Error;
+
+use super::quota::QuotaExceeded;
+
+/// Numeric codes carried by error frames. The thousands digit groups them:
+/// 1xxx malformed input, 2xxx authentication, 3xxx channel state, 4xxx limits
+/// and 5xxx server failures. Codes are part of the protocol, never renumber them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ErrorCode {
+    InvalidMessage = 1000,
+    UnknownMessageType = 1001,
+    InvalidInitState = 1002,
+    InvalidUpdate = 1003,
+    Unauthorized = 2000,
+    OriginNotAllowed = 2001,
+    DocumentNotFound = 3000,
+    ResumeExpired = 3001,
+    ChannelModeMismatch = 3002,
+    RelayNotAllowed = 3003,
+    RateLimited = 4000,
+    ConnectionQuota = 4001,
+    ChannelQuota = 4002,
+    DocumentSizeQuota = 4003,
+    TransferQuota = 4004,
+    Internal = 5000,
+}
+
+impl ErrorCode {
+    pub fn as_u16(&self) -> u16 {
+        *self as u16
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(self.as_u16())
+    }
+}
+
+impl From<QuotaExceeded> for ErrorCode {
+    fn from(quota: QuotaExceeded) -> Self {
+        match quota {
+            QuotaExceeded::Connections => ErrorCode::ConnectionQuota,
+            QuotaExceeded::Channels => ErrorCode::ChannelQuota,
+            QuotaExceeded::DocumentSize => ErrorCode::DocumentSizeQuota,
+            QuotaExceeded::Transfer => ErrorCode::TransferQuota,
+        }
+    }
+}
+
+/// A request the client got wrong. It is reported with an error frame and the
+/// connection stays open.
+#[derive(Error, Debug, Clone)]
+#[error("{message}")]
+pub struct ClientError {
+    pub code: ErrorCode,
+    pub channel_id: Option<String>,
+    pub message: String,
+}
+
+impl ClientError {
+    pub fn new(code: ErrorCode, message: impl ToString) -> Self {
+        Self {
+            code,
+            channel_id: None,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn with_channel(mut self, channel_id: impl ToString) -> Self {
+        self.channel_id = Some(channel_id.to_string());
+        self
+    }
+}
@@ -1,45 +1,547 @@
+use crate::observability::metrics::{MESSAGE_BYTES_TOTAL, MESSAGES_TOTAL};
+use crate::observability::telemetry;
 use crate::ws::{crdt::Crdt, metrics::data_transfer::MessageType};
 use crate::ws::{dto::outgoing_message::ToWsMessage, metrics::DataTransferMetric};
 
 use crate::{messaging::BroadcastMessage, ws::WsMessageHandler};
-use crate::{storage::DocumentStorage, ws::connection::WsWrite};
+use crate::{
+    storage::{DocumentStorage, EncryptedLog, MembershipIndex, PatchLog, RelayStore},
+    ws::connection::WsWrite,
+};
 use async_trait::async_trait;
 use futures_util::SinkExt;
-use serde_json::json;
-use std::{collections::HashSet, hash::RandomState, sync::Arc};
-use tokio::sync::Mutex;
-use tracing::error;
+use serde_json::{Value as JsonValue, json};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::RandomState,
+    sync::Arc,
+};
+use tokio::sync::{Mutex, oneshot};
+use tracing::{debug, error, instrument};
 use uuid::Uuid;
 
 use crate::{
     messaging::bus::MessagePublisher,
-    ws::{IncomingMessage, connection::ConnectionState},
+    ws::{
+        IncomingMessage,
+        connection::{ChannelMode, ConnectionState},
+    },
 };
 
-use super::{crdt::CrdtDocument, dto::OutgoingMessage, metrics::MetricsCollector};
+use super::{
+    bus_proxy::{PatchOutcome, PatchPublisher, PatchRejected, RelayRetention},
+    crdt::{CrdtDocument, CrdtValue, UpdateEncoding},
+    document_cache::DocumentCache,
+    dto::{OutgoingMessage, ProtocolVersion},
+    errors::{ClientError, ErrorCode},
+    metrics::MetricsCollector,
+    quota::QuotaEnforcer,
+    resume::ResumeStore,
+};
+
+// Updates of an encrypted channel's log read and sent to a joiner at a time
+const ENCRYPTED_LOG_PAGE_SIZE: usize = 256;
+
+// The connection joining a channel, as the handler needs it
+struct Participant {
+    app_id: String,
+    user_id: String,
+    connection_id: Uuid,
+    protocol_version: ProtocolVersion,
+    update_encoding: UpdateEncoding,
+    // Kept in the membership index, and projected into the document if the
+    // app wants it there
+    metadata: JsonValue,
+    members_in_document: bool,
+    mode: ChannelMode,
+    relay_keep_last: bool,
+}
+
+/// The mode a client joins a channel in: the stored one, which the client
+/// may only declare as is, or the declared one for a new channel. Only apps
+/// that allow it let clients declare relay channels of their own.
+fn settle_mode(
+    stored: Option<ChannelMode>,
+    declared: Option<ChannelMode>,
+    allow_client_relay: bool,
+) -> Result<ChannelMode, ClientError> {
+    match (stored, declared) {
+        (Some(stored), Some(declared)) if stored != declared => Err(ClientError::new(
+            ErrorCode::ChannelModeMismatch,
+            format!(
+                "Channel is a {} channel, not a {} one",
+                stored.as_str(),
+                declared.as_str()
+            ),
+        )),
+        (Some(stored), _) => Ok(stored),
+        (None, Some(ChannelMode::Relay)) if !allow_client_relay => Err(ClientError::new(
+            ErrorCode::RelayNotAllowed,
+            "App only relays channels named with its relay prefixes",
+        )),
+        (None, declared) => Ok(declared.unwrap_or_default()),
+    }
+}
+
+/// The frames bringing a member of a relay or encrypted channel up to date:
+/// the patches relayed after `since` while they are all logged, otherwise a
+/// Scan of what the channel keeps. That is the last patch of a relay channel,
+/// and nothing for an encrypted one whose log is sent after it page by page.
+async fn relay_catch_up(
+    patch_log: &dyn PatchLog,
+    relay_store: &dyn RelayStore,
+    participant: &Participant,
+    channel_id: &str,
+    since: Option<u64>,
+) -> Result<Vec<OutgoingMessage>, Box<dyn std::error::Error>> {
+    let app_id = &participant.app_id;
+
+    if let Some(since) = since {
+        let missed = patch_log.since(app_id, channel_id, since).await?;
+
+        if let Some(missed) = missed {
+            return Ok(missed
+                .into_iter()
+                .map(|patch| OutgoingMessage::Patch {
+                    channel_id: channel_id.to_string(),
+                    payload: patch.payload,
+                    seq: Some(patch.seq),
+                })
+                .collect());
+        }
+    }
+
+    // Read before the last patch, which then is at least as recent
+    let seq = if participant.protocol_version.has_sequence_numbers() {
+        Some(patch_log.last_seq(app_id, channel_id).await?)
+    } else {
+        None
+    };
+
+    let last_patch = if participant.relay_keep_last && participant.mode == ChannelMode::Relay {
+        relay_store.get_last(app_id, channel_id).await?
+    } else {
+        None
+    };
+
+    Ok(vec![OutgoingMessage::Scan {
+        channel_id: channel_id.to_string(),
+        state_update: last_patch.unwrap_or_default(),
+        seq,
+    }])
+}
 
 pub struct MessageHandler {
     publisher: Arc<dyn MessagePublisher>,
+    patch_publisher: Arc<dyn PatchPublisher>,
     storage: Arc<dyn DocumentStorage>,
+    patch_log: Arc<dyn PatchLog>,
+    membership: Arc<dyn MembershipIndex>,
+    relay_store: Arc<dyn RelayStore>,
+    encrypted_log: Arc<dyn EncryptedLog>,
+    document_cache: Arc<DocumentCache>,
+    resume_store: Arc<ResumeStore>,
     metrics_collector: Arc<MetricsCollector>,
+    quota_enforcer: Arc<QuotaEnforcer>,
 }
 
 impl MessageHandler {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         publisher: Arc<dyn MessagePublisher>,
+        patch_publisher: Arc<dyn PatchPublisher>,
         storage: Arc<dyn DocumentStorage>,
+        patch_log: Arc<dyn PatchLog>,
+        membership: Arc<dyn MembershipIndex>,
+        relay_store: Arc<dyn RelayStore>,
+        encrypted_log: Arc<dyn EncryptedLog>,
+        document_cache: Arc<DocumentCache>,
+        resume_store: Arc<ResumeStore>,
         metrics_collector: Arc<MetricsCollector>,
+        quota_enforcer: Arc<QuotaEnforcer>,
     ) -> Self {
         Self {
             publisher,
+            patch_publisher,
             storage,
+            patch_log,
+            membership,
+            relay_store,
+            encrypted_log,
+            document_cache,
+            resume_store,
             metrics_collector,
+            quota_enforcer,
+        }
+    }
+
+    /// How the channel treats patches, `None` for a channel not in use. It
+    /// is relayed when named with one of the app's relay prefixes, otherwise
+    /// it keeps the mode it was created in.
+    async fn stored_mode(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+        relay_prefixed: bool,
+    ) -> Result<Option<ChannelMode>, Box<dyn std::error::Error>> {
+        if relay_prefixed {
+            return Ok(Some(ChannelMode::Relay));
+        }
+
+        if self.encrypted_log.is_encrypted(app_id, channel_id).await? {
+            return Ok(Some(ChannelMode::Encrypted));
+        }
+
+        if self.relay_store.is_relay(app_id, channel_id).await? {
+            return Ok(Some(ChannelMode::Relay));
+        }
+
+        let document = self.storage.get_document(app_id, channel_id).await?;
+
+        Ok(document.map(|_| ChannelMode::Document))
+    }
+
+    /// How the channel treats patches, as stored or as the joining client
+    /// declares for a new channel. `declared` is `None` when the client left
+    /// it to the channel.
+    async fn channel_mode(
+        &self,
+        state: &Mutex<ConnectionState>,
+        channel_id: &str,
+        declared: Option<ChannelMode>,
+    ) -> Result<ChannelMode, Box<dyn std::error::Error>> {
+        let (app_id, relay_prefixed, allow_client_relay) = {
+            let state = state.lock().await;
+            let relay_prefixed = state
+                .relay_channel_prefixes
+                .iter()
+                .any(|prefix| channel_id.starts_with(prefix.as_str()));
+
+            (
+                state.app_id.clone(),
+                relay_prefixed,
+                state.allow_client_relay,
+            )
+        };
+
+        let stored = self
+            .stored_mode(&app_id, channel_id, relay_prefixed)
+            .await?;
+        let mode = settle_mode(stored, declared, allow_client_relay)
+            .map_err(|e| e.with_channel(channel_id))?;
+
+        match mode {
+            ChannelMode::Relay if stored.is_none() => {
+                self.relay_store.declare(&app_id, channel_id).await?
+            }
+            // Every join keeps the encrypted log from expiring
+            ChannelMode::Encrypted => self.encrypted_log.declare(&app_id, channel_id).await?,
+            _ => {}
+        }
+
+        Ok(mode)
+    }
+
+    /// Add the channel to the connection
+    async fn join_channel(
+        &self,
+        state: &Mutex<ConnectionState>,
+        channel_id: &str,
+        mode: ChannelMode,
+    ) -> Result<Participant, Box<dyn std::error::Error>> {
+        let participant = {
+            let mut state = state.lock().await;
+
+            if state.channel_ids.insert(channel_id.to_string()) {
+                state.gauges.add_channel();
+            }
+
+            state.channel_modes.insert(channel_id.to_string(), mode);
+
+            Participant {
+                app_id: state.app_id.clone(),
+                user_id: state.user_id.clone(),
+                connection_id: state.connection_id,
+                protocol_version: state.protocol_version,
+                update_encoding: state.update_encoding,
+                metadata: match &state.custom {
+                    JsonValue::Null => json!({}),
+                    custom => custom.clone(),
+                },
+                members_in_document: state.members_in_document,
+                mode,
+                relay_keep_last: state.relay_keep_last,
+            }
+        };
+
+        self.quota_enforcer
+            .acquire_channel(&participant.app_id, channel_id)
+            .await?;
+        self.seed_members(&participant.app_id, channel_id).await?;
+
+        Ok(participant)
+    }
+
+    /// Seed the membership index of a channel in use before it was kept,
+    /// from the members projected into the document as every app did then
+    async fn seed_members(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.membership.is_seeded(app_id, channel_id).await? {
+            return Ok(());
+        }
+
+        let document = self.storage.get_document(app_id, channel_id).await?;
+        let mut members = Vec::new();
+
+        if let Some(update) = document {
+            let crdt = CrdtDocument::from_update(&update).await;
+
+            for user_id in crdt.get_members().await {
+                let metadata = crdt
+                    .get_value(&["members", &user_id])
+                    .await
+                    .unwrap_or_else(|| json!({}));
+
+                members.push((user_id, metadata));
+            }
+        }
+
+        self.membership.seed(app_id, channel_id, &members).await
+    }
+
+    /// Add the participant to the members of an existing document, and when
+    /// they are projected into it let the other members know. Returns the
+    /// state to send to the participant, left empty unless `with_state` as
+    /// a resuming participant catches up on patches instead.
+    async fn add_member(
+        &self,
+        participant: &Participant,
+        channel_id: &str,
+        existing_update: Vec<u8>,
+        with_state: bool,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let Participant {
+            app_id,
+            user_id,
+            metadata,
+            ..
+        } = participant;
+
+        let recipients = self
+            .membership
+            .get_member_ids(app_id, channel_id)
+            .await?
+            .into_iter()
+            .filter(|member| member != user_id)
+            .collect();
+
+        self.membership
+            .add_member(app_id, channel_id, user_id, metadata)
+            .await?;
+
+        if !participant.members_in_document {
+            return Ok(if with_state {
+                existing_update
+            } else {
+                Vec::new()
+            });
+        }
+
+        let mut crdt = CrdtDocument::from_update(&existing_update).await;
+        let prev_state_vector = crdt.state_vector().await;
+
+        crdt.insert_value(&["members", user_id], metadata.clone())
+            .await?;
+
+        let state_update = if with_state {
+            crdt.get_state_as_update().await
+        } else {
+            Vec::new()
+        };
+        let member_update = crdt.to_update(&prev_state_vector).await;
+
+        self.publisher
+            .publish(BroadcastMessage::Patch {
+                app_id: app_id.to_string(),
+                senders: vec![user_id.to_string()],
+                channel_id: channel_id.to_string(),
+                payload: member_update,
+                recipients,
+                without_sender: HashMap::new(),
+                seq: None,
+                relay: false,
+                trace_context: telemetry::current_context(),
+            })
+            .await?;
+
+        Ok(state_update)
+    }
+
+    /// Add the participant to the members of a relay or encrypted channel
+    /// and bring it up to date. Joiners of an encrypted channel get its log
+    /// after the Scan, a page at a time however long it grew.
+    async fn join_relay(
+        &self,
+        write: &WsWrite,
+        participant: &Participant,
+        channel_id: &str,
+        since: Option<u64>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Participant {
+            app_id,
+            user_id,
+            metadata,
+            ..
+        } = participant;
+
+        self.membership
+            .add_member(app_id, channel_id, user_id, metadata)
+            .await?;
+
+        let frames = relay_catch_up(
+            self.patch_log.as_ref(),
+            self.relay_store.as_ref(),
+            participant,
+            channel_id,
+            since,
+        )
+        .await?;
+
+        // A resumed member caught up on the patches alone
+        let starts_over = matches!(frames.first(), Some(OutgoingMessage::Scan { .. }));
+
+        self.send_catch_up(write, participant, channel_id, frames)
+            .await?;
+
+        if participant.mode != ChannelMode::Encrypted || !starts_over {
+            return Ok(());
         }
+
+        let mut start = Some(0);
+
+        while let Some(from) = start {
+            let (frames, next) =
+                encrypted_log_page(self.encrypted_log.as_ref(), app_id, channel_id, from).await?;
+
+            if !frames.is_empty() {
+                self.send_catch_up(write, participant, channel_id, frames)
+                    .await?;
+            }
+
+            start = next;
+        }
+
+        Ok(())
+    }
+
+    /// Tell the client whether its patch was saved once its batch is flushed
+    async fn acknowledge(
+        write: WsWrite,
+        protocol_version: ProtocolVersion,
+        binary: bool,
+        channel_id: String,
+        request_id: Option<String>,
+        outcome: oneshot::Receiver<PatchOutcome>,
+    ) {
+        // Dropped without an outcome when the batch never got flushed
+        let outcome = outcome.await.unwrap_or(Err(PatchRejected::NotSaved));
+        let (label, frame) = acknowledgement(channel_id, request_id, outcome);
+
+        let message = match frame.encode(protocol_version, binary) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Error converting {label} frame: {e}");
+                return;
+            }
+        };
+
+        let message_size = message.len();
+
+        if let Err(e) = write.lock().await.1.send(message).await {
+            debug!("Error sending {label} frame: {e}");
+            return;
+        }
+
+        MESSAGES_TOTAL.with_label_values(&["out", label]).inc();
+        MESSAGE_BYTES_TOTAL
+            .with_label_values(&["out", label])
+            .inc_by(message_size as u64);
+    }
+
+    /// Send the frames bringing a joining connection up to date with the
+    /// channel in its update encoding, accounted as a single Init transfer.
+    /// Relayed patches are sent as they are.
+    async fn send_catch_up(
+        &self,
+        write: &WsWrite,
+        participant: &Participant,
+        channel_id: &str,
+        frames: Vec<OutgoingMessage>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut messages = Vec::with_capacity(frames.len());
+
+        for mut frame in frames {
+            let (label, update) = match &mut frame {
+                OutgoingMessage::Scan { state_update, .. } => ("scan", state_update),
+                OutgoingMessage::Patch { payload, .. } => ("patch", payload),
+                _ => return Err("Only Scan and Patch frames catch up".into()),
+            };
+
+            if participant.mode == ChannelMode::Document {
+                *update = participant.update_encoding.transcode(update)?;
+            }
+
+            messages.push((label, frame.to_ws_message()?));
+        }
+
+        let Participant {
+            app_id,
+            connection_id,
+            ..
+        } = participant;
+
+        let message_size = messages
+            .iter()
+            .map(|(_, message)| message.len())
+            .sum::<usize>();
+
+        self.quota_enforcer
+            .record_transfer(app_id, message_size)
+            .await?;
+
+        self.metrics_collector
+            .record_data_transfer(DataTransferMetric::new(
+                channel_id.to_string(),
+                *connection_id,
+                MessageType::Init,
+                message_size,
+                1,
+            ))
+            .await?;
+
+        let mut write = write.lock().await;
+
+        for (label, message) in messages {
+            let message_size = message.len();
+
+            write.1.send(message).await?;
+
+            MESSAGES_TOTAL.with_label_values(&["out", label]).inc();
+            MESSAGE_BYTES_TOTAL
+                .with_label_values(&["out", label])
+                .inc_by(message_size as u64);
+        }
+
+        Ok(())
     }
 }
 
 #[async_trait]
 impl WsMessageHandler for MessageHandler {
+    #[instrument(name = "ws.handle", skip_all, fields(message_type = message.message_type()))]
     async fn handle(
         &self,
         write: &WsWrite,
@@ -50,60 +552,81 @@ impl WsMessageHandler for MessageHandler {
             IncomingMessage::Init {
                 channel_id,
                 init_state,
+                relay,
+                encrypted,
+                ..
             } => {
-                let app_id: String;
-                let user_id: String;
-                let connection_id: Uuid;
-
-                {
-                    let mut state = state.lock().await;
-                    app_id = state.app_id.clone();
-                    user_id = state.user_id.clone();
-                    state.channel_ids.insert(channel_id.clone());
-                    connection_id = state.connection_id;
-                }
+                let init_state = match init_state {
+                    Some(state_bytes) => serde_json::from_slice(&state_bytes)
+                        .map_err(|e| e.to_string())
+                        .and_then(CrdtValue::from_schema)
+                        .map_err(|e| {
+                            ClientError::new(ErrorCode::InvalidInitState, e)
+                                .with_channel(&channel_id)
+                        })?,
+                    None => CrdtValue::Map(vec![]),
+                };
 
-                let existing_update = self.storage.get_document(&app_id, &channel_id).await?;
+                // Encrypted channels relay too
+                let declared = match (encrypted, relay) {
+                    (Some(true), _) => Some(ChannelMode::Encrypted),
+                    (_, Some(true)) => Some(ChannelMode::Relay),
+                    (None, None) => None,
+                    _ => Some(ChannelMode::Document),
+                };
 
-                let state_update = if let Some(existing_update) = existing_update {
-                    let mut crdt = CrdtDocument::from_update(&existing_update).await;
+                let mode = self.channel_mode(&state, &channel_id, declared).await?;
+                let participant = self.join_channel(&state, &channel_id, mode).await?;
 
-                    let prev_state_vector = crdt.state_vector().await;
-                    let recipients = crdt.get_members().await;
+                if participant.mode != ChannelMode::Document {
+                    return self
+                        .join_relay(write, &participant, &channel_id, None)
+                        .await;
+                }
 
-                    crdt.insert_value(&["members", &user_id], json!({})).await;
+                let Participant {
+                    app_id,
+                    user_id,
+                    metadata,
+                    ..
+                } = &participant;
 
-                    let state_update = crdt.get_state_as_update().await;
-                    let member_update = crdt.to_update(&prev_state_vector).await;
+                // Read before the document, which then holds every patch up to it
+                let seq = if participant.protocol_version.has_sequence_numbers() {
+                    Some(self.patch_log.last_seq(app_id, &channel_id).await?)
+                } else {
+                    None
+                };
 
-                    self.publisher
-                        .publish(BroadcastMessage::Patch {
-                            app_id: app_id.clone(),
-                            sender: user_id.clone(),
-                            channel_id: channel_id.clone(),
-                            payload: member_update,
-                            recipients,
-                        })
-                        .await?;
+                let existing_update = self.storage.get_document(app_id, &channel_id).await?;
 
-                    state_update
+                let state_update = if let Some(existing_update) = existing_update {
+                    self.add_member(&participant, &channel_id, existing_update, true)
+                        .await?
                 } else {
-                    let init_state = if let Some(state_bytes) = init_state {
-                        let json_str = String::from_utf8(state_bytes)?;
-                        serde_json::from_str(&json_str)?
-                    } else {
-                        json!({})
-                    };
-
                     let mut crdt = CrdtDocument::new().await;
-                    crdt.insert_value(&["state"], init_state.clone()).await;
-                    crdt.insert_value(&["members", &user_id], json!({})).await;
+                    crdt.insert(&["state"], init_state).await?;
+
+                    if participant.members_in_document {
+                        crdt.insert_value(&["members", user_id], metadata.clone())
+                            .await?;
+                    }
 
                     let state_update = crdt.get_state_as_update().await;
 
+                    self.quota_enforcer
+                        .check_document_size(app_id, state_update.len())
+                        .await?;
+
                     self.storage
-                        .save_document(&app_id, &channel_id, &state_update)
+                        .save_document(app_id, &channel_id, &state_update)
                         .await?;
+                    self.membership
+                        .add_member(app_id, &channel_id, user_id, metadata)
+                        .await?;
+
+                    // Whatever is cached belongs to a deleted document
+                    self.document_cache.remove(app_id, &channel_id).await;
 
                     state_update
                 };
@@ -111,73 +634,279 @@ impl WsMessageHandler for MessageHandler {
                 let outgoing_message = OutgoingMessage::Scan {
                     channel_id: channel_id.clone(),
                     state_update,
+                    seq,
                 };
 
-                let binary_message = outgoing_message.to_ws_message()?;
-                let message_size = binary_message.len();
+                self.send_catch_up(write, &participant, &channel_id, vec![outgoing_message])
+                    .await?;
+            }
+            IncomingMessage::Resume {
+                channel_id,
+                resume_token,
+                last_seq,
+                ..
+            } => {
+                let (app_id, user_id) = {
+                    let state = state.lock().await;
+                    (state.app_id.clone(), state.user_id.clone())
+                };
 
-                self.metrics_collector
-                    .record_data_transfer(DataTransferMetric::new(
-                        channel_id.clone(),
-                        connection_id,
-                        MessageType::Init,
-                        message_size,
-                        1,
-                    ))
+                let issued_to = self.resume_store.get(&resume_token).await?;
+
+                if issued_to != Some((app_id, user_id)) {
+                    return Err(ClientError::new(
+                        ErrorCode::ResumeExpired,
+                        "Resume token expired, send Init instead",
+                    )
+                    .with_channel(&channel_id)
+                    .into());
+                }
+
+                let mode = self.channel_mode(&state, &channel_id, None).await?;
+                let participant = self.join_channel(&state, &channel_id, mode).await?;
+
+                if participant.mode != ChannelMode::Document {
+                    return self
+                        .join_relay(write, &participant, &channel_id, Some(last_seq))
+                        .await;
+                }
+
+                let app_id = &participant.app_id;
+
+                let missed = self.patch_log.since(app_id, &channel_id, last_seq).await?;
+
+                // Without the full history since `last_seq` the client starts over
+                let seq = match missed {
+                    Some(_) => None,
+                    None => Some(self.patch_log.last_seq(app_id, &channel_id).await?),
+                };
+
+                let existing_update = self
+                    .storage
+                    .get_document(app_id, &channel_id)
+                    .await?
+                    .ok_or_else(|| {
+                        ClientError::new(
+                            ErrorCode::DocumentNotFound,
+                            "Cannot resume a deleted document, send Init instead",
+                        )
+                        .with_channel(&channel_id)
+                    })?;
+
+                let state_update = self
+                    .add_member(&participant, &channel_id, existing_update, missed.is_none())
                     .await?;
 
-                write.lock().await.1.send(binary_message).await?;
+                let frames = match missed {
+                    Some(missed) => missed
+                        .into_iter()
+                        .map(|patch| OutgoingMessage::Patch {
+                            channel_id: channel_id.clone(),
+                            payload: patch.payload,
+                            seq: Some(patch.seq),
+                        })
+                        .collect(),
+                    None => vec![OutgoingMessage::Scan {
+                        channel_id: channel_id.clone(),
+                        state_update,
+                        seq,
+                    }],
+                };
+
+                self.send_catch_up(write, &participant, &channel_id, frames)
+                    .await?;
             }
-            IncomingMessage::Patch { channel_id, delta } => {
+            IncomingMessage::Patch {
+                channel_id,
+                delta,
+                request_id,
+            } => {
                 let state = state.lock().await;
                 let app_id = &state.app_id;
+                let mode = state
+                    .channel_modes
+                    .get(&channel_id)
+                    .copied()
+                    .unwrap_or_default();
 
-                let state_update = self
-                    .storage
-                    .get_document(app_id, &channel_id)
-                    .await?
-                    .ok_or("Cannot apply patch to non-existent document")?;
+                // Relayed patches are opaque, only document patches are updates
+                let delta = match mode {
+                    ChannelMode::Relay => delta,
+                    ChannelMode::Encrypted => {
+                        let log_size = self.encrypted_log.size(app_id, &channel_id).await?;
 
-                let crdt = CrdtDocument::from_update(&state_update).await;
+                        self.quota_enforcer
+                            .check_document_size(app_id, log_size + delta.len())
+                            .await?;
 
-                let recipients = crdt
-                    .get_members()
-                    .await
+                        delta
+                    }
+                    ChannelMode::Document => {
+                        let delta = state.update_encoding.canonicalize(&delta).map_err(|e| {
+                            ClientError::new(ErrorCode::InvalidUpdate, e).with_channel(&channel_id)
+                        })?;
+
+                        let document_size = self
+                            .document_cache
+                            .size(app_id, &channel_id)
+                            .await?
+                            .ok_or_else(|| {
+                                ClientError::new(
+                                    ErrorCode::DocumentNotFound,
+                                    "Cannot apply patch to non-existent document",
+                                )
+                                .with_channel(&channel_id)
+                            })?;
+
+                        self.quota_enforcer
+                            .check_document_size(app_id, document_size + delta.len())
+                            .await?;
+
+                        delta
+                    }
+                };
+
+                let recipients = self
+                    .membership
+                    .get_member_ids(app_id, &channel_id)
+                    .await?
                     .into_iter()
                     .filter(|member| member != &state.user_id)
                     .collect::<Vec<String>>();
 
-                let message_size = delta.len();
+                // Charged by the bus proxy once the published size is known
+                self.quota_enforcer.check_transfer(app_id).await?;
+
+                let message = BroadcastMessage::Patch {
+                    app_id: state.app_id.clone(),
+                    senders: vec![state.user_id.clone()],
+                    channel_id: channel_id.clone(),
+                    payload: delta,
+                    recipients,
+                    without_sender: HashMap::new(),
+                    seq: None,
+                    relay: mode != ChannelMode::Document,
+                    trace_context: telemetry::current_context(),
+                };
+
+                let retention = match mode {
+                    ChannelMode::Document => None,
+                    ChannelMode::Relay if state.relay_keep_last => Some(RelayRetention::Last),
+                    ChannelMode::Relay => Some(RelayRetention::Nothing),
+                    ChannelMode::Encrypted => Some(RelayRetention::All),
+                };
+
+                // The transfer is recorded once the patch reaches the others
+                let outcome = match retention {
+                    Some(retention) => {
+                        self.patch_publisher
+                            .publish_relayed(message, state.connection_id, retention)
+                            .await
+                    }
+                    None => {
+                        self.patch_publisher
+                            .publish_acked(message, state.connection_id)
+                            .await
+                    }
+                };
+
+                if state.protocol_version.has_sequence_numbers() {
+                    tokio::spawn(Self::acknowledge(
+                        write.clone(),
+                        state.protocol_version,
+                        state.binary,
+                        channel_id,
+                        request_id,
+                        outcome,
+                    ));
+                }
+            }
+            IncomingMessage::KeyExchange {
+                channel_id,
+                recipient,
+                payload,
+                ..
+            } => {
+                let state = state.lock().await;
+                let app_id = &state.app_id;
+
+                if state.channel_modes.get(&channel_id) != Some(&ChannelMode::Encrypted) {
+                    return Err(ClientError::new(
+                        ErrorCode::InvalidMessage,
+                        "Keys are only exchanged on joined encrypted channels",
+                    )
+                    .with_channel(&channel_id)
+                    .into());
+                }
+
+                let recipient_connection = recipient
+                    .map(|recipient| {
+                        recipient.parse::<Uuid>().map_err(|_| {
+                            ClientError::new(
+                                ErrorCode::InvalidMessage,
+                                "Key exchange recipient must be a connection id",
+                            )
+                            .with_channel(&channel_id)
+                        })
+                    })
+                    .transpose()?;
+
+                // Every member, whose devices other than the sending one get
+                // the key material unless it is meant for a single device
+                let recipients = self.membership.get_member_ids(app_id, &channel_id).await?;
+                let deliveries = match recipient_connection {
+                    Some(_) => 1,
+                    None => recipients.len(),
+                };
+
+                let message_size = payload.len();
+
+                self.quota_enforcer
+                    .record_transfer(app_id, message_size * deliveries)
+                    .await?;
 
                 self.metrics_collector
                     .record_data_transfer(DataTransferMetric::new(
                         channel_id.clone(),
                         state.connection_id,
-                        MessageType::Patch,
+                        MessageType::Broadcast,
                         message_size,
-                        recipients.len(),
+                        deliveries,
                     ))
                     .await?;
 
                 self.publisher
-                    .publish(BroadcastMessage::Patch {
-                        app_id: state.app_id.clone(),
+                    .publish(BroadcastMessage::KeyExchange {
+                        app_id: app_id.clone(),
+                        channel_id,
                         sender: state.user_id.clone(),
-                        channel_id: channel_id.clone(),
-                        payload: delta,
+                        sender_connection: state.connection_id.to_string(),
                         recipients,
+                        recipient_connection: recipient_connection
+                            .map(|connection_id| connection_id.to_string()),
+                        payload,
+                        trace_context: telemetry::current_context(),
                     })
                     .await?;
             }
+            IncomingMessage::Auth { .. } => {
+                return Err(
+                    ClientError::new(ErrorCode::InvalidMessage, "Already authenticated").into(),
+                );
+            }
             IncomingMessage::Unknown => {
-                error!("Unknown message type");
-                return Err("Unknown message type".into());
+                return Err(ClientError::new(
+                    ErrorCode::UnknownMessageType,
+                    "Unknown message type",
+                )
+                .into());
             }
         }
 
         Ok(())
     }
 
+    #[instrument(name = "ws.close", skip_all)]
     async fn on_close(
         &self,
         state: Arc<Mutex<ConnectionState>>,
@@ -185,38 +914,75 @@ impl WsMessageHandler for MessageHandler {
         let app_id: String;
         let user_id: String;
         let channel_ids: HashSet<String, RandomState>;
+        let channel_modes: HashMap<String, ChannelMode>;
+        let members_in_document: bool;
 
         {
             let state = state.lock().await;
             app_id = state.app_id.clone();
             user_id = state.user_id.clone();
             channel_ids = state.channel_ids.clone();
+            channel_modes = state.channel_modes.clone();
+            members_in_document = state.members_in_document;
         }
 
         for channel_id in channel_ids {
+            // Only a seeded index knows whether anyone is left
+            self.seed_members(&app_id, &channel_id).await?;
+
+            let remaining = self
+                .membership
+                .remove_member(&app_id, &channel_id, &user_id)
+                .await?;
+
+            if remaining == Some(0) {
+                self.storage.delete_document(&app_id, &channel_id).await?;
+                self.document_cache.remove(&app_id, &channel_id).await;
+                self.patch_log.delete(&app_id, &channel_id).await?;
+                self.relay_store.delete(&app_id, &channel_id).await?;
+                // The encrypted log stays for late joiners until it expires
+                self.quota_enforcer
+                    .release_channel(&app_id, &channel_id)
+                    .await?;
+                continue;
+            }
+
+            // Relay and encrypted channels have no document to project members into
+            let has_document = channel_modes
+                .get(&channel_id)
+                .is_none_or(|mode| *mode == ChannelMode::Document);
+
+            if !members_in_document || !has_document {
+                continue;
+            }
+
             let Some(update) = self.storage.get_document(&app_id, &channel_id).await? else {
                 continue;
             };
 
             let mut crdt = CrdtDocument::from_update(&update).await;
+
+            if !crdt.get_members().await.contains(&user_id) {
+                continue;
+            }
+
             let prev_state_vector = crdt.state_vector().await;
             crdt.remove_member(&user_id).await;
 
             let patch = crdt.to_update(&prev_state_vector).await;
-            let members = crdt.get_members().await;
-
-            if members.is_empty() {
-                self.storage.delete_document(&app_id, &channel_id).await?;
-                continue;
-            }
+            let members = self.membership.get_member_ids(&app_id, &channel_id).await?;
 
             self.publisher
                 .publish(BroadcastMessage::Patch {
                     app_id: app_id.clone(),
-                    sender: user_id.clone(),
+                    senders: vec![user_id.clone()],
                     channel_id: channel_id.clone(),
                     payload: patch,
                     recipients: members,
+                    without_sender: HashMap::new(),
+                    seq: None,
+                    relay: false,
+                    trace_context: telemetry::current_context(),
                 })
                 .await?;
         }
@@ -224,3 +990,394 @@ impl WsMessageHandler for MessageHandler {
         Ok(())
     }
 }
+
+/// Frame answering a patch, and its label in the message metrics
+fn acknowledgement(
+    channel_id: String,
+    request_id: Option<String>,
+    outcome: PatchOutcome,
+) -> (&'static str, OutgoingMessage) {
+    match outcome {
+        Ok(seq) => (
+            "ack",
+            OutgoingMessage::Ack {
+                channel_id,
+                request_id,
+                seq,
+            },
+        ),
+        Err(rejected) => (
+            "nack",
+            OutgoingMessage::Nack {
+                channel_id,
+                request_id,
+                message: rejected.to_string(),
+            },
+        ),
+    }
+}
+
+/// One page of the encrypted log from its `start`th update as Patch frames,
+/// with where the next page starts, `None` once the log is exhausted
+async fn encrypted_log_page(
+    encrypted_log: &dyn EncryptedLog,
+    app_id: &str,
+    channel_id: &str,
+    start: usize,
+) -> Result<(Vec<OutgoingMessage>, Option<usize>), Box<dyn std::error::Error>> {
+    let updates = encrypted_log
+        .get_updates(app_id, channel_id, start, ENCRYPTED_LOG_PAGE_SIZE)
+        .await?;
+    let count = updates.len();
+
+    let frames = updates
+        .into_iter()
+        .map(|payload| OutgoingMessage::Patch {
+            channel_id: channel_id.to_string(),
+            payload,
+            seq: None,
+        })
+        .collect();
+
+    // A short page is the last one
+    let next = (count == ENCRYPTED_LOG_PAGE_SIZE).then_some(start + count);
+
+    Ok((frames, next))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{LoggedPatch, memory::MemoryEncryptedLog};
+
+    // A relay channel whose log kept the broadcasts numbered 3 and 4
+    struct RelayChannel {
+        logged: Vec<LoggedPatch>,
+        last: Option<Vec<u8>>,
+    }
+
+    impl RelayChannel {
+        fn new() -> Self {
+            Self {
+                logged: vec![
+                    LoggedPatch {
+                        seq: 3,
+                        payload: b"third".to_vec(),
+                    },
+                    LoggedPatch {
+                        seq: 4,
+                        payload: b"fourth".to_vec(),
+                    },
+                ],
+                last: Some(b"fourth".to_vec()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl PatchLog for RelayChannel {
+        async fn append(
+            &self,
+            _app_id: &str,
+            _channel_id: &str,
+            _count: u64,
+            _payload: &[u8],
+        ) -> Result<u64, Box<dyn std::error::Error>> {
+            unreachable!("joining never appends")
+        }
+
+        async fn last_seq(
+            &self,
+            _app_id: &str,
+            _channel_id: &str,
+        ) -> Result<u64, Box<dyn std::error::Error>> {
+            Ok(self.logged.last().map_or(0, |patch| patch.seq))
+        }
+
+        async fn since(
+            &self,
+            _app_id: &str,
+            _channel_id: &str,
+            seq: u64,
+        ) -> Result<Option<Vec<LoggedPatch>>, Box<dyn std::error::Error>> {
+            let oldest = self.logged.first().map_or(1, |patch| patch.seq);
+
+            Ok((seq + 1 >= oldest).then(|| {
+                self.logged
+                    .iter()
+                    .filter(|patch| patch.seq > seq)
+                    .cloned()
+                    .collect()
+            }))
+        }
+
+        async fn delete(
+            &self,
+            _app_id: &str,
+            _channel_id: &str,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            unreachable!("joining never deletes")
+        }
+    }
+
+    #[async_trait]
+    impl RelayStore for RelayChannel {
+        async fn declare(
+            &self,
+            _app_id: &str,
+            _channel_id: &str,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        async fn is_relay(
+            &self,
+            _app_id: &str,
+            _channel_id: &str,
+        ) -> Result<bool, Box<dyn std::error::Error>> {
+            Ok(true)
+        }
+
+        async fn save_last(
+            &self,
+            _app_id: &str,
+            _channel_id: &str,
+            _payload: &[u8],
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            unreachable!("joining never relays")
+        }
+
+        async fn get_last(
+            &self,
+            _app_id: &str,
+            _channel_id: &str,
+        ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+            Ok(self.last.clone())
+        }
+
+        async fn delete(
+            &self,
+            _app_id: &str,
+            _channel_id: &str,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            unreachable!("joining never deletes")
+        }
+    }
+
+    fn participant(relay_keep_last: bool) -> Participant {
+        Participant {
+            app_id: "app".to_string(),
+            user_id: "user".to_string(),
+            connection_id: Uuid::new_v4(),
+            protocol_version: ProtocolVersion::V3,
+            update_encoding: UpdateEncoding::V2,
+            metadata: json!({}),
+            members_in_document: false,
+            mode: ChannelMode::Relay,
+            relay_keep_last,
+        }
+    }
+
+    async fn catch_up(relay_keep_last: bool, since: Option<u64>) -> Vec<OutgoingMessage> {
+        let channel = RelayChannel::new();
+
+        relay_catch_up(
+            &channel,
+            &channel,
+            &participant(relay_keep_last),
+            "cursors",
+            since,
+        )
+        .await
+        .unwrap()
+    }
+
+    // Kind, payload and sequence number of each frame
+    fn frames(messages: Vec<OutgoingMessage>) -> Vec<(&'static str, Vec<u8>, Option<u64>)> {
+        messages
+            .into_iter()
+            .map(|message| match message {
+                OutgoingMessage::Scan {
+                    state_update, seq, ..
+                } => ("scan", state_update, seq),
+                OutgoingMessage::Patch { payload, seq, .. } => ("patch", payload, seq),
+                other => panic!("unexpected frame {other:?}"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn relay_joiners_get_the_last_patch_when_kept() {
+        assert_eq!(
+            frames(catch_up(true, None).await),
+            vec![("scan", b"fourth".to_vec(), Some(4))]
+        );
+    }
+
+    #[tokio::test]
+    async fn relay_joiners_get_an_empty_scan_unless_kept() {
+        assert_eq!(
+            frames(catch_up(false, None).await),
+            vec![("scan", vec![], Some(4))]
+        );
+    }
+
+    #[tokio::test]
+    async fn resuming_relay_members_get_the_patches_they_missed() {
+        assert_eq!(
+            frames(catch_up(true, Some(2)).await),
+            vec![
+                ("patch", b"third".to_vec(), Some(3)),
+                ("patch", b"fourth".to_vec(), Some(4)),
+            ]
+        );
+        assert_eq!(frames(catch_up(true, Some(4)).await), vec![]);
+    }
+
+    #[tokio::test]
+    async fn resuming_relay_members_start_over_once_the_log_moved_on() {
+        assert_eq!(
+            frames(catch_up(true, Some(1)).await),
+            vec![("scan", b"fourth".to_vec(), Some(4))]
+        );
+    }
+
+    #[test]
+    fn declaring_another_mode_than_the_channel_has_is_rejected() {
+        for (stored, declared) in [
+            (ChannelMode::Relay, ChannelMode::Document),
+            (ChannelMode::Document, ChannelMode::Relay),
+            (ChannelMode::Encrypted, ChannelMode::Relay),
+        ] {
+            let error = settle_mode(Some(stored), Some(declared), true).unwrap_err();
+            assert_eq!(error.code, ErrorCode::ChannelModeMismatch);
+        }
+    }
+
+    #[test]
+    fn encrypted_joins_of_relay_channels_are_rejected() {
+        // Channels named with a relay prefix are stored as relay channels
+        let error =
+            settle_mode(Some(ChannelMode::Relay), Some(ChannelMode::Encrypted), true).unwrap_err();
+
+        assert_eq!(error.code, ErrorCode::ChannelModeMismatch);
+    }
+
+    #[test]
+    fn undeclared_joins_take_the_channel_as_it_is() {
+        assert_eq!(
+            settle_mode(Some(ChannelMode::Relay), None, false).unwrap(),
+            ChannelMode::Relay
+        );
+        assert_eq!(
+            settle_mode(Some(ChannelMode::Relay), Some(ChannelMode::Relay), false).unwrap(),
+            ChannelMode::Relay
+        );
+        assert_eq!(
+            settle_mode(None, None, false).unwrap(),
+            ChannelMode::Document
+        );
+    }
+
+    #[test]
+    fn clients_declare_relay_channels_only_where_the_app_allows() {
+        let error = settle_mode(None, Some(ChannelMode::Relay), false).unwrap_err();
+        assert_eq!(error.code, ErrorCode::RelayNotAllowed);
+
+        assert_eq!(
+            settle_mode(None, Some(ChannelMode::Relay), true).unwrap(),
+            ChannelMode::Relay
+        );
+    }
+
+    #[test]
+    fn saved_patches_are_acked_with_their_sequence() {
+        let (label, frame) = acknowledgement("doc".to_string(), Some("r1".to_string()), Ok(7));
+
+        assert_eq!(label, "ack");
+        assert!(matches!(
+            frame,
+            OutgoingMessage::Ack { channel_id, request_id: Some(request_id), seq: 7 }
+                if channel_id == "doc" && request_id == "r1"
+        ));
+    }
+
+    #[test]
+    fn rejected_patches_are_nacked_with_the_reason() {
+        for rejected in [
+            PatchRejected::Invalid,
+            PatchRejected::NotSaved,
+            PatchRejected::OwnerUnavailable,
+        ] {
+            let (label, frame) =
+                acknowledgement("doc".to_string(), Some("r1".to_string()), Err(rejected));
+
+            assert_eq!(label, "nack");
+            assert!(matches!(
+                frame,
+                OutgoingMessage::Nack { request_id: Some(request_id), message, .. }
+                    if request_id == "r1" && message == rejected.to_string()
+            ));
+        }
+    }
+
+    // Sizes of the pages a joiner is sent and the updates in them, in order
+    async fn encrypted_pages(updates: usize) -> (Vec<usize>, Vec<Vec<u8>>) {
+        let log = MemoryEncryptedLog::default();
+
+        for update in 0..updates as u32 {
+            log.append("app", "secret", &update.to_be_bytes())
+                .await
+                .unwrap();
+        }
+
+        let (mut sizes, mut sent) = (vec![], vec![]);
+        let mut start = Some(0);
+
+        while let Some(from) = start {
+            let (page, next) = encrypted_log_page(&log, "app", "secret", from)
+                .await
+                .unwrap();
+
+            sizes.push(page.len());
+            sent.extend(frames(page).into_iter().map(|(kind, payload, seq)| {
+                assert_eq!((kind, seq), ("patch", None));
+                payload
+            }));
+            start = next;
+        }
+
+        (sizes, sent)
+    }
+
+    fn updates(count: usize) -> Vec<Vec<u8>> {
+        (0..count as u32)
+            .map(|update| update.to_be_bytes().to_vec())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn encrypted_logs_are_sent_page_by_page_in_order() {
+        let count = 2 * ENCRYPTED_LOG_PAGE_SIZE + 3;
+
+        assert_eq!(
+            encrypted_pages(count).await,
+            (
+                vec![ENCRYPTED_LOG_PAGE_SIZE, ENCRYPTED_LOG_PAGE_SIZE, 3],
+                updates(count)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn encrypted_logs_of_whole_pages_end_on_an_empty_one() {
+        assert_eq!(
+            encrypted_pages(ENCRYPTED_LOG_PAGE_SIZE).await,
+            (
+                vec![ENCRYPTED_LOG_PAGE_SIZE, 0],
+                updates(ENCRYPTED_LOG_PAGE_SIZE)
+            )
+        );
+        assert_eq!(encrypted_pages(0).await, (vec![0], vec![]));
+    }
+}
@@ -1,6 +1,8 @@
 use super::{DataTransferMetric, MetricsRepository};
+use crate::observability::metrics::METRICS_COLLECTOR_QUEUE_DEPTH;
 use std::sync::Arc;
 use std::time::Duration;
+use time::OffsetDateTime;
 use tokio::sync::mpsc;
 use tokio::time::interval;
 use tracing::{debug, error, info};
@@ -14,6 +16,7 @@ const FLUSH_INTERVAL_SECONDS: u64 = 5;
 pub enum MetricsEvent {
     ConnectionStart { app_id: String, connection_id: Uuid },
     ConnectionEnd { connection_id: Uuid },
+    ConnectionEndAt { connection_id: Uuid, disconnected_at: OffsetDateTime },
     DataTransfer { metric: DataTransferMetric },
 }
 
@@ -52,6 +55,8 @@ impl MetricsCollector {
             return Err(Box::new(e));
         }
 
+        METRICS_COLLECTOR_QUEUE_DEPTH.inc();
+
         Ok(())
     }
 
@@ -68,6 +73,31 @@ impl MetricsCollector {
             error!("Failed to send connection end event: {e}");
             return Err(Box::new(e));
         }
+
+        METRICS_COLLECTOR_QUEUE_DEPTH.inc();
+
+        Ok(())
+    }
+
+    /// Record the end of a WebSocket connection that ended earlier, e.g. on a
+    /// node that died
+    pub async fn record_connection_end_at(
+        &self,
+        connection_id: &Uuid,
+        disconnected_at: OffsetDateTime,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let event = MetricsEvent::ConnectionEndAt {
+            connection_id: *connection_id,
+            disconnected_at,
+        };
+
+        if let Err(e) = self.sender.send(event) {
+            error!("Failed to send connection end event: {e}");
+            return Err(Box::new(e));
+        }
+
+        METRICS_COLLECTOR_QUEUE_DEPTH.inc();
+
         Ok(())
     }
 
@@ -83,10 +113,22 @@ impl MetricsCollector {
             return Err(Box::new(e));
         }
 
+        METRICS_COLLECTOR_QUEUE_DEPTH.inc();
+
         Ok(())
     }
 }
 
+#[cfg(test)]
+impl MetricsCollector {
+    /// A collector without a worker, its events are left in the receiver
+    pub fn detached() -> (Self, mpsc::UnboundedReceiver<MetricsEvent>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        (Self { sender }, receiver)
+    }
+}
+
 impl Clone for MetricsCollector {
     fn clone(&self) -> Self {
         Self {
@@ -122,6 +164,8 @@ impl MetricsWorker {
                 event = self.receiver.recv() => {
                     match event {
                         Some(event) => {
+                            METRICS_COLLECTOR_QUEUE_DEPTH.dec();
+
                             if let Err(e) = self.handle_event(event).await {
                                 error!("Failed to handle metrics event: {e}");
                             }
@@ -170,6 +214,15 @@ impl MetricsWorker {
                     .await?
             }
 
+            MetricsEvent::ConnectionEndAt {
+                connection_id,
+                disconnected_at,
+            } => {
+                self.repository
+                    .close_connection_session_at(&connection_id, disconnected_at)
+                    .await?;
+            }
+
             MetricsEvent::DataTransfer { metric } => {
                 self.data_transfer_batch.push(metric);
             }
@@ -2,8 +2,10 @@ pub mod collector;
 pub mod connection;
 pub mod data_transfer;
 pub mod repository;
+pub mod sweeper;
 
 pub use collector::MetricsCollector;
 pub use connection::ConnectionSession;
 pub use data_transfer::DataTransferMetric;
-pub use repository::MetricsRepository;
+pub use repository::{MetricsRepository, OpenConnectionSession};
+pub use sweeper::SessionSweeper;
@@ -1,10 +1,20 @@
 use async_trait::async_trait;
 use sqlx::PgPool;
+use time::OffsetDateTime;
 use tracing::{debug, error};
 use uuid::Uuid;
 
 use super::DataTransferMetric;
 
+/// A `connection_session` row that has not been closed yet
+#[derive(Debug, Clone)]
+pub struct OpenConnectionSession {
+    pub id: Uuid,
+    pub app_id: String,
+    pub connected_at: OffsetDateTime,
+    pub last_activity_at: Option<OffsetDateTime>,
+}
+
 #[derive(Debug, Clone)]
 pub struct MetricsRepository {
     pool: PgPool,
@@ -61,6 +71,75 @@ impl MetricsRepository {
         Ok(())
     }
 
+    /// Close a connection session at a known end time rather than now, for
+    /// connections that ended without `close_connection_session` running.
+    /// Returns whether the session was still open.
+    pub async fn close_connection_session_at(
+        &self,
+        connection_id: &Uuid,
+        disconnected_at: OffsetDateTime,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        debug!("Closing connection session {connection_id} at {disconnected_at}");
+
+        let result = sqlx::query!(
+            r#"
+            UPDATE connection_session
+            SET
+                disconnected_at = GREATEST($2, connected_at),
+                duration_ms = GREATEST(
+                    EXTRACT(EPOCH FROM ($2::TIMESTAMPTZ - connected_at)) * 1000,
+                    0
+                )::BIGINT
+            WHERE id = $1
+            AND disconnected_at IS NULL
+            "#,
+            connection_id,
+            disconnected_at
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Open connection sessions that started before `connected_before`, with
+    /// their last recorded data transfer. Paged by id, starting after `after`.
+    pub async fn open_connection_sessions(
+        &self,
+        connected_before: OffsetDateTime,
+        after: Option<Uuid>,
+        limit: i64,
+    ) -> Result<Vec<OpenConnectionSession>, Box<dyn std::error::Error>> {
+        let sessions = sqlx::query_as!(
+            OpenConnectionSession,
+            r#"
+            SELECT
+                cs.id,
+                cs.app_id,
+                cs.connected_at,
+                activity.last_activity_at
+            FROM connection_session cs
+            LEFT JOIN LATERAL (
+                SELECT MAX(created_at) AS last_activity_at
+                FROM data_transfer_metrics
+                WHERE connection_session_id = cs.id
+            ) activity ON TRUE
+            WHERE cs.disconnected_at IS NULL
+            AND cs.connected_at < $1
+            AND ($2::UUID IS NULL OR cs.id > $2)
+            ORDER BY cs.id
+            LIMIT $3
+            "#,
+            connected_before,
+            after,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(sessions)
+    }
+
     /// Record data transfer metrics
     pub async fn record_data_transfer(
         &self,
@@ -137,6 +216,19 @@ pub trait MetricsRepositoryTrait: Send + Sync {
         connection_id: &Uuid,
     ) -> Result<(), Box<dyn std::error::Error>>;
 
+    async fn close_connection_session_at(
+        &self,
+        connection_id: &Uuid,
+        disconnected_at: OffsetDateTime,
+    ) -> Result<bool, Box<dyn std::error::Error>>;
+
+    async fn open_connection_sessions(
+        &self,
+        connected_before: OffsetDateTime,
+        after: Option<Uuid>,
+        limit: i64,
+    ) -> Result<Vec<OpenConnectionSession>, Box<dyn std::error::Error>>;
+
     async fn record_data_transfer(
         &self,
         metric: &DataTransferMetric,
@@ -165,6 +257,25 @@ impl MetricsRepositoryTrait for MetricsRepository {
         self.close_connection_session(connection_id).await
     }
 
+    async fn close_connection_session_at(
+        &self,
+        connection_id: &Uuid,
+        disconnected_at: OffsetDateTime,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        self.close_connection_session_at(connection_id, disconnected_at)
+            .await
+    }
+
+    async fn open_connection_sessions(
+        &self,
+        connected_before: OffsetDateTime,
+        after: Option<Uuid>,
+        limit: i64,
+    ) -> Result<Vec<OpenConnectionSession>, Box<dyn std::error::Error>> {
+        self.open_connection_sessions(connected_before, after, limit)
+            .await
+    }
+
     async fn record_data_transfer(
         &self,
         metric: &DataTransferMetric,
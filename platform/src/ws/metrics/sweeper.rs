@@ -0,0 +1,448 @@
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, interval};
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+use super::OpenConnectionSession;
+use super::repository::MetricsRepositoryTrait;
+use crate::ws::session::ConnectionRegistry;
+
+// Sessions that started or transferred data more recently than this are left
+// alone: their connection may not be in the registry yet, or their end may
+// still be queued in a node's metrics collector
+const SWEEP_GRACE: time::Duration = time::Duration::minutes(1);
+
+const SWEEP_BATCH_SIZE: i64 = 500;
+
+/// A session the sweeper closed, or would close in dry-run mode
+#[derive(Debug, Clone)]
+pub struct StaleSession {
+    pub id: Uuid,
+    pub app_id: String,
+    pub connected_at: OffsetDateTime,
+    pub disconnected_at: OffsetDateTime,
+    /// The node that owned the connection, if it is still registered
+    pub node_id: Option<Uuid>,
+}
+
+#[derive(Debug, Default)]
+pub struct SweepReport {
+    pub checked: usize,
+    pub live: usize,
+    pub stale: Vec<StaleSession>,
+}
+
+/// Closes `connection_session` rows left open by connections that are no
+/// longer in the session registry, typically because their node crashed
+/// before `record_connection_end` ran.
+///
+/// The end time is the best known one: the owning node's last heartbeat if it
+/// is still registered, otherwise the last data transfer of the session, or
+/// its start when it never transferred anything.
+pub struct SessionSweeper {
+    repository: Arc<dyn MetricsRepositoryTrait>,
+    registry: Arc<dyn ConnectionRegistry>,
+    node_ttl: Duration,
+    dry_run: bool,
+}
+
+impl SessionSweeper {
+    pub fn new(
+        repository: Arc<dyn MetricsRepositoryTrait>,
+        registry: Arc<dyn ConnectionRegistry>,
+        node_ttl: Duration,
+        dry_run: bool,
+    ) -> Self {
+        Self {
+            repository,
+            registry,
+            node_ttl,
+            dry_run,
+        }
+    }
+
+    fn best_known_end(
+        open_session: &OpenConnectionSession,
+        last_heartbeat: Option<i64>,
+    ) -> OffsetDateTime {
+        last_heartbeat
+            .and_then(|last_seen| OffsetDateTime::from_unix_timestamp(last_seen).ok())
+            .or(open_session.last_activity_at)
+            .unwrap_or(open_session.connected_at)
+            .max(open_session.connected_at)
+    }
+
+    /// Find open sessions without a live connection and close them, or only
+    /// report them in dry-run mode
+    pub async fn sweep(&self) -> Result<SweepReport, Box<dyn std::error::Error>> {
+        let connected_before = OffsetDateTime::now_utc() - SWEEP_GRACE;
+
+        // Loaded after the cutoff, so every node that owns a swept session is in it
+        let heartbeats = self.registry.heartbeats().await?;
+        let deadline = OffsetDateTime::now_utc().unix_timestamp() - self.node_ttl.as_secs() as i64;
+
+        let mut report = SweepReport::default();
+        let mut after = None;
+
+        loop {
+            let open_sessions = self
+                .repository
+                .open_connection_sessions(connected_before, after, SWEEP_BATCH_SIZE)
+                .await?;
+
+            let Some(last) = open_sessions.last() else {
+                break;
+            };
+
+            after = Some(last.id);
+
+            let ids = open_sessions
+                .iter()
+                .map(|open_session| open_session.id)
+                .collect::<Vec<_>>();
+            let owners = self.registry.connection_owners(&ids).await?;
+
+            for (open_session, node_id) in open_sessions.into_iter().zip(owners) {
+                report.checked += 1;
+
+                if open_session
+                    .last_activity_at
+                    .is_some_and(|last_activity_at| last_activity_at >= connected_before)
+                {
+                    report.live += 1;
+                    continue;
+                }
+
+                let last_heartbeat = node_id.and_then(|node_id| heartbeats.get(&node_id).copied());
+
+                if last_heartbeat.is_some_and(|last_seen| last_seen >= deadline) {
+                    report.live += 1;
+                    continue;
+                }
+
+                let disconnected_at = Self::best_known_end(&open_session, last_heartbeat);
+
+                if !self.dry_run {
+                    self.repository
+                        .close_connection_session_at(&open_session.id, disconnected_at)
+                        .await?;
+                }
+
+                report.stale.push(StaleSession {
+                    id: open_session.id,
+                    app_id: open_session.app_id,
+                    connected_at: open_session.connected_at,
+                    disconnected_at,
+                    node_id,
+                });
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn log_report(&self, report: &SweepReport) {
+        let action = if self.dry_run {
+            "Would close"
+        } else {
+            "Closed"
+        };
+
+        for stale in &report.stale {
+            let line = format!(
+                "{action} connection session {} of app {} (node {:?}): connected at {}, ended at {}",
+                stale.id, stale.app_id, stale.node_id, stale.connected_at, stale.disconnected_at
+            );
+
+            if self.dry_run {
+                info!("{line}");
+            } else {
+                debug!("{line}");
+            }
+        }
+
+        if self.dry_run || !report.stale.is_empty() {
+            info!(
+                "Connection session sweep: {} open, {} live, {} stale{}",
+                report.checked,
+                report.live,
+                report.stale.len(),
+                if self.dry_run { " (dry run)" } else { "" }
+            );
+        }
+    }
+
+    /// Sweep every `period`, starting immediately
+    pub fn start(self: Arc<Self>, period: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+
+            loop {
+                ticker.tick().await;
+
+                match self.sweep().await {
+                    Ok(report) => self.log_report(&report),
+                    Err(e) => error!("Failed to sweep connection sessions: {e}"),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws::metrics::DataTransferMetric;
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+    use std::sync::Mutex as StdMutex;
+
+    #[derive(Default)]
+    struct FakeRepository {
+        open: StdMutex<Vec<OpenConnectionSession>>,
+        closed: StdMutex<Vec<(Uuid, OffsetDateTime)>>,
+    }
+
+    #[async_trait]
+    impl MetricsRepositoryTrait for FakeRepository {
+        async fn create_connection_session(
+            &self,
+            _app_id: &str,
+            _connection_id: &Uuid,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        async fn close_connection_session(
+            &self,
+            _connection_id: &Uuid,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        async fn close_connection_session_at(
+            &self,
+            connection_id: &Uuid,
+            disconnected_at: OffsetDateTime,
+        ) -> Result<bool, Box<dyn std::error::Error>> {
+            let mut open = self.open.lock().unwrap();
+            let before = open.len();
+
+            open.retain(|open_session| open_session.id != *connection_id);
+            self.closed
+                .lock()
+                .unwrap()
+                .push((*connection_id, disconnected_at));
+
+            Ok(open.len() < before)
+        }
+
+        async fn open_connection_sessions(
+            &self,
+            connected_before: OffsetDateTime,
+            after: Option<Uuid>,
+            limit: i64,
+        ) -> Result<Vec<OpenConnectionSession>, Box<dyn std::error::Error>> {
+            let mut open = self
+                .open
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|open_session| open_session.connected_at < connected_before)
+                .filter(|open_session| after.is_none_or(|after| open_session.id > after))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            open.sort_by_key(|open_session| open_session.id);
+            open.truncate(limit as usize);
+
+            Ok(open)
+        }
+
+        async fn record_data_transfer(
+            &self,
+            _metric: &DataTransferMetric,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        async fn record_data_transfer_batch(
+            &self,
+            _metrics: &[DataTransferMetric],
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeRegistry {
+        owners: HashMap<Uuid, Uuid>,
+        heartbeats: HashMap<Uuid, i64>,
+    }
+
+    #[async_trait]
+    impl ConnectionRegistry for FakeRegistry {
+        async fn connection_owners(
+            &self,
+            connection_ids: &[Uuid],
+        ) -> Result<Vec<Option<Uuid>>, Box<dyn std::error::Error>> {
+            Ok(connection_ids
+                .iter()
+                .map(|connection_id| self.owners.get(connection_id).copied())
+                .collect())
+        }
+
+        async fn heartbeats(&self) -> Result<HashMap<Uuid, i64>, Box<dyn std::error::Error>> {
+            Ok(self.heartbeats.clone())
+        }
+    }
+
+    const NODE_TTL: Duration = Duration::from_secs(30);
+
+    fn minutes_ago(minutes: i64) -> OffsetDateTime {
+        // Whole seconds, as heartbeats are
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        OffsetDateTime::from_unix_timestamp(now - minutes * 60).unwrap()
+    }
+
+    fn open_session(
+        connected_at: OffsetDateTime,
+        last_activity_at: Option<OffsetDateTime>,
+    ) -> OpenConnectionSession {
+        OpenConnectionSession {
+            id: Uuid::new_v4(),
+            app_id: "app".to_string(),
+            connected_at,
+            last_activity_at,
+        }
+    }
+
+    fn sweeper(
+        open: Vec<OpenConnectionSession>,
+        registry: FakeRegistry,
+        dry_run: bool,
+    ) -> (Arc<FakeRepository>, SessionSweeper) {
+        let repository = Arc::new(FakeRepository {
+            open: StdMutex::new(open),
+            ..Default::default()
+        });
+        let sweeper =
+            SessionSweeper::new(repository.clone(), Arc::new(registry), NODE_TTL, dry_run);
+
+        (repository, sweeper)
+    }
+
+    #[test]
+    fn the_end_falls_back_from_heartbeat_to_activity_to_start() {
+        let connected_at = minutes_ago(60);
+        let last_activity_at = minutes_ago(30);
+        let last_seen = minutes_ago(10);
+        let active = open_session(connected_at, Some(last_activity_at));
+        let idle = open_session(connected_at, None);
+
+        assert_eq!(
+            SessionSweeper::best_known_end(&active, Some(last_seen.unix_timestamp())),
+            last_seen
+        );
+        assert_eq!(
+            SessionSweeper::best_known_end(&active, None),
+            last_activity_at
+        );
+        assert_eq!(SessionSweeper::best_known_end(&idle, None), connected_at);
+    }
+
+    #[test]
+    fn the_end_is_never_before_the_start() {
+        let connected_at = minutes_ago(10);
+        let open_session = open_session(connected_at, None);
+
+        assert_eq!(
+            SessionSweeper::best_known_end(&open_session, Some(minutes_ago(20).unix_timestamp())),
+            connected_at
+        );
+    }
+
+    #[tokio::test]
+    async fn sessions_without_a_live_connection_are_closed_at_their_best_known_end() {
+        let dead_node = Uuid::new_v4();
+        let last_seen = minutes_ago(5);
+        let owned = open_session(minutes_ago(60), Some(minutes_ago(30)));
+        let unregistered = open_session(minutes_ago(60), Some(minutes_ago(20)));
+        let registry = FakeRegistry {
+            owners: HashMap::from([(owned.id, dead_node)]),
+            heartbeats: HashMap::from([(dead_node, last_seen.unix_timestamp())]),
+        };
+
+        let (repository, sweeper) =
+            sweeper(vec![owned.clone(), unregistered.clone()], registry, false);
+        let report = sweeper.sweep().await.unwrap();
+
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.live, 0);
+        assert_eq!(report.stale.len(), 2);
+
+        let mut closed = repository.closed.lock().unwrap().clone();
+        closed.sort();
+        let mut expected = vec![
+            (owned.id, last_seen),
+            (unregistered.id, unregistered.last_activity_at.unwrap()),
+        ];
+        expected.sort();
+        assert_eq!(closed, expected);
+        assert!(repository.open.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn live_and_recent_sessions_are_left_open() {
+        let live_node = Uuid::new_v4();
+        let owned = open_session(minutes_ago(60), None);
+        let recently_active = open_session(minutes_ago(60), Some(OffsetDateTime::now_utc()));
+        let recently_connected = open_session(OffsetDateTime::now_utc(), None);
+        let registry = FakeRegistry {
+            owners: HashMap::from([(owned.id, live_node)]),
+            heartbeats: HashMap::from([(live_node, OffsetDateTime::now_utc().unix_timestamp())]),
+        };
+
+        let (repository, sweeper) = sweeper(
+            vec![owned, recently_active, recently_connected],
+            registry,
+            false,
+        );
+        let report = sweeper.sweep().await.unwrap();
+
+        // Sessions within the grace period are not even checked
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.live, 2);
+        assert!(report.stale.is_empty());
+        assert!(repository.closed.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dry_runs_report_stale_sessions_without_closing_them() {
+        let stale = open_session(minutes_ago(60), None);
+
+        let (repository, sweeper) = sweeper(vec![stale.clone()], FakeRegistry::default(), true);
+        let report = sweeper.sweep().await.unwrap();
+
+        assert_eq!(report.stale.len(), 1);
+        assert_eq!(report.stale[0].id, stale.id);
+        assert_eq!(report.stale[0].disconnected_at, stale.connected_at);
+        assert!(repository.closed.lock().unwrap().is_empty());
+        assert_eq!(repository.open.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn every_batch_of_open_sessions_is_swept() {
+        let open = (0..SWEEP_BATCH_SIZE * 2 + 1)
+            .map(|_| open_session(minutes_ago(60), None))
+            .collect::<Vec<_>>();
+
+        let (repository, sweeper) = sweeper(open, FakeRegistry::default(), false);
+        let report = sweeper.sweep().await.unwrap();
+
+        assert_eq!(report.checked, SWEEP_BATCH_SIZE as usize * 2 + 1);
+        assert!(repository.open.lock().unwrap().is_empty());
+    }
+}
@@ -2,14 +2,22 @@ use futures_util::SinkExt;
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use tokio::sync::{Mutex, broadcast};
-use tracing::error;
+use tokio::sync::{Mutex, broadcast, broadcast::error::RecvError};
+use tokio_tungstenite::tungstenite::{
+    Message,
+    protocol::{CloseFrame, frame::coding::CloseCode},
+};
+use tracing::{Instrument, error, info_span, warn};
 
 use crate::{
     messaging::BroadcastMessage,
+    observability::{
+        metrics::{BROADCAST_LAGGED_TOTAL, MESSAGE_BYTES_TOTAL, MESSAGES_TOTAL},
+        telemetry,
+    },
     ws::{
         Middleware,
-        connection::{ConnectionState, WsWrite},
+        connection::{ConnectionState, Handshake, WsWrite},
         dto::outgoing_message::{OutgoingMessage, ToWsMessage},
     },
 };
@@ -30,51 +38,158 @@ impl Middleware for BroadcastMiddleware {
     async fn run(
         &self,
         write: &WsWrite,
-        _url: &str,
+        _handshake: &Handshake,
         state: Arc<Mutex<ConnectionState>>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let mut rx = self.tx.subscribe();
 
         let payload_app_id = state.lock().await.app_id.clone();
         let payload_user_id = state.lock().await.user_id.clone();
+        let sequenced = state.lock().await.protocol_version.has_sequence_numbers();
+        let update_encoding = state.lock().await.update_encoding;
+        let connection_id = state.lock().await.connection_id;
         let write = write.clone();
 
         let broadcast_task = tokio::spawn(async move {
-            while let Ok(msg) = rx.recv().await {
-                match msg {
+            loop {
+                let msg = match rx.recv().await {
+                    Ok(msg) => msg,
+                    // The client missed patches, make it reconnect and resync
+                    // rather than leave it open and silently diverged
+                    Err(RecvError::Lagged(skipped)) => {
+                        BROADCAST_LAGGED_TOTAL.inc_by(skipped);
+                        warn!("Closing connection {connection_id}: lagged by {skipped} messages");
+
+                        let close_frame = CloseFrame {
+                            code: CloseCode::Again,
+                            reason: "Lagged behind the broadcast stream".into(),
+                        };
+
+                        if let Err(e) = write
+                            .lock()
+                            .await
+                            .1
+                            .send(Message::Close(Some(close_frame)))
+                            .await
+                        {
+                            error!("Error sending close frame: {e}");
+                        }
+
+                        break;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                let (label, message, span) = match msg {
                     BroadcastMessage::Patch {
                         app_id,
-                        sender,
+                        senders,
                         channel_id,
                         recipients,
                         payload,
+                        mut without_sender,
+                        seq,
+                        relay,
+                        trace_context,
                     } => {
-                        if app_id != payload_app_id
-                            || sender == payload_user_id
-                            || !recipients.contains(&payload_user_id)
-                        {
+                        if app_id != payload_app_id || !recipients.contains(&payload_user_id) {
                             continue;
                         }
 
-                        let message = OutgoingMessage::Patch {
-                            channel_id,
-                            payload: payload.to_vec(),
+                        // Never echo the user's own patches back
+                        let payload = if senders.contains(&payload_user_id) {
+                            match without_sender.remove(&payload_user_id) {
+                                Some(payload) => payload,
+                                None => continue,
+                            }
+                        } else {
+                            payload
                         };
 
-                        match message.to_ws_message() {
-                            Ok(ws_message) => {
-                                if let Err(e) = write.lock().await.1.send(ws_message).await {
-                                    error!("Error sending message: {e}");
-                                    break;
-                                }
-                            }
+                        let span = info_span!("broadcast.deliver", channel_id = %channel_id);
+                        telemetry::set_parent(&span, &trace_context);
+
+                        // Relayed payloads are opaque, never transcoded
+                        let transcoded = if relay {
+                            Ok(payload)
+                        } else {
+                            update_encoding.transcode(&payload)
+                        };
+
+                        let payload = match transcoded {
+                            Ok(payload) => payload,
                             Err(e) => {
-                                error!("Error converting message: {e}");
+                                error!("Error transcoding patch: {e}");
                                 continue;
                             }
+                        };
+
+                        let message = OutgoingMessage::Patch {
+                            channel_id,
+                            payload,
+                            seq: seq.filter(|_| sequenced),
+                        };
+
+                        ("patch", message, span)
+                    }
+                    BroadcastMessage::KeyExchange {
+                        app_id,
+                        channel_id,
+                        sender,
+                        sender_connection,
+                        recipients,
+                        recipient_connection,
+                        payload,
+                        trace_context,
+                    } => {
+                        if app_id != payload_app_id
+                            || !receives_key_exchange(
+                                &recipients,
+                                &sender_connection,
+                                recipient_connection.as_deref(),
+                                &payload_user_id,
+                                &connection_id.to_string(),
+                            )
+                        {
+                            continue;
                         }
+
+                        let span = info_span!("broadcast.deliver", channel_id = %channel_id);
+                        telemetry::set_parent(&span, &trace_context);
+
+                        let message = OutgoingMessage::KeyExchange {
+                            channel_id,
+                            sender,
+                            sender_connection,
+                            payload,
+                        };
+
+                        ("key_exchange", message, span)
                     }
+                };
+
+                let ws_message = match message.to_ws_message() {
+                    Ok(ws_message) => ws_message,
+                    Err(e) => {
+                        error!("Error converting message: {e}");
+                        continue;
+                    }
+                };
+
+                let message_size = ws_message.len() as u64;
+                let sent = async { write.lock().await.1.send(ws_message).await }
+                    .instrument(span)
+                    .await;
+
+                if let Err(e) = sent {
+                    error!("Error sending message: {e}");
+                    break;
                 }
+
+                MESSAGES_TOTAL.with_label_values(&["out", label]).inc();
+                MESSAGE_BYTES_TOTAL
+                    .with_label_values(&["out", label])
+                    .inc_by(message_size);
             }
         });
 
@@ -84,3 +199,99 @@ impl Middleware for BroadcastMiddleware {
         Ok(())
     }
 }
+
+/// Whether key material reaches a connection of `user_id`. Devices are
+/// addressed by connection, the sender's other devices included, so only the
+/// sending connection and those passed over for another one are left out.
+fn receives_key_exchange(
+    recipients: &[String],
+    sender_connection: &str,
+    recipient_connection: Option<&str>,
+    user_id: &str,
+    connection_id: &str,
+) -> bool {
+    recipients.iter().any(|recipient| recipient == user_id)
+        && sender_connection != connection_id
+        && recipient_connection.is_none_or(|recipient| recipient == connection_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENDER: &str = "sender-connection";
+
+    fn members() -> Vec<String> {
+        vec!["alice".to_string(), "bob".to_string()]
+    }
+
+    #[test]
+    fn key_exchanges_reach_every_member_connection_but_the_sending_one() {
+        assert!(receives_key_exchange(
+            &members(),
+            SENDER,
+            None,
+            "bob",
+            "bob-phone"
+        ));
+        assert!(receives_key_exchange(
+            &members(),
+            SENDER,
+            None,
+            "alice",
+            "alice-laptop"
+        ));
+        assert!(!receives_key_exchange(
+            &members(),
+            SENDER,
+            None,
+            "alice",
+            SENDER
+        ));
+        assert!(!receives_key_exchange(
+            &members(),
+            SENDER,
+            None,
+            "carol",
+            "carol-phone"
+        ));
+    }
+
+    #[test]
+    fn key_exchanges_for_one_connection_reach_only_it() {
+        let recipient = Some("bob-phone");
+
+        assert!(receives_key_exchange(
+            &members(),
+            SENDER,
+            recipient,
+            "bob",
+            "bob-phone"
+        ));
+        assert!(!receives_key_exchange(
+            &members(),
+            SENDER,
+            recipient,
+            "bob",
+            "bob-laptop"
+        ));
+        assert!(!receives_key_exchange(
+            &members(),
+            SENDER,
+            recipient,
+            "alice",
+            "alice-laptop"
+        ));
+    }
+
+    #[test]
+    fn key_exchanges_for_one_connection_still_need_a_member() {
+        assert!(!receives_key_exchange(
+            &members(),
+            SENDER,
+            Some("carol-phone"),
+            "carol",
+            "carol-phone"
+        ));
+    }
+}
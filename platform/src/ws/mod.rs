@@ -1,16 +1,31 @@
+pub mod apps;
 pub mod bus_proxy;
 pub mod connection;
 pub mod crdt;
+pub mod document_cache;
 pub mod dto;
+pub mod errors;
 pub mod handler;
 pub mod metrics;
 pub mod middlewares;
+pub mod ownership;
+pub mod quota;
+pub mod rate_limit;
+pub mod reaper;
+pub mod resume;
 pub mod session;
 
 pub use bus_proxy::BusProxy;
-pub use connection::{MessageHandler as WsMessageHandler, Middleware, WsConnection};
-pub use crdt::Crdt;
+pub use connection::{MessageHandler as WsMessageHandler, Middleware, RateLimiter, WsConnection};
+pub use crdt::{Crdt, CrdtValue, UpdateEncoding, XmlNode};
+pub use document_cache::DocumentCache;
 pub use dto::incoming_message::IncomingMessage;
+pub use errors::{ClientError, ErrorCode};
 pub use handler::MessageHandler;
 pub use middlewares::{AuthMiddleware, BroadcastMiddleware};
+pub use ownership::ChannelOwnership;
+pub use quota::QuotaEnforcer;
+pub use rate_limit::RedisRateLimiter;
+pub use reaper::SessionReaper;
+pub use resume::ResumeStore;
 pub use session::Session;
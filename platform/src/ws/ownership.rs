@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use async_nats::Client;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant, sleep};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use super::session::ConnectionRegistry;
+
+// Points each node takes on the ring, so channels spread evenly
+const VIRTUAL_NODES: u64 = 64;
+
+// Subject nodes announce they released the channels a ring change took away
+const HANDOFF_SUBJECT: &str = "nodes.handoff";
+
+// How often a new owner checks whether the previous one released a channel
+const HANDOFF_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Consistent hash ring of platform nodes. A node joining or leaving only
+/// moves the channels on its own share of the ring.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HashRing {
+    // Sorted by point
+    points: Vec<(u64, Uuid)>,
+}
+
+impl HashRing {
+    pub fn new(nodes: impl IntoIterator<Item = Uuid>) -> Self {
+        let mut points = nodes
+            .into_iter()
+            .flat_map(|node_id| {
+                (0..VIRTUAL_NODES)
+                    .map(move |i| (hash(format!("{node_id}:{i}").as_bytes()), node_id))
+            })
+            .collect::<Vec<_>>();
+        points.sort();
+
+        Self { points }
+    }
+
+    /// The node owning `key`, `None` on an empty ring
+    pub fn owner(&self, key: &str) -> Option<Uuid> {
+        let point = hash(key.as_bytes());
+        let index = self.points.partition_point(|(other, _)| *other < point);
+
+        self.points
+            .get(index)
+            .or_else(|| self.points.first())
+            .map(|(_, node_id)| *node_id)
+    }
+
+    /// Nodes on the ring, sorted
+    pub fn nodes(&self) -> Vec<Uuid> {
+        let mut nodes = self
+            .points
+            .iter()
+            .map(|(_, node_id)| *node_id)
+            .collect::<Vec<_>>();
+        nodes.sort();
+        nodes.dedup();
+
+        nodes
+    }
+}
+
+// FNV-1a with a final mix. Every node must place keys the same way, which
+// std's hasher does not promise across builds.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+
+    hash
+}
+
+fn get_channel_key(app_id: &str, channel_id: &str) -> String {
+    format!("{app_id}:{channel_id}")
+}
+
+/// Announces that `node_id` flushed and dropped every channel it no longer
+/// owns once the ring holds `nodes`
+#[derive(Debug, Serialize, Deserialize)]
+struct HandoffDone {
+    node_id: String,
+    nodes: Vec<String>,
+}
+
+struct RingState {
+    ring: HashRing,
+    previous: HashRing,
+    changed_at: Instant,
+}
+
+/// Which platform node owns each channel. The owner holds the channel's
+/// authoritative document and merges and saves its patches, other nodes
+/// forward patches to it.
+///
+/// Owners are picked on a consistent hash ring of the nodes heartbeating in
+/// the session registry. When the ring changes, a node flushes the channels
+/// it gave up and announces it is done. New owners hold off merging a
+/// channel until its previous owner is done, or the handoff timeout passed
+/// in case it died.
+pub struct ChannelOwnership {
+    node_id: Uuid,
+    registry: Arc<dyn ConnectionRegistry>,
+    nats: Client,
+    node_ttl: Duration,
+    handoff_timeout: Duration,
+    state: RwLock<RingState>,
+    // Ring each node last announced it was done handing off for
+    handoffs: RwLock<HashMap<Uuid, Vec<Uuid>>>,
+}
+
+impl ChannelOwnership {
+    pub fn new(
+        node_id: Uuid,
+        registry: Arc<dyn ConnectionRegistry>,
+        nats: Client,
+        node_ttl: Duration,
+        handoff_timeout: Duration,
+    ) -> Self {
+        // Alone until the first refresh, like a node starting a cluster
+        let ring = HashRing::new([node_id]);
+
+        Self {
+            node_id,
+            registry,
+            nats,
+            node_ttl,
+            handoff_timeout,
+            state: RwLock::new(RingState {
+                ring: ring.clone(),
+                previous: ring,
+                changed_at: Instant::now(),
+            }),
+            handoffs: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn node_id(&self) -> Uuid {
+        self.node_id
+    }
+
+    pub fn nats(&self) -> &Client {
+        &self.nats
+    }
+
+    /// Subject the node receives patches forwarded by other nodes on
+    pub fn get_patch_subject(node_id: &Uuid) -> String {
+        format!("node.{node_id}.patches")
+    }
+
+    /// The node owning the channel
+    pub fn owner(&self, app_id: &str, channel_id: &str) -> Uuid {
+        let Ok(state) = self.state.read() else {
+            return self.node_id();
+        };
+
+        state
+            .ring
+            .owner(&get_channel_key(app_id, channel_id))
+            .unwrap_or(self.node_id())
+    }
+
+    /// The owner of the channel when it is another node
+    pub fn remote_owner(&self, app_id: &str, channel_id: &str) -> Option<Uuid> {
+        Some(self.owner(app_id, channel_id)).filter(|owner| *owner != self.node_id())
+    }
+
+    /// Rebuild the ring from the nodes that heartbeat within the node TTL,
+    /// returning whether it changed. A node whose own heartbeat is missing
+    /// leaves the ring too, as the other nodes already see it dead.
+    pub async fn refresh(&self) -> Result<bool, Box<dyn std::error::Error>> {
+        let deadline = OffsetDateTime::now_utc().unix_timestamp() - self.node_ttl.as_secs() as i64;
+
+        let live_nodes = self
+            .registry
+            .heartbeats()
+            .await?
+            .into_iter()
+            .filter(|(_, last_seen)| *last_seen >= deadline)
+            .map(|(node_id, _)| node_id)
+            .collect::<Vec<_>>();
+
+        if !live_nodes.contains(&self.node_id()) {
+            warn!("Own heartbeat is missing, leaving the ring");
+        }
+
+        let ring = HashRing::new(live_nodes);
+        let mut state = self.state.write().map_err(|e| e.to_string())?;
+
+        if ring == state.ring {
+            return Ok(false);
+        }
+
+        info!("Ring changed to nodes {:?}", ring.nodes());
+
+        state.previous = std::mem::replace(&mut state.ring, ring);
+        state.changed_at = Instant::now();
+
+        Ok(true)
+    }
+
+    /// Tell the other nodes this node is done flushing the channels the last
+    /// ring change took away
+    pub async fn announce_handoff(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let nodes = {
+            let state = self.state.read().map_err(|e| e.to_string())?;
+            state.ring.nodes()
+        };
+
+        let message = HandoffDone {
+            node_id: self.node_id().to_string(),
+            nodes: nodes.iter().map(Uuid::to_string).collect(),
+        };
+
+        self.nats
+            .publish(HANDOFF_SUBJECT, serde_json::to_vec(&message)?.into())
+            .await?;
+
+        Ok(())
+    }
+
+    // How long to keep waiting for the previous owner of a channel this node
+    // took over, `None` once it can be merged
+    fn handoff_pending(&self, app_id: &str, channel_id: &str) -> Option<Duration> {
+        let state = self.state.read().ok()?;
+        let remaining = self
+            .handoff_timeout
+            .checked_sub(state.changed_at.elapsed())?;
+
+        let key = get_channel_key(app_id, channel_id);
+        let previous_owner = state.previous.owner(&key)?;
+
+        if previous_owner == self.node_id() || state.ring.owner(&key) != Some(self.node_id()) {
+            return None;
+        }
+
+        let handoffs = self.handoffs.read().ok()?;
+        let done = handoffs
+            .get(&previous_owner)
+            .is_some_and(|nodes| *nodes == state.ring.nodes());
+
+        (!done).then_some(remaining)
+    }
+
+    /// Wait until the previous owner of a channel this node took over has
+    /// flushed it, or the handoff timeout passed
+    pub async fn wait_for_handoff(&self, app_id: &str, channel_id: &str) {
+        let started_at = Instant::now();
+
+        while let Some(remaining) = self.handoff_pending(app_id, channel_id) {
+            sleep(remaining.min(HANDOFF_POLL_INTERVAL)).await;
+        }
+
+        let waited = started_at.elapsed();
+
+        if waited >= HANDOFF_POLL_INTERVAL {
+            info!("Waited {waited:?} for the handoff of channel {channel_id}");
+        }
+    }
+
+    /// Record handoff announcements from the other nodes
+    pub async fn listen(self: Arc<Self>) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
+        let mut subscriber = self.nats.subscribe(HANDOFF_SUBJECT).await?;
+
+        Ok(tokio::spawn(async move {
+            while let Some(message) = subscriber.next().await {
+                let handoff: HandoffDone = match serde_json::from_slice(&message.payload) {
+                    Ok(handoff) => handoff,
+                    Err(e) => {
+                        error!("Invalid handoff announcement: {e}");
+                        continue;
+                    }
+                };
+
+                let Ok(node_id) = handoff.node_id.parse::<Uuid>() else {
+                    continue;
+                };
+
+                let mut nodes = handoff
+                    .nodes
+                    .iter()
+                    .filter_map(|node_id| node_id.parse().ok())
+                    .collect::<Vec<Uuid>>();
+                nodes.sort();
+
+                if let Ok(mut handoffs) = self.handoffs.write() {
+                    handoffs.insert(node_id, nodes);
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+impl ChannelOwnership {
+    /// A node alone in a ring it never refreshes, owning every channel
+    pub async fn alone() -> Self {
+        // Never reaches a server, the node has no one to forward to
+        let nats = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect("nats://127.0.0.1:1")
+            .await
+            .expect("Failed to create NATS client");
+
+        Self::new(
+            Uuid::new_v4(),
+            Arc::new(tests::FakeRegistry::default()),
+            nats,
+            Duration::from_secs(30),
+            Duration::ZERO,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::sync::Mutex as StdMutex;
+
+    const KEYS: usize = 10_000;
+    const NODE_TTL: Duration = Duration::from_secs(30);
+
+    fn nodes(count: u128) -> Vec<Uuid> {
+        (1..=count).map(Uuid::from_u128).collect()
+    }
+
+    fn owners(ring: &HashRing) -> Vec<Uuid> {
+        (0..KEYS)
+            .map(|i| ring.owner(&get_channel_key("app", &format!("channel-{i}"))))
+            .collect::<Option<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn channels_spread_evenly_across_nodes() {
+        let nodes = nodes(4);
+        let owners = owners(&HashRing::new(nodes.clone()));
+
+        for node_id in nodes {
+            let share = owners.iter().filter(|owner| **owner == node_id).count();
+
+            // A quarter each, give or take what 64 points per node allow
+            assert!(
+                (KEYS * 15 / 100..KEYS * 35 / 100).contains(&share),
+                "node {node_id} owns {share} of {KEYS} channels"
+            );
+        }
+    }
+
+    #[test]
+    fn a_joining_node_only_takes_channels_for_itself() {
+        let before = owners(&HashRing::new(nodes(4)));
+        let after = owners(&HashRing::new(nodes(5)));
+        let joined = Uuid::from_u128(5);
+
+        let moved = before
+            .iter()
+            .zip(&after)
+            .filter(|(before, after)| before != after)
+            .collect::<Vec<_>>();
+
+        assert!(moved.iter().all(|(_, after)| **after == joined));
+        assert!(
+            (KEYS * 10 / 100..KEYS * 30 / 100).contains(&moved.len()),
+            "{} of {KEYS} channels moved",
+            moved.len()
+        );
+    }
+
+    #[test]
+    fn a_leaving_node_only_gives_up_its_channels() {
+        let before = owners(&HashRing::new(nodes(5)));
+        let after = owners(&HashRing::new(nodes(4)));
+        let left = Uuid::from_u128(5);
+
+        for (before, after) in before.iter().zip(&after) {
+            if *before == left {
+                assert_ne!(*after, left);
+            } else {
+                assert_eq!(before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn an_empty_ring_has_no_owner() {
+        assert_eq!(HashRing::new([]).owner("app:channel"), None);
+        assert!(HashRing::new([]).nodes().is_empty());
+    }
+
+    #[derive(Default)]
+    pub(super) struct FakeRegistry {
+        heartbeats: StdMutex<HashMap<Uuid, i64>>,
+    }
+
+    impl FakeRegistry {
+        fn beat(&self, node_id: Uuid, last_seen: i64) {
+            self.heartbeats.lock().unwrap().insert(node_id, last_seen);
+        }
+    }
+
+    #[async_trait]
+    impl ConnectionRegistry for FakeRegistry {
+        async fn connection_owners(
+            &self,
+            connection_ids: &[Uuid],
+        ) -> Result<Vec<Option<Uuid>>, Box<dyn std::error::Error>> {
+            Ok(vec![None; connection_ids.len()])
+        }
+
+        async fn heartbeats(&self) -> Result<HashMap<Uuid, i64>, Box<dyn std::error::Error>> {
+            Ok(self.heartbeats.lock().unwrap().clone())
+        }
+    }
+
+    fn now() -> i64 {
+        OffsetDateTime::now_utc().unix_timestamp()
+    }
+
+    async fn ownership(
+        node_id: Uuid,
+        registry: Arc<FakeRegistry>,
+        handoff_timeout: Duration,
+    ) -> ChannelOwnership {
+        // Never reaches a server, nothing here publishes
+        let nats = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect("nats://127.0.0.1:1")
+            .await
+            .unwrap();
+
+        ChannelOwnership::new(node_id, registry, nats, NODE_TTL, handoff_timeout)
+    }
+
+    // A channel the ring gives to `node_id`
+    fn channel_owned_by(ring: &HashRing, node_id: Uuid) -> String {
+        (0..KEYS)
+            .map(|i| format!("channel-{i}"))
+            .find(|channel_id| ring.owner(&get_channel_key("app", channel_id)) == Some(node_id))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn refresh_keeps_only_nodes_with_fresh_heartbeats() {
+        let [own, live, dead] = [1, 2, 3].map(Uuid::from_u128);
+        let registry = Arc::new(FakeRegistry::default());
+        registry.beat(own, now());
+        registry.beat(live, now() - 5);
+        registry.beat(dead, now() - NODE_TTL.as_secs() as i64 - 5);
+
+        let ownership = ownership(own, registry.clone(), Duration::ZERO).await;
+
+        assert!(ownership.refresh().await.unwrap());
+        assert_eq!(
+            ownership.state.read().unwrap().ring.nodes(),
+            vec![own, live]
+        );
+
+        // Same nodes, same ring
+        assert!(!ownership.refresh().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_node_without_its_own_heartbeat_leaves_the_ring() {
+        let [own, other] = [1, 2].map(Uuid::from_u128);
+        let registry = Arc::new(FakeRegistry::default());
+        registry.beat(other, now());
+
+        let ownership = ownership(own, registry, Duration::ZERO).await;
+        let channel_id = channel_owned_by(&HashRing::new([own, other]), own);
+
+        assert!(ownership.refresh().await.unwrap());
+        assert_eq!(ownership.owner("app", &channel_id), other);
+        assert_eq!(ownership.remote_owner("app", &channel_id), Some(other));
+    }
+
+    #[tokio::test]
+    async fn a_taken_over_channel_waits_for_the_previous_owner() {
+        let [own, other] = [1, 2].map(Uuid::from_u128);
+        let registry = Arc::new(FakeRegistry::default());
+        registry.beat(other, now());
+
+        let ownership = ownership(own, registry.clone(), Duration::from_secs(60)).await;
+        ownership.refresh().await.unwrap();
+
+        // Joining takes channels away from the other node
+        registry.beat(own, now());
+        ownership.refresh().await.unwrap();
+
+        let ring = HashRing::new([own, other]);
+        let taken = channel_owned_by(&ring, own);
+        let kept = channel_owned_by(&ring, other);
+
+        assert!(ownership.handoff_pending("app", &taken).is_some());
+        assert!(ownership.handoff_pending("app", &kept).is_none());
+
+        // Done handing off a ring this node no longer has
+        ownership
+            .handoffs
+            .write()
+            .unwrap()
+            .insert(other, vec![other]);
+        assert!(ownership.handoff_pending("app", &taken).is_some());
+
+        ownership
+            .handoffs
+            .write()
+            .unwrap()
+            .insert(other, ring.nodes());
+        assert!(ownership.handoff_pending("app", &taken).is_none());
+    }
+
+    #[tokio::test]
+    async fn a_handoff_stops_waiting_after_the_timeout() {
+        let [own, other] = [1, 2].map(Uuid::from_u128);
+        let registry = Arc::new(FakeRegistry::default());
+        registry.beat(other, now());
+
+        let ownership = ownership(own, registry.clone(), Duration::ZERO).await;
+        ownership.refresh().await.unwrap();
+
+        registry.beat(own, now());
+        ownership.refresh().await.unwrap();
+
+        let taken = channel_owned_by(&HashRing::new([own, other]), own);
+
+        assert!(ownership.handoff_pending("app", &taken).is_none());
+    }
+}
@@ -0,0 +1,500 @@
+use db::quota;
+use redis::aio::ConnectionManager;
+use std::collections::HashMap;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+use tracing::debug;
+use uuid::Uuid;
+
+use super::{
+    Limits, QuotaExceeded, QuotaRepository, QuotaRepositoryTrait, QuotaStore, Quotas,
+    RedisQuotaStore,
+};
+
+// How long quotas are cached before being reloaded from Postgres
+const QUOTAS_CACHE_TTL: Duration = Duration::from_secs(60);
+
+// Monthly transfer counters outlive the month they count
+const TRANSFER_COUNTER_TTL_SECONDS: i64 = 35 * 24 * 3600;
+
+/// Enforces plan limits with Redis counters shared by every platform node.
+///
+/// Connections and channels are tracked as sets of ids rather than counted,
+/// so releasing twice is harmless and `QuotaReconciler` can drop the ids of
+/// connections and channels that ended without releasing their slot.
+pub struct QuotaEnforcer {
+    store: Arc<dyn QuotaStore>,
+    repository: Arc<dyn QuotaRepositoryTrait>,
+    quotas_cache: Mutex<HashMap<String, (Instant, Option<Quotas>)>>,
+}
+
+impl QuotaEnforcer {
+    pub fn new(redis: ConnectionManager, repository: Arc<QuotaRepository>) -> Self {
+        Self::with_store(Arc::new(RedisQuotaStore::new(redis)), repository)
+    }
+
+    pub fn with_store(
+        store: Arc<dyn QuotaStore>,
+        repository: Arc<dyn QuotaRepositoryTrait>,
+    ) -> Self {
+        Self {
+            store,
+            repository,
+            quotas_cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn quotas(&self, app_id: &str) -> Result<Option<Quotas>, Box<dyn std::error::Error>> {
+        if let Some((loaded_at, quotas)) = self.quotas_cache.lock().await.get(app_id)
+            && loaded_at.elapsed() < QUOTAS_CACHE_TTL
+        {
+            return Ok(*quotas);
+        }
+
+        let quotas = self.repository.get_quotas(app_id).await?;
+
+        self.quotas_cache
+            .lock()
+            .await
+            .insert(app_id.to_string(), (Instant::now(), quotas));
+
+        Ok(quotas)
+    }
+
+    fn scoped_keys(
+        app_id: &str,
+        quotas: &Quotas,
+        counter: &str,
+        limit: fn(&Limits) -> Option<i64>,
+    ) -> [(String, i64); 2] {
+        [
+            (
+                quota::organization_key(&quotas.organization_id.to_string(), counter),
+                limit(&quotas.organization).unwrap_or(-1),
+            ),
+            (
+                quota::app_key(app_id, counter),
+                limit(&quotas.app).unwrap_or(-1),
+            ),
+        ]
+    }
+
+    // The app's set, and its organization's when the app is still known, so
+    // ids taken under a plan that was removed since are released all the same
+    async fn release_member(
+        &self,
+        app_id: &str,
+        counter: &str,
+        member: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut sets = vec![quota::app_key(app_id, counter)];
+
+        if let Some(quotas) = self.quotas(app_id).await? {
+            sets.push(quota::organization_key(
+                &quotas.organization_id.to_string(),
+                counter,
+            ));
+        }
+
+        self.store.remove_member(&sets, member).await
+    }
+
+    /// Reserve a concurrent connection slot for the app
+    pub async fn acquire_connection(
+        &self,
+        app_id: &str,
+        connection_id: &Uuid,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(quotas) = self.quotas(app_id).await? else {
+            return Ok(());
+        };
+
+        let sets = Self::scoped_keys(app_id, &quotas, quota::CONNECTIONS_COUNTER, |limits| {
+            limits.max_connections
+        });
+
+        if !self
+            .store
+            .add_member(&sets, &connection_id.to_string())
+            .await?
+        {
+            debug!("Connection limit reached for app {app_id}");
+            return Err(QuotaExceeded::Connections.into());
+        }
+
+        Ok(())
+    }
+
+    /// Release a connection slot reserved with `acquire_connection`
+    pub async fn release_connection(
+        &self,
+        app_id: &str,
+        connection_id: &Uuid,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.release_member(
+            app_id,
+            quota::CONNECTIONS_COUNTER,
+            &connection_id.to_string(),
+        )
+        .await
+    }
+
+    /// Register a channel, failing if the app or organization has too many
+    pub async fn acquire_channel(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(quotas) = self.quotas(app_id).await? else {
+            return Ok(());
+        };
+
+        let sets = Self::scoped_keys(app_id, &quotas, quota::CHANNELS_COUNTER, |limits| {
+            limits.max_channels
+        });
+
+        if !self
+            .store
+            .add_member(&sets, &channel_member(app_id, channel_id))
+            .await?
+        {
+            debug!("Channel limit reached for app {app_id}");
+            return Err(QuotaExceeded::Channels.into());
+        }
+
+        Ok(())
+    }
+
+    /// Forget a channel once its document is deleted
+    pub async fn release_channel(
+        &self,
+        app_id: &str,
+        channel_id: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.release_member(
+            app_id,
+            quota::CHANNELS_COUNTER,
+            &channel_member(app_id, channel_id),
+        )
+        .await
+    }
+
+    /// Fail if a document of `size` bytes would exceed the allowed size
+    pub async fn check_document_size(
+        &self,
+        app_id: &str,
+        size: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(quotas) = self.quotas(app_id).await? else {
+            return Ok(());
+        };
+
+        match quotas.max_document_size_bytes() {
+            Some(max) if size as i64 > max => Err(QuotaExceeded::DocumentSize.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn transfer_keys(app_id: &str, quotas: &Quotas) -> [(String, i64); 2] {
+        let counter = quota::transfer_counter(OffsetDateTime::now_utc());
+
+        Self::scoped_keys(app_id, quotas, &counter, |limits| {
+            limits.monthly_transfer_bytes
+        })
+    }
+
+    /// Count transferred bytes against the monthly cap, failing once it is used up
+    pub async fn record_transfer(
+        &self,
+        app_id: &str,
+        bytes: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(quotas) = self.quotas(app_id).await? else {
+            return Ok(());
+        };
+
+        let counters = Self::transfer_keys(app_id, &quotas);
+
+        if !self
+            .store
+            .increment(&counters, bytes as i64, TRANSFER_COUNTER_TTL_SECONDS)
+            .await?
+        {
+            debug!("Monthly transfer limit reached for app {app_id}");
+            return Err(QuotaExceeded::Transfer.into());
+        }
+
+        Ok(())
+    }
+
+    /// Fail if the monthly transfer cap is used up, for transfers that are
+    /// only charged once their size is known
+    pub async fn check_transfer(&self, app_id: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(quotas) = self.quotas(app_id).await? else {
+            return Ok(());
+        };
+
+        let counters = Self::transfer_keys(app_id, &quotas);
+        let keys = counters
+            .iter()
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        let used = self.store.get(&keys).await?;
+
+        if counters
+            .iter()
+            .zip(used)
+            .any(|((_, limit), used)| *limit >= 0 && used >= *limit)
+        {
+            debug!("Monthly transfer limit reached for app {app_id}");
+            return Err(QuotaExceeded::Transfer.into());
+        }
+
+        Ok(())
+    }
+
+    /// Charge bytes that were already transferred, even past the cap
+    pub async fn charge_transfer(
+        &self,
+        app_id: &str,
+        bytes: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(quotas) = self.quotas(app_id).await? else {
+            return Ok(());
+        };
+
+        let counters = Self::transfer_keys(app_id, &quotas).map(|(key, _)| (key, -1));
+
+        self.store
+            .increment(&counters, bytes as i64, TRANSFER_COUNTER_TTL_SECONDS)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Member of the channel quota sets
+pub fn channel_member(app_id: &str, channel_id: &str) -> String {
+    format!("{app_id}:{channel_id}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws::quota::store::MemoryQuotaStore;
+    use async_trait::async_trait;
+    use std::sync::Mutex as StdMutex;
+    use tokio::time::advance;
+
+    const ORGANIZATION_ID: Uuid = Uuid::from_u128(1);
+
+    #[derive(Default)]
+    struct FakeQuotas {
+        quotas: StdMutex<HashMap<String, Quotas>>,
+    }
+
+    impl FakeQuotas {
+        fn set(&self, app_id: &str, organization: Limits, app: Limits) {
+            self.quotas.lock().unwrap().insert(
+                app_id.to_string(),
+                Quotas {
+                    organization_id: ORGANIZATION_ID,
+                    organization,
+                    app,
+                },
+            );
+        }
+    }
+
+    #[async_trait]
+    impl QuotaRepositoryTrait for FakeQuotas {
+        async fn get_quotas(
+            &self,
+            app_id: &str,
+        ) -> Result<Option<Quotas>, Box<dyn std::error::Error>> {
+            Ok(self.quotas.lock().unwrap().get(app_id).copied())
+        }
+    }
+
+    fn enforcer() -> (QuotaEnforcer, Arc<MemoryQuotaStore>, Arc<FakeQuotas>) {
+        let store = Arc::new(MemoryQuotaStore::default());
+        let repository = Arc::new(FakeQuotas::default());
+        let enforcer = QuotaEnforcer::with_store(store.clone(), repository.clone());
+
+        (enforcer, store, repository)
+    }
+
+    fn exceeded(result: Result<(), Box<dyn std::error::Error>>) -> Option<QuotaExceeded> {
+        result
+            .err()
+            .and_then(|e| e.downcast_ref::<QuotaExceeded>().copied())
+    }
+
+    fn connections(max: i64) -> Limits {
+        Limits {
+            max_connections: Some(max),
+            ..Limits::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_past_the_app_limit_are_refused_until_one_is_released() {
+        let (enforcer, _, repository) = enforcer();
+        let [first, second, third] = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+
+        repository.set("app", Limits::default(), connections(2));
+
+        enforcer.acquire_connection("app", &first).await.unwrap();
+        enforcer.acquire_connection("app", &second).await.unwrap();
+        assert_eq!(
+            exceeded(enforcer.acquire_connection("app", &third).await),
+            Some(QuotaExceeded::Connections)
+        );
+
+        enforcer.release_connection("app", &first).await.unwrap();
+        enforcer.acquire_connection("app", &third).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_connection_holds_one_slot_however_often_it_is_counted() {
+        let (enforcer, _, repository) = enforcer();
+        let [first, second, third] = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+
+        repository.set("app", Limits::default(), connections(2));
+
+        enforcer.acquire_connection("app", &first).await.unwrap();
+        enforcer.acquire_connection("app", &first).await.unwrap();
+        enforcer.acquire_connection("app", &second).await.unwrap();
+
+        // Releasing twice frees a single slot
+        enforcer.release_connection("app", &first).await.unwrap();
+        enforcer.release_connection("app", &first).await.unwrap();
+        enforcer.acquire_connection("app", &third).await.unwrap();
+        assert_eq!(
+            exceeded(enforcer.acquire_connection("app", &first).await),
+            Some(QuotaExceeded::Connections)
+        );
+    }
+
+    #[tokio::test]
+    async fn the_organization_limit_spans_its_apps() {
+        let (enforcer, _, repository) = enforcer();
+
+        repository.set("first", connections(1), Limits::default());
+        repository.set("second", connections(1), Limits::default());
+
+        enforcer
+            .acquire_connection("first", &Uuid::new_v4())
+            .await
+            .unwrap();
+        assert_eq!(
+            exceeded(enforcer.acquire_connection("second", &Uuid::new_v4()).await),
+            Some(QuotaExceeded::Connections)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slots_taken_under_a_removed_plan_are_released() {
+        let (enforcer, store, repository) = enforcer();
+        let connection_id = Uuid::new_v4();
+
+        repository.set("app", connections(5), connections(5));
+        enforcer
+            .acquire_connection("app", &connection_id)
+            .await
+            .unwrap();
+
+        repository.set("app", Limits::default(), Limits::default());
+        advance(QUOTAS_CACHE_TTL + Duration::from_secs(1)).await;
+
+        enforcer
+            .release_connection("app", &connection_id)
+            .await
+            .unwrap();
+
+        let sets = store.sets.lock().unwrap();
+
+        assert!(sets.values().all(|members| members.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn channels_past_the_limit_are_refused() {
+        let (enforcer, _, repository) = enforcer();
+        let limits = Limits {
+            max_channels: Some(1),
+            ..Limits::default()
+        };
+
+        repository.set("app", Limits::default(), limits);
+
+        enforcer.acquire_channel("app", "first").await.unwrap();
+        enforcer.acquire_channel("app", "first").await.unwrap();
+        assert_eq!(
+            exceeded(enforcer.acquire_channel("app", "second").await),
+            Some(QuotaExceeded::Channels)
+        );
+
+        enforcer.release_channel("app", "first").await.unwrap();
+        enforcer.acquire_channel("app", "second").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn transfers_are_refused_once_the_monthly_cap_is_used_up() {
+        let (enforcer, _, repository) = enforcer();
+        let limits = Limits {
+            monthly_transfer_bytes: Some(100),
+            ..Limits::default()
+        };
+
+        repository.set("app", limits, Limits::default());
+
+        enforcer.record_transfer("app", 60).await.unwrap();
+        assert_eq!(
+            exceeded(enforcer.record_transfer("app", 50).await),
+            Some(QuotaExceeded::Transfer)
+        );
+
+        // Transfers charged after the fact are counted in full
+        enforcer.check_transfer("app").await.unwrap();
+        enforcer.charge_transfer("app", 50).await.unwrap();
+        assert_eq!(
+            exceeded(enforcer.check_transfer("app").await),
+            Some(QuotaExceeded::Transfer)
+        );
+    }
+
+    #[tokio::test]
+    async fn documents_are_held_to_the_tighter_size_limit() {
+        let (enforcer, _, repository) = enforcer();
+        let size = |max| Limits {
+            max_document_size_bytes: Some(max),
+            ..Limits::default()
+        };
+
+        repository.set("app", size(1_000), size(100));
+
+        enforcer.check_document_size("app", 100).await.unwrap();
+        assert_eq!(
+            exceeded(enforcer.check_document_size("app", 101).await),
+            Some(QuotaExceeded::DocumentSize)
+        );
+    }
+
+    #[tokio::test]
+    async fn apps_without_quotas_are_not_limited() {
+        let (enforcer, store, _) = enforcer();
+
+        enforcer
+            .acquire_connection("unknown", &Uuid::new_v4())
+            .await
+            .unwrap();
+        enforcer
+            .record_transfer("unknown", usize::MAX / 2)
+            .await
+            .unwrap();
+
+        assert!(store.sets.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,25 @@
+use thiserror::Error;
+
+/// A plan limit was hit. Each variant maps to the close code sent to the client.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaExceeded {
+    #[error("Concurrent connection limit reached")]
+    Connections,
+    #[error("Channel limit reached")]
+    Channels,
+    #[error("Document size limit reached")]
+    DocumentSize,
+    #[error("Monthly transfer limit reached")]
+    Transfer,
+}
+
+impl QuotaExceeded {
+    pub fn close_code(&self) -> u16 {
+        match self {
+            QuotaExceeded::Connections => 4001,
+            QuotaExceeded::Channels => 4002,
+            QuotaExceeded::DocumentSize => 4003,
+            QuotaExceeded::Transfer => 4004,
+        }
+    }
+}
@@ -0,0 +1,11 @@
+pub mod enforcer;
+pub mod errors;
+pub mod reconciler;
+pub mod repository;
+pub mod store;
+
+pub use enforcer::QuotaEnforcer;
+pub use errors::QuotaExceeded;
+pub use reconciler::QuotaReconciler;
+pub use repository::{Limits, QuotaRepository, QuotaRepositoryTrait, Quotas};
+pub use store::{QuotaStore, RedisQuotaStore};
@@ -0,0 +1,390 @@
+use db::quota;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, interval};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use super::QuotaStore;
+use crate::storage::MembershipIndex;
+use crate::ws::session::ConnectionRegistry;
+
+/// Drops connections and channels from the quota sets once they ended without
+/// releasing their slot, as when every node stopped at once.
+///
+/// A connection is live while the registry records it and a channel while its
+/// membership index is seeded, both expire without heartbeats. An id is only
+/// dropped once two passes in a row found it dead, so a slot taken just before
+/// its connection or channel is registered is kept.
+pub struct QuotaReconciler {
+    store: Arc<dyn QuotaStore>,
+    registry: Arc<dyn ConnectionRegistry>,
+    membership: Arc<dyn MembershipIndex>,
+    // (set, member) pairs found dead by the previous pass
+    suspects: Mutex<HashSet<(String, String)>>,
+}
+
+impl QuotaReconciler {
+    pub fn new(
+        store: Arc<dyn QuotaStore>,
+        registry: Arc<dyn ConnectionRegistry>,
+        membership: Arc<dyn MembershipIndex>,
+    ) -> Self {
+        Self {
+            store,
+            registry,
+            membership,
+            suspects: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Every app and organization set of `counter`, with its members
+    async fn sets(
+        &self,
+        counter: &str,
+    ) -> Result<Vec<(String, Vec<String>)>, Box<dyn std::error::Error>> {
+        let mut sets = vec![];
+
+        for pattern in [
+            quota::app_key("*", counter),
+            quota::organization_key("*", counter),
+        ] {
+            let matching = self.store.sets(&pattern).await?;
+
+            for set in matching {
+                let members = self.store.members(&set).await?;
+                sets.push((set, members));
+            }
+        }
+
+        Ok(sets)
+    }
+
+    async fn live_connections(
+        &self,
+        members: HashSet<&String>,
+    ) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+        let connection_ids = members
+            .into_iter()
+            .filter_map(|member| member.parse().ok())
+            .collect::<Vec<Uuid>>();
+
+        let owners = self.registry.connection_owners(&connection_ids).await?;
+
+        Ok(connection_ids
+            .into_iter()
+            .zip(owners)
+            .filter(|(_, owner)| owner.is_some())
+            .map(|(connection_id, _)| connection_id.to_string())
+            .collect())
+    }
+
+    async fn live_channels(
+        &self,
+        members: HashSet<&String>,
+    ) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
+        let mut live = HashSet::new();
+
+        for member in members {
+            let Some((app_id, channel_id)) = member.split_once(':') else {
+                continue;
+            };
+
+            if self.membership.is_seeded(app_id, channel_id).await? {
+                live.insert(member.clone());
+            }
+        }
+
+        Ok(live)
+    }
+
+    /// Drop the ids that were dead in this pass and the previous one,
+    /// returning how many were dropped
+    pub async fn reconcile(&self) -> Result<usize, Box<dyn std::error::Error>> {
+        let connection_sets = self.sets(quota::CONNECTIONS_COUNTER).await?;
+        let channel_sets = self.sets(quota::CHANNELS_COUNTER).await?;
+
+        let live_connections = self
+            .live_connections(connection_sets.iter().flat_map(|(_, m)| m).collect())
+            .await?;
+        let live_channels = self
+            .live_channels(channel_sets.iter().flat_map(|(_, m)| m).collect())
+            .await?;
+
+        let dead = connection_sets
+            .into_iter()
+            .map(|(set, members)| (set, members, &live_connections))
+            .chain(
+                channel_sets
+                    .into_iter()
+                    .map(|(set, members)| (set, members, &live_channels)),
+            )
+            .flat_map(|(set, members, live)| {
+                members
+                    .into_iter()
+                    .filter(|member| !live.contains(member))
+                    .map(move |member| (set.clone(), member))
+            })
+            .collect::<HashSet<_>>();
+
+        let mut suspects = self.suspects.lock().await;
+        let mut dropped = 0;
+
+        for (set, member) in dead.intersection(&suspects) {
+            self.store
+                .remove_member(std::slice::from_ref(set), member)
+                .await?;
+            dropped += 1;
+        }
+
+        *suspects = dead;
+
+        Ok(dropped)
+    }
+
+    /// Reconcile every `period`
+    pub fn start(self: Arc<Self>, period: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+
+            loop {
+                ticker.tick().await;
+
+                match self.reconcile().await {
+                    Ok(0) => {}
+                    Ok(dropped) => info!("Released {dropped} quota slots nothing held anymore"),
+                    Err(e) => error!("Failed to reconcile quotas: {e}"),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws::quota::store::MemoryQuotaStore;
+    use async_trait::async_trait;
+    use serde_json::Value as JsonValue;
+    use std::collections::HashMap;
+    use std::sync::Mutex as StdMutex;
+
+    #[derive(Default)]
+    struct FakeRegistry {
+        live: StdMutex<HashSet<Uuid>>,
+    }
+
+    #[async_trait]
+    impl ConnectionRegistry for FakeRegistry {
+        async fn connection_owners(
+            &self,
+            connection_ids: &[Uuid],
+        ) -> Result<Vec<Option<Uuid>>, Box<dyn std::error::Error>> {
+            let live = self.live.lock().unwrap();
+
+            Ok(connection_ids
+                .iter()
+                .map(|connection_id| live.contains(connection_id).then(Uuid::nil))
+                .collect())
+        }
+
+        async fn heartbeats(&self) -> Result<HashMap<Uuid, i64>, Box<dyn std::error::Error>> {
+            Ok(Default::default())
+        }
+    }
+
+    #[derive(Default)]
+    struct SeededChannels {
+        seeded: StdMutex<HashSet<(String, String)>>,
+    }
+
+    #[async_trait]
+    impl MembershipIndex for SeededChannels {
+        async fn add_member(
+            &self,
+            _app_id: &str,
+            _channel_id: &str,
+            _user_id: &str,
+            _metadata: &JsonValue,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        async fn remove_member(
+            &self,
+            _app_id: &str,
+            _channel_id: &str,
+            _user_id: &str,
+        ) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+            Ok(None)
+        }
+
+        async fn get_member_ids(
+            &self,
+            _app_id: &str,
+            _channel_id: &str,
+        ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+            Ok(vec![])
+        }
+
+        async fn seed(
+            &self,
+            _app_id: &str,
+            _channel_id: &str,
+            _members: &[(String, JsonValue)],
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        async fn is_seeded(
+            &self,
+            app_id: &str,
+            channel_id: &str,
+        ) -> Result<bool, Box<dyn std::error::Error>> {
+            Ok(self
+                .seeded
+                .lock()
+                .unwrap()
+                .contains(&(app_id.to_string(), channel_id.to_string())))
+        }
+
+        async fn refresh(
+            &self,
+            _channels: &[(String, String)],
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    struct Fixture {
+        store: Arc<MemoryQuotaStore>,
+        registry: Arc<FakeRegistry>,
+        membership: Arc<SeededChannels>,
+        reconciler: QuotaReconciler,
+    }
+
+    fn fixture() -> Fixture {
+        let store = Arc::new(MemoryQuotaStore::default());
+        let registry = Arc::new(FakeRegistry::default());
+        let membership = Arc::new(SeededChannels::default());
+        let reconciler = QuotaReconciler::new(store.clone(), registry.clone(), membership.clone());
+
+        Fixture {
+            store,
+            registry,
+            membership,
+            reconciler,
+        }
+    }
+
+    fn fill(store: &MemoryQuotaStore, counter: &str, members: &[String]) {
+        let mut sets = store.sets.lock().unwrap();
+
+        for key in [
+            quota::app_key("app", counter),
+            quota::organization_key("org", counter),
+        ] {
+            sets.entry(key).or_default().extend(members.iter().cloned());
+        }
+    }
+
+    fn members(store: &MemoryQuotaStore, key: &str) -> HashSet<String> {
+        store
+            .sets
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn connections_missing_from_the_registry_are_dropped_on_the_second_pass() {
+        let fixture = fixture();
+        let live = Uuid::new_v4();
+        let gone = Uuid::new_v4();
+
+        fixture.registry.live.lock().unwrap().insert(live);
+        fill(
+            &fixture.store,
+            quota::CONNECTIONS_COUNTER,
+            &[live.to_string(), gone.to_string()],
+        );
+
+        assert_eq!(fixture.reconciler.reconcile().await.unwrap(), 0);
+        assert_eq!(fixture.reconciler.reconcile().await.unwrap(), 2);
+
+        let expected = HashSet::from([live.to_string()]);
+
+        assert_eq!(
+            members(
+                &fixture.store,
+                &quota::app_key("app", quota::CONNECTIONS_COUNTER)
+            ),
+            expected
+        );
+        assert_eq!(
+            members(
+                &fixture.store,
+                &quota::organization_key("org", quota::CONNECTIONS_COUNTER)
+            ),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn connections_registered_between_passes_are_kept() {
+        let fixture = fixture();
+        let connection_id = Uuid::new_v4();
+
+        fill(
+            &fixture.store,
+            quota::CONNECTIONS_COUNTER,
+            &[connection_id.to_string()],
+        );
+
+        assert_eq!(fixture.reconciler.reconcile().await.unwrap(), 0);
+
+        fixture.registry.live.lock().unwrap().insert(connection_id);
+
+        assert_eq!(fixture.reconciler.reconcile().await.unwrap(), 0);
+        assert_eq!(fixture.reconciler.reconcile().await.unwrap(), 0);
+        assert!(
+            members(
+                &fixture.store,
+                &quota::app_key("app", quota::CONNECTIONS_COUNTER)
+            )
+            .contains(&connection_id.to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn channels_whose_membership_expired_are_dropped() {
+        let fixture = fixture();
+
+        fixture
+            .membership
+            .seeded
+            .lock()
+            .unwrap()
+            .insert(("app".to_string(), "active".to_string()));
+        fill(
+            &fixture.store,
+            quota::CHANNELS_COUNTER,
+            &["app:active".to_string(), "app:abandoned".to_string()],
+        );
+
+        fixture.reconciler.reconcile().await.unwrap();
+        assert_eq!(fixture.reconciler.reconcile().await.unwrap(), 2);
+
+        assert_eq!(
+            members(
+                &fixture.store,
+                &quota::app_key("app", quota::CHANNELS_COUNTER)
+            ),
+            HashSet::from(["app:active".to_string()])
+        );
+    }
+}
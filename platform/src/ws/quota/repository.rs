@@ -0,0 +1,104 @@
+use async_trait::async_trait;
+use sqlx::PgPool;
+use tracing::debug;
+use uuid::Uuid;
+
+/// Plan limits for one scope. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub max_connections: Option<i64>,
+    pub max_channels: Option<i64>,
+    pub max_document_size_bytes: Option<i64>,
+    pub monthly_transfer_bytes: Option<i64>,
+}
+
+/// Limits that apply to an app: the organization plan and the app's own caps
+#[derive(Debug, Clone, Copy)]
+pub struct Quotas {
+    pub organization_id: Uuid,
+    pub organization: Limits,
+    pub app: Limits,
+}
+
+impl Quotas {
+    /// The effective maximum document size, the tighter of both scopes
+    pub fn max_document_size_bytes(&self) -> Option<i64> {
+        match (
+            self.organization.max_document_size_bytes,
+            self.app.max_document_size_bytes,
+        ) {
+            (Some(org), Some(app)) => Some(org.min(app)),
+            (org, app) => org.or(app),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QuotaRepository {
+    pool: PgPool,
+}
+
+impl QuotaRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Load the organization and app limits for an app
+    pub async fn get_quotas(
+        &self,
+        app_id: &str,
+    ) -> Result<Option<Quotas>, Box<dyn std::error::Error>> {
+        debug!("Loading quotas for app {app_id}");
+
+        let row = sqlx::query!(
+            r#"
+            SELECT
+                a.organization_id,
+                ol.max_connections AS "org_max_connections?",
+                ol.max_channels AS "org_max_channels?",
+                ol.max_document_size_bytes AS "org_max_document_size_bytes?",
+                ol.monthly_transfer_bytes AS "org_monthly_transfer_bytes?",
+                al.max_connections AS "app_max_connections?",
+                al.max_channels AS "app_max_channels?",
+                al.max_document_size_bytes AS "app_max_document_size_bytes?",
+                al.monthly_transfer_bytes AS "app_monthly_transfer_bytes?"
+            FROM apps a
+            LEFT JOIN organization_limits ol ON ol.organization_id = a.organization_id
+            LEFT JOIN app_limits al ON al.app_id = a.app_id
+            WHERE a.app_id = $1
+            "#,
+            app_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| Quotas {
+            organization_id: row.organization_id,
+            organization: Limits {
+                max_connections: row.org_max_connections.map(i64::from),
+                max_channels: row.org_max_channels.map(i64::from),
+                max_document_size_bytes: row.org_max_document_size_bytes,
+                monthly_transfer_bytes: row.org_monthly_transfer_bytes,
+            },
+            app: Limits {
+                max_connections: row.app_max_connections.map(i64::from),
+                max_channels: row.app_max_channels.map(i64::from),
+                max_document_size_bytes: row.app_max_document_size_bytes,
+                monthly_transfer_bytes: row.app_monthly_transfer_bytes,
+            },
+        }))
+    }
+}
+
+/// Trait for quota repository operations
+#[async_trait]
+pub trait QuotaRepositoryTrait: Send + Sync {
+    async fn get_quotas(&self, app_id: &str) -> Result<Option<Quotas>, Box<dyn std::error::Error>>;
+}
+
+#[async_trait]
+impl QuotaRepositoryTrait for QuotaRepository {
+    async fn get_quotas(&self, app_id: &str) -> Result<Option<Quotas>, Box<dyn std::error::Error>> {
+        self.get_quotas(app_id).await
+    }
+}
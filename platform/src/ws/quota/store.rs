@@ -0,0 +1,354 @@
+use async_trait::async_trait;
+use redis::{Script, aio::ConnectionManager};
+#[cfg(test)]
+use std::collections::{HashMap, HashSet};
+#[cfg(test)]
+use std::sync::Mutex as StdMutex;
+
+// Increment counters only if none of them would exceed its limit. ARGV holds a
+// (limit, amount, ttl) triple per key, a negative limit means unlimited and a
+// zero ttl leaves the key without expiry. Returns the 1-based index of the
+// first counter over its limit, or 0 on success.
+const COUNTER_SCRIPT: &str = r#"
+for i, key in ipairs(KEYS) do
+    local limit = tonumber(ARGV[i * 3 - 2])
+    local amount = tonumber(ARGV[i * 3 - 1])
+    local current = tonumber(redis.call('GET', key)) or 0
+
+    if limit >= 0 and current + amount > limit then
+        return i
+    end
+end
+
+for i, key in ipairs(KEYS) do
+    local value = redis.call('INCRBY', key, ARGV[i * 3 - 1])
+
+    if value < 0 then
+        redis.call('SET', key, 0)
+    end
+
+    local ttl = tonumber(ARGV[i * 3])
+
+    if ttl > 0 then
+        redis.call('EXPIRE', key, ttl)
+    end
+end
+
+return 0
+"#;
+
+// Add ARGV[1] to every set unless a set that does not contain it yet is full.
+// ARGV[i + 1] is the limit of KEYS[i], negative meaning unlimited.
+const SET_MEMBER_SCRIPT: &str = r#"
+for i, key in ipairs(KEYS) do
+    local limit = tonumber(ARGV[i + 1])
+
+    if limit >= 0
+        and redis.call('SISMEMBER', key, ARGV[1]) == 0
+        and redis.call('SCARD', key) >= limit then
+        return i
+    end
+end
+
+for _, key in ipairs(KEYS) do
+    redis.call('SADD', key, ARGV[1])
+end
+
+return 0
+"#;
+
+// Keys scanned per SCAN call when listing quota sets
+const SCAN_COUNT: usize = 500;
+
+/// Counters and sets holding quota usage, shared by every platform node
+#[async_trait]
+pub trait QuotaStore: Send + Sync {
+    /// Add `amount` to every `(key, limit)` counter unless one would go over
+    /// its limit, a negative limit meaning unlimited. A zero `ttl` leaves the
+    /// counters without expiry. Returns whether they were incremented.
+    async fn increment(
+        &self,
+        counters: &[(String, i64)],
+        amount: i64,
+        ttl: i64,
+    ) -> Result<bool, Box<dyn std::error::Error>>;
+
+    /// Current value of each counter, zero for missing ones
+    async fn get(&self, keys: &[String]) -> Result<Vec<i64>, Box<dyn std::error::Error>>;
+
+    /// Add `member` to every `(key, limit)` set unless a set that does not
+    /// hold it yet is full. Returns whether it was added.
+    async fn add_member(
+        &self,
+        sets: &[(String, i64)],
+        member: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>>;
+
+    async fn remove_member(
+        &self,
+        sets: &[String],
+        member: &str,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    async fn members(&self, set: &str) -> Result<Vec<String>, Box<dyn std::error::Error>>;
+
+    /// Sets whose key matches the glob `pattern`. Keys of another type, left
+    /// by counters that predate the sets, are dropped.
+    async fn sets(&self, pattern: &str) -> Result<Vec<String>, Box<dyn std::error::Error>>;
+}
+
+pub struct RedisQuotaStore {
+    redis: ConnectionManager,
+    counter_script: Script,
+    set_member_script: Script,
+}
+
+impl RedisQuotaStore {
+    pub fn new(redis: ConnectionManager) -> Self {
+        Self {
+            redis,
+            counter_script: Script::new(COUNTER_SCRIPT),
+            set_member_script: Script::new(SET_MEMBER_SCRIPT),
+        }
+    }
+}
+
+#[async_trait]
+impl QuotaStore for RedisQuotaStore {
+    async fn increment(
+        &self,
+        counters: &[(String, i64)],
+        amount: i64,
+        ttl: i64,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut invocation = self.counter_script.prepare_invoke();
+
+        for (key, limit) in counters {
+            invocation.key(key).arg(*limit).arg(amount).arg(ttl);
+        }
+
+        let mut conn = self.redis.clone();
+        let exceeded: usize = invocation.invoke_async(&mut conn).await?;
+
+        Ok(exceeded == 0)
+    }
+
+    async fn get(&self, keys: &[String]) -> Result<Vec<i64>, Box<dyn std::error::Error>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut conn = self.redis.clone();
+        let values: Vec<Option<i64>> = redis::cmd("MGET").arg(keys).query_async(&mut conn).await?;
+
+        Ok(values.into_iter().map(Option::unwrap_or_default).collect())
+    }
+
+    async fn add_member(
+        &self,
+        sets: &[(String, i64)],
+        member: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut invocation = self.set_member_script.prepare_invoke();
+
+        invocation.arg(member);
+
+        for (key, limit) in sets {
+            invocation.key(key).arg(*limit);
+        }
+
+        let mut conn = self.redis.clone();
+        let exceeded: usize = invocation.invoke_async(&mut conn).await?;
+
+        Ok(exceeded == 0)
+    }
+
+    async fn remove_member(
+        &self,
+        sets: &[String],
+        member: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut pipe = redis::pipe();
+
+        for key in sets {
+            pipe.cmd("SREM").arg(key).arg(member).ignore();
+        }
+
+        let mut conn = self.redis.clone();
+        pipe.query_async::<()>(&mut conn).await?;
+
+        Ok(())
+    }
+
+    async fn members(&self, set: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        Ok(redis::cmd("SMEMBERS")
+            .arg(set)
+            .query_async(&mut conn)
+            .await?)
+    }
+
+    async fn sets(&self, pattern: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+        let mut cursor = 0u64;
+        let mut keys = vec![];
+
+        loop {
+            let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(pattern)
+                .arg("COUNT")
+                .arg(SCAN_COUNT)
+                .query_async(&mut conn)
+                .await?;
+
+            keys.extend(batch);
+            cursor = next;
+
+            if cursor == 0 {
+                break;
+            }
+        }
+
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut pipe = redis::pipe();
+
+        for key in &keys {
+            pipe.cmd("TYPE").arg(key);
+        }
+
+        let types: Vec<String> = pipe.query_async(&mut conn).await?;
+        let mut sets = vec![];
+
+        for (key, kind) in keys.into_iter().zip(types) {
+            match kind.as_str() {
+                "set" => sets.push(key),
+                "none" => {}
+                _ => {
+                    redis::cmd("DEL")
+                        .arg(&key)
+                        .query_async::<()>(&mut conn)
+                        .await?;
+                }
+            }
+        }
+
+        Ok(sets)
+    }
+}
+
+/// In-memory store with the same semantics as the Redis scripts
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryQuotaStore {
+    pub counters: StdMutex<HashMap<String, i64>>,
+    pub sets: StdMutex<HashMap<String, HashSet<String>>>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl QuotaStore for MemoryQuotaStore {
+    async fn increment(
+        &self,
+        counters: &[(String, i64)],
+        amount: i64,
+        _ttl: i64,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut stored = self.counters.lock().unwrap();
+
+        for (key, limit) in counters {
+            let current = stored.get(key).copied().unwrap_or_default();
+
+            if *limit >= 0 && current + amount > *limit {
+                return Ok(false);
+            }
+        }
+
+        for (key, _) in counters {
+            let value = stored.entry(key.clone()).or_default();
+            *value = (*value + amount).max(0);
+        }
+
+        Ok(true)
+    }
+
+    async fn get(&self, keys: &[String]) -> Result<Vec<i64>, Box<dyn std::error::Error>> {
+        let stored = self.counters.lock().unwrap();
+
+        Ok(keys
+            .iter()
+            .map(|key| stored.get(key).copied().unwrap_or_default())
+            .collect())
+    }
+
+    async fn add_member(
+        &self,
+        sets: &[(String, i64)],
+        member: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut stored = self.sets.lock().unwrap();
+
+        for (key, limit) in sets {
+            let set = stored.entry(key.clone()).or_default();
+
+            if *limit >= 0 && !set.contains(member) && set.len() as i64 >= *limit {
+                return Ok(false);
+            }
+        }
+
+        for (key, _) in sets {
+            stored
+                .entry(key.clone())
+                .or_default()
+                .insert(member.to_string());
+        }
+
+        Ok(true)
+    }
+
+    async fn remove_member(
+        &self,
+        sets: &[String],
+        member: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut stored = self.sets.lock().unwrap();
+
+        for key in sets {
+            if let Some(set) = stored.get_mut(key) {
+                set.remove(member);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn members(&self, set: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Ok(self
+            .sets
+            .lock()
+            .unwrap()
+            .get(set)
+            .map(|members| members.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn sets(&self, pattern: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let (prefix, suffix) = pattern.split_once('*').unwrap_or((pattern, ""));
+
+        Ok(self
+            .sets
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, members)| {
+                !members.is_empty() && key.starts_with(prefix) && key.ends_with(suffix)
+            })
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+}
@@ -0,0 +1,164 @@
+use tokio::time::Instant;
+
+/// In-memory token bucket refilled continuously at `rate` tokens per second,
+/// holding at most one second worth of tokens.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Change the refill rate, keeping the tokens already earned up to the new
+    /// capacity. A bucket that was disabled starts full.
+    pub fn set_rate(&mut self, rate: u32) {
+        self.refill();
+
+        let rate = rate as f64;
+
+        self.tokens = if self.rate == 0.0 {
+            rate
+        } else {
+            self.tokens.min(rate)
+        };
+        self.rate = rate;
+    }
+
+    /// Whether `cost` tokens can be taken. A rate of zero disables the bucket.
+    ///
+    /// A cost larger than the bucket capacity is accepted once the bucket is
+    /// full and leaves it in debt, so oversized messages are throttled rather
+    /// than rejected forever.
+    pub fn has(&mut self, cost: u64) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+
+        self.refill();
+
+        self.tokens >= (cost as f64).min(self.rate)
+    }
+
+    /// Take `cost` tokens without checking, see [`TokenBucket::has`].
+    pub fn take(&mut self, cost: u64) {
+        if self.rate == 0.0 {
+            return;
+        }
+
+        self.refill();
+        self.tokens -= cost as f64;
+    }
+
+    /// Take `cost` tokens from the bucket if it has them.
+    pub fn try_take(&mut self, cost: u64) -> bool {
+        if !self.has(cost) {
+            return false;
+        }
+
+        self.take(cost);
+
+        true
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{Duration, advance};
+
+    #[tokio::test(start_paused = true)]
+    async fn takes_until_empty_then_refills_over_time() {
+        let mut bucket = TokenBucket::new(10);
+
+        for _ in 0..10 {
+            assert!(bucket.try_take(1));
+        }
+        assert!(!bucket.try_take(1));
+
+        advance(Duration::from_millis(100)).await;
+        assert!(bucket.try_take(1));
+        assert!(!bucket.try_take(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refill_is_capped_at_one_second_of_tokens() {
+        let mut bucket = TokenBucket::new(5);
+
+        advance(Duration::from_secs(60)).await;
+
+        for _ in 0..5 {
+            assert!(bucket.try_take(1));
+        }
+        assert!(!bucket.try_take(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn oversized_costs_pass_when_full_and_leave_debt() {
+        let mut bucket = TokenBucket::new(100);
+
+        assert!(bucket.try_take(250));
+        assert!(!bucket.has(1));
+
+        // 250 tokens of debt take 1.5s to repay before one token is available
+        advance(Duration::from_millis(1_500)).await;
+        assert!(!bucket.has(1));
+
+        advance(Duration::from_millis(20)).await;
+        assert!(bucket.has(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn checking_does_not_take() {
+        let mut bucket = TokenBucket::new(1);
+
+        assert!(bucket.has(1));
+        assert!(bucket.has(1));
+        assert!(bucket.try_take(1));
+        assert!(!bucket.has(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn zero_rate_disables_the_bucket() {
+        let mut bucket = TokenBucket::new(0);
+
+        for _ in 0..1_000 {
+            assert!(bucket.try_take(u64::MAX));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_changes_keep_tokens_within_the_new_capacity() {
+        let mut bucket = TokenBucket::new(10);
+
+        bucket.set_rate(2);
+        assert!(bucket.try_take(1));
+        assert!(bucket.try_take(1));
+        assert!(!bucket.try_take(1));
+
+        bucket.set_rate(0);
+        assert!(bucket.try_take(1));
+
+        bucket.set_rate(3);
+        for _ in 0..3 {
+            assert!(bucket.try_take(1));
+        }
+        assert!(!bucket.try_take(1));
+    }
+}
@@ -0,0 +1,563 @@
+use async_trait::async_trait;
+use redis::{Script, aio::ConnectionManager};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use super::{
+    Limit, RateLimitDecision, RateLimitRepository, RateLimitRepositoryTrait, RateLimitScope,
+    RateLimits, TokenBucket,
+};
+use crate::ws::connection::RateLimiter;
+
+// How long per-app limits are cached before being reloaded from Postgres
+const LIMITS_CACHE_TTL: Duration = Duration::from_secs(30);
+
+// Violations older than this no longer count towards closing the connection
+const VIOLATION_WINDOW: Duration = Duration::from_secs(60);
+
+// Token buckets shared across nodes. KEYS are bucket keys, ARGV holds a
+// (rate, cost) pair per key. Returns 0 when every bucket had enough tokens,
+// otherwise the 1-based index of the first exhausted bucket. Nothing is
+// consumed unless all buckets allow the message.
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local remaining = {}
+local rates = {}
+
+for i, key in ipairs(KEYS) do
+    local rate = tonumber(ARGV[i * 2 - 1])
+    local cost = tonumber(ARGV[i * 2])
+    local bucket = redis.call('HMGET', key, 'tokens', 'ts')
+    local tokens = tonumber(bucket[1]) or rate
+    local ts = tonumber(bucket[2]) or now
+
+    tokens = math.min(rate, tokens + math.max(0, now - ts) * rate / 1000)
+
+    if tokens < math.min(cost, rate) then
+        return i
+    end
+
+    remaining[i] = tokens - cost
+    rates[i] = rate
+end
+
+for i, key in ipairs(KEYS) do
+    redis.call('HSET', key, 'tokens', remaining[i], 'ts', now)
+    local refill_ms = math.ceil((rates[i] - remaining[i]) * 1000 / rates[i])
+    redis.call('PEXPIRE', key, refill_ms + 1000)
+end
+
+return 0
+"#;
+
+struct ConnectionBuckets {
+    limit: Limit,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    violations: u32,
+    last_violation: Instant,
+}
+
+impl ConnectionBuckets {
+    fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            messages: TokenBucket::new(limit.messages_per_second),
+            bytes: TokenBucket::new(limit.bytes_per_second),
+            violations: 0,
+            last_violation: Instant::now(),
+        }
+    }
+
+    /// Follow changes to the app's limits without resetting earned tokens
+    fn update(&mut self, limit: Limit) {
+        if self.limit == limit {
+            return;
+        }
+
+        self.messages.set_rate(limit.messages_per_second);
+        self.bytes.set_rate(limit.bytes_per_second);
+        self.limit = limit;
+    }
+
+    fn has(&mut self, message_size: u64) -> bool {
+        self.messages.has(1) && self.bytes.has(message_size)
+    }
+
+    fn take(&mut self, message_size: u64) {
+        self.messages.take(1);
+        self.bytes.take(message_size);
+    }
+}
+
+/// Token buckets shared by every node
+#[async_trait]
+pub trait SharedBucketStore: Send + Sync {
+    /// Take `cost` tokens from every `(key, rate, cost)` bucket, or from none
+    /// of them. Returns the index of the first bucket without enough tokens.
+    async fn take(
+        &self,
+        buckets: &[(String, u32, u64)],
+    ) -> Result<Option<usize>, Box<dyn std::error::Error>>;
+}
+
+pub struct RedisBucketStore {
+    redis: ConnectionManager,
+    script: Script,
+}
+
+impl RedisBucketStore {
+    pub fn new(redis: ConnectionManager) -> Self {
+        Self {
+            redis,
+            script: Script::new(TOKEN_BUCKET_SCRIPT),
+        }
+    }
+}
+
+#[async_trait]
+impl SharedBucketStore for RedisBucketStore {
+    async fn take(
+        &self,
+        buckets: &[(String, u32, u64)],
+    ) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+        let mut invocation = self.script.prepare_invoke();
+
+        for (key, rate, cost) in buckets {
+            invocation.key(key).arg(*rate).arg(*cost);
+        }
+
+        let mut conn = self.redis.clone();
+        let exhausted: usize = invocation.invoke_async(&mut conn).await?;
+
+        Ok(exhausted.checked_sub(1))
+    }
+}
+
+/// Token-bucket rate limiter. Connection buckets live in memory, user and app
+/// buckets live in Redis so every node draws from the same budget.
+pub struct RedisRateLimiter {
+    store: Arc<dyn SharedBucketStore>,
+    repository: Arc<dyn RateLimitRepositoryTrait>,
+    limits_cache: Mutex<HashMap<String, (Instant, RateLimits)>>,
+    connections: Mutex<HashMap<Uuid, ConnectionBuckets>>,
+}
+
+impl RedisRateLimiter {
+    pub fn new(redis: ConnectionManager, repository: Arc<RateLimitRepository>) -> Self {
+        Self::with_store(Arc::new(RedisBucketStore::new(redis)), repository)
+    }
+
+    pub fn with_store(
+        store: Arc<dyn SharedBucketStore>,
+        repository: Arc<dyn RateLimitRepositoryTrait>,
+    ) -> Self {
+        Self {
+            store,
+            repository,
+            limits_cache: Mutex::new(HashMap::new()),
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get_key(app_id: &str, scope: &str, unit: &str) -> String {
+        format!("ratelimit:{app_id}:{scope}:{unit}")
+    }
+
+    async fn limits(&self, app_id: &str) -> Result<RateLimits, Box<dyn std::error::Error>> {
+        if let Some((loaded_at, limits)) = self.limits_cache.lock().await.get(app_id)
+            && loaded_at.elapsed() < LIMITS_CACHE_TTL
+        {
+            return Ok(*limits);
+        }
+
+        let limits = self.repository.get_limits(app_id).await?;
+
+        self.limits_cache
+            .lock()
+            .await
+            .insert(app_id.to_string(), (Instant::now(), limits));
+
+        Ok(limits)
+    }
+
+    async fn check_shared(
+        &self,
+        app_id: &str,
+        user_id: &str,
+        limits: &RateLimits,
+        message_size: u64,
+    ) -> Result<Option<RateLimitScope>, Box<dyn std::error::Error>> {
+        let user_scope = format!("user:{user_id}");
+
+        let buckets = [
+            (
+                RateLimitScope::User,
+                Self::get_key(app_id, &user_scope, "messages"),
+                limits.user.messages_per_second,
+                1,
+            ),
+            (
+                RateLimitScope::User,
+                Self::get_key(app_id, &user_scope, "bytes"),
+                limits.user.bytes_per_second,
+                message_size,
+            ),
+            (
+                RateLimitScope::App,
+                Self::get_key(app_id, "app", "messages"),
+                limits.app.messages_per_second,
+                1,
+            ),
+            (
+                RateLimitScope::App,
+                Self::get_key(app_id, "app", "bytes"),
+                limits.app.bytes_per_second,
+                message_size,
+            ),
+        ];
+
+        let (scopes, buckets): (Vec<_>, Vec<_>) = buckets
+            .into_iter()
+            .filter(|(_, _, rate, _)| *rate > 0)
+            .map(|(scope, key, rate, cost)| (scope, (key, rate, cost)))
+            .unzip();
+
+        if buckets.is_empty() {
+            return Ok(None);
+        }
+
+        let exhausted = self.store.take(&buckets).await?;
+
+        Ok(exhausted.and_then(|index| scopes.get(index)).copied())
+    }
+}
+
+#[async_trait]
+impl RateLimiter for RedisRateLimiter {
+    async fn check(
+        &self,
+        app_id: &str,
+        user_id: &str,
+        connection_id: &Uuid,
+        message_size: usize,
+    ) -> Result<RateLimitDecision, Box<dyn std::error::Error>> {
+        let limits = self.limits(app_id).await?;
+        let message_size = message_size as u64;
+
+        // Nothing is taken from the connection buckets unless every bucket,
+        // shared ones included, has room for the message
+        let connection_exceeded = {
+            let mut connections = self.connections.lock().await;
+            let buckets = connections
+                .entry(*connection_id)
+                .or_insert_with(|| ConnectionBuckets::new(limits.connection));
+
+            buckets.update(limits.connection);
+
+            !buckets.has(message_size)
+        };
+
+        let exceeded = if connection_exceeded {
+            Some(RateLimitScope::Connection)
+        } else {
+            self.check_shared(app_id, user_id, &limits, message_size)
+                .await?
+        };
+
+        let mut connections = self.connections.lock().await;
+        let Some(buckets) = connections.get_mut(connection_id) else {
+            return Ok(exceeded.map_or(RateLimitDecision::Allow, RateLimitDecision::Reject));
+        };
+
+        let Some(scope) = exceeded else {
+            buckets.take(message_size);
+            return Ok(RateLimitDecision::Allow);
+        };
+
+        if buckets.last_violation.elapsed() > VIOLATION_WINDOW {
+            buckets.violations = 0;
+        }
+
+        buckets.violations += 1;
+        buckets.last_violation = Instant::now();
+
+        debug!(
+            "Connection {connection_id} exceeded {} rate limit ({} violations)",
+            scope.as_str(),
+            buckets.violations
+        );
+
+        if limits.max_violations > 0 && buckets.violations >= limits.max_violations {
+            warn!("Closing connection {connection_id} after repeated rate limit violations");
+            return Ok(RateLimitDecision::Close(scope));
+        }
+
+        Ok(RateLimitDecision::Reject(scope))
+    }
+
+    async fn release(&self, connection_id: &Uuid) {
+        self.connections.lock().await.remove(connection_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+    use tokio::time::advance;
+
+    /// In-memory stand-in for the Redis buckets with the same all-or-nothing rule
+    #[derive(Default)]
+    struct MemoryBucketStore {
+        buckets: StdMutex<HashMap<String, TokenBucket>>,
+    }
+
+    #[async_trait]
+    impl SharedBucketStore for MemoryBucketStore {
+        async fn take(
+            &self,
+            buckets: &[(String, u32, u64)],
+        ) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+            let mut stored = self.buckets.lock().unwrap();
+
+            for (index, (key, rate, cost)) in buckets.iter().enumerate() {
+                let bucket = stored
+                    .entry(key.clone())
+                    .or_insert_with(|| TokenBucket::new(*rate));
+
+                if !bucket.has(*cost) {
+                    return Ok(Some(index));
+                }
+            }
+
+            for (key, _, cost) in buckets {
+                stored.get_mut(key).unwrap().take(*cost);
+            }
+
+            Ok(None)
+        }
+    }
+
+    struct FixedLimits(StdMutex<RateLimits>);
+
+    #[async_trait]
+    impl RateLimitRepositoryTrait for FixedLimits {
+        async fn get_limits(
+            &self,
+            _app_id: &str,
+        ) -> Result<RateLimits, Box<dyn std::error::Error>> {
+            Ok(*self.0.lock().unwrap())
+        }
+    }
+
+    fn limits(connection: (u32, u32), user: (u32, u32), app: (u32, u32)) -> RateLimits {
+        let limit = |(messages_per_second, bytes_per_second)| Limit {
+            messages_per_second,
+            bytes_per_second,
+        };
+
+        RateLimits {
+            connection: limit(connection),
+            user: limit(user),
+            app: limit(app),
+            max_violations: 0,
+        }
+    }
+
+    fn limiter(limits: RateLimits) -> (RedisRateLimiter, Arc<FixedLimits>) {
+        let repository = Arc::new(FixedLimits(StdMutex::new(limits)));
+        let limiter = RedisRateLimiter::with_store(
+            Arc::new(MemoryBucketStore::default()),
+            repository.clone(),
+        );
+
+        (limiter, repository)
+    }
+
+    async fn check(
+        limiter: &RedisRateLimiter,
+        user_id: &str,
+        connection_id: &Uuid,
+        size: usize,
+    ) -> RateLimitDecision {
+        limiter
+            .check("app", user_id, connection_id, size)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejected_bytes_do_not_spend_a_message_token() {
+        let (limiter, _) = limiter(limits((2, 10), (0, 0), (0, 0)));
+        let connection = Uuid::new_v4();
+
+        assert_eq!(
+            check(&limiter, "u", &connection, 8).await,
+            RateLimitDecision::Allow
+        );
+        assert_eq!(
+            check(&limiter, "u", &connection, 8).await,
+            RateLimitDecision::Reject(RateLimitScope::Connection)
+        );
+        assert_eq!(
+            check(&limiter, "u", &connection, 1).await,
+            RateLimitDecision::Allow
+        );
+        assert_eq!(
+            check(&limiter, "u", &connection, 1).await,
+            RateLimitDecision::Reject(RateLimitScope::Connection)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeated_violations_close_the_connection() {
+        let mut limits = limits((1, 0), (0, 0), (0, 0));
+        limits.max_violations = 3;
+
+        let (limiter, _) = limiter(limits);
+        let connection = Uuid::new_v4();
+
+        assert_eq!(
+            check(&limiter, "u", &connection, 1).await,
+            RateLimitDecision::Allow
+        );
+
+        for _ in 0..2 {
+            assert_eq!(
+                check(&limiter, "u", &connection, 1).await,
+                RateLimitDecision::Reject(RateLimitScope::Connection)
+            );
+        }
+
+        assert_eq!(
+            check(&limiter, "u", &connection, 1).await,
+            RateLimitDecision::Close(RateLimitScope::Connection)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn violations_outside_the_window_are_forgotten() {
+        let mut limits = limits((1, 0), (0, 0), (0, 0));
+        limits.max_violations = 2;
+
+        let (limiter, _) = limiter(limits);
+        let connection = Uuid::new_v4();
+
+        assert_eq!(
+            check(&limiter, "u", &connection, 1).await,
+            RateLimitDecision::Allow
+        );
+        assert_eq!(
+            check(&limiter, "u", &connection, 1).await,
+            RateLimitDecision::Reject(RateLimitScope::Connection)
+        );
+
+        advance(VIOLATION_WINDOW + Duration::from_secs(1)).await;
+
+        assert_eq!(
+            check(&limiter, "u", &connection, 1).await,
+            RateLimitDecision::Allow
+        );
+        assert_eq!(
+            check(&limiter, "u", &connection, 1).await,
+            RateLimitDecision::Reject(RateLimitScope::Connection)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_of_a_user_share_the_user_bucket() {
+        let (limiter, _) = limiter(limits((10, 0), (2, 0), (0, 0)));
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+
+        assert_eq!(
+            check(&limiter, "alice", &first, 1).await,
+            RateLimitDecision::Allow
+        );
+        assert_eq!(
+            check(&limiter, "alice", &second, 1).await,
+            RateLimitDecision::Allow
+        );
+        assert_eq!(
+            check(&limiter, "alice", &second, 1).await,
+            RateLimitDecision::Reject(RateLimitScope::User)
+        );
+        assert_eq!(
+            check(&limiter, "bob", &Uuid::new_v4(), 1).await,
+            RateLimitDecision::Allow
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn users_of_an_app_share_the_app_bucket() {
+        let (limiter, _) = limiter(limits((10, 0), (10, 0), (2, 0)));
+
+        assert_eq!(
+            check(&limiter, "alice", &Uuid::new_v4(), 1).await,
+            RateLimitDecision::Allow
+        );
+        assert_eq!(
+            check(&limiter, "bob", &Uuid::new_v4(), 1).await,
+            RateLimitDecision::Allow
+        );
+        assert_eq!(
+            check(&limiter, "carol", &Uuid::new_v4(), 1).await,
+            RateLimitDecision::Reject(RateLimitScope::App)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shared_rejections_do_not_spend_connection_tokens() {
+        let (limiter, _) = limiter(limits((2, 0), (1, 0), (0, 0)));
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+
+        assert_eq!(
+            check(&limiter, "alice", &first, 1).await,
+            RateLimitDecision::Allow
+        );
+        assert_eq!(
+            check(&limiter, "alice", &second, 1).await,
+            RateLimitDecision::Reject(RateLimitScope::User)
+        );
+
+        // The second connection still holds both of its own tokens
+        assert_eq!(
+            check(&limiter, "bob", &second, 1).await,
+            RateLimitDecision::Allow
+        );
+        assert_eq!(
+            check(&limiter, "carol", &second, 1).await,
+            RateLimitDecision::Allow
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_buckets_follow_changed_limits() {
+        let (limiter, repository) = limiter(limits((5, 0), (0, 0), (0, 0)));
+        let connection = Uuid::new_v4();
+
+        assert_eq!(
+            check(&limiter, "u", &connection, 1).await,
+            RateLimitDecision::Allow
+        );
+
+        *repository.0.lock().unwrap() = limits((1, 0), (0, 0), (0, 0));
+        advance(LIMITS_CACHE_TTL + Duration::from_secs(1)).await;
+
+        assert_eq!(
+            check(&limiter, "u", &connection, 1).await,
+            RateLimitDecision::Allow
+        );
+        assert_eq!(
+            check(&limiter, "u", &connection, 1).await,
+            RateLimitDecision::Reject(RateLimitScope::Connection)
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use serde::Serialize;
+
+// Default limits applied when an app has no override in `app_rate_limits`
+const DEFAULT_CONNECTION_MESSAGES_PER_SECOND: u32 = 50;
+const DEFAULT_CONNECTION_BYTES_PER_SECOND: u32 = 1024 * 1024;
+const DEFAULT_USER_MESSAGES_PER_SECOND: u32 = 100;
+const DEFAULT_USER_BYTES_PER_SECOND: u32 = 2 * 1024 * 1024;
+const DEFAULT_APP_MESSAGES_PER_SECOND: u32 = 5_000;
+const DEFAULT_APP_BYTES_PER_SECOND: u32 = 50 * 1024 * 1024;
+const DEFAULT_MAX_VIOLATIONS: u32 = 10;
+
+/// Messages and bytes allowed per second for a single scope. Zero disables the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    pub messages_per_second: u32,
+    pub bytes_per_second: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimits {
+    pub connection: Limit,
+    pub user: Limit,
+    pub app: Limit,
+    /// Number of rejected messages after which the connection is closed
+    pub max_violations: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            connection: Limit {
+                messages_per_second: DEFAULT_CONNECTION_MESSAGES_PER_SECOND,
+                bytes_per_second: DEFAULT_CONNECTION_BYTES_PER_SECOND,
+            },
+            user: Limit {
+                messages_per_second: DEFAULT_USER_MESSAGES_PER_SECOND,
+                bytes_per_second: DEFAULT_USER_BYTES_PER_SECOND,
+            },
+            app: Limit {
+                messages_per_second: DEFAULT_APP_MESSAGES_PER_SECOND,
+                bytes_per_second: DEFAULT_APP_BYTES_PER_SECOND,
+            },
+            max_violations: DEFAULT_MAX_VIOLATIONS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitScope {
+    Connection,
+    User,
+    App,
+}
+
+impl RateLimitScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RateLimitScope::Connection => "connection",
+            RateLimitScope::User => "user",
+            RateLimitScope::App => "app",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitDecision {
+    Allow,
+    Reject(RateLimitScope),
+    Close(RateLimitScope),
+}
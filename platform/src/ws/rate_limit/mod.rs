@@ -0,0 +1,9 @@
+pub mod bucket;
+pub mod limiter;
+pub mod limits;
+pub mod repository;
+
+pub use bucket::TokenBucket;
+pub use limiter::{RedisBucketStore, RedisRateLimiter, SharedBucketStore};
+pub use limits::{Limit, RateLimitDecision, RateLimitScope, RateLimits};
+pub use repository::{RateLimitRepository, RateLimitRepositoryTrait};
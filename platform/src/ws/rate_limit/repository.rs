@@ -0,0 +1,96 @@
+use async_trait::async_trait;
+use sqlx::PgPool;
+use tracing::debug;
+
+use super::{Limit, RateLimits};
+
+#[derive(Debug, Clone)]
+pub struct RateLimitRepository {
+    pool: PgPool,
+}
+
+impl RateLimitRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    /// Load the rate limits of an app, falling back to defaults for missing values
+    pub async fn get_limits(&self, app_id: &str) -> Result<RateLimits, Box<dyn std::error::Error>> {
+        debug!("Loading rate limits for app {app_id}");
+
+        let defaults = RateLimits::default();
+
+        let row = sqlx::query!(
+            r#"
+            SELECT
+                connection_messages_per_second,
+                connection_bytes_per_second,
+                user_messages_per_second,
+                user_bytes_per_second,
+                app_messages_per_second,
+                app_bytes_per_second,
+                max_violations
+            FROM app_rate_limits
+            WHERE app_id = $1
+            "#,
+            app_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some(row) = row else {
+            return Ok(defaults);
+        };
+
+        Ok(RateLimits {
+            connection: Limit {
+                messages_per_second: or_default(
+                    row.connection_messages_per_second,
+                    defaults.connection.messages_per_second,
+                ),
+                bytes_per_second: or_default(
+                    row.connection_bytes_per_second,
+                    defaults.connection.bytes_per_second,
+                ),
+            },
+            user: Limit {
+                messages_per_second: or_default(
+                    row.user_messages_per_second,
+                    defaults.user.messages_per_second,
+                ),
+                bytes_per_second: or_default(
+                    row.user_bytes_per_second,
+                    defaults.user.bytes_per_second,
+                ),
+            },
+            app: Limit {
+                messages_per_second: or_default(
+                    row.app_messages_per_second,
+                    defaults.app.messages_per_second,
+                ),
+                bytes_per_second: or_default(
+                    row.app_bytes_per_second,
+                    defaults.app.bytes_per_second,
+                ),
+            },
+            max_violations: or_default(row.max_violations, defaults.max_violations),
+        })
+    }
+}
+
+/// Trait for rate limit repository operations
+#[async_trait]
+pub trait RateLimitRepositoryTrait: Send + Sync {
+    async fn get_limits(&self, app_id: &str) -> Result<RateLimits, Box<dyn std::error::Error>>;
+}
+
+#[async_trait]
+impl RateLimitRepositoryTrait for RateLimitRepository {
+    async fn get_limits(&self, app_id: &str) -> Result<RateLimits, Box<dyn std::error::Error>> {
+        self.get_limits(app_id).await
+    }
+}
+
+fn or_default(value: Option<i32>, default: u32) -> u32 {
+    value.map(|v| v.max(0) as u32).unwrap_or(default)
+}
@@ -0,0 +1,427 @@
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, interval};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use super::connection::{ConnectionState, MessageHandler};
+use super::session::NodeRegistry;
+
+// How long a node holds the claim on a dead node it is cleaning up. Another
+// node picks up whatever is left if the claim lapses.
+const REAP_CLAIM_TTL: Duration = Duration::from_secs(60);
+
+/// Cleans up after platform nodes that stopped heartbeating: their
+/// connections are removed from the registry, and users left without a
+/// connection are removed from their documents as if they had disconnected
+pub struct SessionReaper {
+    registry: Arc<dyn NodeRegistry>,
+    message_handler: Arc<dyn MessageHandler>,
+    node_ttl: Duration,
+}
+
+impl SessionReaper {
+    pub fn new(
+        registry: Arc<dyn NodeRegistry>,
+        message_handler: Arc<dyn MessageHandler>,
+        node_ttl: Duration,
+    ) -> Self {
+        Self {
+            registry,
+            message_handler,
+            node_ttl,
+        }
+    }
+
+    /// Remove one connection of a dead node, and its user from the documents
+    /// if it was their last connection
+    async fn reap_connection(
+        &self,
+        node_id: &Uuid,
+        connection_id: &Uuid,
+        disconnected_at: OffsetDateTime,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(connection) = self.registry.get_connection(connection_id).await? else {
+            return Ok(());
+        };
+
+        let remaining = self
+            .registry
+            .remove_orphan(node_id, &connection, disconnected_at)
+            .await?;
+
+        if remaining != Some(0) {
+            return Ok(());
+        }
+
+        let state = ConnectionState {
+            app_id: connection.app_id,
+            user_id: connection.user_id,
+            channel_ids: connection.channel_ids,
+            connection_id: connection.connection_id,
+            // Whatever the app's setting, the member must not be left behind
+            members_in_document: true,
+            ..Default::default()
+        };
+
+        self.message_handler
+            .on_close(Arc::new(Mutex::new(state)))
+            .await
+    }
+
+    /// Remove the connections of a dead node. One failing connection does not
+    /// hold up the others, the node is only forgotten once none failed so the
+    /// next reap retries the rest.
+    async fn reap_node(
+        &self,
+        node_id: &Uuid,
+        last_seen: i64,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let connection_ids = self.registry.node_connections(node_id).await?;
+        let disconnected_at = OffsetDateTime::from_unix_timestamp(last_seen)?;
+        let mut failed = 0;
+
+        for connection_id in &connection_ids {
+            if let Err(e) = self
+                .reap_connection(node_id, connection_id, disconnected_at)
+                .await
+            {
+                error!("Failed to reap connection {connection_id} of node {node_id}: {e}");
+                failed += 1;
+            }
+        }
+
+        if failed > 0 {
+            warn!("{failed} connections of dead node {node_id} are left for the next reap");
+        } else {
+            self.registry.remove_node(node_id).await?;
+        }
+
+        Ok(connection_ids.len() - failed)
+    }
+
+    /// Reap every node whose heartbeat is older than the node TTL
+    pub async fn reap(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let dead_nodes = self.registry.dead_nodes(self.node_ttl).await?;
+
+        for (node_id, last_seen) in dead_nodes {
+            // Make sure no other node is reaping it
+            if !self.registry.claim_node(&node_id, REAP_CLAIM_TTL).await? {
+                continue;
+            }
+
+            warn!(
+                "Node {node_id} missed its heartbeats since {last_seen}, removing its connections"
+            );
+
+            match self.reap_node(&node_id, last_seen).await {
+                Ok(removed) => info!("Removed {removed} connections of dead node {node_id}"),
+                Err(e) => error!("Failed to reap dead node {node_id}: {e}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Look for dead nodes every `period`
+    pub fn start(self: Arc<Self>, period: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+
+            loop {
+                ticker.tick().await;
+
+                if let Err(e) = self.reap().await {
+                    error!("Failed to reap dead nodes: {e}");
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws::connection::WsWrite;
+    use crate::ws::dto::incoming_message::IncomingMessage;
+    use crate::ws::session::{RegisteredConnection, Session};
+    use async_trait::async_trait;
+    use redis::Arg;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Mutex as StdMutex;
+
+    #[derive(Default)]
+    struct FakeRegistry {
+        dead_nodes: Vec<(Uuid, i64)>,
+        claims: StdMutex<HashSet<Uuid>>,
+        // Owning node of each registered connection
+        connections: StdMutex<HashMap<Uuid, (Uuid, RegisteredConnection)>>,
+        failing: HashSet<Uuid>,
+        disconnected_at: StdMutex<Vec<(Uuid, OffsetDateTime)>>,
+        removed_nodes: StdMutex<Vec<Uuid>>,
+    }
+
+    impl FakeRegistry {
+        fn register(&self, node_id: Uuid, user_id: &str, channel_ids: &[&str]) -> Uuid {
+            let connection_id = Uuid::new_v4();
+            let connection = RegisteredConnection {
+                connection_id,
+                app_id: "app".to_string(),
+                user_id: user_id.to_string(),
+                channel_ids: channel_ids.iter().map(|id| id.to_string()).collect(),
+            };
+
+            self.connections
+                .lock()
+                .unwrap()
+                .insert(connection_id, (node_id, connection));
+
+            connection_id
+        }
+    }
+
+    #[async_trait]
+    impl NodeRegistry for FakeRegistry {
+        async fn dead_nodes(
+            &self,
+            _ttl: Duration,
+        ) -> Result<Vec<(Uuid, i64)>, Box<dyn std::error::Error>> {
+            Ok(self.dead_nodes.clone())
+        }
+
+        async fn claim_node(
+            &self,
+            node_id: &Uuid,
+            _ttl: Duration,
+        ) -> Result<bool, Box<dyn std::error::Error>> {
+            Ok(self.claims.lock().unwrap().insert(*node_id))
+        }
+
+        async fn node_connections(
+            &self,
+            node_id: &Uuid,
+        ) -> Result<Vec<Uuid>, Box<dyn std::error::Error>> {
+            Ok(self
+                .connections
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, (owner, _))| owner == node_id)
+                .map(|(connection_id, _)| *connection_id)
+                .collect())
+        }
+
+        async fn get_connection(
+            &self,
+            connection_id: &Uuid,
+        ) -> Result<Option<RegisteredConnection>, Box<dyn std::error::Error>> {
+            if self.failing.contains(connection_id) {
+                return Err("registry unavailable".into());
+            }
+
+            Ok(self
+                .connections
+                .lock()
+                .unwrap()
+                .get(connection_id)
+                .map(|(_, connection)| connection.clone()))
+        }
+
+        async fn remove_orphan(
+            &self,
+            _node_id: &Uuid,
+            connection: &RegisteredConnection,
+            disconnected_at: OffsetDateTime,
+        ) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+            let mut connections = self.connections.lock().unwrap();
+
+            if connections.remove(&connection.connection_id).is_none() {
+                return Ok(None);
+            }
+
+            self.disconnected_at
+                .lock()
+                .unwrap()
+                .push((connection.connection_id, disconnected_at));
+
+            Ok(Some(
+                connections
+                    .values()
+                    .filter(|(_, other)| {
+                        other.app_id == connection.app_id && other.user_id == connection.user_id
+                    })
+                    .count(),
+            ))
+        }
+
+        async fn remove_node(&self, node_id: &Uuid) -> Result<(), Box<dyn std::error::Error>> {
+            self.removed_nodes.lock().unwrap().push(*node_id);
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct ClosedConnections {
+        closed: StdMutex<Vec<ConnectionState>>,
+    }
+
+    #[async_trait]
+    impl MessageHandler for ClosedConnections {
+        async fn handle(
+            &self,
+            _write: &WsWrite,
+            _message: IncomingMessage,
+            _state: Arc<Mutex<ConnectionState>>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        async fn on_close(
+            &self,
+            state: Arc<Mutex<ConnectionState>>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            let state = std::mem::take(&mut *state.lock().await);
+            self.closed.lock().unwrap().push(state);
+            Ok(())
+        }
+    }
+
+    const LAST_SEEN: i64 = 1_700_000_000;
+
+    fn reaper(registry: &Arc<FakeRegistry>, handler: &Arc<ClosedConnections>) -> SessionReaper {
+        SessionReaper::new(registry.clone(), handler.clone(), Duration::from_secs(30))
+    }
+
+    #[test]
+    fn the_claim_is_only_set_when_absent_and_expires() {
+        let node_id = Uuid::new_v4();
+        let claimant = Uuid::new_v4();
+
+        let args = Session::claim_command(&node_id, &claimant, REAP_CLAIM_TTL)
+            .args_iter()
+            .map(|arg| match arg {
+                Arg::Simple(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+                Arg::Cursor => "CURSOR".to_string(),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            args,
+            [
+                "SET".to_string(),
+                format!("node:{node_id}:reaper"),
+                claimant.to_string(),
+                "NX".to_string(),
+                "EX".to_string(),
+                "60".to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn nodes_claimed_by_another_node_are_left_alone() {
+        let dead_node = Uuid::new_v4();
+        let registry = Arc::new(FakeRegistry {
+            dead_nodes: vec![(dead_node, LAST_SEEN)],
+            ..Default::default()
+        });
+        let handler = Arc::new(ClosedConnections::default());
+
+        registry.claims.lock().unwrap().insert(dead_node);
+        registry.register(dead_node, "alice", &[]);
+
+        reaper(&registry, &handler).reap().await.unwrap();
+
+        assert_eq!(registry.connections.lock().unwrap().len(), 1);
+        assert!(registry.removed_nodes.lock().unwrap().is_empty());
+        assert!(handler.closed.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn connections_of_dead_nodes_are_removed_as_of_their_last_heartbeat() {
+        let dead_node = Uuid::new_v4();
+        let live_node = Uuid::new_v4();
+        let registry = Arc::new(FakeRegistry {
+            dead_nodes: vec![(dead_node, LAST_SEEN)],
+            ..Default::default()
+        });
+        let handler = Arc::new(ClosedConnections::default());
+
+        let orphans = [
+            registry.register(dead_node, "alice", &[]),
+            registry.register(dead_node, "bob", &[]),
+        ];
+        let live = registry.register(live_node, "carol", &[]);
+
+        reaper(&registry, &handler).reap().await.unwrap();
+
+        let connections = registry.connections.lock().unwrap();
+        assert_eq!(connections.keys().collect::<Vec<_>>(), [&live]);
+
+        let last_seen = OffsetDateTime::from_unix_timestamp(LAST_SEEN).unwrap();
+        let mut disconnected_at = registry.disconnected_at.lock().unwrap().clone();
+        disconnected_at.sort();
+        let mut expected = orphans.map(|connection_id| (connection_id, last_seen));
+        expected.sort();
+        assert_eq!(disconnected_at, expected);
+
+        assert_eq!(*registry.removed_nodes.lock().unwrap(), [dead_node]);
+    }
+
+    #[tokio::test]
+    async fn users_are_only_closed_once_they_have_no_connection_left() {
+        let dead_node = Uuid::new_v4();
+        let live_node = Uuid::new_v4();
+        let registry = Arc::new(FakeRegistry {
+            dead_nodes: vec![(dead_node, LAST_SEEN)],
+            ..Default::default()
+        });
+        let handler = Arc::new(ClosedConnections::default());
+
+        registry.register(dead_node, "alice", &["doc-1"]);
+        registry.register(live_node, "alice", &["doc-1"]);
+        let bob = registry.register(dead_node, "bob", &["doc-1", "doc-2"]);
+
+        reaper(&registry, &handler).reap().await.unwrap();
+
+        let closed = handler.closed.lock().unwrap();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].user_id, "bob");
+        assert_eq!(closed[0].connection_id, bob);
+        assert_eq!(
+            closed[0].channel_ids,
+            HashSet::from(["doc-1".to_string(), "doc-2".to_string()])
+        );
+        assert!(closed[0].members_in_document);
+    }
+
+    #[tokio::test]
+    async fn nodes_with_failed_connections_are_kept_for_the_next_reap() {
+        let dead_node = Uuid::new_v4();
+        let mut registry = FakeRegistry {
+            dead_nodes: vec![(dead_node, LAST_SEEN)],
+            ..Default::default()
+        };
+        let failing = registry.register(dead_node, "alice", &[]);
+        registry.register(dead_node, "bob", &[]);
+        registry.failing.insert(failing);
+
+        let registry = Arc::new(registry);
+        let handler = Arc::new(ClosedConnections::default());
+
+        reaper(&registry, &handler).reap().await.unwrap();
+
+        assert_eq!(
+            registry
+                .connections
+                .lock()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            [&failing]
+        );
+        assert!(registry.removed_nodes.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,85 @@
+use redis::aio::ConnectionManager;
+use uuid::Uuid;
+
+// Upper bound on how long a token lives while its connection stays open
+const RESUME_TOKEN_TTL_SECONDS: u64 = 24 * 3600;
+
+// How long after a disconnect the token can still resume the connection's
+// channels. Patch logs keep far longer than this.
+const RESUME_WINDOW_SECONDS: u64 = 60;
+
+/// Resume tokens issued to v3 connections. A client that reconnects within
+/// the resume window presents its token with the last sequence number it saw
+/// and only receives the patches it missed.
+pub struct ResumeStore {
+    redis: ConnectionManager,
+}
+
+impl ResumeStore {
+    pub fn new(redis: ConnectionManager) -> Self {
+        Self { redis }
+    }
+
+    fn get_key(token: &str) -> String {
+        format!("resume:{token}")
+    }
+
+    /// Issue a token for a new connection of `user_id`
+    pub async fn issue(
+        &self,
+        app_id: &str,
+        user_id: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let token = Uuid::new_v4().simple().to_string();
+        let key = Self::get_key(&token);
+        let mut conn = self.redis.clone();
+
+        redis::pipe()
+            .atomic()
+            .cmd("HSET")
+            .arg(&key)
+            .arg("app_id")
+            .arg(app_id)
+            .arg("user_id")
+            .arg(user_id)
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(RESUME_TOKEN_TTL_SECONDS)
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(token)
+    }
+
+    /// Start the resume window once the token's connection is gone
+    pub async fn suspend(&self, token: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        redis::cmd("EXPIRE")
+            .arg(Self::get_key(token))
+            .arg(RESUME_WINDOW_SECONDS)
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// The app and user the token was issued to, `None` once it expired
+    pub async fn get(
+        &self,
+        token: &str,
+    ) -> Result<Option<(String, String)>, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        let (app_id, user_id): (Option<String>, Option<String>) = redis::cmd("HMGET")
+            .arg(Self::get_key(token))
+            .arg("app_id")
+            .arg("user_id")
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(app_id.zip(user_id))
+    }
+}
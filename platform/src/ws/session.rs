@@ -1,27 +1,509 @@
 use super::connection::SessionHandler;
+use crate::storage::MembershipIndex;
 use crate::ws::metrics::MetricsCollector;
+use crate::ws::quota::QuotaEnforcer;
 use async_trait::async_trait;
 use redis::aio::ConnectionManager;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tracing::debug;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, interval};
+use tracing::{debug, error};
 use uuid::Uuid;
 
+// Sorted set of platform nodes scored by their last heartbeat (unix seconds)
+const NODES_KEY: &str = "nodes";
+
+/// A connection as recorded in the registry
+#[derive(Debug, Clone)]
+pub struct RegisteredConnection {
+    pub connection_id: Uuid,
+    pub app_id: String,
+    pub user_id: String,
+    pub channel_ids: HashSet<String>,
+}
+
+/// Registry of live connections shared by every platform node.
+///
+/// Each connection is recorded under the node that owns it, and nodes
+/// heartbeat into `NODES_KEY`. A node that misses heartbeats for longer than
+/// its TTL is dead, and `SessionReaper` cleans up the connections it owned.
+/// Registry keys expire after `key_ttl` unless their node's heartbeat
+/// refreshes them, so nothing outlives a cluster that stopped entirely, and
+/// `QuotaReconciler` then releases the quota slots they held. The heartbeat
+/// keeps the membership of the channels its connections joined too.
 pub struct Session {
     pub redis: ConnectionManager,
     pub metrics_collector: Arc<MetricsCollector>,
+    pub quota_enforcer: Arc<QuotaEnforcer>,
+    membership: Arc<dyn MembershipIndex>,
+    pub node_id: Uuid,
+    key_ttl: Duration,
+    // App and user of each connection this node owns, whose keys it refreshes
+    connections: Mutex<HashMap<Uuid, (String, String)>>,
+    // Channels joined by each connection this node owns
+    channels: Mutex<HashMap<Uuid, HashSet<String>>>,
 }
 
 impl Session {
-    pub fn new(redis: ConnectionManager, metrics_collector: Arc<MetricsCollector>) -> Self {
+    pub fn new(
+        redis: ConnectionManager,
+        metrics_collector: Arc<MetricsCollector>,
+        quota_enforcer: Arc<QuotaEnforcer>,
+        membership: Arc<dyn MembershipIndex>,
+        key_ttl: Duration,
+    ) -> Self {
         Self {
             redis,
             metrics_collector,
+            quota_enforcer,
+            membership,
+            node_id: Uuid::new_v4(),
+            key_ttl,
+            connections: Mutex::new(HashMap::new()),
+            channels: Mutex::new(HashMap::new()),
         }
     }
 
     pub fn get_key(app_id: &str, user_id: &str) -> String {
         format!("session:{}:{}", app_id, user_id)
     }
+
+    pub fn get_connection_key(connection_id: &Uuid) -> String {
+        format!("connection:{connection_id}")
+    }
+
+    pub fn get_connection_channels_key(connection_id: &Uuid) -> String {
+        format!("connection:{connection_id}:channels")
+    }
+
+    pub fn get_node_connections_key(node_id: &Uuid) -> String {
+        format!("node:{node_id}:connections")
+    }
+
+    pub fn get_node_claim_key(node_id: &Uuid) -> String {
+        format!("node:{node_id}:reaper")
+    }
+
+    /// Set the claim on `node_id` unless another node holds it, expiring after `ttl`
+    pub fn claim_command(node_id: &Uuid, claimant: &Uuid, ttl: Duration) -> redis::Cmd {
+        let mut cmd = redis::cmd("SET");
+
+        cmd.arg(Self::get_node_claim_key(node_id))
+            .arg(claimant.to_string())
+            .arg("NX")
+            .arg("EX")
+            .arg(ttl.as_secs());
+
+        cmd
+    }
+
+    /// Record that this node is alive and keep the keys of its connections
+    /// and of the channels they joined
+    pub async fn heartbeat(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let connections = self.connections.lock().await.clone();
+        let channels = self.joined_channels(&connections).await;
+        let ttl = self.key_ttl.as_secs();
+        let mut pipe = redis::pipe();
+
+        pipe.cmd("ZADD")
+            .arg(NODES_KEY)
+            .arg(OffsetDateTime::now_utc().unix_timestamp())
+            .arg(self.node_id.to_string())
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(Self::get_node_connections_key(&self.node_id))
+            .arg(ttl)
+            .ignore();
+
+        for (connection_id, (app_id, user_id)) in &connections {
+            pipe.cmd("EXPIRE")
+                .arg(Self::get_key(app_id, user_id))
+                .arg(ttl)
+                .ignore()
+                .cmd("EXPIRE")
+                .arg(Self::get_connection_key(connection_id))
+                .arg(ttl)
+                .ignore()
+                .cmd("EXPIRE")
+                .arg(Self::get_connection_channels_key(connection_id))
+                .arg(ttl)
+                .ignore();
+        }
+
+        let mut conn = self.redis.clone();
+        pipe.query_async::<()>(&mut conn).await?;
+
+        if !channels.is_empty() {
+            self.membership.refresh(&channels).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Every channel joined by one of `connections`, with its app
+    async fn joined_channels(
+        &self,
+        connections: &HashMap<Uuid, (String, String)>,
+    ) -> Vec<(String, String)> {
+        let channels = self.channels.lock().await;
+        let mut joined = HashSet::new();
+
+        for (connection_id, (app_id, _)) in connections {
+            for channel_id in channels.get(connection_id).into_iter().flatten() {
+                joined.insert((app_id.clone(), channel_id.clone()));
+            }
+        }
+
+        joined.into_iter().collect()
+    }
+
+    /// Heartbeat every `period`, starting immediately
+    pub fn start_heartbeat(self: Arc<Self>, period: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+
+            loop {
+                ticker.tick().await;
+
+                if let Err(e) = self.heartbeat().await {
+                    error!("Failed to record node heartbeat: {e}");
+                }
+            }
+        })
+    }
+
+    /// Nodes whose last heartbeat is older than `ttl`, with that heartbeat time
+    pub async fn dead_nodes(
+        &self,
+        ttl: Duration,
+    ) -> Result<Vec<(Uuid, i64)>, Box<dyn std::error::Error>> {
+        let deadline = OffsetDateTime::now_utc().unix_timestamp() - ttl.as_secs() as i64;
+        let mut conn = self.redis.clone();
+
+        let nodes: Vec<(String, i64)> = redis::cmd("ZRANGEBYSCORE")
+            .arg(NODES_KEY)
+            .arg("-inf")
+            .arg(format!("({deadline}"))
+            .arg("WITHSCORES")
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(nodes
+            .into_iter()
+            .filter_map(|(node_id, last_seen)| Some((node_id.parse().ok()?, last_seen)))
+            .filter(|(node_id, _)| *node_id != self.node_id)
+            .collect())
+    }
+
+    /// Last heartbeat (unix seconds) of every node that has not been reaped
+    pub async fn heartbeats(&self) -> Result<HashMap<Uuid, i64>, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        let nodes: Vec<(String, i64)> = redis::cmd("ZRANGE")
+            .arg(NODES_KEY)
+            .arg(0)
+            .arg(-1)
+            .arg("WITHSCORES")
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(nodes
+            .into_iter()
+            .filter_map(|(node_id, last_seen)| Some((node_id.parse().ok()?, last_seen)))
+            .collect())
+    }
+
+    /// The node owning each connection, `None` for connections not in the registry
+    pub async fn connection_owners(
+        &self,
+        connection_ids: &[Uuid],
+    ) -> Result<Vec<Option<Uuid>>, Box<dyn std::error::Error>> {
+        if connection_ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut pipe = redis::pipe();
+
+        for connection_id in connection_ids {
+            pipe.cmd("HGET")
+                .arg(Self::get_connection_key(connection_id))
+                .arg("node_id");
+        }
+
+        let mut conn = self.redis.clone();
+        let owners: Vec<Option<String>> = pipe.query_async(&mut conn).await?;
+
+        Ok(owners
+            .into_iter()
+            .map(|node_id| node_id.and_then(|node_id| node_id.parse().ok()))
+            .collect())
+    }
+
+    /// Connection ids still registered to `node_id`
+    pub async fn node_connections(
+        &self,
+        node_id: &Uuid,
+    ) -> Result<Vec<Uuid>, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        let connection_ids: Vec<String> = redis::cmd("SMEMBERS")
+            .arg(Self::get_node_connections_key(node_id))
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(connection_ids
+            .iter()
+            .filter_map(|connection_id| connection_id.parse().ok())
+            .collect())
+    }
+
+    pub async fn get_connection(
+        &self,
+        connection_id: &Uuid,
+    ) -> Result<Option<RegisteredConnection>, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        let (app_id, user_id, channel_ids): (Option<String>, Option<String>, HashSet<String>) =
+            redis::pipe()
+                .cmd("HGET")
+                .arg(Self::get_connection_key(connection_id))
+                .arg("app_id")
+                .cmd("HGET")
+                .arg(Self::get_connection_key(connection_id))
+                .arg("user_id")
+                .cmd("SMEMBERS")
+                .arg(Self::get_connection_channels_key(connection_id))
+                .query_async(&mut conn)
+                .await?;
+
+        let (Some(app_id), Some(user_id)) = (app_id, user_id) else {
+            return Ok(None);
+        };
+
+        Ok(Some(RegisteredConnection {
+            connection_id: *connection_id,
+            app_id,
+            user_id,
+            channel_ids,
+        }))
+    }
+
+    /// Drop a connection from the registry, returning whether it was still
+    /// registered and how many connections its user still has in the app
+    async fn unregister(
+        &self,
+        app_id: &str,
+        user_id: &str,
+        connection_id: &Uuid,
+        node_id: &Uuid,
+    ) -> Result<(bool, usize), Box<dyn std::error::Error>> {
+        let key = Self::get_key(app_id, user_id);
+        let mut conn = self.redis.clone();
+
+        let (registered, remaining): (bool, usize) = redis::pipe()
+            .atomic()
+            .cmd("SREM")
+            .arg(&key)
+            .arg(connection_id.to_string())
+            .ignore()
+            .cmd("DEL")
+            .arg(Self::get_connection_key(connection_id))
+            .cmd("DEL")
+            .arg(Self::get_connection_channels_key(connection_id))
+            .ignore()
+            .cmd("SREM")
+            .arg(Self::get_node_connections_key(node_id))
+            .arg(connection_id.to_string())
+            .ignore()
+            .cmd("SCARD")
+            .arg(&key)
+            .query_async(&mut conn)
+            .await?;
+
+        Ok((registered, remaining))
+    }
+
+    /// Remove a connection owned by a dead node, returning how many connections
+    /// its user still has, or `None` if it was already removed. The node was
+    /// last seen alive at `disconnected_at`.
+    pub async fn remove_orphan(
+        &self,
+        node_id: &Uuid,
+        connection: &RegisteredConnection,
+        disconnected_at: OffsetDateTime,
+    ) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+        let (registered, remaining) = self
+            .unregister(
+                &connection.app_id,
+                &connection.user_id,
+                &connection.connection_id,
+                node_id,
+            )
+            .await?;
+
+        // Only the caller that deleted the entry releases the quota
+        if !registered {
+            return Ok(None);
+        }
+
+        self.quota_enforcer
+            .release_connection(&connection.app_id, &connection.connection_id)
+            .await?;
+
+        self.metrics_collector
+            .record_connection_end_at(&connection.connection_id, disconnected_at)
+            .await?;
+
+        Ok(Some(remaining))
+    }
+
+    /// Claim a dead node so no other node cleans it up at the same time. The
+    /// claim lapses after `ttl`, letting another node pick up what is left.
+    pub async fn claim_node(
+        &self,
+        node_id: &Uuid,
+        ttl: Duration,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        let claimed: Option<String> = Self::claim_command(node_id, &self.node_id, ttl)
+            .query_async(&mut conn)
+            .await?;
+
+        Ok(claimed.is_some())
+    }
+
+    /// Forget a dead node once all of its connections are removed
+    pub async fn remove_node(&self, node_id: &Uuid) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.redis.clone();
+
+        redis::pipe()
+            .atomic()
+            .cmd("ZREM")
+            .arg(NODES_KEY)
+            .arg(node_id.to_string())
+            .ignore()
+            .cmd("DEL")
+            .arg(Self::get_node_connections_key(node_id))
+            .ignore()
+            .query_async::<()>(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Registry lookups for tasks that look after connections of every node
+#[async_trait]
+pub trait ConnectionRegistry: Send + Sync {
+    /// The node owning each connection, `None` for connections not in the registry
+    async fn connection_owners(
+        &self,
+        connection_ids: &[Uuid],
+    ) -> Result<Vec<Option<Uuid>>, Box<dyn std::error::Error>>;
+
+    /// Last heartbeat (unix seconds) of every node that has not been reaped
+    async fn heartbeats(&self) -> Result<HashMap<Uuid, i64>, Box<dyn std::error::Error>>;
+}
+
+#[async_trait]
+impl ConnectionRegistry for Session {
+    async fn connection_owners(
+        &self,
+        connection_ids: &[Uuid],
+    ) -> Result<Vec<Option<Uuid>>, Box<dyn std::error::Error>> {
+        self.connection_owners(connection_ids).await
+    }
+
+    async fn heartbeats(&self) -> Result<HashMap<Uuid, i64>, Box<dyn std::error::Error>> {
+        self.heartbeats().await
+    }
+}
+
+/// Registry operations for cleaning up after nodes that stopped heartbeating
+#[async_trait]
+pub trait NodeRegistry: Send + Sync {
+    /// Nodes whose last heartbeat is older than `ttl`, with that heartbeat time
+    async fn dead_nodes(
+        &self,
+        ttl: Duration,
+    ) -> Result<Vec<(Uuid, i64)>, Box<dyn std::error::Error>>;
+
+    /// Whether this node got the claim on `node_id`, held for `ttl`
+    async fn claim_node(
+        &self,
+        node_id: &Uuid,
+        ttl: Duration,
+    ) -> Result<bool, Box<dyn std::error::Error>>;
+
+    async fn node_connections(
+        &self,
+        node_id: &Uuid,
+    ) -> Result<Vec<Uuid>, Box<dyn std::error::Error>>;
+
+    async fn get_connection(
+        &self,
+        connection_id: &Uuid,
+    ) -> Result<Option<RegisteredConnection>, Box<dyn std::error::Error>>;
+
+    /// How many connections the user still has, `None` if already removed
+    async fn remove_orphan(
+        &self,
+        node_id: &Uuid,
+        connection: &RegisteredConnection,
+        disconnected_at: OffsetDateTime,
+    ) -> Result<Option<usize>, Box<dyn std::error::Error>>;
+
+    async fn remove_node(&self, node_id: &Uuid) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+#[async_trait]
+impl NodeRegistry for Session {
+    async fn dead_nodes(
+        &self,
+        ttl: Duration,
+    ) -> Result<Vec<(Uuid, i64)>, Box<dyn std::error::Error>> {
+        self.dead_nodes(ttl).await
+    }
+
+    async fn claim_node(
+        &self,
+        node_id: &Uuid,
+        ttl: Duration,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        self.claim_node(node_id, ttl).await
+    }
+
+    async fn node_connections(
+        &self,
+        node_id: &Uuid,
+    ) -> Result<Vec<Uuid>, Box<dyn std::error::Error>> {
+        self.node_connections(node_id).await
+    }
+
+    async fn get_connection(
+        &self,
+        connection_id: &Uuid,
+    ) -> Result<Option<RegisteredConnection>, Box<dyn std::error::Error>> {
+        self.get_connection(connection_id).await
+    }
+
+    async fn remove_orphan(
+        &self,
+        node_id: &Uuid,
+        connection: &RegisteredConnection,
+        disconnected_at: OffsetDateTime,
+    ) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+        self.remove_orphan(node_id, connection, disconnected_at)
+            .await
+    }
+
+    async fn remove_node(&self, node_id: &Uuid) -> Result<(), Box<dyn std::error::Error>> {
+        self.remove_node(node_id).await
+    }
 }
 
 #[async_trait]
@@ -32,14 +514,62 @@ impl SessionHandler for Session {
         user_id: &str,
         connection_id: &Uuid,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        self.quota_enforcer
+            .acquire_connection(app_id, connection_id)
+            .await?;
+
         let key = Self::get_key(app_id, user_id);
+        let connection_key = Self::get_connection_key(connection_id);
+        let node_connections_key = Self::get_node_connections_key(&self.node_id);
+        let ttl = self.key_ttl.as_secs();
         let mut conn = self.redis.clone();
 
-        redis::cmd("SADD")
+        let registered = redis::pipe()
+            .atomic()
+            .cmd("SADD")
             .arg(&key)
             .arg(connection_id.to_string())
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(ttl)
+            .ignore()
+            .cmd("HSET")
+            .arg(&connection_key)
+            .arg("app_id")
+            .arg(app_id)
+            .arg("user_id")
+            .arg(user_id)
+            .arg("node_id")
+            .arg(self.node_id.to_string())
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(&connection_key)
+            .arg(ttl)
+            .ignore()
+            .cmd("SADD")
+            .arg(&node_connections_key)
+            .arg(connection_id.to_string())
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(&node_connections_key)
+            .arg(ttl)
+            .ignore()
             .query_async::<()>(&mut conn)
-            .await?;
+            .await;
+
+        if let Err(e) = registered {
+            self.quota_enforcer
+                .release_connection(app_id, connection_id)
+                .await?;
+
+            return Err(e.into());
+        }
+
+        self.connections
+            .lock()
+            .await
+            .insert(*connection_id, (app_id.to_string(), user_id.to_string()));
 
         debug!("Added connection {connection_id} to app {app_id}");
 
@@ -50,21 +580,59 @@ impl SessionHandler for Session {
         Ok(())
     }
 
-    async fn remove(
+    async fn join(
         &self,
-        app_id: &str,
-        user_id: &str,
         connection_id: &Uuid,
+        channel_id: &str,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let key = Self::get_key(app_id, user_id);
+        let key = Self::get_connection_channels_key(connection_id);
         let mut conn = self.redis.clone();
 
-        redis::cmd("SREM")
+        redis::pipe()
+            .cmd("SADD")
             .arg(&key)
-            .arg(connection_id.to_string())
+            .arg(channel_id)
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(&key)
+            .arg(self.key_ttl.as_secs())
+            .ignore()
             .query_async::<()>(&mut conn)
             .await?;
 
+        self.channels
+            .lock()
+            .await
+            .entry(*connection_id)
+            .or_default()
+            .insert(channel_id.to_string());
+
+        Ok(())
+    }
+
+    async fn remove(
+        &self,
+        app_id: &str,
+        user_id: &str,
+        connection_id: &Uuid,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.connections.lock().await.remove(connection_id);
+        self.channels.lock().await.remove(connection_id);
+
+        let (registered, _) = self
+            .unregister(app_id, user_id, connection_id, &self.node_id)
+            .await?;
+
+        // The reaper may have removed it already if this node looked dead,
+        // only the caller that deleted the entry releases the quota
+        if !registered {
+            return Ok(());
+        }
+
+        self.quota_enforcer
+            .release_connection(app_id, connection_id)
+            .await?;
+
         self.metrics_collector
             .record_connection_end(connection_id)
             .await?;
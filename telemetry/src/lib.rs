@@ -0,0 +1,120 @@
+//! Tracing export shared by the api, the platform and cron. Each service keeps
+//! its own request and message propagation on top of this.
+
+use opentelemetry::global;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    Resource,
+    error::{OTelSdkError, OTelSdkResult},
+    propagation::TraceContextPropagator,
+    trace::{SdkTracerProvider, SpanData, SpanExporter},
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TracingExporter {
+    #[default]
+    None,
+    Otlp,
+    File,
+}
+
+/// Build the tracer provider selected by `exporter` and install it globally
+/// with the W3C propagator, or return `None` when tracing export is disabled
+pub fn setup_tracer_provider(
+    service_name: &'static str,
+    exporter: TracingExporter,
+    otlp_endpoint: &str,
+    file_path: &str,
+) -> Result<Option<SdkTracerProvider>, Box<dyn std::error::Error>> {
+    let builder = SdkTracerProvider::builder()
+        .with_resource(Resource::builder().with_service_name(service_name).build());
+
+    let provider = match exporter {
+        TracingExporter::None => return Ok(None),
+        TracingExporter::Otlp => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(otlp_endpoint)
+                .build()?;
+
+            builder.with_batch_exporter(exporter).build()
+        }
+        TracingExporter::File => builder
+            .with_batch_exporter(FileSpanExporter::new(file_path)?)
+            .build(),
+    };
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+
+    Ok(Some(provider))
+}
+
+/// Writes finished spans as JSON lines, handy for local debugging and tests
+/// without a collector
+#[derive(Debug)]
+pub struct FileSpanExporter {
+    file: Mutex<File>,
+}
+
+impl FileSpanExporter {
+    pub fn new(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default()
+}
+
+impl SpanExporter for FileSpanExporter {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let mut lines = String::new();
+
+        for span in batch {
+            let attributes = span
+                .attributes
+                .iter()
+                .map(|kv| (kv.key.to_string(), kv.value.to_string()))
+                .collect::<HashMap<_, _>>();
+
+            let line = json!({
+                "trace_id": span.span_context.trace_id().to_string(),
+                "span_id": span.span_context.span_id().to_string(),
+                "parent_span_id": span.parent_span_id.to_string(),
+                "name": span.name,
+                "start_time_unix_nano": unix_nanos(span.start_time),
+                "end_time_unix_nano": unix_nanos(span.end_time),
+                "attributes": attributes,
+                "links": span
+                    .links
+                    .iter()
+                    .map(|link| link.span_context.span_id().to_string())
+                    .collect::<Vec<_>>(),
+            });
+
+            lines.push_str(&line.to_string());
+            lines.push('\n');
+        }
+
+        self.file
+            .lock()
+            .map_err(|e| OTelSdkError::InternalFailure(e.to_string()))?
+            .write_all(lines.as_bytes())
+            .map_err(|e| OTelSdkError::InternalFailure(e.to_string()))
+    }
+}